@@ -8,6 +8,7 @@
 enum LFAngularBasis {
     LFPillbox = 0,
     LFDirac = 1,
+    LFLinear = 2,
 };
 
 /// Optical transformation
@@ -0,0 +1,247 @@
+extern crate num;
+extern crate nalgebra;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use geom::*;
+use imager::*;
+use backend::*;
+
+/// Adjoint mismatches of an imager
+///
+/// Each entry is the relative difference between `<A x, y>` and
+/// `<x, A' y>` for random `x` and `y`; see `relative_mismatch`.
+#[derive(Clone, Debug)]
+pub struct AdjointReport<F> {
+    /// Mismatch of each angle on its own
+    pub angles: Vec<F>,
+
+    /// Mismatch of each strided subset of angles
+    pub subsets: Vec<F>,
+
+    /// Mismatch of the projection over all angles
+    pub full: F,
+}
+
+impl<F: Float> AdjointReport<F> {
+    /// Returns the largest mismatch in the report
+    pub fn worst(self: &Self) -> F {
+        self.angles
+            .iter()
+            .chain(self.subsets.iter())
+            .fold(self.full, |w, &e| w.max(e))
+    }
+}
+
+/// Returns `|a - b| / max(|a|, |b|)`, or zero if both are zero
+pub fn relative_mismatch<F: Float>(a: F, b: F) -> F {
+    let scale = a.abs().max(b.abs());
+    if scale == F::zero() {
+        F::zero()
+    } else {
+        (a - b).abs() / scale
+    }
+}
+
+/// Inner product accumulated in double precision
+fn dot<F: Float + ToPrimitive>(a: &[F], b: &[F]) -> f64 {
+    a.iter().zip(b.iter()).fold(0f64, |s, (x, y)| {
+        s + x.to_f64().unwrap() * y.to_f64().unwrap()
+    })
+}
+
+/// Checks that an imager's backprojection is the transpose of its projection
+///
+/// Random `x` and `y` are drawn once and compared through every single
+/// angle, through `num_subsets` strided subsets and through the full
+/// projection.  Returns the mismatches; thresholds are up to the caller.
+pub fn check_adjoint<F, G, B, I>(imager: &mut I,
+                                 num_subsets: usize,
+                                 queue: &B::Queue)
+                                 -> Result<AdjointReport<F>, B::Error>
+    where F: Float + FromPrimitive + ToPrimitive,
+          G: Geometry<F>,
+          B: Backend<F>,
+          I: Imager<F, G, B> + ?Sized
+{
+    let image_geom = imager.detector().image_geometry();
+    let x_host = imager.geometry().rands();
+    let y_host = image_geom.rands();
+    let x = try!(B::create_buffer(queue, &x_host));
+    let y = try!(B::create_buffer(queue, &y_host));
+
+    let image_zeros = image_geom.zeros();
+    let object_zeros = imager.geometry().zeros();
+    let mut ax_host = image_zeros.clone();
+    let mut aty_host = object_zeros.clone();
+
+    // mismatch of the angles in `angles`, or all of them
+    let mut mismatch = |imager: &mut I, angles: Option<&[usize]>| -> Result<F, B::Error> {
+        let mut ax = try!(B::create_buffer(queue, &image_zeros));
+        let mut aty = try!(B::create_buffer(queue, &object_zeros));
+        let (forw_evt, back_evt) = match angles {
+            Some(angles) => {
+                (try!(imager.forw_subset(&x, &mut ax, angles, &[])),
+                 try!(imager.back_subset(&y, &mut aty, angles, &[])))
+            }
+            None => (try!(imager.forw(&x, &mut ax, &[])), try!(imager.back(&y, &mut aty, &[]))),
+        };
+        try!(B::read_buffer(queue, &ax, &mut ax_host, &[forw_evt]));
+        try!(B::read_buffer(queue, &aty, &mut aty_host, &[back_evt]));
+
+        let forw = dot(&ax_host, &y_host);
+        let back = dot(&x_host, &aty_host);
+        Ok(F::from_f64(relative_mismatch(forw, back)).unwrap())
+    };
+
+    let mut angles = Vec::with_capacity(imager.na());
+    for ia in 0..imager.na() {
+        angles.push(try!(mismatch(imager, Some(&[ia]))));
+    }
+
+    let mut subsets = Vec::new();
+    let strided = imager.angular_plane().subsets_strided(num_subsets);
+    for subset in strided.iter() {
+        subsets.push(try!(mismatch(imager, Some(subset))));
+    }
+
+    let full = try!(mismatch(imager, None));
+
+    Ok(AdjointReport {
+        angles: angles,
+        subsets: subsets,
+        full: full,
+    })
+}
+
+/// Camera configurations in `cfg/cameras`, without their assets loaded
+///
+/// Detectors are cropped to their central `64x64` pixels to keep the tests
+/// quick; the pixel pitch is unchanged.
+#[cfg(test)]
+fn test_camera_configs() -> Vec<(::std::path::PathBuf, ::camera::CameraConfig<f32>)> {
+    use camera::*;
+    use serialize::*;
+    use scene::*;
+    use std::cmp::min;
+    use std::fs::read_dir;
+
+    let mut paths: Vec<_> = read_dir("cfg/cameras")
+                                .unwrap()
+                                .map(|entry| entry.unwrap().path())
+                                .filter(|path| path.extension().map_or(false, |ext| ext == "toml"))
+                                .collect();
+    paths.sort();
+
+    let mut tr = Vec::new();
+    for path in paths.into_iter() {
+        let mut config = CameraConfig::<f32>::from_map(&table_from_file(&path).unwrap()).unwrap();
+        {
+            let detector = match config {
+                CameraConfig::SingleLensCamera(ref mut c) => &mut c.detector,
+                CameraConfig::CodedApertureCamera(ref mut c) => &mut c.detector,
+                CameraConfig::PlenopticCamera(ref mut c) => &mut c.detector,
+            };
+            detector.ns = min(detector.ns, 64);
+            detector.nt = min(detector.nt, 64);
+        }
+        tr.push((path, config));
+    }
+    tr
+}
+
+/// A small volume a few focal lengths in front of the camera, about as wide
+/// as the main lens
+#[cfg(test)]
+fn test_scene_for(config: &::camera::CameraConfig<f32>)
+                  -> (::light_volume::LightVolume<f32>, self::nalgebra::Vector3<f32>) {
+    use camera::*;
+    use light_volume::*;
+    use self::nalgebra::Vector3;
+
+    let lens = match config {
+        &CameraConfig::SingleLensCamera(ref c) => &c.lens,
+        &CameraConfig::CodedApertureCamera(ref c) => &c.lens,
+        &CameraConfig::PlenopticCamera(ref c) => &c.lens,
+    };
+    let d = 2f32 * lens.radius_s.max(lens.radius_t) / 16f32;
+    let vg = LightVolume {
+        nx: 16,
+        ny: 16,
+        nz: 4,
+        dx: d,
+        dy: d,
+        dz: d,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    (vg, Vector3::new(0f32, 0f32, -4f32 * lens.focal_length_s))
+}
+
+#[cfg(test)]
+fn test_bases() -> Vec<::angular_plane::AngularBasis> {
+    use angular_plane::*;
+    vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear]
+}
+
+#[test]
+fn test_host_imagers_are_adjoint() {
+    use camera::*;
+    use single_lens_imager::*;
+
+    for (path, config) in test_camera_configs().into_iter() {
+        let camera = match config {
+            CameraConfig::SingleLensCamera(camera) => camera,
+            _ => continue,
+        };
+        let (vg, position) = test_scene_for(&CameraConfig::SingleLensCamera(camera.clone()));
+        for basis in test_bases().into_iter() {
+            let mut imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                             camera.clone(),
+                                                             position,
+                                                             3,
+                                                             basis.clone());
+            let report = check_adjoint(&mut imager, 2, &HostQueue).unwrap();
+            println!("{} {:?}: worst adjoint mismatch {}",
+                     path.display(),
+                     basis,
+                     report.worst());
+            assert!(report.worst() < 1e-3);
+        }
+    }
+}
+
+#[test]
+fn test_imagers_are_adjoint() {
+    use env::*;
+    use serialize::*;
+    use isometry::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    for (path, mut config) in test_camera_configs().into_iter() {
+        config.load_assets(&path).unwrap();
+        let (vg, position) = test_scene_for(&config);
+        let rotations = vec![None, Some(rotation_from_euler_angles(10f32, 0f32, 20f32))];
+        for basis in test_bases().into_iter() {
+            for rotation in rotations.iter() {
+                let mut imager = config.volume_imager(vg.clone(),
+                                                      position,
+                                                      rotation.clone(),
+                                                      3,
+                                                      basis.clone(),
+                                                      queue.clone())
+                                       .unwrap();
+                let report = check_adjoint(&mut *imager, 2, queue).unwrap();
+                println!("{} {:?} rotated {}: worst adjoint mismatch {}",
+                         path.display(),
+                         basis,
+                         rotation.is_some(),
+                         report.worst());
+                assert!(report.worst() < 1e-3);
+            }
+        }
+    }
+}
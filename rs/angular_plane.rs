@@ -1,13 +1,21 @@
 extern crate num;
-use self::num::{Float, FromPrimitive};
+extern crate toml;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
 use bounding_geometry::*;
 use occluder::*;
+use spline_kernel::*;
+use serialize::*;
 
 /// Basis functions for angles
 #[derive(Clone, Debug)]
 pub enum AngularBasis {
     Pillbox,
     Dirac,
+
+    /// Tent functions reaching zero at the neighbouring samples, i.e.,
+    /// linear interpolation between angles
+    Linear,
 }
 
 /// Objects that can produce angular planes
@@ -53,6 +61,96 @@ impl<F: Float> AngularPlane<F> {
     }
 }
 
+impl<F: Float + FromPrimitive> AngularPlane<F> {
+    /// Returns the weights that resample coefficients on this plane onto
+    /// another angular plane
+    ///
+    /// Weights are returned as `(dst_ia, src_ia, weight)` triplets sorted by
+    /// `dst_ia`. Both planes must live in the same coordinate system.
+    ///
+    /// Pillbox coefficients are averages over their cell, so a Pillbox
+    /// destination averages the source over each destination cell: Pillbox
+    /// sources are weighted by their overlap and Dirac sources by their cell
+    /// area when their sample point falls in the cell.  A Dirac destination
+    /// samples the source cell containing each destination point.
+    ///
+    /// Linear coefficients are the values at their sample points, so a
+    /// Linear destination samples the source like a Dirac destination does,
+    /// and a Linear source is interpolated at Dirac and Linear destination
+    /// points and averaged over Pillbox destination cells.
+    ///
+    /// Aperture weights `w` are not applied.
+    pub fn rebin_weights(self: &Self, dst: &AngularPlane<F>) -> Vec<(usize, usize, F)> {
+        let c2 = F::one() + F::one();
+        let src_hs = self.ds.abs() / c2;
+        let src_ht = self.dt.abs() / c2;
+        let dst_hs = dst.ds.abs() / c2;
+        let dst_ht = dst.dt.abs() / c2;
+        let src_area = self.ds.abs() * self.dt.abs();
+        let dst_area = dst.ds.abs() * dst.dt.abs();
+
+        // half-open containment avoids double-counting points on cell edges
+        let contains = |sc: F, tc: F, hs: F, ht: F, s: F, t: F| {
+            s >= sc - hs && s < sc + hs && t >= tc - ht && t < tc + ht
+        };
+
+        // source tents reach zero at the neighbouring source samples
+        let tent = |c: F, h: F, x: F| (F::one() - (x - c).abs() / h).max(F::zero());
+        let tent_kernel = |c: F, h: F| {
+            SplineKernel::new_trapezoid(F::one(), F::zero(), &[c - h, c, c, c + h])
+        };
+
+        let mut tr = Vec::new();
+        for dst_ia in 0..dst.na() {
+            let (ds_c, dt_c) = (dst.s[dst_ia], dst.t[dst_ia]);
+            for src_ia in 0..self.na() {
+                let (ss_c, st_c) = (self.s[src_ia], self.t[src_ia]);
+                let weight = match (&self.basis, &dst.basis) {
+                    (&AngularBasis::Linear, &AngularBasis::Dirac) |
+                    (&AngularBasis::Linear, &AngularBasis::Linear) => {
+                        tent(ss_c, self.ds.abs(), ds_c) * tent(st_c, self.dt.abs(), dt_c)
+                    }
+                    (&AngularBasis::Linear, &AngularBasis::Pillbox) => {
+                        let ws = tent_kernel(ss_c, self.ds.abs())
+                                     .integrate(F::zero(), ds_c - dst_hs, ds_c + dst_hs);
+                        let wt = tent_kernel(st_c, self.dt.abs())
+                                     .integrate(F::zero(), dt_c - dst_ht, dt_c + dst_ht);
+                        ws * wt / dst_area
+                    }
+                    (_, &AngularBasis::Dirac) |
+                    (_, &AngularBasis::Linear) => {
+                        if contains(ss_c, st_c, src_hs, src_ht, ds_c, dt_c) {
+                            F::one()
+                        } else {
+                            F::zero()
+                        }
+                    }
+                    (&AngularBasis::Dirac, &AngularBasis::Pillbox) => {
+                        if contains(ds_c, dt_c, dst_hs, dst_ht, ss_c, st_c) {
+                            src_area / dst_area
+                        } else {
+                            F::zero()
+                        }
+                    }
+                    (&AngularBasis::Pillbox, &AngularBasis::Pillbox) => {
+                        let ws = ((ss_c + src_hs).min(ds_c + dst_hs) -
+                                  (ss_c - src_hs).max(ds_c - dst_hs))
+                                     .max(F::zero());
+                        let wt = ((st_c + src_ht).min(dt_c + dst_ht) -
+                                  (st_c - src_ht).max(dt_c - dst_ht))
+                                     .max(F::zero());
+                        ws * wt / dst_area
+                    }
+                };
+                if weight > F::zero() {
+                    tr.push((dst_ia, src_ia, weight));
+                }
+            }
+        }
+        tr
+    }
+}
+
 impl<F, T> AsAngularPlane<F> for T
     where F: Float + FromPrimitive,
           T: BoundingGeometry<F> + Occluder<F>
@@ -89,3 +187,94 @@ impl<F, T> AsAngularPlane<F> for T
         }
     }
 }
+
+impl AngularBasis {
+    /// Parses a basis name as used in configuration files and on the
+    /// command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dirac" => Some(AngularBasis::Dirac),
+            "pillbox" => Some(AngularBasis::Pillbox),
+            "linear" => Some(AngularBasis::Linear),
+            _ => None,
+        }
+    }
+
+    /// Returns the name parsed by `from_name`
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            &AngularBasis::Dirac => "dirac",
+            &AngularBasis::Pillbox => "pillbox",
+            &AngularBasis::Linear => "linear",
+        }
+    }
+}
+
+fn floats_from_value<F: FromPrimitive>(value: Option<&Value>) -> Option<Vec<F>> {
+    match value {
+        Some(&Value::Array(ref arr)) => {
+            let mut tr = Vec::with_capacity(arr.len());
+            for it in arr.iter() {
+                match it {
+                    &Value::Float(f) => tr.push(F::from_f64(f).unwrap()),
+                    _ => return None,
+                }
+            }
+            Some(tr)
+        }
+        _ => None,
+    }
+}
+
+fn floats_into_value<F: ToPrimitive>(floats: &[F]) -> Value {
+    Value::Array(floats.iter().map(|f| Value::Float(f.to_f64().unwrap())).collect())
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for AngularPlane<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let ds = map.get("ds");
+        let dt = map.get("dt");
+        let basis = map.get("basis");
+        let s = floats_from_value(map.get("s"));
+        let t = floats_from_value(map.get("t"));
+        let w = floats_from_value(map.get("w"));
+
+        match (ds, dt, basis, s, t, w) {
+            (Some(&Value::Float(ds)),
+             Some(&Value::Float(dt)),
+             Some(&Value::String(ref basis)),
+             Some(s),
+             Some(t),
+             Some(w)) => {
+                let basis = match AngularBasis::from_name(basis) {
+                    Some(basis) => basis,
+                    None => return None,
+                };
+                if s.len() != t.len() || s.len() != w.len() {
+                    return None;
+                }
+                Some(AngularPlane {
+                    ds: F::from_f64(ds).unwrap(),
+                    dt: F::from_f64(dt).unwrap(),
+                    basis: basis,
+                    s: s,
+                    t: t,
+                    w: w,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        tr.insert("ds".to_string(), Value::Float(F::to_f64(&self.ds).unwrap()));
+        tr.insert("dt".to_string(), Value::Float(F::to_f64(&self.dt).unwrap()));
+        tr.insert("basis".to_string(),
+                  Value::String(self.basis.name().to_string()));
+        tr.insert("s".to_string(), floats_into_value(&self.s));
+        tr.insert("t".to_string(), floats_into_value(&self.t));
+        tr.insert("w".to_string(), floats_into_value(&self.w));
+        tr
+    }
+}
@@ -0,0 +1,554 @@
+extern crate num;
+extern crate toml;
+use serialize::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
+use image_geom::*;
+use occluder::*;
+use bounding_geometry::*;
+use polygon::*;
+use geom::*;
+use std::path::Path;
+use lens::*;
+use angular_plane::*;
+
+/// Image geometry covering the box `center +/- half_width`
+fn bounding_box<F: Float + FromPrimitive>(center_s: F,
+                                          center_t: F,
+                                          half_s: F,
+                                          half_t: F,
+                                          ns: usize,
+                                          nt: usize)
+                                          -> ImageGeometry<F> {
+    let ds = F::from_f32(2f32).unwrap() * half_s / F::from_usize(ns).unwrap();
+    let dt = F::from_f32(2f32).unwrap() * half_t / F::from_usize(nt).unwrap();
+    ImageGeometry {
+        ns: ns,
+        nt: nt,
+        ds: ds,
+        dt: dt,
+        offset_s: -center_s / ds,
+        offset_t: -center_t / dt,
+    }
+}
+
+/// Fraction of `[s0, s1] x [t0, t1]` outside an aperture letting `area`
+/// of it through
+fn occluded_fraction<F: Float>(area: F, s0: F, s1: F, t0: F, t1: F) -> F {
+    let total = ((s1 - s0) * (t1 - t0)).abs();
+    (F::one() - area / total).max(F::zero()).min(F::one())
+}
+
+fn get_float<F: FromPrimitive>(map: &Table, key: &str) -> Option<F> {
+    match map.get(key) {
+        Some(&Value::Float(x)) => F::from_f64(x),
+        _ => None,
+    }
+}
+
+fn put_float<F: ToPrimitive>(map: &mut Table, key: &str, x: F) {
+    map.insert(key.to_string(), Value::Float(F::to_f64(&x).unwrap()));
+}
+
+/// Regular polygon, e.g., the opening of an iris with straight blades
+#[derive(Clone, Debug)]
+pub struct PolygonAperture<F: Float> {
+    pub center_s: F,
+    pub center_t: F,
+
+    /// Distance from the center to each vertex
+    pub radius: F,
+    pub sides: usize,
+
+    /// Angle of the first vertex in radians
+    pub rotation: F,
+}
+
+impl<F: Float + FromPrimitive> PolygonAperture<F> {
+    /// Returns the vertices in counterclockwise order
+    pub fn vertices(self: &Self) -> Vec<(F, F)> {
+        let n = F::from_usize(self.sides).unwrap();
+        let two_pi = F::from_f64(2f64 * ::std::f64::consts::PI).unwrap();
+        (0..self.sides)
+            .map(|k| {
+                let angle = self.rotation + two_pi * F::from_usize(k).unwrap() / n;
+                (self.center_s + self.radius * angle.cos(),
+                 self.center_t + self.radius * angle.sin())
+            })
+            .collect()
+    }
+}
+
+impl<F: Float + FromPrimitive> BoundingGeometry<F> for PolygonAperture<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        bounding_box(self.center_s, self.center_t, self.radius, self.radius, ns, nt)
+    }
+}
+
+impl<F: Float + FromPrimitive> Occluder<F> for PolygonAperture<F> {
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        let v = self.vertices();
+        for ix in 0..v.len() {
+            let (a, b) = (v[ix], v[(ix + 1) % v.len()]);
+            if (b.0 - a.0) * (t - a.1) - (b.1 - a.1) * (s - a.0) < F::zero() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, _: usize) -> F {
+        let area = polygon_box_area(&self.vertices(),
+                                    s0.min(s1),
+                                    s0.max(s1),
+                                    t0.min(t1),
+                                    t0.max(t1));
+        occluded_fraction(area, s0, s1, t0, t1)
+    }
+}
+
+/// Circular aperture with a circular central obstruction, e.g., a
+/// catadioptric lens
+#[derive(Clone, Debug)]
+pub struct AnnularAperture<F: Float> {
+    pub center_s: F,
+    pub center_t: F,
+    pub outer_radius: F,
+
+    /// Radius of the obstruction; zero for a plain circular aperture
+    pub inner_radius: F,
+}
+
+impl<F: Float + FromPrimitive> BoundingGeometry<F> for AnnularAperture<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        bounding_box(self.center_s,
+                     self.center_t,
+                     self.outer_radius,
+                     self.outer_radius,
+                     ns,
+                     nt)
+    }
+}
+
+impl<F: Float + FromPrimitive> Occluder<F> for AnnularAperture<F> {
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        let r2 = (s - self.center_s).powi(2) + (t - self.center_t).powi(2);
+        r2 >= self.outer_radius.powi(2) || r2 < self.inner_radius.powi(2)
+    }
+
+    fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, _: usize) -> F {
+        let (s0, s1) = (s0.min(s1) - self.center_s, s0.max(s1) - self.center_s);
+        let (t0, t1) = (t0.min(t1) - self.center_t, t0.max(t1) - self.center_t);
+        let area = circle_box_area(self.outer_radius, s0, s1, t0, t1) -
+                   circle_box_area(self.inner_radius, s0, s1, t0, t1);
+        occluded_fraction(area, s0, s1, t0, t1)
+    }
+}
+
+/// Rectangular slit
+#[derive(Clone, Debug)]
+pub struct SlitAperture<F: Float> {
+    pub center_s: F,
+    pub center_t: F,
+
+    /// Extent along `s`
+    pub width: F,
+
+    /// Extent along `t`
+    pub height: F,
+}
+
+impl<F: Float + FromPrimitive> BoundingGeometry<F> for SlitAperture<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        let c2 = F::from_f32(2f32).unwrap();
+        bounding_box(self.center_s,
+                     self.center_t,
+                     self.width / c2,
+                     self.height / c2,
+                     ns,
+                     nt)
+    }
+}
+
+impl<F: Float + FromPrimitive> Occluder<F> for SlitAperture<F> {
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        let c2 = F::from_f32(2f32).unwrap();
+        (s - self.center_s).abs() * c2 >= self.width ||
+        (t - self.center_t).abs() * c2 >= self.height
+    }
+
+    fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, _: usize) -> F {
+        let c2 = F::from_f32(2f32).unwrap();
+        let ls = (s0.max(s1).min(self.center_s + self.width / c2) -
+                  s0.min(s1).max(self.center_s - self.width / c2))
+                     .max(F::zero());
+        let lt = (t0.max(t1).min(self.center_t + self.height / c2) -
+                  t0.min(t1).max(self.center_t - self.height / c2))
+                     .max(F::zero());
+        occluded_fraction(ls * lt, s0, s1, t0, t1)
+    }
+}
+
+/// Aperture given by a transmission image
+///
+/// The image is loaded from `path` by `load_assets` and scaled so its
+/// brightest pixel is fully transparent.  Everything outside the image is
+/// opaque.
+#[derive(Clone, Debug)]
+pub struct ImageAperture<F: Float> {
+    pub geometry: ImageGeometry<F>,
+    pub path: String,
+    pub transmission: Option<Vec<F>>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> ImageAperture<F> {
+    fn loaded_transmission(self: &Self) -> &[F] {
+        match self.transmission {
+            Some(ref t) => t,
+            None => panic!("ImageAperture used before its image was loaded"),
+        }
+    }
+
+    /// Sets the transmission image, scaling its maximum to one
+    pub fn set_image(self: &mut Self, image: Vec<F>) {
+        let max = image.iter().fold(F::zero(), |m, &x| m.max(x));
+        let scale = if max > F::zero() {
+            F::one() / max
+        } else {
+            F::one()
+        };
+        self.transmission = Some(image.into_iter().map(|x| x.max(F::zero()) * scale).collect());
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> BoundingGeometry<F> for ImageAperture<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        let (s0, s1, t0, t1) = self.geometry.spatial_bounds();
+        let c2 = F::from_f32(2f32).unwrap();
+        bounding_box((s0 + s1) / c2,
+                     (t0 + t1) / c2,
+                     (s1 - s0).abs() / c2,
+                     (t1 - t0).abs() / c2,
+                     ns,
+                     nt)
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Occluder<F> for ImageAperture<F> {
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        let (is, it) = (self.geometry.s2is(s).floor(), self.geometry.t2it(t).floor());
+        let ns = F::from_usize(self.geometry.ns).unwrap();
+        let nt = F::from_usize(self.geometry.nt).unwrap();
+        if is < F::zero() || it < F::zero() || is >= ns || it >= nt {
+            return true;
+        }
+        let ix = self.geometry.address_linear(is.to_usize().unwrap(), it.to_usize().unwrap());
+        self.loaded_transmission()[ix] < F::from_f32(0.5f32).unwrap()
+    }
+
+    /// Averages the transmission over the region, weighting each image
+    /// pixel by its overlap
+    fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, _: usize) -> F {
+        let geom = &self.geometry;
+        let (s0, s1) = (s0.min(s1), s0.max(s1));
+        let (t0, t1) = (t0.min(t1), t0.max(t1));
+        let (mut is0, mut is1, mut it0, mut it1) = geom.region_pixels(s0, s1, t0, t1);
+        if is0 > is1 {
+            ::std::mem::swap(&mut is0, &mut is1);
+        }
+        if it0 > it1 {
+            ::std::mem::swap(&mut it0, &mut it1);
+        }
+
+        let transmission = self.loaded_transmission();
+        let mut area = F::zero();
+        for it in it0..(it1 + 1).min(geom.nt) {
+            for is in is0..(is1 + 1).min(geom.ns) {
+                let (a0, a1, b0, b1) = geom.pixel_bounds(is, it);
+                let ls = (s1.min(a0.max(a1)) - s0.max(a0.min(a1))).max(F::zero());
+                let lt = (t1.min(b0.max(b1)) - t0.max(b0.min(b1))).max(F::zero());
+                area = area + ls * lt * transmission[geom.address_linear(is, it)];
+            }
+        }
+        occluded_fraction(area, s0, s1, t0, t1)
+    }
+}
+
+/// Aperture stop of a camera
+///
+/// In a camera configuration, a `[stop]` table with a `shape` of
+/// `"polygon"`, `"annulus"`, `"slit"` or `"image"` replaces the main lens'
+/// elliptical aperture when building angular planes.  The stop sits in the
+/// plane of the main lens.
+#[derive(Clone, Debug)]
+pub enum Aperture<F: Float> {
+    Polygon(PolygonAperture<F>),
+    Annulus(AnnularAperture<F>),
+    Slit(SlitAperture<F>),
+    Image(ImageAperture<F>),
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> BoundingGeometry<F> for Aperture<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        match self {
+            &Aperture::Polygon(ref a) => a.bounding_geometry(ns, nt),
+            &Aperture::Annulus(ref a) => a.bounding_geometry(ns, nt),
+            &Aperture::Slit(ref a) => a.bounding_geometry(ns, nt),
+            &Aperture::Image(ref a) => a.bounding_geometry(ns, nt),
+        }
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Occluder<F> for Aperture<F> {
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        match self {
+            &Aperture::Polygon(ref a) => a.occludes(s, t),
+            &Aperture::Annulus(ref a) => a.occludes(s, t),
+            &Aperture::Slit(ref a) => a.occludes(s, t),
+            &Aperture::Image(ref a) => a.occludes(s, t),
+        }
+    }
+
+    fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, discretization: usize) -> F {
+        match self {
+            &Aperture::Polygon(ref a) => a.rasterize(s0, s1, t0, t1, discretization),
+            &Aperture::Annulus(ref a) => a.rasterize(s0, s1, t0, t1, discretization),
+            &Aperture::Slit(ref a) => a.rasterize(s0, s1, t0, t1, discretization),
+            &Aperture::Image(ref a) => a.rasterize(s0, s1, t0, t1, discretization),
+        }
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for Aperture<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let center_s = get_float(map, "center_s");
+        let center_t = get_float(map, "center_t");
+
+        match (map.get("shape"), center_s, center_t) {
+            (Some(&Value::String(ref shape)), Some(center_s), Some(center_t)) => {
+                match &shape[..] {
+                    "polygon" => {
+                        match (get_float(map, "radius"), map.get("sides")) {
+                            (Some(radius), Some(&Value::Integer(sides))) if sides >= 3 => {
+                                Some(Aperture::Polygon(PolygonAperture {
+                                    center_s: center_s,
+                                    center_t: center_t,
+                                    radius: radius,
+                                    sides: sides as usize,
+                                    rotation: get_float(map, "rotation").unwrap_or(F::zero()),
+                                }))
+                            }
+                            _ => None,
+                        }
+                    }
+                    "annulus" => {
+                        match get_float(map, "outer_radius") {
+                            Some(outer_radius) => {
+                                Some(Aperture::Annulus(AnnularAperture {
+                                    center_s: center_s,
+                                    center_t: center_t,
+                                    outer_radius: outer_radius,
+                                    inner_radius: get_float(map, "inner_radius")
+                                                      .unwrap_or(F::zero()),
+                                }))
+                            }
+                            None => None,
+                        }
+                    }
+                    "slit" => {
+                        match (get_float(map, "width"), get_float(map, "height")) {
+                            (Some(width), Some(height)) => {
+                                Some(Aperture::Slit(SlitAperture {
+                                    center_s: center_s,
+                                    center_t: center_t,
+                                    width: width,
+                                    height: height,
+                                }))
+                            }
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                }
+            }
+            (Some(&Value::String(ref shape)), _, _) if shape == "image" => {
+                match (map.get("geometry"), map.get("path")) {
+                    (Some(&Value::Table(ref geom_tab)), Some(&Value::String(ref path))) => {
+                        ImageGeometry::from_map(geom_tab).map(|geom| {
+                            Aperture::Image(ImageAperture {
+                                geometry: geom,
+                                path: path.clone(),
+                                transmission: None,
+                            })
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        let shape = match self {
+            &Aperture::Polygon(ref a) => {
+                put_float(&mut tr, "center_s", a.center_s);
+                put_float(&mut tr, "center_t", a.center_t);
+                put_float(&mut tr, "radius", a.radius);
+                put_float(&mut tr, "rotation", a.rotation);
+                tr.insert("sides".to_string(), Value::Integer(a.sides as i64));
+                "polygon"
+            }
+            &Aperture::Annulus(ref a) => {
+                put_float(&mut tr, "center_s", a.center_s);
+                put_float(&mut tr, "center_t", a.center_t);
+                put_float(&mut tr, "outer_radius", a.outer_radius);
+                put_float(&mut tr, "inner_radius", a.inner_radius);
+                "annulus"
+            }
+            &Aperture::Slit(ref a) => {
+                put_float(&mut tr, "center_s", a.center_s);
+                put_float(&mut tr, "center_t", a.center_t);
+                put_float(&mut tr, "width", a.width);
+                put_float(&mut tr, "height", a.height);
+                "slit"
+            }
+            &Aperture::Image(ref a) => {
+                tr.insert("geometry".to_string(), Value::Table(a.geometry.into_map()));
+                tr.insert("path".to_string(), Value::String(a.path.clone()));
+                "image"
+            }
+        };
+        tr.insert("shape".to_string(), Value::String(shape.to_string()));
+        tr
+    }
+
+    fn load_assets<P: AsRef<Path>>(self: &mut Self, root_path: P) -> Result<(), ()> {
+        match self {
+            &mut Aperture::Image(ref mut a) if a.transmission.is_none() => {
+                let mut pb = root_path.as_ref().to_path_buf();
+                pb.pop(); // pop off configuration file name
+                pb.push(&a.path);
+                match a.geometry.load(&pb) {
+                    Ok(image) => {
+                        a.set_image(image);
+                        Ok(())
+                    }
+                    Err(_) => Err(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the optional `stop` of a camera configuration
+///
+/// Returns `Err` if there is a `stop` table that cannot be read.
+pub fn stop_from_map<F>(map: &Table) -> Result<Option<Aperture<F>>, ()>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    match map.get("stop") {
+        None => Ok(None),
+        Some(&Value::Table(ref tab)) => Aperture::from_map(tab).map(Some).ok_or(()),
+        Some(_) => Err(()),
+    }
+}
+
+/// Angular plane on a camera's main lens, limited by its stop if it has one
+pub fn main_lens_angular_plane<F>(lens: &Lens<F>,
+                                  stop: &Option<Aperture<F>>,
+                                  basis: AngularBasis,
+                                  na: usize)
+                                  -> AngularPlane<F>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    match stop {
+        &Some(ref stop) => stop.as_angular_plane(basis, na),
+        &None => lens.as_angular_plane(basis, na),
+    }
+}
+
+#[test]
+fn test_aperture_rasterize() {
+    let apertures = vec![
+        Aperture::Polygon(PolygonAperture {
+            center_s: 0.3,
+            center_t: -0.2,
+            radius: 2.0,
+            sides: 6,
+            rotation: 0.1,
+        }),
+        Aperture::Annulus(AnnularAperture {
+            center_s: 0.3,
+            center_t: -0.2,
+            outer_radius: 2.0,
+            inner_radius: 0.7,
+        }),
+        Aperture::Slit(SlitAperture {
+            center_s: 0.3,
+            center_t: -0.2,
+            width: 3.0,
+            height: 0.9,
+        }),
+    ];
+
+    // analytic rasterization agrees with dense sampling
+    for aperture in apertures.iter() {
+        let geom = aperture.bounding_geometry(7, 7);
+        for it in 0..geom.nt {
+            for is in 0..geom.ns {
+                let (s0, s1, t0, t1) = geom.pixel_bounds(is, it);
+                let exact = aperture.rasterize(s0, s1, t0, t1, 10);
+
+                let n = 200;
+                let mut hits = 0;
+                for j in 0..n {
+                    for i in 0..n {
+                        let s = s0 + (s1 - s0) * (i as f64 + 0.5) / n as f64;
+                        let t = t0 + (t1 - t0) * (j as f64 + 0.5) / n as f64;
+                        if aperture.occludes(s, t) {
+                            hits += 1;
+                        }
+                    }
+                }
+                let sampled = hits as f64 / (n * n) as f64;
+                assert!((exact - sampled).abs() < 2e-2);
+            }
+        }
+
+        let tab = aperture.into_map();
+        let back: Aperture<f64> = Aperture::from_map(&tab).unwrap();
+        assert_eq!(format!("{:?}", back), format!("{:?}", aperture));
+    }
+}
+
+#[test]
+fn test_image_aperture() {
+    let geometry = ImageGeometry {
+        ns: 4,
+        nt: 2,
+        ds: 1.0,
+        dt: 1.0,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+    let mut aperture = ImageAperture {
+        geometry: geometry,
+        path: "stop.png".to_string(),
+        transmission: None,
+    };
+    aperture.set_image(vec![0.0, 255.0, 255.0, 0.0, 0.0, 127.5, 255.0, 0.0]);
+
+    // pixel (1, 1) lets half through
+    let (s0, s1, t0, t1) = aperture.geometry.pixel_bounds(1, 1);
+    assert!((aperture.rasterize(s0, s1, t0, t1, 10) - 0.5).abs() < 1e-12);
+    assert!(aperture.occludes((s0 + s1) / 2.0 - 1.0, (t0 + t1) / 2.0));
+    assert!(!aperture.occludes((s0 + s1) / 2.0 + 1.0, (t0 + t1) / 2.0));
+
+    // halves of pixels (1, 0) and (2, 0), then of (1, 1) and (2, 1) too
+    let (_, a1, b0, b1) = aperture.geometry.pixel_bounds(1, 0);
+    assert!(aperture.rasterize(a1 - 0.5, a1 + 0.5, b0, b1, 10).abs() < 1e-12);
+    assert!((aperture.rasterize(a1 - 0.5, a1 + 0.5, b0, b1 + 1.0, 10) - 0.125).abs() < 1e-12);
+}
@@ -0,0 +1,151 @@
+extern crate num;
+extern crate proust;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::proust::*;
+use vector_math::*;
+use std::fmt::Debug;
+
+/// Compute backend for imagers, vector operations and solvers
+///
+/// A backend names the buffer, event and queue types that operations are
+/// expressed in and knows how to move data between those buffers and the
+/// host.
+pub trait Backend<F>: Sized {
+    /// Storage for a vector of `F`
+    type Buffer: Clone;
+
+    /// Marks the completion of enqueued work
+    type Event: Clone;
+
+    /// Handle used to allocate buffers and enqueue work
+    type Queue: Clone;
+
+    /// Error type for backend operations
+    type Error: Debug;
+
+    /// Vector operations on buffers from this backend
+    type VectorMath: VectorOps<F, Self>;
+
+    /// Creates a buffer holding a copy of `data`
+    fn create_buffer(queue: &Self::Queue, data: &[F]) -> Result<Self::Buffer, Self::Error>;
+
+    /// Reads a buffer into host memory, blocking until the read completes
+    fn read_buffer(queue: &Self::Queue,
+                   buf: &Self::Buffer,
+                   data: &mut [F],
+                   wait_for: &[Self::Event])
+                   -> Result<(), Self::Error>;
+
+    /// Writes host memory into a buffer
+    fn write_buffer(queue: &Self::Queue,
+                    buf: &mut Self::Buffer,
+                    data: &[F],
+                    wait_for: &[Self::Event])
+                    -> Result<Self::Event, Self::Error>;
+
+    /// Blocks until an event completes
+    fn wait(evt: &Self::Event) -> Result<(), Self::Error>;
+
+    /// Creates a vector math object for this backend
+    fn vector_math(queue: &Self::Queue) -> Result<Self::VectorMath, Self::Error>;
+}
+
+/// OpenCL backend using proust
+#[derive(Clone, Debug)]
+pub struct ClBackend;
+
+impl<F: Float + ToPrimitive + FromPrimitive> Backend<F> for ClBackend {
+    type Buffer = Mem;
+    type Event = Event;
+    type Queue = CommandQueue;
+    type Error = Error;
+    type VectorMath = VectorMath<F>;
+
+    fn create_buffer(queue: &CommandQueue, data: &[F]) -> Result<Mem, Error> {
+        queue.create_buffer_from_slice(data)
+    }
+
+    fn read_buffer(queue: &CommandQueue,
+                   buf: &Mem,
+                   data: &mut [F],
+                   wait_for: &[Event])
+                   -> Result<(), Error> {
+        for evt in wait_for.iter() {
+            try!(evt.wait());
+        }
+        try!(try!(queue.read_buffer(buf, data)).wait());
+        Ok(())
+    }
+
+    fn write_buffer(queue: &CommandQueue,
+                    buf: &mut Mem,
+                    data: &[F],
+                    wait_for: &[Event])
+                    -> Result<Event, Error> {
+        for evt in wait_for.iter() {
+            try!(evt.wait());
+        }
+        queue.write_buffer(buf, data)
+    }
+
+    fn wait(evt: &Event) -> Result<(), Error> {
+        evt.wait()
+    }
+
+    fn vector_math(queue: &CommandQueue) -> Result<VectorMath<F>, Error> {
+        VectorMath::new(queue.clone())
+    }
+}
+
+/// Backend that runs everything synchronously on the host
+#[derive(Clone, Debug)]
+pub struct HostBackend;
+
+/// Event for the host backend
+///
+/// Host operations complete before they return, so these events are always
+/// already complete.
+#[derive(Clone, Debug)]
+pub struct HostEvent;
+
+/// Queue for the host backend
+#[derive(Clone, Debug)]
+pub struct HostQueue;
+
+impl<F: Float + ToPrimitive + FromPrimitive> Backend<F> for HostBackend {
+    type Buffer = Vec<F>;
+    type Event = HostEvent;
+    type Queue = HostQueue;
+    type Error = ();
+    type VectorMath = HostVectorMath<F>;
+
+    fn create_buffer(_: &HostQueue, data: &[F]) -> Result<Vec<F>, ()> {
+        Ok(data.to_owned())
+    }
+
+    fn read_buffer(_: &HostQueue,
+                   buf: &Vec<F>,
+                   data: &mut [F],
+                   _: &[HostEvent])
+                   -> Result<(), ()> {
+        data.clone_from_slice(&buf[..data.len()]);
+        Ok(())
+    }
+
+    fn write_buffer(_: &HostQueue,
+                    buf: &mut Vec<F>,
+                    data: &[F],
+                    _: &[HostEvent])
+                    -> Result<HostEvent, ()> {
+        buf[..data.len()].clone_from_slice(data);
+        Ok(HostEvent)
+    }
+
+    fn wait(_: &HostEvent) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn vector_math(_: &HostQueue) -> Result<HostVectorMath<F>, ()> {
+        Ok(HostVectorMath::new())
+    }
+}
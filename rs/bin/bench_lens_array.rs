@@ -0,0 +1,157 @@
+extern crate lightfield;
+extern crate getopts;
+extern crate time;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+use time::precise_time_s;
+
+// usage example:
+// bench_lens_array --camera cfg/cameras/raytrix.toml --angles 5 --basis pillbox
+
+fn print_usage(name: &String, opt: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opt.usage(&brief));
+}
+
+fn main() {
+    // get binary name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("c", "camera", "TOML file describing a plenoptic camera", "FILE");
+    opts.reqopt("a", "angles", "Angular discretization", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
+    opts.optopt("n",
+                "repeat",
+                "Number of passes over all angles (default 1)",
+                "INT");
+    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+
+    // parse number of angles, basis function
+    let na: usize = matches.opt_str("angles")
+                           .unwrap()
+                           .parse()
+                           .expect("Error parsing number of angles");
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
+    let repeat = match matches.opt_str("repeat") {
+        Some(s) => s.parse().expect("Error parsing number of passes"),
+        None => 1usize,
+    };
+
+    // create opencl environment
+    let env = Environment::new_easy().expect("Error starting OpenCL environment");
+
+    // use selected device
+    let device_id = match matches.opt_str("device") {
+        Some(s) => s.parse().expect("Error parsing device number"),
+        None => 0usize,
+    };
+
+    let queue = &env.queues[device_id];
+    println!("Using device id {} (of {}): {}",
+             device_id,
+             env.queues.len(),
+             queue.device()
+                  .expect("Error getting device info")
+                  .name()
+                  .expect("Error getting device name"));
+
+    // load the camera and its lenses
+    let camera_path = matches.opt_str("camera").unwrap();
+    let mut config = CameraConfig::<f32>::from_map(&table_from_file(&camera_path)
+                                                        .expect("Error reading camera file"))
+                         .expect("Error parsing camera file");
+    config.load_assets(&camera_path).expect("Error loading camera assets");
+    let camera = match config {
+        CameraConfig::PlenopticCamera(pc) => pc,
+        _ => panic!("bench_lens_array requires a plenoptic camera"),
+    };
+    let lenses = camera.array.clone().unwrap();
+    println!("Microlenses: {}", lenses.len());
+
+    let array_lfg = LightFieldGeometry {
+        geom: camera.detector.image_geometry(),
+        plane: camera.angular_plane(basis, na),
+        to_plane: Optics::translation(&camera.distance_lens_array),
+    };
+    let ig = camera.detector.image_geometry();
+
+    // per-lens transports
+    let start = precise_time_s();
+    let mut per_lens = LensArray::new(array_lfg.clone(),
+                                      camera.detector.clone(),
+                                      camera.distance_detector_array,
+                                      &lenses,
+                                      queue.clone())
+                           .expect("Error creating LensArray");
+    println!("LensArray setup: {:.3}s", precise_time_s() - start);
+
+    // batched transport
+    let start = precise_time_s();
+    let mut batched = BatchedLensArray::new(array_lfg.clone(),
+                                            camera.detector.clone(),
+                                            camera.distance_detector_array,
+                                            &lenses,
+                                            queue.clone())
+                          .expect("Error creating BatchedLensArray");
+    println!("BatchedLensArray setup: {:.3}s", precise_time_s() - start);
+
+    let view = ig.rands_buf(queue).expect("Error creating buffer");
+    let mut det = ig.zeros_buf(queue).expect("Error creating buffer");
+    let mut back = ig.zeros_buf(queue).expect("Error creating buffer");
+
+    // time forward and back projections over all angles
+    let start = precise_time_s();
+    for _ in 0..repeat {
+        for ia in 0..na {
+            per_lens.forw(&view, &mut det, ia, &[])
+                    .expect("Error in LensArray::forw")
+                    .wait()
+                    .unwrap();
+            per_lens.back(&det, &mut back, ia, &[])
+                    .expect("Error in LensArray::back")
+                    .wait()
+                    .unwrap();
+        }
+    }
+    let per_lens_time = (precise_time_s() - start) / (repeat as f64);
+    println!("LensArray forw+back: {:.3}s per pass", per_lens_time);
+
+    let start = precise_time_s();
+    for _ in 0..repeat {
+        for ia in 0..na {
+            batched.forw(&view, &mut det, ia, &[])
+                   .expect("Error in BatchedLensArray::forw")
+                   .wait()
+                   .unwrap();
+            batched.back(&det, &mut back, ia, &[])
+                   .expect("Error in BatchedLensArray::back")
+                   .wait()
+                   .unwrap();
+        }
+    }
+    let batched_time = (precise_time_s() - start) / (repeat as f64);
+    println!("BatchedLensArray forw+back: {:.3}s per pass", batched_time);
+    println!("Speedup: {:.2}x", per_lens_time / batched_time);
+}
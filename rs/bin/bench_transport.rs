@@ -0,0 +1,368 @@
+extern crate lightfield;
+extern crate getopts;
+extern crate proust;
+extern crate time;
+
+use self::getopts::Options;
+use std::env;
+use std::cmp::max;
+use std::mem::{size_of, swap};
+use self::lightfield::*;
+use self::proust::*;
+use time::precise_time_s;
+
+// usage example:
+// bench_transport --angles 10 --basis pillbox --repeat 5
+
+fn print_usage(name: &String, opt: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opt.usage(&brief));
+}
+
+/// `Transport` as it was before footprints were precomputed
+///
+/// Computes each angle's footprint on the host for every call and integrates
+/// it inline in the kernels.  Covers whole planes with overwriting,
+/// non-conservative transport, like `Transport::new_simple`.
+struct InlineTransport {
+    src: LightFieldGeometry<f32>,
+    dst: LightFieldGeometry<f32>,
+
+    queue: CommandQueue,
+    kernel_s: Kernel,
+    kernel_t: Kernel,
+
+    src_geom_buf: Mem,
+    dst_geom_buf: Mem,
+    tmp_buf: Mem,
+
+    src_to_dst: Optics<f32>,
+    dst_to_src: Optics<f32>,
+}
+
+impl InlineTransport {
+    fn new(src: LightFieldGeometry<f32>,
+           dst: LightFieldGeometry<f32>,
+           queue: CommandQueue)
+           -> Result<Self, Error> {
+        let kernels = match &src.plane.basis {
+            &AngularBasis::Dirac => {
+                include_str!("../../cl/bench/transport_dirac_inline_f32.opencl")
+            }
+            &AngularBasis::Pillbox => {
+                include_str!("../../cl/bench/transport_pillbox_inline_f32.opencl")
+            }
+            _ => panic!("Inline transport supports only dirac and pillbox bases"),
+        };
+        let sources = [ImageGeometry::<f32>::header(), Optics::<f32>::header(), kernels];
+        let program = try!(cached_program::<f32>(&queue, &sources));
+
+        let ns = max(src.geom.ns, dst.geom.ns);
+        let nt = max(src.geom.nt, dst.geom.nt);
+        Ok(InlineTransport {
+            kernel_s: try!(program.create_kernel("transport_s")),
+            kernel_t: try!(program.create_kernel("transport_t")),
+
+            src_geom_buf: try!(src.geom.as_cl_buffer(&queue)),
+            dst_geom_buf: try!(dst.geom.as_cl_buffer(&queue)),
+            tmp_buf: try!(queue.create_buffer(size_of::<f32>() * ns * nt)),
+
+            src_to_dst: dst.to_plane.invert().compose(&src.to_plane),
+            dst_to_src: src.to_plane.invert().compose(&dst.to_plane),
+
+            queue: queue,
+            src: src,
+            dst: dst,
+        })
+    }
+
+    fn bind_common_args(self: &Self, forw: bool, kernel: &mut Kernel) -> Result<(), Error> {
+        let (from, into, from_buf, into_buf) = if forw {
+            (&self.src.geom, &self.dst.geom, &self.src_geom_buf, &self.dst_geom_buf)
+        } else {
+            (&self.dst.geom, &self.src.geom, &self.dst_geom_buf, &self.src_geom_buf)
+        };
+        try!(kernel.bind(0, from_buf));
+        try!(kernel.bind(1, into_buf));
+        try!(kernel.bind_scalar(2, &0i32));
+        try!(kernel.bind_scalar(3, &(from.ns as i32)));
+        try!(kernel.bind_scalar(4, &0i32));
+        try!(kernel.bind_scalar(5, &(from.nt as i32)));
+        try!(kernel.bind_scalar(6, &0i32));
+        try!(kernel.bind_scalar(7, &(into.ns as i32)));
+        try!(kernel.bind_scalar(8, &0i32));
+        try!(kernel.bind_scalar(9, &(into.nt as i32)));
+        Ok(())
+    }
+
+    /// Returns `1/alpha`, the sorted footprint breakpoints and the height of
+    /// the footprint along `t` (if `along_t`) or `s`
+    #[allow(non_snake_case)] // allow us to break style guide to match docs
+    fn footprint(self: &Self, forw: bool, along_t: bool, ia: usize) -> (f32, Vec<f32>, f32) {
+        let (Rqp, Rp, into_geom) = if forw {
+            (&self.src_to_dst, &self.src.to_plane, &self.dst.geom)
+        } else {
+            (&self.dst_to_src, &self.dst.to_plane, &self.src.geom)
+        };
+        let plane = &self.src.plane;
+        let (qq, qv, q, pq, pv, p, u, du, dq) = if along_t {
+            (Rqp.tt, Rqp.tv, Rqp.t, Rp.tt, Rp.tv, Rp.t, plane.t[ia], plane.dt, into_geom.dt)
+        } else {
+            (Rqp.ss, Rqp.su, Rqp.s, Rp.ss, Rp.su, Rp.s, plane.s[ia], plane.ds, into_geom.ds)
+        };
+
+        let alpha = qq - pq * qv / pv;
+        let (mut taus, mut h) = match &plane.basis {
+            &AngularBasis::Dirac => {
+                let beta = q + qv * (u - p) / pv;
+                let mut tau0 = (-dq / 2f32 - beta) / alpha;
+                let mut tau1 = (dq / 2f32 - beta) / alpha;
+                if tau0 > tau1 {
+                    swap(&mut tau0, &mut tau1);
+                }
+                (vec![tau0, tau1], (du / pv).abs())
+            }
+            _ => {
+                let beta = qv / pv;
+                let gamma = q - qv * p / pv;
+                (vec![(dq / 2f32 - beta * (u + du / 2f32) - gamma) / alpha,
+                      (dq / 2f32 - beta * (u - du / 2f32) - gamma) / alpha,
+                      (-dq / 2f32 - beta * (u + du / 2f32) - gamma) / alpha,
+                      (-dq / 2f32 - beta * (u - du / 2f32) - gamma) / alpha],
+                 (du / pv).abs().min((dq / qv).abs()))
+            }
+        };
+        taus.sort_by(|l, r| l.partial_cmp(r).unwrap());
+        if along_t {
+            h = h / self.dst.pixel_volume();
+        }
+        (1f32 / alpha, taus, h)
+    }
+
+    fn transport(self: &mut Self,
+                 forw: bool,
+                 from: &Mem,
+                 into: &mut Mem,
+                 ia: usize,
+                 wait_for: &[Event])
+                 -> Result<Event, Error> {
+        let (from_geom, into_geom) = if forw {
+            (self.src.geom.clone(), self.dst.geom.clone())
+        } else {
+            (self.dst.geom.clone(), self.src.geom.clone())
+        };
+        let local_size = (32usize, 8usize, 1usize);
+
+        // along t, into the tmp buffer
+        let (d_scale, taus, h) = self.footprint(forw, true, ia);
+        let mut kernel = self.kernel_t.clone();
+        try!(self.bind_common_args(forw, &mut kernel));
+        try!(kernel.bind_scalar(10, &d_scale));
+        for (i, tau) in taus.iter().enumerate() {
+            try!(kernel.bind_scalar(11 + i, tau));
+        }
+        let next = 11 + taus.len();
+        try!(kernel.bind_scalar(next, &h));
+        try!(kernel.bind(next + 1, from));
+        try!(kernel.bind_mut(next + 2, &mut self.tmp_buf));
+        let evt = try!(run_kernel(&self.queue,
+                                  &mut kernel,
+                                  "transport_t",
+                                  local_size,
+                                  (from_geom.ns, into_geom.nt, 1),
+                                  wait_for));
+
+        // along s, overwriting the destination
+        let (d_scale, taus, h) = self.footprint(forw, false, ia);
+        let mut kernel = self.kernel_s.clone();
+        try!(self.bind_common_args(forw, &mut kernel));
+        try!(kernel.bind_scalar(10, &d_scale));
+        for (i, tau) in taus.iter().enumerate() {
+            try!(kernel.bind_scalar(11 + i, tau));
+        }
+        let next = 11 + taus.len();
+        try!(kernel.bind_scalar(next, &h));
+        try!(kernel.bind(next + 1, &self.tmp_buf));
+        try!(kernel.bind_mut(next + 2, into));
+        try!(kernel.bind_scalar(next + 3, &0u32)); // conservative
+        try!(kernel.bind_scalar(next + 4, &1u32)); // overwrite
+        run_kernel(&self.queue,
+                   &mut kernel,
+                   "transport_s",
+                   local_size,
+                   (into_geom.nt, into_geom.ns, 1),
+                   &[evt])
+    }
+}
+
+fn read(queue: &CommandQueue, buf: &Mem, np: usize) -> Vec<f32> {
+    let mut tr = vec![0f32; np];
+    queue.read_buffer(buf, &mut tr)
+         .expect("Error reading buffer")
+         .wait()
+         .expect("Error waiting for read");
+    tr
+}
+
+fn relative_difference(a: &[f32], b: &[f32]) -> f32 {
+    let num = a.iter().zip(b.iter()).fold(0f32, |s, (x, y)| s + (x - y) * (x - y));
+    let den = a.iter().fold(0f32, |s, x| s + x * x);
+    (num / den).sqrt()
+}
+
+fn main() {
+    // get binary name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("a", "angles", "Angular discretization", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac");
+    opts.optopt("n",
+                "repeat",
+                "Number of passes over all angles (default 1)",
+                "INT");
+    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+
+    // parse number of angles, basis function
+    let na: usize = matches.opt_str("angles")
+                           .unwrap()
+                           .parse()
+                           .expect("Error parsing number of angles");
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
+    let repeat = match matches.opt_str("repeat") {
+        Some(s) => s.parse().expect("Error parsing number of passes"),
+        None => 1usize,
+    };
+
+    // create opencl environment
+    let env = Environment::new_easy().expect("Error starting OpenCL environment");
+
+    // use selected device
+    let device_id = match matches.opt_str("device") {
+        Some(s) => s.parse().expect("Error parsing device number"),
+        None => 0usize,
+    };
+
+    let queue = &env.queues[device_id];
+    println!("Using device id {} (of {}): {}",
+             device_id,
+             env.queues.len(),
+             queue.device()
+                  .expect("Error getting device info")
+                  .name()
+                  .expect("Error getting device name"));
+
+    // an object plane imaged through a lens onto a detector
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 20f32,
+        radius_t: 20f32,
+        focal_length_s: 30f32,
+        focal_length_t: 30f32,
+    };
+    let plane = lens.as_angular_plane(basis, na);
+    let src = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 512,
+            nt: 512,
+            ds: 0.2,
+            dt: 0.2,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        plane: plane.clone(),
+        to_plane: lens.optics().then(&Optics::translation(&500f32)).invert(),
+    };
+    let dst = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 1024,
+            nt: 1024,
+            ds: 0.01,
+            dt: 0.01,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        plane: plane,
+        to_plane: Optics::translation(&35f32),
+    };
+    let na = src.plane.na();
+    println!("Angles: {}", na);
+
+    let mut precomputed = Transport::new_simple(src.clone(), dst.clone(), queue.clone())
+                              .expect("Error creating Transport");
+    let mut inline = InlineTransport::new(src.clone(), dst.clone(), queue.clone())
+                         .expect("Error creating inline transport");
+
+    let u = src.geom.rands_buf(queue).expect("Error creating buffer");
+    let v = dst.geom.rands_buf(queue).expect("Error creating buffer");
+    let mut forw0 = dst.geom.zeros_buf(queue).expect("Error creating buffer");
+    let mut forw1 = dst.geom.zeros_buf(queue).expect("Error creating buffer");
+    let mut back0 = src.geom.zeros_buf(queue).expect("Error creating buffer");
+    let mut back1 = src.geom.zeros_buf(queue).expect("Error creating buffer");
+
+    // both implement the same operator
+    let ia = na / 2;
+    precomputed.forw(&u, &mut forw0, ia, &[]).unwrap().wait().unwrap();
+    inline.transport(true, &u, &mut forw1, ia, &[]).unwrap().wait().unwrap();
+    precomputed.back(&v, &mut back0, ia, &[]).unwrap().wait().unwrap();
+    inline.transport(false, &v, &mut back1, ia, &[]).unwrap().wait().unwrap();
+    println!("Relative difference forw: {:e} back: {:e}",
+             relative_difference(&read(queue, &forw0, dst.geom.dimension()),
+                                 &read(queue, &forw1, dst.geom.dimension())),
+             relative_difference(&read(queue, &back0, src.geom.dimension()),
+                                 &read(queue, &back1, src.geom.dimension())));
+
+    // time forward and back projections over all angles
+    let start = precise_time_s();
+    for _ in 0..repeat {
+        for ia in 0..na {
+            inline.transport(true, &u, &mut forw1, ia, &[])
+                  .expect("Error in inline forw")
+                  .wait()
+                  .unwrap();
+            inline.transport(false, &v, &mut back1, ia, &[])
+                  .expect("Error in inline back")
+                  .wait()
+                  .unwrap();
+        }
+    }
+    let inline_time = (precise_time_s() - start) / (repeat as f64);
+    println!("Inline footprints forw+back: {:.3}s per pass", inline_time);
+
+    let start = precise_time_s();
+    for _ in 0..repeat {
+        for ia in 0..na {
+            precomputed.forw(&u, &mut forw0, ia, &[])
+                       .expect("Error in Transport::forw")
+                       .wait()
+                       .unwrap();
+            precomputed.back(&v, &mut back0, ia, &[])
+                       .expect("Error in Transport::back")
+                       .wait()
+                       .unwrap();
+        }
+    }
+    let precomputed_time = (precise_time_s() - start) / (repeat as f64);
+    println!("Precomputed footprints forw+back: {:.3}s per pass", precomputed_time);
+    println!("Speedup: {:.2}x", inline_time / precomputed_time);
+}
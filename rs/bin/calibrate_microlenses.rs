@@ -0,0 +1,102 @@
+extern crate lightfield;
+extern crate getopts;
+extern crate toml;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+use self::toml::*;
+use std::fs::File;
+use std::io::Write;
+
+// usage example, for a three-type multi-focus array:
+// calibrate_microlenses --camera plenoptic.toml --white white.png --out array.toml \
+//                       --report array.txt raytrix1.toml raytrix2.toml raytrix3.toml
+
+fn print_usage(name: &String, opts: Options) {
+    let brief = format!("Usage: {} [options] lens1.toml [lens2.toml ...]\n\nLens files give \
+                         one microlens type each, in order of increasing brightness in the \
+                         white image.",
+                        name);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    // get program name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("c", "camera", "TOML file describing a plenoptic camera", "FILE");
+    opts.reqopt("w", "white", "Flat-field capture from the camera's detector", "FILE");
+    opts.reqopt("o", "out", "Output TOML path for the microlens array", "FILE");
+    opts.optopt("l",
+                "layout",
+                "Layout of the microlens grid (default: detected)",
+                "quad | hex | hex_t");
+    opts.optopt("r", "report", "Path to write the residual report to", "FILE");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+    if matches.free.len() == 0 {
+        print_usage(my_name, opts);
+        panic!("Give at least one microlens file");
+    }
+
+    // read camera configuration; its microlens array is what we are after,
+    // so its assets are not loaded
+    let camera_path = matches.opt_str("camera").unwrap();
+    let config = CameraConfig::<f32>::from_map(&table_from_file(&camera_path)
+                                                    .expect("Error reading config"))
+                     .expect("Error parsing camera config");
+    let camera = match config {
+        CameraConfig::PlenopticCamera(camera) => camera,
+        _ => panic!("calibrate_microlenses needs a plenoptic camera"),
+    };
+
+    // read lens types
+    let mut prototypes: Vec<Lens<f32>> = Vec::new();
+    for lens_path in matches.free.iter() {
+        let lens = Lens::<f32>::from_map(&table_from_file(lens_path)
+                                              .expect("Error reading lens file"))
+                       .expect("Error parsing lens file");
+        prototypes.push(lens);
+    }
+
+    let layout = matches.opt_str("layout")
+                        .map(|name| LensLayout::from_name(&name).expect("Invalid layout"));
+
+    let white = camera.detector
+                      .load(matches.opt_str("white").unwrap())
+                      .expect("Error reading white image");
+    let calibration = MicrolensCalibration::new(&camera, &white, layout, &prototypes)
+                          .expect("Found no microlens grid in the white image");
+
+    let report = calibration.report();
+    print!("{}", report);
+    if let Some(path) = matches.opt_str("report") {
+        let mut report_file = File::create(path).expect("Error opening report file");
+        write!(&mut report_file, "{}", report).expect("Error writing report");
+    }
+
+    let lens_string = encode_str(&calibration.lenses.into_map());
+    let mut out_file = File::create(matches.opt_str("out").unwrap())
+                           .expect("Error opening output file");
+    write!(&mut out_file, "{}", lens_string).expect("Error writing output to file");
+
+    println!("Saved a microlens array with {} lenses", calibration.lenses.len());
+}
@@ -67,7 +67,7 @@ fn main() {
                                          .parse()
                                          .expect("Error parsing focus distance");
         println!("Focusing camera at {}", focus_distance);
-        camera.focus_at_distance(focus_distance);
+        camera.focus_at_distance(focus_distance).expect("Main lens does not focus");
     }
     if matches.opt_present("describe") {
         println!("{}", camera.describe());
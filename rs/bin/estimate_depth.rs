@@ -0,0 +1,143 @@
+extern crate lightfield;
+extern crate getopts;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+
+// usage example, on data simulated with generate_data:
+// estimate_depth --scene scene.toml --angles 7 --basis dirac \
+//                --near 300 --far 800 --steps 32 --out depth_{}.fld
+
+fn print_usage(name: &String, opts: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    // get program name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("s", "scene", "TOML file describing scene", "FILE");
+    opts.reqopt("a", "angles", "Angular discretization", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
+    opts.reqopt("n", "near", "Nearest object distance to consider", "DISTANCE");
+    opts.reqopt("f", "far", "Furthest object distance to consider", "DISTANCE");
+    opts.optopt("z", "steps", "Number of depths in the sweep (default: 32)", "INT");
+    opts.reqopt("o",
+                "out",
+                "Output path for each camera's depth map, with {} replaced by the camera name",
+                "FILE");
+    opts.optopt("c",
+                "confidence",
+                "Output path for each camera's confidence map, with {} replaced by the camera \
+                 name",
+                "FILE");
+    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+
+    // parse number of angles, basis function, sweep
+    let na: usize = matches.opt_str("angles")
+                           .unwrap()
+                           .parse()
+                           .expect("Error parsing number of angles");
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
+    let near: f32 = matches.opt_str("near").unwrap().parse().expect("Error parsing near distance");
+    let far: f32 = matches.opt_str("far").unwrap().parse().expect("Error parsing far distance");
+    let steps: usize = match matches.opt_str("steps") {
+        Some(s) => s.parse().expect("Error parsing number of steps"),
+        None => 32,
+    };
+    let depths = inverse_depth_sweep(near.min(far), near.max(far), steps);
+
+    // create opencl environment
+    let env = Environment::new_easy().expect("Error starting OpenCL environment");
+
+    // use selected device
+    let device_id = match matches.opt_str("device") {
+        Some(s) => s.parse().expect("Error parsing device number"),
+        None => 0usize,
+    };
+    let queue = &env.queues[device_id];
+    println!("Using device id {} (of {}): {}",
+             device_id,
+             env.queues.len(),
+             queue.device()
+                  .expect("Error getting device info")
+                  .name()
+                  .expect("Error getting device name"));
+
+    // load scene description; the object gives the true depth range
+    let scene = Scene::<f32>::read(matches.opt_str("s").unwrap())
+                    .expect("Error loading scene file");
+    let object_config: ObjectConfig<f32> = scene.object
+                                                .get_config()
+                                                .expect("Error reading object configuration");
+
+    for scene_cam in scene.cameras.iter() {
+        let camera = match scene_cam.get_config().expect("Error reading camera configuration") {
+            CameraConfig::PlenopticCamera(camera) => camera,
+            _ => {
+                println!("Skipping camera {}: depth needs a plenoptic camera",
+                         scene_cam.name);
+                continue;
+            }
+        };
+        println!("Estimating depth for camera {}", scene_cam.name);
+
+        let raw = camera.detector
+                        .load(&scene_cam.data_path)
+                        .expect("Error reading measurements");
+        let map = plenoptic_depth(&camera, basis.clone(), na, &raw, &depths, queue)
+                      .expect("Error estimating depth");
+
+        // compare confident pixels against where the object actually is
+        let mut confident: Vec<f32> = map.depth
+                                         .iter()
+                                         .zip(map.confidence.iter())
+                                         .filter(|&(_, &c)| c > 0.5)
+                                         .map(|(&z, _)| z)
+                                         .collect();
+        confident.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        if confident.len() > 0 {
+            println!("Median depth of {} confident pixels: {}",
+                     confident.len(),
+                     confident[confident.len() / 2]);
+        }
+        match (&object_config, &scene_cam.rotation) {
+            (&ObjectConfig::LightVolume(ref geom), &None) => {
+                let distance_to_object = -scene_cam.position.z;
+                println!("Object spans distances {} to {}",
+                         distance_to_object - geom.iz2z(geom.nz - 1),
+                         distance_to_object - geom.iz2z(0));
+            }
+            _ => (),
+        }
+
+        let out = matches.opt_str("out").unwrap().replace("{}", &scene_cam.name);
+        map.geom.save(&map.depth, &out).expect("Error saving depth map");
+        if let Some(path) = matches.opt_str("confidence") {
+            let path = path.replace("{}", &scene_cam.name);
+            map.geom.save(&map.confidence, &path).expect("Error saving confidence map");
+        }
+    }
+}
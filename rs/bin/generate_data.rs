@@ -25,8 +25,8 @@ fn main() {
     let mut opts = Options::new();
     opts.reqopt("s", "scene", "TOML file describing scene", "FILE");
     opts.reqopt("a", "angles", "Angular discretization", "INT");
-    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac");
-    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
+    opts.optopt("d", "device", "OpenCL device to use (default: all)", "INT");
     opts.optopt("v", "view", "Project only a single view", "INT");
     opts.optflag("h", "help", "Print help and exit");
 
@@ -50,29 +50,30 @@ fn main() {
                            .unwrap()
                            .parse()
                            .expect("Error parsing number of angles");
-    let basis = match &matches.opt_str("basis").unwrap()[..] {
-        "dirac" => AngularBasis::Dirac,
-        "pillbox" => AngularBasis::Pillbox,
-        _ => panic!("Invalid angular basis"),
-    };
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
 
     // create opencl environment
     let env = Environment::new_easy().expect("Error starting OpenCL environment");
 
-    // use selected device
-    let device_id = match matches.opt_str("device") {
-        Some(s) => s.parse().expect("Error parsing device number"),
-        None => 0usize,
+    // use selected device, or spread cameras over all devices
+    let device_ids: Vec<usize> = match matches.opt_str("device") {
+        Some(s) => vec![s.parse().expect("Error parsing device number")],
+        None => (0..env.queues.len()).collect(),
     };
-
-    let queue = &env.queues[device_id];
-    println!("Using device id {} (of {}): {}",
-             device_id,
-             env.queues.len(),
-             queue.device()
-                  .expect("Error getting device info")
-                  .name()
-                  .expect("Error getting device name"));
+    let mut queues: Vec<CommandQueue> = Vec::new();
+    for &device_id in device_ids.iter() {
+        let queue = &env.queues[device_id];
+        println!("Using device id {} (of {}): {}",
+                 device_id,
+                 env.queues.len(),
+                 queue.device()
+                      .expect("Error getting device info")
+                      .name()
+                      .expect("Error getting device name"));
+        queues.push(queue.clone());
+    }
+    let queue = &queues[0];
 
     // load scene description, object descriptions
     let scene = Scene::<f32>::read(matches.opt_str("s").unwrap())
@@ -88,19 +89,24 @@ fn main() {
             let object = queue.create_buffer_from_slice(&object_buf)
                               .expect("Error loading object onto GPU");
 
-            // loop through cameras
-            for scene_cam in scene.cameras.iter() {
-                println!("Simulating data for camera {}", scene_cam.name);
+            // launch projections for every camera, assigning cameras to
+            // devices round-robin so the devices run concurrently
+            let mut launched = Vec::new();
+            for (icam, scene_cam) in scene.cameras.iter().enumerate() {
+                let device = icam % queues.len();
+                println!("Simulating data for camera {} on device id {}",
+                         scene_cam.name,
+                         device_ids[device]);
                 let config = scene_cam.get_config().expect("Error reading camera configuration");
                 let mut imager = config.volume_imager(geom.clone(),
                                                       scene_cam.position.clone(),
                                                       scene_cam.rotation.clone(),
                                                       na,
                                                       basis.clone(),
-                                                      queue.clone())
+                                                      queues[device].clone())
                                        .expect("Error creating Imager for camera");
                 let mut img = imager.detector()
-                                    .zeros_buf(&queue)
+                                    .zeros_buf(&queues[device])
                                     .expect("Error creating GPU detector buffer");
 
                 let views = match matches.opt_str("view") {
@@ -119,14 +125,20 @@ fn main() {
                 };
 
                 // Perform projection
-                imager.forw_subset(&object, &mut img, &views, &[])
-                      .expect("Error projecting")
-                      .wait()
-                      .expect("Error waiting for projection to complete");
+                let evt = imager.forw_subset(&object, &mut img, &views, &[])
+                                .expect("Error projecting");
+                launched.push((scene_cam, device, imager, img, evt));
+            }
+
+            for (scene_cam, device, imager, img, evt) in launched.into_iter() {
+                evt.wait().expect("Error waiting for projection to complete");
 
                 // Read projection to host
                 let mut img_buf = imager.detector().zeros();
-                queue.read_buffer(&img, &mut img_buf).expect("Error reading projection");
+                queues[device].read_buffer(&img, &mut img_buf)
+                              .expect("Error reading projection")
+                              .wait()
+                              .expect("Error waiting for projection transfer to complete");
 
                 // Save result
                 imager.detector()
@@ -0,0 +1,81 @@
+extern crate lightfield;
+extern crate getopts;
+extern crate proust;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+use self::proust::*;
+
+// usage example:
+// list_devices
+
+fn print_usage(name: &String, opts: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    // get program name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+
+    let platforms = Platform::platforms().expect("Error listing OpenCL platforms");
+    let infos = DeviceInfo::query_all().expect("Error querying OpenCL devices");
+    for (ip, platform) in platforms.iter().enumerate() {
+        println!("Platform {}", ip);
+        let devices = platform.devices().expect("Error listing OpenCL devices");
+        for (id, device) in devices.iter().enumerate() {
+            let name = device.name().expect("Error getting device name");
+            let kind = DeviceKind::of(device).expect("Error getting device type");
+
+            // probe double precision support with a queue of its own
+            let context = Context::new(&[device.clone()]).expect("Error creating context");
+            let queue = CommandQueue::new(context, device.clone())
+                            .expect("Error creating command queue");
+            let fp64 = supports_double(&queue).expect("Error probing double precision");
+
+            println!("  Device {}: {}", id, name);
+            println!("    type: {:?}", kind);
+            println!("    fp64: {}", if fp64 { "yes" } else { "no" });
+            match infos.get(ip).and_then(|p| p.get(id)) {
+                Some(info) if info.name == name => {
+                    println!("    vendor: {}", info.vendor);
+                    println!("    global memory: {} MB", info.global_mem_size / (1 << 20));
+                    println!("    local memory: {} KB", info.local_mem_size / (1 << 10));
+                    println!("    max work-group size: {}", info.max_work_group_size);
+                }
+                _ => println!("    (no device info)"),
+            }
+        }
+    }
+
+    match EnvironmentBuilder::new().resolved() {
+        Ok((platform, selector)) => {
+            println!("Default selection: platform {:?}, devices {:?}", platform, selector)
+        }
+        Err(e) => println!("Invalid default selection: {:?}", e),
+    }
+    println!("(override with {}=INDEX and {}=all|gpu|cpu|other|INDEX|vendor:VENDOR|NAME)",
+             PLATFORM_VAR,
+             DEVICE_VAR);
+}
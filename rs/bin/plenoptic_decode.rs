@@ -0,0 +1,144 @@
+extern crate lightfield;
+extern crate getopts;
+extern crate toml;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+use self::toml::*;
+use std::fs::{File, create_dir_all};
+use std::io::Write;
+use std::path::PathBuf;
+
+// usage example:
+// plenoptic_decode --camera plenoptic.toml --input raw.png --angles 9 \
+//                  --vignetting white --white white.png --out decoded.toml --grid views
+
+fn print_usage(name: &String, opts: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    // get program name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("c", "camera", "TOML file describing a plenoptic camera", "FILE");
+    opts.reqopt("i", "input", "Raw capture from the camera's detector", "FILE");
+    opts.reqopt("a", "angles", "Angular discretization", "INT");
+    opts.optopt("n",
+                "interpolation",
+                "Interpolation of raw pixels (default: bilinear)",
+                "nearest | bilinear");
+    opts.optopt("v",
+                "vignetting",
+                "Vignetting normalization (default: none)",
+                "none | coverage | white");
+    opts.optopt("w", "white", "Flat-field capture for white normalization", "FILE");
+    opts.optopt("p",
+                "pitch",
+                "Spacing of view pixels (default: diameter of a microlens)",
+                "DISTANCE");
+    opts.optopt("o", "out", "TOML file to write the decoded light field to", "FILE");
+    opts.optopt("g", "grid", "Directory to write sub-aperture images to", "DIR");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+    if !matches.opt_present("out") && !matches.opt_present("grid") {
+        print_usage(my_name, opts);
+        panic!("Nothing to do; give --out and/or --grid");
+    }
+
+    // parse number of angles, interpolation
+    let na: usize = matches.opt_str("angles")
+                           .unwrap()
+                           .parse()
+                           .expect("Error parsing number of angles");
+    let interpolation = match matches.opt_str("interpolation") {
+        Some(name) => Interpolation::from_name(&name).expect("Invalid interpolation"),
+        None => Interpolation::Bilinear,
+    };
+
+    // read camera configuration
+    let camera_path = matches.opt_str("camera").unwrap();
+    let mut config = CameraConfig::<f32>::from_map(&table_from_file(&camera_path)
+                                                        .expect("Error reading config"))
+                         .expect("Error parsing camera config");
+    config.load_assets(&camera_path).expect("Error loading camera assets");
+    let camera = match config {
+        CameraConfig::PlenopticCamera(camera) => camera,
+        _ => panic!("plenoptic_decode needs a plenoptic camera"),
+    };
+
+    // vignetting normalization
+    let vignetting = match &matches.opt_str("vignetting").unwrap_or("none".to_string())[..] {
+        "none" => Vignetting::None,
+        "coverage" => Vignetting::Coverage,
+        "white" => {
+            let white_path = matches.opt_str("white").expect("White normalization needs --white");
+            Vignetting::White(camera.detector
+                                    .load(white_path)
+                                    .expect("Error reading white image"))
+        }
+        _ => panic!("Invalid vignetting normalization"),
+    };
+
+    // geometry of the views
+    let mut geom = PlenopticDecoder::lens_grid_geometry(&camera);
+    if let Some(pitch) = matches.opt_str("pitch") {
+        let pitch: f32 = pitch.parse().expect("Error parsing pitch");
+        let (s0, s1, t0, t1) = geom.spatial_bounds();
+        geom = ImageGeometry {
+            ns: ((s1 - s0) / pitch).ceil() as usize,
+            nt: ((t1 - t0) / pitch).ceil() as usize,
+            ds: pitch,
+            dt: pitch,
+            offset_s: -(s0 + s1) / 2f32 / pitch,
+            offset_t: -(t0 + t1) / 2f32 / pitch,
+        };
+    }
+
+    let raw = camera.detector
+                    .load(matches.opt_str("input").unwrap())
+                    .expect("Error reading raw capture");
+    let decoder = PlenopticDecoder::new(camera, geom, na, interpolation, vignetting);
+    let lf = decoder.light_field();
+    println!("Decoding {} views of {}x{} pixels",
+             lf.na(),
+             decoder.lfg.geom.ns,
+             decoder.lfg.geom.nt);
+    let buf = decoder.decode(&raw);
+
+    if let Some(out) = matches.opt_str("out") {
+        lf.write(&buf, &out).expect("Error writing light field");
+    }
+
+    if let Some(dir) = matches.opt_str("grid") {
+        let grid = SubapertureGrid::from_light_field_geometry(&decoder.lfg);
+        create_dir_all(&dir).expect("Error creating grid directory");
+        grid.export(&lf, &buf, &dir).expect("Error writing sub-aperture images");
+
+        let mut desc_path = PathBuf::from(&dir);
+        desc_path.push("grid.toml");
+        let mut file = File::create(&desc_path).expect("Error creating grid description");
+        write!(&mut file, "{}", encode_str(&grid.into_map()))
+            .expect("Error writing grid description");
+        println!("Wrote {}x{} sub-aperture images to {}", grid.rows, grid.cols, dir);
+    }
+}
@@ -17,25 +17,83 @@ fn print_usage(name: &String, opt: Options) {
     print!("{}", opt.usage(&brief));
 }
 
+/// Backprojects `images`, one per camera, on each camera's device
+fn back_all<F: Float + FromPrimitive>(geom: &LightVolume<F>,
+                                      imagers: &mut [Box<Imager<F, LightVolume<F>>>],
+                                      images: &[Vec<F>],
+                                      devices: &[usize],
+                                      queues: &[CommandQueue])
+                                      -> Result<Vec<Vec<F>>, Error> {
+    // launch every backprojection before waiting on any, so the devices
+    // run concurrently
+    let mut launched = Vec::new();
+    for ((im, img), &device) in imagers.iter_mut().zip(images.iter()).zip(devices.iter()) {
+        let queue = &queues[device];
+        let img_buf = try!(queue.create_buffer_from_slice(img));
+        let mut obj_buf = try!(geom.zeros_buf(queue));
+        let evt = try!(im.back(&img_buf, &mut obj_buf, &[]));
+        launched.push((device, img_buf, obj_buf, evt));
+    }
+
+    let mut tr = Vec::new();
+    for (device, _, obj_buf, evt) in launched.into_iter() {
+        try!(evt.wait());
+        let mut obj = geom.zeros();
+        try!(try!(queues[device].read_buffer(&obj_buf, &mut obj)).wait());
+        tr.push(obj);
+    }
+    Ok(tr)
+}
+
+/// Projects `objects`, one per camera, on each camera's device
+fn forw_all<F: Float + FromPrimitive>(imagers: &mut [Box<Imager<F, LightVolume<F>>>],
+                                      objects: &[Vec<F>],
+                                      devices: &[usize],
+                                      queues: &[CommandQueue])
+                                      -> Result<Vec<Vec<F>>, Error> {
+    let mut launched = Vec::new();
+    for ((im, obj), &device) in imagers.iter_mut().zip(objects.iter()).zip(devices.iter()) {
+        let queue = &queues[device];
+        let obj_buf = try!(queue.create_buffer_from_slice(obj));
+        let mut img_buf = try!(im.detector().zeros_buf(queue));
+        let evt = try!(im.forw(&obj_buf, &mut img_buf, &[]));
+        launched.push((device, obj_buf, img_buf, evt));
+    }
+
+    let mut tr = Vec::new();
+    for ((device, _, img_buf, evt), im) in launched.into_iter().zip(imagers.iter()) {
+        try!(evt.wait());
+        let mut img = im.detector().zeros();
+        try!(try!(queues[device].read_buffer(&img_buf, &mut img)).wait());
+        tr.push(img);
+    }
+    Ok(tr)
+}
+
+/// Computes the "FBP" of the measurements
+///
+/// `imagers[i]` was created on `queues[devices[i]]`.
 fn volume_fbp<F: Float + FromPrimitive + ToPrimitive>(geom: &LightVolume<F>,
                                                       mut imagers: Vec<Box<Imager<F,
                                                                                   LightVolume<F>>>>,
                                                       measurements: &[&[F]],
-                                                      queue: &CommandQueue)
+                                                      devices: &[usize],
+                                                      queues: &[CommandQueue])
                                                       -> Result<Vec<F>, Error> {
     let mut tr = geom.zeros();
 
     // TODO - filter measurements
     let mut filtered_measurements = Vec::new();
     for m in measurements.iter() {
-        filtered_measurements.push(m.to_owned());
+        filtered_measurements.push(m.to_vec());
     }
 
     // backproject filtered measurements
-    let mut backprojected_images = Vec::new();
-    for (im, m) in imagers.iter_mut().zip(filtered_measurements.iter()) {
-        backprojected_images.push(try!(im.back_host(m, queue)));
-    }
+    let backprojected_images = try!(back_all(geom,
+                                             &mut imagers,
+                                             &filtered_measurements,
+                                             devices,
+                                             queues));
 
     // add backprojected measurements
     for m in backprojected_images.iter() {
@@ -55,10 +113,7 @@ fn volume_fbp<F: Float + FromPrimitive + ToPrimitive>(geom: &LightVolume<F>,
     }
 
     // project new image
-    let mut projected_images = Vec::new();
-    for (im, m) in imagers.iter_mut().zip(backprojected_images.iter()) {
-        projected_images.push(try!(im.forw_host(m, queue)));
-    }
+    let projected_images = try!(forw_all(&mut imagers, &backprojected_images, devices, queues));
 
     // compute scale (from data-fidelity line search)
     let mut num = F::zero();
@@ -90,8 +145,8 @@ fn main() {
     let mut opts = Options::new();
     opts.reqopt("s", "scene", "TOML file describing scene", "FILE");
     opts.reqopt("a", "angles", "Angular discretization", "INT");
-    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac");
-    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
+    opts.optopt("d", "device", "OpenCL device to use (default: all)", "INT");
     opts.optflag("h", "help", "Print help and exit");
 
     // parse options
@@ -114,29 +169,29 @@ fn main() {
                            .unwrap()
                            .parse()
                            .expect("Error parsing number of angles");
-    let basis = match &matches.opt_str("basis").unwrap()[..] {
-        "dirac" => AngularBasis::Dirac,
-        "pillbox" => AngularBasis::Pillbox,
-        _ => panic!("Invalid angular basis"),
-    };
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
 
     // create opencl environment
     let env = Environment::new_easy().expect("Error starting OpenCL environment");
 
-    // use selected device
-    let device_id = match matches.opt_str("device") {
-        Some(s) => s.parse().expect("Error parsing device number"),
-        None => 0usize,
+    // use selected device, or spread cameras over all devices
+    let device_ids: Vec<usize> = match matches.opt_str("device") {
+        Some(s) => vec![s.parse().expect("Error parsing device number")],
+        None => (0..env.queues.len()).collect(),
     };
-
-    let queue = &env.queues[device_id];
-    println!("Using device id {} (of {}): {}",
-             device_id,
-             env.queues.len(),
-             queue.device()
-                  .expect("Error getting device info")
-                  .name()
-                  .expect("Error getting device name"));
+    let mut queues: Vec<CommandQueue> = Vec::new();
+    for &device_id in device_ids.iter() {
+        let queue = &env.queues[device_id];
+        println!("Using device id {} (of {}): {}",
+                 device_id,
+                 env.queues.len(),
+                 queue.device()
+                      .expect("Error getting device info")
+                      .name()
+                      .expect("Error getting device name"));
+        queues.push(queue.clone());
+    }
 
     // load scene description, object descriptions
     let scene = Scene::<f32>::read(matches.opt_str("s").unwrap())
@@ -148,19 +203,23 @@ fn main() {
     // branch based on the type of object given
     match object_config {
         ObjectConfig::LightVolume(geom) => {
-            // loop through cameras
+            // loop through cameras, assigning cameras to devices round-robin
             let mut imagers = Vec::new();
             let mut measurements = Vec::new();
-            for scene_cam in scene.cameras.iter() {
+            let mut devices = Vec::new();
+            for (icam, scene_cam) in scene.cameras.iter().enumerate() {
                 // create imager object for camera
-                println!("Loading camera {}", scene_cam.name);
+                let device = icam % queues.len();
+                println!("Loading camera {} on device id {}",
+                         scene_cam.name,
+                         device_ids[device]);
                 let config = scene_cam.get_config().expect("Error reading camera configuration");
                 let imager = config.volume_imager(geom.clone(),
                                                   scene_cam.position.clone(),
                                                   scene_cam.rotation.clone(),
                                                   na,
                                                   basis.clone(),
-                                                  queue.clone())
+                                                  queues[device].clone())
                                    .expect("Error creating Imager for camera");
 
                 // load data
@@ -170,12 +229,13 @@ fn main() {
 
                 measurements.push(meas);
                 imagers.push(imager);
+                devices.push(device);
             }
 
             let measurement_slices: Vec<&[f32]> = measurements.iter().map(|m| &m[..]).collect();
             println!("Running \"FBP\"");
 
-            let x_fbp = volume_fbp(&geom, imagers, &measurement_slices, queue)
+            let x_fbp = volume_fbp(&geom, imagers, &measurement_slices, &devices, &queues)
                             .expect("Error computing FBP");
 
             geom.save(&x_fbp, &scene.object.data_path).expect("Error saving image");
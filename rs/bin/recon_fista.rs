@@ -26,7 +26,7 @@ fn main() {
     let mut opts = Options::new();
     opts.reqopt("s", "scene", "TOML file describing scene", "FILE");
     opts.reqopt("a", "angles", "Angular discretization", "INT");
-    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
     opts.optopt("i",
                 "interval",
                 "Save an image every N iterations (default 1)",
@@ -39,9 +39,26 @@ fn main() {
                 "subsets",
                 "Number of view subsets for acceleration (default 1)",
                 "INT");
+    opts.optopt("",
+                "lanes",
+                "Project each camera's subsets through N imagers, each on a command queue of \
+                 its own (default 1; ignored with --memory-budget)",
+                "N");
     opts.optflag("m", "mask", "Use spherical mask");
     opts.optflag("g", "gain", "Use gain estimation for multiple cameras");
-    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.optopt("d", "device", "OpenCL device to use (default: all)", "INT");
+    opts.optopt("",
+                "memory-budget",
+                "Stream the volume through the device in slabs of at most MB megabytes \
+                 (default: keep the whole volume on the device)",
+                "MB");
+    opts.optflag("p",
+                 "profile",
+                 "Time OpenCL kernels and print a summary every iteration (serializes kernels)");
+    opts.optopt("",
+                "trace",
+                "Time OpenCL kernels and write a Chrome trace (chrome://tracing) to FILE",
+                "FILE");
     opts.optflag("h", "help", "Print help and exit");
 
     // parse options
@@ -64,11 +81,8 @@ fn main() {
                            .unwrap()
                            .parse()
                            .expect("Error parsing number of angles");
-    let basis = match &matches.opt_str("basis").unwrap()[..] {
-        "dirac" => AngularBasis::Dirac,
-        "pillbox" => AngularBasis::Pillbox,
-        _ => panic!("Invalid angular basis"),
-    };
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
 
     let gain_estimation = matches.opt_present("gain");
     if gain_estimation {
@@ -78,20 +92,23 @@ fn main() {
     // create opencl environment
     let env = Environment::new_easy().expect("Error starting OpenCL environment");
 
-    // use selected device
-    let device_id = match matches.opt_str("device") {
-        Some(s) => s.parse().expect("Error parsing device number"),
-        None => 0usize,
+    // use selected device, or spread cameras over all devices
+    let device_ids: Vec<usize> = match matches.opt_str("device") {
+        Some(s) => vec![s.parse().expect("Error parsing device number")],
+        None => (0..env.queues.len()).collect(),
     };
-
-    let queue = &env.queues[device_id];
-    println!("Using device id {} (of {}): {}",
-             device_id,
-             env.queues.len(),
-             queue.device()
-                  .expect("Error getting device info")
-                  .name()
-                  .expect("Error getting device name"));
+    let mut queues: Vec<CommandQueue> = Vec::new();
+    for &device_id in device_ids.iter() {
+        let queue = &env.queues[device_id];
+        println!("Using device id {} (of {}): {}",
+                 device_id,
+                 env.queues.len(),
+                 queue.device()
+                      .expect("Error getting device info")
+                      .name()
+                      .expect("Error getting device name"));
+        queues.push(queue.clone());
+    }
 
     // load scene description, object descriptions
     let scene = Scene::<f32>::read(matches.opt_str("s").unwrap())
@@ -121,31 +138,108 @@ fn main() {
     };
     println!("Number of subsets: {}", nsubset);
 
+    // parse number of imager lanes per camera
+    let num_lanes: usize = match matches.opt_str("lanes") {
+        Some(s) => s.parse().expect("Error parsing number of lanes"),
+        None => 1usize,
+    };
+    assert!(num_lanes > 0, "--lanes must be at least 1");
+
+    // parse device memory budget
+    let memory_budget: Option<usize> = match matches.opt_str("memory-budget") {
+        Some(s) => {
+            let mb: usize = s.parse().expect("Error parsing memory budget");
+            Some(mb * 1024 * 1024)
+        }
+        None => None,
+    };
+
+    // kernel profiling
+    let print_profile = matches.opt_present("profile");
+    let trace_path = matches.opt_str("trace");
+    if print_profile || trace_path.is_some() {
+        println!("Profiling OpenCL kernels");
+        enable_profiling();
+    }
+
     // branch based on the type of object given
     match object_config {
         ObjectConfig::LightVolume(geom) => {
             // loop through cameras
             let mut imagers = Vec::new();
+            let mut slab_imagers = Vec::new();
             let mut measurements = Vec::new();
-            for scene_cam in scene.cameras.iter() {
-                // create imager object for camera
-                println!("Loading camera {}", scene_cam.name);
+            let mut camera_devices = Vec::new();
+            let mut camera_lanes = Vec::new();
+            for (icam, scene_cam) in scene.cameras.iter().enumerate() {
+                // create imager object for camera, assigning cameras to
+                // devices round-robin
+                let device = icam % queues.len();
+                println!("Loading camera {} on device id {}",
+                         scene_cam.name,
+                         device_ids[device]);
                 let config = scene_cam.get_config().expect("Error reading camera configuration");
-                let imager = config.volume_imager(geom.clone(),
-                                                  scene_cam.position.clone(),
-                                                  scene_cam.rotation.clone(),
-                                                  na,
-                                                  basis.clone(),
-                                                  queue.clone())
-                                   .expect("Error creating Imager for camera");
+                let detector_ig = match memory_budget {
+                    None => {
+                        let imager = config.volume_imager(geom.clone(),
+                                                          scene_cam.position.clone(),
+                                                          scene_cam.rotation.clone(),
+                                                          na,
+                                                          basis.clone(),
+                                                          queues[device].clone())
+                                           .expect("Error creating Imager for camera");
+                        let detector_ig = imager.detector().image_geometry();
+                        imagers.push(imager);
+
+                        // further imagers for the camera, on queues of their own
+                        let device = queues[device].device().expect("Error getting device");
+                        let mut lanes = Vec::new();
+                        for _ in 1..num_lanes {
+                            let queue = CommandQueue::new(env.ctx.clone(), device.clone())
+                                            .expect("Error creating command queue");
+                            lanes.push(config.volume_imager(geom.clone(),
+                                                            scene_cam.position.clone(),
+                                                            scene_cam.rotation.clone(),
+                                                            na,
+                                                            basis.clone(),
+                                                            queue)
+                                             .expect("Error creating Imager for camera"));
+                        }
+                        camera_lanes.push(lanes);
+                        detector_ig
+                    }
+                    Some(budget) => {
+                        // stream z-slabs of the volume through the device
+                        assert!(scene_cam.rotation.is_none(),
+                                "--memory-budget does not support rotated cameras");
+                        let queue = &queues[device];
+                        let imager = SlabImager::<f32, ClBackend>::with_budget(geom.clone(),
+                                                                               budget,
+                                                                               queue.clone(),
+                                                                               |slab_geom| {
+                            config.volume_imager(slab_geom,
+                                                 scene_cam.position.clone(),
+                                                 None,
+                                                 na,
+                                                 basis.clone(),
+                                                 queue.clone())
+                        })
+                                         .expect("Error creating Imager for camera");
+                        println!("Streaming volume in {} slabs", imager.num_slabs());
+                        let detector_ig = imager.detector().image_geometry();
+                        let imager: Box<Imager<f32, LightVolume<f32>, HostBackend>> =
+                            Box::new(imager);
+                        slab_imagers.push(imager);
+                        detector_ig
+                    }
+                };
 
                 // load data
-                let detector_ig = imager.detector().image_geometry();
                 let meas = detector_ig.load(&scene_cam.data_path)
                                       .expect("Error reading measurements");
 
                 measurements.push(meas);
-                imagers.push(imager);
+                camera_devices.push(device);
             }
 
             // load initial image
@@ -163,56 +257,68 @@ fn main() {
             // create fista solver
             let measurement_slices: Vec<&[f32]> = measurements.iter().map(|m| &m[..]).collect();
             println!("Initializing FISTA solver");
-            let mut solver = FistaVolumeSolver::new(geom.clone(),
-                                                    imagers,
-                                                    &measurement_slices,
-                                                    Some(&x0),
-                                                    &scene.object.sparsifying,
-                                                    &scene.object.edge_preserving,
-                                                    nsubset,
-                                                    scene.object.box_min,
-                                                    scene.object.box_max,
-                                                    gain_estimation,
-                                                    queue.clone())
-                                 .expect("Error creating FISTA solver");
-
-            if matches.opt_present("mask") {
-                println!("Using spherical mask");
-                solver.compute_mask3().expect("Error computing spherical mask");
-            }
-
-            // loop iterations
-            for iter in 0.. {
-                match niter {
-                    Some(niter) => {
-                        if niter == iter {
-                            break;
+            match memory_budget {
+                None => {
+                    let mut solver = FistaVolumeSolver::new_multi_device(geom.clone(),
+                                                                     imagers,
+                                                                     &measurement_slices,
+                                                                     Some(&x0),
+                                                                     &scene.object.sparsifying,
+                                                                     &scene.object.edge_preserving,
+                                                                     nsubset,
+                                                                     scene.object.box_min,
+                                                                     scene.object.box_max,
+                                                                     gain_estimation,
+                                                                     queues.clone(),
+                                                                     camera_devices)
+                                     .expect("Error creating FISTA solver");
+                    if num_lanes > 1 {
+                        println!("Projecting each camera through {} lanes", num_lanes);
+                        for (icam, lanes) in camera_lanes.into_iter().enumerate() {
+                            solver.set_imager_lanes(icam, lanes)
+                                  .expect("Error creating imager lanes");
                         }
                     }
-                    None => {}
+                    run_fista(solver,
+                              &queues[0],
+                              &geom,
+                              &scene,
+                              matches.opt_present("mask"),
+                              interval,
+                              niter,
+                              nsubset,
+                              &device_ids,
+                              print_profile,
+                              &trace_path);
                 }
-
-                // Run FISTA iteration
-                let time_start = precise_time_s();
-                println!("Starting iteration {}", iter + 1);
-                solver.run_subset(iter % nsubset, &[])
-                      .expect("Error running FISTA iteration")
-                      .wait()
-                      .expect("Error waiting for FISTA iteration to complete");
-                let time_stop = precise_time_s();
-                println!("Iteration {} took {} seconds", iter + 1, time_stop - time_start);
-
-                // Get image
-                if iter % interval == 0 {
-                    let x_buf = solver.image_buffer();
-                    let mut x = geom.zeros();
-                    queue.read_buffer(&x_buf, &mut x)
-                         .expect("Error reading image buffer")
-                         .wait()
-                         .expect("Error waiting waiting for image buffer transfer to complete");
-
-                    geom.save(&x, &scene.object.data_path).expect("Error saving image");
-                    println!("Saved image");
+                Some(_) => {
+                    // the image and solver state stay in host memory
+                    let host_queues = vec![HostQueue; queues.len()];
+                    let solver = FistaVolumeSolver::<f32, HostBackend>::new_multi_device(
+                                     geom.clone(),
+                                     slab_imagers,
+                                     &measurement_slices,
+                                     Some(&x0),
+                                     &scene.object.sparsifying,
+                                     &scene.object.edge_preserving,
+                                     nsubset,
+                                     scene.object.box_min,
+                                     scene.object.box_max,
+                                     gain_estimation,
+                                     host_queues,
+                                     camera_devices)
+                                     .expect("Error creating FISTA solver");
+                    run_fista(solver,
+                              &HostQueue,
+                              &geom,
+                              &scene,
+                              matches.opt_present("mask"),
+                              interval,
+                              niter,
+                              nsubset,
+                              &device_ids,
+                              print_profile,
+                              &trace_path);
                 }
             }
 
@@ -220,3 +326,69 @@ fn main() {
         }
     }
 }
+
+/// Runs FISTA iterations, saving the image along the way
+fn run_fista<B: FistaBackend<f32>>(mut solver: FistaVolumeSolver<f32, B>,
+                                   queue: &B::Queue,
+                                   geom: &LightVolume<f32>,
+                                   scene: &Scene<f32>,
+                                   use_mask: bool,
+                                   interval: usize,
+                                   niter: Option<usize>,
+                                   nsubset: usize,
+                                   device_ids: &[usize],
+                                   print_profile: bool,
+                                   trace_path: &Option<String>) {
+    let mut trace = Profile::default();
+
+    if use_mask {
+        println!("Using spherical mask");
+        solver.compute_mask3().expect("Error computing spherical mask");
+    }
+
+    // loop iterations
+    for iter in 0.. {
+        match niter {
+            Some(niter) => {
+                if niter == iter {
+                    break;
+                }
+            }
+            None => {}
+        }
+
+        // Run FISTA iteration
+        let time_start = precise_time_s();
+        println!("Starting iteration {}", iter + 1);
+        let evt = solver.run_subset(iter % nsubset, &[])
+                        .expect("Error running FISTA iteration");
+        B::wait(&evt).expect("Error waiting for FISTA iteration to complete");
+        let time_stop = precise_time_s();
+        println!("Iteration {} took {} seconds", iter + 1, time_stop - time_start);
+        if device_ids.len() > 1 {
+            for (id, u) in device_ids.iter().zip(solver.device_utilization().iter()) {
+                println!("Device id {} utilization: {:.1}%", id, 100f64 * u);
+            }
+        }
+
+        // report kernel times
+        let profile = take_profile();
+        if print_profile {
+            print!("{}", profile.summary());
+        }
+        if let &Some(ref path) = trace_path {
+            trace.extend(profile);
+            trace.save_chrome_trace(path).expect("Error saving trace");
+        }
+
+        // Get image
+        if iter % interval == 0 {
+            let x_buf = solver.image_buffer();
+            let mut x = geom.zeros();
+            B::read_buffer(queue, &x_buf, &mut x, &[]).expect("Error reading image buffer");
+
+            geom.save(&x, &scene.object.data_path).expect("Error saving image");
+            println!("Saved image");
+        }
+    }
+}
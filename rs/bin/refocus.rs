@@ -0,0 +1,138 @@
+extern crate lightfield;
+extern crate getopts;
+
+use self::getopts::Options;
+use std::env;
+use self::lightfield::*;
+
+// usage example:
+// refocus --camera plenoptic.toml --input raw.png --angles 7 --basis dirac \
+//         --depths 400,500,600 --out stack_{}.png
+
+fn print_usage(name: &String, opts: Options) {
+    let brief = format!("Usage: {} [options]", name);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    // get program name
+    let args: Vec<String> = env::args().collect();
+    let my_name = &args[0];
+
+    // set up command line options parser
+    let mut opts = Options::new();
+    opts.reqopt("c", "camera", "TOML file describing a plenoptic camera", "FILE");
+    opts.reqopt("i", "input", "Raw capture from the camera's detector", "FILE");
+    opts.reqopt("a", "angles", "Angular discretization", "INT");
+    opts.reqopt("b", "basis", "Angular basis function", "pillbox | dirac | linear");
+    opts.reqopt("z",
+                "depths",
+                "Comma-separated object distances to focus at",
+                "DISTANCE,...");
+    opts.reqopt("o",
+                "out",
+                "Output path for each image, with {} replaced by its index in the stack",
+                "FILE");
+    opts.optopt("r",
+                "radius",
+                "Radius of the synthetic aperture on the main lens (default: full aperture)",
+                "RADIUS");
+    opts.optopt("d", "device", "OpenCL device to use (default: 0)", "INT");
+    opts.optflag("h", "help", "Print help and exit");
+
+    // parse options
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => {
+            print_usage(my_name, opts);
+            panic!(f.to_string());
+        }
+    };
+
+    // if help requested, display and exit
+    if matches.opt_present("h") {
+        print_usage(my_name, opts);
+        return;
+    }
+
+    // parse number of angles, basis function, depths
+    let na: usize = matches.opt_str("angles")
+                           .unwrap()
+                           .parse()
+                           .expect("Error parsing number of angles");
+    let basis = AngularBasis::from_name(&matches.opt_str("basis").unwrap())
+                    .expect("Invalid angular basis");
+    let depths: Vec<f32> = matches.opt_str("depths")
+                                  .unwrap()
+                                  .split(',')
+                                  .map(|z| z.trim().parse().expect("Error parsing depth"))
+                                  .collect();
+    let out = matches.opt_str("out").unwrap();
+
+    // create opencl environment
+    let env = Environment::new_easy().expect("Error starting OpenCL environment");
+
+    // use selected device
+    let device_id = match matches.opt_str("device") {
+        Some(s) => s.parse().expect("Error parsing device number"),
+        None => 0usize,
+    };
+    let queue = &env.queues[device_id];
+    println!("Using device id {} (of {}): {}",
+             device_id,
+             env.queues.len(),
+             queue.device()
+                  .expect("Error getting device info")
+                  .name()
+                  .expect("Error getting device name"));
+
+    // read camera configuration
+    let camera_path = matches.opt_str("camera").unwrap();
+    let mut config = CameraConfig::<f32>::from_map(&table_from_file(&camera_path)
+                                                        .expect("Error reading config"))
+                         .expect("Error parsing camera config");
+    config.load_assets(&camera_path).expect("Error loading camera assets");
+    let camera = match config {
+        CameraConfig::PlenopticCamera(camera) => camera,
+        _ => panic!("refocus needs a plenoptic camera"),
+    };
+
+    // light field on the microlens array, parameterized on the main lens
+    let array_lfg = camera.array_light_field_geometry(basis, na);
+    let raw = camera.detector
+                    .load(matches.opt_str("input").unwrap())
+                    .expect("Error reading raw capture");
+    println!("Extracting {} views", array_lfg.plane.na());
+    let lf = backprojected_light_field(&camera, &array_lfg, &raw, queue)
+                 .expect("Error extracting light field");
+
+    // synthetic aperture
+    let aperture = match matches.opt_str("radius") {
+        Some(r) => {
+            SyntheticAperture::Stop(Aperture::Annulus(AnnularAperture {
+                center_s: camera.lens.center_s,
+                center_t: camera.lens.center_t,
+                outer_radius: r.parse().expect("Error parsing aperture radius"),
+                inner_radius: 0f32,
+            }))
+        }
+        None => SyntheticAperture::Full,
+    };
+    let refocuser = Refocuser::new(array_lfg.clone(), &aperture)
+                        .expect("Invalid synthetic aperture");
+
+    // render one image per depth, on a virtual sensor behind the main lens
+    let lens_optics = camera.lens_transfer()
+                            .as_optics()
+                            .expect("Main lens transfer couples s and t");
+    for (iz, &z) in depths.iter().enumerate() {
+        let post_optics = lens_optics.then(&Optics::translation(&z));
+        let (ds, dt) = Optics::focus_at_distance(&Optics::identity(), &post_optics);
+        let focus = refocuser.focal_plane(array_lfg.geom.clone(), (ds + dt) / 2f32);
+
+        let image = refocuser.refocus(&lf, &focus, queue).expect("Error refocusing");
+        let path = out.replace("{}", &iz.to_string());
+        println!("Focused at {} -> {}", z, path);
+        focus.geom.save(&image, &path).expect("Error saving image");
+    }
+}
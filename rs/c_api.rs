@@ -132,6 +132,7 @@ impl LFAngularPlane {
         let basis = match self.basis {
             0 => AngularBasis::Pillbox,
             1 => AngularBasis::Dirac,
+            2 => AngularBasis::Linear,
             _ => panic!("LFAngularPlane.basis enum had unexpected value"),
         };
 
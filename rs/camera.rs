@@ -29,7 +29,10 @@ pub enum CameraConfig<F: Float> {
 }
 
 impl<F: 'static + Float + FromPrimitive + BaseFloat + ApproxEq<F>> CameraConfig<F> {
-    pub fn focus_at_distance(self: &mut Self, distance: F) -> () {
+    /// Focuses the camera at `distance`
+    ///
+    /// Returns `Err` if the main lens has no real focus.
+    pub fn focus_at_distance(self: &mut Self, distance: F) -> Result<(), ()> {
         match self {
             &mut CameraConfig::SingleLensCamera(ref mut slc) => slc.focus_at_distance(distance),
             &mut CameraConfig::CodedApertureCamera(ref mut cac) => cac.focus_at_distance(distance),
@@ -1,5 +1,44 @@
 extern crate proust;
+extern crate num;
+extern crate byteorder;
 use self::proust::*;
+use self::num::ToPrimitive;
+use self::byteorder::*;
+use std::mem::size_of;
+
+/// Returns true if `F` is represented by `double` rather than `float` in OpenCL
+///
+/// OpenCL sources are written in terms of a `real` type; see `real_header`.
+pub fn is_double<F>() -> bool {
+    size_of::<F>() == 8
+}
+
+/// Returns the OpenCL source defining `real` for `F`
+pub fn real_header<F>() -> &'static str {
+    if is_double::<F>() {
+        include_str!("../cl/real_f64.opencl")
+    } else {
+        include_str!("../cl/real_f32.opencl")
+    }
+}
+
+/// Writes a value as an OpenCL `real` for `F`
+pub fn write_real<F: ToPrimitive>(buf: &mut Vec<u8>, x: &F) {
+    if is_double::<F>() {
+        buf.write_f64::<LittleEndian>(F::to_f64(x).unwrap()).unwrap();
+    } else {
+        buf.write_f32::<LittleEndian>(F::to_f32(x).unwrap()).unwrap();
+    }
+}
+
+/// Pads a buffer with zeros up to the alignment of an OpenCL `real` for `F`
+///
+/// Needed after `int` fields that precede `real` fields or end a struct.
+pub fn align_real<F>(buf: &mut Vec<u8>) {
+    while buf.len() % size_of::<F>() != 0 {
+        buf.push(0u8);
+    }
+}
 
 /// Object that has useful headers/defines
 pub trait ClHeader {
@@ -28,3 +67,22 @@ impl<T> ClBuffer for Vec<T> where T: ClBuffer
         }
     }
 }
+
+#[test]
+fn test_write_real() {
+    let mut b32 = Vec::new();
+    b32.write_i32::<LittleEndian>(1).unwrap();
+    align_real::<f32>(&mut b32);
+    write_real(&mut b32, &1.5f32);
+    assert_eq!(b32.len(), 8);
+
+    let mut b64 = Vec::new();
+    b64.write_i32::<LittleEndian>(1).unwrap();
+    align_real::<f64>(&mut b64);
+    write_real(&mut b64, &1.5f64);
+    assert_eq!(b64.len(), 16);
+    assert_eq!((&b64[8..]).read_f64::<LittleEndian>().unwrap(), 1.5f64);
+
+    assert!(real_header::<f32>().contains("typedef float real;"));
+    assert!(real_header::<f64>().contains("cl_khr_fp64"));
+}
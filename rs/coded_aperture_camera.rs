@@ -4,10 +4,12 @@ use serialize::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::toml::*;
 use lens::*;
+use aperture::*;
+use angular_plane::*;
 use detector::*;
 use image_geom::*;
 use std::path::Path;
-use optics::*;
+use ray_transfer::*;
 use geom::*;
 
 /// Description of a coded aperture camera
@@ -20,26 +22,60 @@ pub struct CodedApertureCamera<F: Float> {
     pub distance_detector_mask: F,
     pub mask_path: String,
     pub mask: Option<Vec<F>>,
+
+    /// Aperture stop replacing the main lens' aperture, if any
+    pub stop: Option<Aperture<F>>,
+
+    /// Ray transfer replacing the main lens' thin lens optics, if any
+    pub transfer: Option<RayTransfer<F>>,
 }
 
 impl<F: Float + FromPrimitive> CodedApertureCamera<F> {
-    pub fn focus_at_distance(self: &mut Self, focus_distance: F) {
-        let pre_optics = Optics::translation(&self.distance_detector_mask);
-        let post_optics = self.lens.optics().then(&Optics::translation(&focus_distance));
-        let (distance_s, distance_t) = Optics::focus_at_distance(&pre_optics, &post_optics);
+    /// Returns the angular plane on the main lens
+    pub fn angular_plane(self: &Self, basis: AngularBasis, na: usize) -> AngularPlane<F> {
+        main_lens_angular_plane(&self.lens, &self.stop, basis, na)
+    }
+
+    /// Returns the ray transfer through the main lens
+    pub fn lens_transfer(self: &Self) -> RayTransfer<F> {
+        main_lens_transfer(&self.lens, &self.transfer)
+    }
+
+    /// Moves the mask and the detector so that the main lens focuses onto
+    /// the mask at `focus_distance`
+    ///
+    /// Returns `Err` if the main lens has no real focus.
+    pub fn focus_at_distance(self: &mut Self, focus_distance: F) -> Result<(), ()> {
+        let pre_optics = RayTransfer::translation(&self.distance_detector_mask);
+        let post_optics = self.lens_transfer().then(&RayTransfer::translation(&focus_distance));
+        let (distance_s, distance_t) = try!(RayTransfer::focus_at_distance(&pre_optics,
+                                                                            &post_optics));
         self.distance_lens_mask = (distance_s + distance_t) / (F::one() + F::one());
+        Ok(())
     }
 
     pub fn describe(self: &Self) -> String {
-        let optics = Optics::translation(&(self.distance_lens_mask + self.distance_detector_mask)).then(&self.lens.optics());
-        let (ds, dt) = optics.focused_distance();
-        format!("Coded Aperture Camera focused at ({}, {})", 
-                F::to_f32(&ds).unwrap(), F::to_f32(&dt).unwrap())
+        let optics = RayTransfer::translation(&(self.distance_lens_mask + self.distance_detector_mask)).then(&self.lens_transfer());
+        match optics.focused_distance() {
+            Ok((ds, dt)) => {
+                format!("Coded Aperture Camera focused at ({}, {})", 
+                        F::to_f32(&ds).unwrap(), F::to_f32(&dt).unwrap())
+            }
+            Err(_) => "Coded Aperture Camera with a main lens that does not focus".to_string(),
+        }
     }
 }
 
 impl<F: Float + FromPrimitive + ToPrimitive> Serialize for CodedApertureCamera<F> {
     fn from_map(map: &Table) -> Option<Self> {
+        let stop = match stop_from_map(map) {
+            Ok(stop) => stop,
+            Err(_) => return None,
+        };
+        let transfer = match transfer_from_map(map) {
+            Ok(transfer) => transfer,
+            Err(_) => return None,
+        };
         let lens = map.get("lens");
         let detector = map.get("detector");
         let mask_geometry = map.get("mask_geometry");
@@ -71,6 +107,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for CodedApertureCamera<F
                             distance_detector_mask: F::from_f64(distance_detector_mask).unwrap(),
                             mask_path: mask_path.clone(),
                             mask: None,
+                            stop: stop,
+                            transfer: transfer,
                         })
                     }
                     _ => None,
@@ -93,10 +131,19 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for CodedApertureCamera<F
                   Value::Float(F::to_f64(&self.distance_detector_mask).unwrap()));
         tr.insert("mask_path".to_string(),
                   Value::String(self.mask_path.clone()));
+        if let Some(ref stop) = self.stop {
+            tr.insert("stop".to_string(), Value::Table(stop.into_map()));
+        }
+        if let Some(ref transfer) = self.transfer {
+            tr.insert("transfer".to_string(), Value::Table(transfer.into_map()));
+        }
         tr
     }
 
     fn load_assets<P: AsRef<Path>>(self: &mut Self, root_path: P) -> Result<(), ()> {
+        if let Some(ref mut stop) = self.stop {
+            try!(stop.load_assets(root_path.as_ref()));
+        }
         if self.mask.is_none() {
             let mut pb = root_path.as_ref().to_path_buf();
             pb.pop(); // pop off configuration file name
@@ -173,3 +220,65 @@ fn test_coded_aperture() {
     assert_eq!(camera.lens.focal_length_s, 12.0);
     assert_eq!(camera.lens.focal_length_t, 24.0);
 }
+
+#[test]
+fn test_coded_aperture_with_transfer() {
+    use ray_transfer::*;
+
+    let test = r#"
+    distance_lens_mask = 32.0
+    distance_detector_mask = 2.0
+    mask_path = "../mask.fld"
+
+    [mask_geometry]
+    ns = 300
+    nt = 200
+    ds = 1e-2
+    dt = 1e-3
+    offset_s = 0.5
+    offset_t = 0.25
+
+    [detector]
+    ns = 1024
+    nt = 2048
+    ds = 5e-2
+    dt = 5e-3
+    offset_s = 1.0
+    offset_t = 2.0
+
+    [lens]
+    center_s = 0.0
+    center_t = 0.0
+    radius_s = 4.0
+    radius_t = 4.0
+    focal_length_s = 12.0
+    focal_length_t = 24.0
+
+    [transfer]
+    ss = 1.0
+    uu = 1.0
+    tt = 1.0
+    vv = 1.0
+    us = -0.0625
+    ut = -0.02
+    vs = -0.02
+    vt = -0.0625
+    "#;
+
+    let map = Parser::new(test).parse().unwrap();
+    let camera: CodedApertureCamera<f32> = CodedApertureCamera::from_map(&map).unwrap();
+    let transfer = camera.lens_transfer();
+    assert!(!transfer.is_separable());
+    assert_eq!(transfer.m[1][2], -0.02);
+    assert_eq!(transfer.m[3][0], -0.02);
+
+    let reread: CodedApertureCamera<f32> = CodedApertureCamera::from_map(&camera.into_map())
+                                               .unwrap();
+    assert_eq!(reread.transfer, camera.transfer);
+
+    let plain: CodedApertureCamera<f32> = CodedApertureCamera {
+        transfer: None,
+        ..camera
+    };
+    assert_eq!(plain.lens_transfer(), RayTransfer::from_optics(&plain.lens.optics()));
+}
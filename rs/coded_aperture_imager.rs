@@ -4,7 +4,7 @@ extern crate num;
 use imager::*;
 use self::proust::*;
 use light_volume::*;
-use self::num::{FromPrimitive, Float};
+use self::num::{FromPrimitive, Float, ToPrimitive};
 use self::nalgebra::Vector3;
 use angular_plane::*;
 use volume_transport::*;
@@ -15,11 +15,12 @@ use mask::*;
 use detector::*;
 use transport::*;
 use geom::*;
+use ray_transfer::*;
 
 /// Implementation of an imager for a volume
 pub struct CodedApertureVolumeImager<F: Float + FromPrimitive> {
     geom: LightVolume<F>,
-    volume_xport: VolumeTransport<F>,
+    volume_xport: LensVolumeTransport<F>,
     tmp_buf: Mem,
     mask: Mask<F>,
     xport: Transport<F>,
@@ -27,7 +28,7 @@ pub struct CodedApertureVolumeImager<F: Float + FromPrimitive> {
     detector: Detector<F>,
 }
 
-impl<F: Float + FromPrimitive> CodedApertureVolumeImager<F> {
+impl<F: Float + FromPrimitive + ToPrimitive> CodedApertureVolumeImager<F> {
     pub fn new(geom: LightVolume<F>,
                camera: CodedApertureCamera<F>,
                position: Vector3<F>,
@@ -36,7 +37,7 @@ impl<F: Float + FromPrimitive> CodedApertureVolumeImager<F> {
                queue: CommandQueue)
                -> Result<Self, Error> {
         // angular plane on main lens
-        let plane = camera.lens.as_angular_plane(basis, na);
+        let plane = camera.angular_plane(basis, na);
 
         // light field geometry on mask plane
         let mask_lfg = LightFieldGeometry {
@@ -72,19 +73,17 @@ impl<F: Float + FromPrimitive> CodedApertureVolumeImager<F> {
         frame_geom.offset_x = frame_geom.offset_x + camera_ox;
         frame_geom.offset_y = frame_geom.offset_y + camera_oy;
 
-        let optics_object_to_plane = camera.lens
-                                           .optics()
-                                           .then(&Optics::translation(&distance_to_object))
-                                           .invert();
+        let to_object = RayTransfer::from_optics(&Optics::translation(&distance_to_object));
+        let object_to_plane = camera.lens_transfer().then(&to_object).invert();
 
         // transport from object to mask
-        let volume_xport = try!(VolumeTransport::new(frame_geom,
-                                                     mask_lfg.clone(),
-                                                     optics_object_to_plane,
-                                                     true, // overwrite_forw
-                                                     false, // overwrite_back
-                                                     false, // onto_detector
-                                                     queue.clone()));
+        let volume_xport = try!(LensVolumeTransport::new(frame_geom,
+                                                         mask_lfg.clone(),
+                                                         object_to_plane,
+                                                         true, // overwrite_forw
+                                                         false, // overwrite_back
+                                                         false, // onto_detector
+                                                         queue.clone()));
 
         let xport = try!(Transport::new(mask_lfg,
                                         det_lfg,
@@ -109,7 +108,8 @@ impl<F: Float + FromPrimitive> CodedApertureVolumeImager<F> {
     }
 }
 
-impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for CodedApertureVolumeImager<F> {
+impl<F: Float + FromPrimitive + ToPrimitive> Imager<F, LightVolume<F>>
+    for CodedApertureVolumeImager<F> {
     fn na(self: &Self) -> usize {
         self.plane.s.len()
     }
@@ -138,6 +138,18 @@ impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for CodedApertureVolume
         self.xport.forw(&tmp_copy, view, ia, &[evt])
     }
 
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &Mem,
+                             view: &mut Mem,
+                             ia: usize,
+                             wait_for: &[Event])
+                             -> Result<Event, Error> {
+        let mut tmp_copy = self.tmp_buf.clone();
+        let mut evt = try!(self.volume_xport.forw_linearized(object, &mut tmp_copy, ia, wait_for));
+        evt = try!(self.mask.apply_mask(&mut tmp_copy, &[evt]));
+        self.xport.forw(&tmp_copy, view, ia, &[evt])
+    }
+
     fn back_angle(self: &mut Self,
                   view: &Mem,
                   object: &mut Mem,
@@ -0,0 +1,507 @@
+extern crate nalgebra;
+extern crate num;
+extern crate proust;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::proust::*;
+use geom::*;
+use image_geom::*;
+use light_field_geom::*;
+use optics::*;
+use refocus::*;
+use angular_plane::*;
+use plenoptic_camera::*;
+use plenoptic_imager::*;
+
+/// Per-pixel depth and confidence
+#[derive(Clone, Debug)]
+pub struct DepthMap<F: Float> {
+    /// Pixels of the map, on the estimator's reference plane
+    pub geom: ImageGeometry<F>,
+
+    /// Object distance in front of the scene optics, in scene units
+    pub depth: Vec<F>,
+
+    /// Confidence in `[0, 1]`; near zero where the sweep found no clear
+    /// minimum, e.g. in textureless regions
+    pub confidence: Vec<F>,
+}
+
+/// Estimates depth from a light field with a correspondence sweep
+///
+/// For each candidate depth, the views are transported to the plane the
+/// scene optics focus that depth onto.  Where the depth is right, every
+/// view sees the same scene point and the weighted variance across views
+/// is smallest.  The focal planes are scaled so that their pixels lie on
+/// the same chief rays through the origin of the angular plane, which
+/// makes the costs of one pixel comparable across depths.
+#[derive(Clone, Debug)]
+pub struct DepthEstimator<F: Float> {
+    pub refocuser: Refocuser<F>,
+
+    /// Optics from the angular plane out into the scene, usually the main
+    /// lens' optics
+    pub to_scene: Optics<F>,
+
+    /// Pixels of the depth map on the reference plane
+    pub geom: ImageGeometry<F>,
+
+    /// Distance from the reference plane to the angular plane
+    pub distance: F,
+
+    /// Variance across views expected from noise alone
+    ///
+    /// Cost differences much smaller than this do not count towards
+    /// confidence.  A small multiple of the light field's power is always
+    /// added so that flat regions get no confidence even without noise.
+    pub noise: F,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> DepthEstimator<F> {
+    /// Creates an estimator whose reference plane is the light field's own
+    ///
+    /// `lfg.to_plane` must be a translation, as for a sensor behind a lens.
+    /// Fails if `aperture` does not fit the light field's angular plane.
+    pub fn new(lfg: LightFieldGeometry<F>,
+               aperture: &SyntheticAperture<F>,
+               to_scene: Optics<F>)
+               -> Result<Self, ()> {
+        Ok(DepthEstimator {
+            geom: lfg.geom.clone(),
+            distance: lfg.to_plane.su,
+            refocuser: try!(Refocuser::new(lfg, aperture)),
+            to_scene: to_scene,
+            noise: F::zero(),
+        })
+    }
+
+    /// Returns the distance behind the angular plane that depth `z` is
+    /// focused at
+    pub fn image_distance(self: &Self, z: F) -> F {
+        let post_optics = self.to_scene.then(&Optics::translation(&z));
+        let (ds, dt) = Optics::focus_at_distance(&Optics::identity(), &post_optics);
+        (ds + dt) / F::from_f32(2f32).unwrap()
+    }
+
+    /// Returns the focal plane for depth `z`
+    pub fn focal_plane(self: &Self, z: F) -> LightFieldGeometry<F> {
+        let distance = self.image_distance(z);
+        let scale = distance / self.distance;
+        let mut geom = self.geom.clone();
+        geom.ds = geom.ds * scale;
+        geom.dt = geom.dt * scale;
+        self.refocuser.focal_plane(geom, distance)
+    }
+
+    /// Weighted variance across views of each pixel
+    fn cost(self: &Self, views: &[(usize, Vec<F>)]) -> Vec<F> {
+        let weights = &self.refocuser.weights;
+        let total = views.iter().fold(F::zero(), |a, &(ia, _)| a + weights[ia]);
+        let np = self.geom.dimension();
+        let mut tr = vec![F::zero(); np];
+        if total == F::zero() {
+            return tr;
+        }
+        for ip in 0..np {
+            let (m1, m2) = views.iter().fold((F::zero(), F::zero()), |(m1, m2), &(ia, ref v)| {
+                let w = weights[ia];
+                (m1 + w * v[ip], m2 + w * v[ip] * v[ip])
+            });
+            let mean = m1 / total;
+            tr[ip] = (m2 / total - mean * mean).max(F::zero());
+        }
+        tr
+    }
+
+    /// Picks the best depth of each pixel from the costs of the sweep
+    ///
+    /// The minimum is refined with a parabola through its neighbours in
+    /// inverse depth, where defocus and so the costs change evenly.  With
+    /// no depths to pick from, every pixel gets zero depth and confidence.
+    fn select(self: &Self, buf: &[F], depths: &[F], costs: &[Vec<F>]) -> DepthMap<F> {
+        let np = self.geom.dimension();
+        let mut depth = vec![F::zero(); np];
+        let mut confidence = vec![F::zero(); np];
+        let nz = depths.len();
+        if nz == 0 {
+            return DepthMap {
+                geom: self.geom.clone(),
+                depth: depth,
+                confidence: confidence,
+            };
+        }
+
+        let power = buf.iter().fold(F::zero(), |a, &v| a + v * v) /
+                    F::from_usize(buf.len()).unwrap();
+        let floor = self.noise + F::from_f32(1e-4f32).unwrap() * power;
+        let nz_f = F::from_usize(nz).unwrap();
+
+        for ip in 0..np {
+            let mut best = 0;
+            let mut mean = F::zero();
+            for iz in 0..nz {
+                if costs[iz][ip] < costs[best][ip] {
+                    best = iz;
+                }
+                mean = mean + costs[iz][ip];
+            }
+            mean = mean / nz_f;
+
+            depth[ip] = depths[best];
+            if best > 0 && best + 1 < nz {
+                let u = [F::one() / depths[best - 1],
+                         F::one() / depths[best],
+                         F::one() / depths[best + 1]];
+                let c = [costs[best - 1][ip], costs[best][ip], costs[best + 1][ip]];
+                if let Some(u_min) = parabola_minimum(&u, &c) {
+                    depth[ip] = F::one() / u_min;
+                }
+            }
+            if mean + floor > F::zero() {
+                confidence[ip] = (mean - costs[best][ip]) / (mean + floor);
+            }
+        }
+
+        DepthMap {
+            geom: self.geom.clone(),
+            depth: depth,
+            confidence: confidence,
+        }
+    }
+
+    /// Estimates depth among `depths`, in increasing order, on the host
+    pub fn estimate_host(self: &Self, buf: &[F], depths: &[F]) -> DepthMap<F> {
+        let costs: Vec<Vec<F>> = depths.iter()
+                                       .map(|&z| {
+                                           let views = self.refocuser
+                                                           .views_host(buf, &self.focal_plane(z));
+                                           self.cost(&views)
+                                       })
+                                       .collect();
+        self.select(buf, depths, &costs)
+    }
+
+    /// Estimates depth among `depths`, in increasing order, using OpenCL
+    pub fn estimate(self: &Self,
+                    buf: &[F],
+                    depths: &[F],
+                    queue: &CommandQueue)
+                    -> Result<DepthMap<F>, Error> {
+        let mut costs = Vec::with_capacity(depths.len());
+        for &z in depths.iter() {
+            let views = try!(self.refocuser.views(buf, &self.focal_plane(z), queue));
+            costs.push(self.cost(&views));
+        }
+        Ok(self.select(buf, depths, &costs))
+    }
+}
+
+/// Estimates depth from a raw plenoptic capture, such as `generate_data`
+/// writes
+///
+/// The views are the light field on the microlens array for `na` angles of
+/// `basis` on the main lens, as extracted by `backprojected_light_field`,
+/// and are swept over `depths`, in increasing order.  Panics if the main
+/// lens transfer couples s and t.
+pub fn plenoptic_depth<F>(camera: &PlenopticCamera<F>,
+                          basis: AngularBasis,
+                          na: usize,
+                          raw: &[F],
+                          depths: &[F],
+                          queue: &CommandQueue)
+                          -> Result<DepthMap<F>, Error>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let array_lfg = camera.array_light_field_geometry(basis, na);
+    let lf = try!(backprojected_light_field(camera, &array_lfg, raw, queue));
+    let to_scene = camera.lens_transfer()
+                         .as_optics()
+                         .expect("Main lens transfer couples s and t");
+    let estimator = DepthEstimator::new(array_lfg, &SyntheticAperture::Full, to_scene)
+                        .expect("The full aperture fits any angular plane");
+    estimator.estimate(&lf, depths, queue)
+}
+
+/// Returns the minimum of the parabola through `(u[i], c[i])`
+///
+/// `None` unless the parabola opens upwards.  The middle point must have the
+/// smallest cost, so the minimum lies between the outer points.
+fn parabola_minimum<F: Float + FromPrimitive>(u: &[F; 3], c: &[F; 3]) -> Option<F> {
+    let (h0, h1) = (u[1] - u[0], u[1] - u[2]);
+    let (d0, d1) = (c[1] - c[2], c[1] - c[0]);
+    let num = h0 * h0 * d0 - h1 * h1 * d1;
+    let den = h0 * d0 - h1 * d1;
+
+    // the leading coefficient of the parabola is den / scale
+    let scale = h0 * h1 * (u[2] - u[0]);
+    if den * scale <= F::zero() {
+        return None;
+    }
+    Some(u[1] - num / (F::from_f32(2f32).unwrap() * den))
+}
+
+/// Returns `n` depths evenly spaced in inverse depth between `near` and `far`
+///
+/// Defocus changes linearly with inverse depth, so this spacing gives every
+/// step of a sweep about the same disparity.
+pub fn inverse_depth_sweep<F: Float + FromPrimitive>(near: F, far: F, n: usize) -> Vec<F> {
+    if n < 2 {
+        return vec![near; n];
+    }
+    let (a, b) = (F::one() / near, F::one() / far);
+    let steps = F::from_usize(n - 1).unwrap();
+    (0..n)
+        .map(|i| {
+            let f = F::from_usize(i).unwrap() / steps;
+            F::one() / (a + (b - a) * f)
+        })
+        .collect()
+}
+
+/// Light field seen by a lens through a textured plane at depth `z`,
+/// along with its estimator
+#[cfg(test)]
+fn test_textured_plane(z: f32) -> (DepthEstimator<f32>, Vec<f32>) {
+    use angular_plane::*;
+    use host_transport::*;
+    use light_volume::*;
+    use lens::*;
+
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 5f32,
+        radius_t: 5f32,
+        focal_length_s: 20f32,
+        focal_length_t: 20f32,
+    };
+
+    // sensor focused at 80 units
+    let lfg = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 32,
+            nt: 32,
+            ds: 0.25,
+            dt: 0.25,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        plane: lens.as_angular_plane(AngularBasis::Dirac, 5),
+        to_plane: Optics::translation(&(20f32 * 80f32 / 60f32)),
+    };
+
+    // one slice of random texture, wide enough to fill the view
+    let vg = LightVolume {
+        nx: 64,
+        ny: 64,
+        nz: 1,
+        dx: 1.0,
+        dy: 1.0,
+        dz: 1.0,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    let to_plane = lens.optics().then(&Optics::translation(&z)).invert();
+    let mut xport = HostVolumeTransport::new_simple(vg.clone(), lfg.clone(), to_plane);
+    let x = vg.rands();
+    let np = lfg.geom.dimension();
+    let mut lf = vec![0f32; np * lfg.plane.na()];
+    for ia in 0..lfg.plane.na() {
+        xport.forw(&x, &mut lf[np * ia..np * (ia + 1)], ia);
+    }
+
+    let estimator = DepthEstimator::new(lfg, &SyntheticAperture::Full, lens.optics()).unwrap();
+    (estimator, lf)
+}
+
+#[test]
+fn test_inverse_depth_sweep() {
+    let depths = inverse_depth_sweep(50f32, 200f32, 4);
+    assert_eq!(depths.len(), 4);
+    assert!((depths[0] - 50f32).abs() < 1e-3);
+    assert!((depths[3] - 200f32).abs() < 1e-2);
+    for i in 1..3 {
+        let step = 1f32 / depths[i - 1] - 1f32 / depths[i];
+        assert!((step - 0.005f32).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_focal_planes_share_chief_rays() {
+    let (estimator, _) = test_textured_plane(100f32);
+
+    // focusing at the sensor's own depth gives back the sensor
+    let sensor = estimator.focal_plane(80f32);
+    assert!((sensor.to_plane.su - estimator.distance).abs() < 1e-3);
+    assert!((sensor.geom.ds - 0.25).abs() < 1e-5);
+
+    // further depths focus closer to the lens, on smaller pixels
+    let far = estimator.focal_plane(160f32);
+    let ratio = far.to_plane.su / estimator.distance;
+    assert!(ratio < 1f32);
+    assert!((far.geom.ds / estimator.geom.ds - ratio).abs() < 1e-5);
+}
+
+#[test]
+fn test_depth_of_textured_plane() {
+    let depths = inverse_depth_sweep(50f32, 200f32, 16);
+    for &z in [70f32, 100f32, 140f32].iter() {
+        let (estimator, lf) = test_textured_plane(z);
+        let map = estimator.estimate_host(&lf, &depths);
+
+        // judge the interior, where every view covers the focal planes
+        let mut errors = Vec::new();
+        for it in 8..24 {
+            for is in 8..24 {
+                let ip = is + 32 * it;
+                errors.push((1f32 / map.depth[ip] - 1f32 / z).abs() * z);
+                assert!(map.confidence[ip] >= 0f32 && map.confidence[ip] <= 1f32);
+            }
+        }
+        errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = errors[errors.len() / 2];
+        println!("plane at {}: median relative depth error {}", z, median);
+        assert!(median < 0.05);
+    }
+
+    // no texture, no confidence
+    let (estimator, lf) = test_textured_plane(100f32);
+    let flat = vec![0.5f32; lf.len()];
+    let map = estimator.estimate_host(&flat, &depths);
+    for it in 8..24 {
+        for is in 8..24 {
+            assert!(map.confidence[is + 32 * it] < 1e-2);
+        }
+    }
+}
+
+#[test]
+fn test_select_refines_in_inverse_depth() {
+    let (estimator, lf) = test_textured_plane(100f32);
+    let np = estimator.geom.dimension();
+
+    // costs quadratic in inverse depth, sampled evenly in depth
+    let z_true = 93f32;
+    let depths: Vec<f32> = (0..16).map(|i| 50f32 + 10f32 * i as f32).collect();
+    let costs: Vec<Vec<f32>> = depths.iter()
+                                     .map(|&z| {
+                                         let du = 1e3f32 * (1f32 / z - 1f32 / z_true);
+                                         vec![du * du; np]
+                                     })
+                                     .collect();
+    let map = estimator.select(&lf, &depths, &costs);
+    for &z in map.depth.iter() {
+        assert!((z - z_true).abs() < 1e-2);
+    }
+
+    // nothing to pick from
+    let map = estimator.select(&lf, &[], &[]);
+    assert_eq!(map.depth.len(), np);
+    assert!(map.confidence.iter().all(|&c| c == 0f32));
+    let map = estimator.estimate_host(&lf, &[]);
+    assert!(map.confidence.iter().all(|&c| c == 0f32));
+}
+
+#[test]
+fn test_depth_from_generated_plenoptic_data() {
+    use env::*;
+    use camera::*;
+    use detector::*;
+    use lens::*;
+    use light_volume::*;
+    use self::nalgebra::Vector3;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    // a fast main lens, so that the views of a plane off focus shift by
+    // about a microlens against one another
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 10f32,
+        radius_t: 10f32,
+        focal_length_s: 20f32,
+        focal_length_t: 20f32,
+    };
+    let detector = Detector {
+        ns: 64,
+        nt: 64,
+        ds: 0.25,
+        dt: 0.25,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+
+    // microlenses of 8x8 pixels, each imaging the main lens onto the
+    // detector without overlap
+    let ulens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 1f32,
+        radius_t: 1f32,
+        focal_length_s: 2.5f32 * 80f32 / 3f32 / (2.5f32 + 80f32 / 3f32),
+        focal_length_t: 2.5f32 * 80f32 / 3f32 / (2.5f32 + 80f32 / 3f32),
+    };
+    let mut camera = PlenopticCamera {
+        lens: lens,
+        array: Some(Lens::tesselate_quad_1(0f32, 0f32, &detector.image_geometry(), &ulens)),
+        detector: detector,
+        distance_lens_array: 0f32,
+        distance_detector_array: 2.5f32,
+        array_path: String::new(),
+        stop: None,
+        transfer: None,
+    };
+    camera.focus_at_distance(80f32).unwrap();
+
+    // a textured plane 50 units in front of the camera, coarser than the
+    // microlenses
+    let vg = LightVolume {
+        nx: 32,
+        ny: 32,
+        nz: 1,
+        dx: 4.0,
+        dy: 4.0,
+        dz: 1.0,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    let z_true = 50f32 - vg.iz2z(0);
+    let x = vg.rands();
+
+    // simulate the capture as generate_data does
+    let basis = AngularBasis::Dirac;
+    let na = 5;
+    let config = CameraConfig::PlenopticCamera(camera.clone());
+    let mut imager = config.volume_imager(vg.clone(),
+                                          Vector3::new(0f32, 0f32, -50f32),
+                                          None,
+                                          na,
+                                          basis.clone(),
+                                          queue.clone())
+                           .unwrap();
+    let raw = imager.forw_host(&x, queue).unwrap();
+
+    // and estimate depth as estimate_depth does
+    let depths = inverse_depth_sweep(30f32, 150f32, 24);
+    let map = plenoptic_depth(&camera, basis, na, &raw, &depths, queue).unwrap();
+
+    // judge the interior, away from microlenses cut by the detector edges
+    let mut errors = Vec::new();
+    for it in 16..48 {
+        for is in 16..48 {
+            let ip = is + 64 * it;
+            errors.push((1f32 / map.depth[ip] - 1f32 / z_true).abs() * z_true);
+        }
+    }
+    errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = errors[errors.len() / 2];
+    println!("plane at {}: median relative depth error {}", z_true, median);
+    assert!(median < 0.15);
+}
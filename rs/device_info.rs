@@ -0,0 +1,179 @@
+use std::os::raw::c_void;
+use std::ptr;
+
+// OpenCL types and constants used below, from cl.h
+#[allow(non_camel_case_types)]
+type cl_int = i32;
+#[allow(non_camel_case_types)]
+type cl_uint = u32;
+#[allow(non_camel_case_types)]
+type cl_platform_id = *mut c_void;
+#[allow(non_camel_case_types)]
+type cl_device_id = *mut c_void;
+
+const CL_SUCCESS: cl_int = 0;
+const CL_DEVICE_TYPE_ALL: u64 = 0xFFFFFFFF;
+const CL_DEVICE_MAX_WORK_GROUP_SIZE: cl_uint = 0x1004;
+const CL_DEVICE_GLOBAL_MEM_SIZE: cl_uint = 0x101F;
+const CL_DEVICE_LOCAL_MEM_SIZE: cl_uint = 0x1023;
+const CL_DEVICE_NAME: cl_uint = 0x102B;
+const CL_DEVICE_VENDOR: cl_uint = 0x102C;
+
+#[cfg_attr(target_os = "macos", link(name = "OpenCL", kind = "framework"))]
+#[cfg_attr(not(target_os = "macos"), link(name = "OpenCL"))]
+extern "C" {
+    fn clGetPlatformIDs(num_entries: cl_uint,
+                        platforms: *mut cl_platform_id,
+                        num_platforms: *mut cl_uint)
+                        -> cl_int;
+    fn clGetDeviceIDs(platform: cl_platform_id,
+                      device_type: u64,
+                      num_entries: cl_uint,
+                      devices: *mut cl_device_id,
+                      num_devices: *mut cl_uint)
+                      -> cl_int;
+    fn clGetDeviceInfo(device: cl_device_id,
+                       param_name: cl_uint,
+                       param_value_size: usize,
+                       param_value: *mut c_void,
+                       param_value_size_ret: *mut usize)
+                       -> cl_int;
+}
+
+/// Properties of an OpenCL device that proust does not expose
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub vendor: String,
+
+    /// Global memory in bytes
+    pub global_mem_size: u64,
+
+    /// Local memory per work-group in bytes
+    pub local_mem_size: u64,
+
+    /// Largest number of work-items in a work-group
+    pub max_work_group_size: usize,
+}
+
+fn platform_ids() -> Result<Vec<cl_platform_id>, ()> {
+    unsafe {
+        let mut n: cl_uint = 0;
+        if clGetPlatformIDs(0, ptr::null_mut(), &mut n) != CL_SUCCESS {
+            return Err(());
+        }
+        let mut ids = vec![ptr::null_mut(); n as usize];
+        if n > 0 && clGetPlatformIDs(n, ids.as_mut_ptr(), ptr::null_mut()) != CL_SUCCESS {
+            return Err(());
+        }
+        Ok(ids)
+    }
+}
+
+fn device_ids(platform: cl_platform_id) -> Result<Vec<cl_device_id>, ()> {
+    unsafe {
+        let mut n: cl_uint = 0;
+        if clGetDeviceIDs(platform, CL_DEVICE_TYPE_ALL, 0, ptr::null_mut(), &mut n) !=
+           CL_SUCCESS {
+            // platforms without devices report CL_DEVICE_NOT_FOUND
+            return Ok(Vec::new());
+        }
+        let mut ids = vec![ptr::null_mut(); n as usize];
+        if n > 0 &&
+           clGetDeviceIDs(platform, CL_DEVICE_TYPE_ALL, n, ids.as_mut_ptr(), ptr::null_mut()) !=
+           CL_SUCCESS {
+            return Err(());
+        }
+        Ok(ids)
+    }
+}
+
+fn device_bytes(device: cl_device_id, param: cl_uint) -> Result<Vec<u8>, ()> {
+    unsafe {
+        let mut size = 0usize;
+        if clGetDeviceInfo(device, param, 0, ptr::null_mut(), &mut size) != CL_SUCCESS {
+            return Err(());
+        }
+        let mut bytes = vec![0u8; size];
+        if clGetDeviceInfo(device,
+                           param,
+                           size,
+                           bytes.as_mut_ptr() as *mut c_void,
+                           ptr::null_mut()) != CL_SUCCESS {
+            return Err(());
+        }
+        Ok(bytes)
+    }
+}
+
+fn device_string(device: cl_device_id, param: cl_uint) -> Result<String, ()> {
+    let mut bytes = try!(device_bytes(device, param));
+    while bytes.last() == Some(&0u8) {
+        bytes.pop();
+    }
+    String::from_utf8(bytes).map_err(|_| ())
+}
+
+fn device_u64(device: cl_device_id, param: cl_uint) -> Result<u64, ()> {
+    let bytes = try!(device_bytes(device, param));
+    if bytes.len() > 8 {
+        return Err(());
+    }
+    Ok(bytes.iter().rev().fold(0u64, |v, &b| (v << 8) | b as u64))
+}
+
+impl DeviceInfo {
+    fn query_id(device: cl_device_id) -> Result<DeviceInfo, ()> {
+        Ok(DeviceInfo {
+            name: try!(device_string(device, CL_DEVICE_NAME)),
+            vendor: try!(device_string(device, CL_DEVICE_VENDOR)),
+            global_mem_size: try!(device_u64(device, CL_DEVICE_GLOBAL_MEM_SIZE)),
+            local_mem_size: try!(device_u64(device, CL_DEVICE_LOCAL_MEM_SIZE)),
+            max_work_group_size: try!(device_u64(device, CL_DEVICE_MAX_WORK_GROUP_SIZE)) as usize,
+        })
+    }
+
+    /// Returns the properties of every device of every platform
+    ///
+    /// Indexed like `Platform::platforms()` and then `platform.devices()`.
+    pub fn query_all() -> Result<Vec<Vec<DeviceInfo>>, ()> {
+        let mut tr = Vec::new();
+        for platform in try!(platform_ids()).into_iter() {
+            let mut infos = Vec::new();
+            for device in try!(device_ids(platform)).into_iter() {
+                infos.push(try!(DeviceInfo::query_id(device)));
+            }
+            tr.push(infos);
+        }
+        Ok(tr)
+    }
+
+    /// Returns the properties of the devices of one platform
+    pub fn query_platform(platform: usize) -> Result<Vec<DeviceInfo>, ()> {
+        let platforms = try!(platform_ids());
+        let platform = match platforms.get(platform) {
+            Some(&p) => p,
+            None => return Err(()),
+        };
+        let mut tr = Vec::new();
+        for device in try!(device_ids(platform)).into_iter() {
+            tr.push(try!(DeviceInfo::query_id(device)));
+        }
+        Ok(tr)
+    }
+}
+
+#[test]
+fn test_device_info() {
+    use env::*;
+
+    let env = Environment::new_easy().unwrap();
+    let name = env.devices[0].name().unwrap();
+
+    let infos = DeviceInfo::query_all().unwrap();
+    let info = infos.iter().flat_map(|p| p.iter()).find(|i| i.name == name).unwrap();
+    assert!(info.vendor.len() > 0);
+    assert!(info.global_mem_size > 0);
+    assert!(info.local_mem_size > 0);
+    assert!(info.max_work_group_size > 0);
+}
@@ -1,12 +1,10 @@
 extern crate num;
 extern crate toml;
-extern crate byteorder;
 
 use serialize::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::toml::*;
 use cl_traits::*;
-use self::byteorder::*;
 
 /// Ellipsoidal phantom
 #[derive(Clone, Debug)]
@@ -173,25 +171,25 @@ impl<F: Float> ClHeader for Ellipsoid<F> {
 
 impl<F: Float + ToPrimitive> ClBuffer for Ellipsoid<F> {
     fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.xx).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.xy).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.xz).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.xr).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.xc).unwrap()).unwrap();
-
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.yx).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.yy).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.yz).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.yr).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.yc).unwrap()).unwrap();
-
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.zx).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.zy).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.zz).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.zr).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.zc).unwrap()).unwrap();
-
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.value).unwrap()).unwrap();
+        write_real(buf, &self.xx);
+        write_real(buf, &self.xy);
+        write_real(buf, &self.xz);
+        write_real(buf, &self.xr);
+        write_real(buf, &self.xc);
+
+        write_real(buf, &self.yx);
+        write_real(buf, &self.yy);
+        write_real(buf, &self.yz);
+        write_real(buf, &self.yr);
+        write_real(buf, &self.yc);
+
+        write_real(buf, &self.zx);
+        write_real(buf, &self.zy);
+        write_real(buf, &self.zz);
+        write_real(buf, &self.zr);
+        write_real(buf, &self.zc);
+
+        write_real(buf, &self.value);
     }
 }
 
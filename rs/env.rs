@@ -1,46 +1,308 @@
 extern crate proust;
 use self::proust::*;
+use device_info::*;
+use program_cache::*;
+use std::env;
+
+/// Environment variable that overrides the platform index
+pub const PLATFORM_VAR: &'static str = "LIGHTFIELD_PLATFORM";
+
+/// Environment variable that overrides the device selector; see
+/// `DeviceSelector::parse`
+pub const DEVICE_VAR: &'static str = "LIGHTFIELD_DEVICE";
+
+/// Error creating an `Environment`
+#[derive(Debug)]
+pub enum EnvironmentError {
+    /// An OpenCL call failed
+    Cl(Error),
+
+    /// The device properties of the platform with this index could not be
+    /// queried
+    DeviceInfo(usize),
+
+    /// `LIGHTFIELD_PLATFORM` is not a platform index
+    InvalidPlatformVar(String),
+
+    /// The requested platform index is out of range
+    NoPlatform {
+        index: usize,
+        available: usize,
+    },
+
+    /// No device matches the selector on the requested platform
+    NoDevice {
+        platform: Option<usize>,
+        selector: DeviceSelector,
+    },
+}
+
+impl From<Error> for EnvironmentError {
+    fn from(e: Error) -> Self {
+        EnvironmentError::Cl(e)
+    }
+}
+
+/// Broad class of an OpenCL device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    Cpu,
+    Gpu,
+    Other,
+}
+
+impl DeviceKind {
+    /// Returns the kind of a device
+    pub fn of(device: &Device) -> Result<DeviceKind, Error> {
+        Ok(match try!(device.device_type()) {
+            DeviceType::GPU => DeviceKind::Gpu,
+            DeviceType::CPU => DeviceKind::Cpu,
+            _ => DeviceKind::Other,
+        })
+    }
+}
+
+/// Rule for choosing devices when building an `Environment`
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSelector {
+    /// GPUs if the platform has any, otherwise every device
+    PreferGpu,
+
+    /// Every device on the platform
+    All,
+
+    /// The device with the given index on the platform
+    Index(usize),
+
+    /// Devices whose name contains the given (case-insensitive) string
+    Name(String),
+
+    /// Devices whose vendor contains the given (case-insensitive) string
+    Vendor(String),
+
+    /// Devices of the given kind
+    Kind(DeviceKind),
+}
+
+impl DeviceSelector {
+    /// Parses a selector from a string
+    ///
+    /// Accepts `all`, `gpu`, `cpu`, `other`, a device index, `vendor:` and
+    /// a substring of the device vendor, or otherwise a substring of the
+    /// device name.
+    pub fn parse(s: &str) -> DeviceSelector {
+        let s = s.trim();
+        if s.to_lowercase().starts_with("vendor:") {
+            return DeviceSelector::Vendor(s[7..].trim().to_string());
+        }
+        match &s.to_lowercase()[..] {
+            "all" => DeviceSelector::All,
+            "gpu" => DeviceSelector::Kind(DeviceKind::Gpu),
+            "cpu" => DeviceSelector::Kind(DeviceKind::Cpu),
+            "other" => DeviceSelector::Kind(DeviceKind::Other),
+            _ => {
+                match s.parse() {
+                    Ok(index) => DeviceSelector::Index(index),
+                    Err(_) => DeviceSelector::Name(s.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Returns the devices this selector picks from `devices`, the devices
+    /// of the platform with index `platform`
+    pub fn select(self: &Self,
+                  platform: usize,
+                  devices: &[Device])
+                  -> Result<Vec<Device>, EnvironmentError> {
+        let mut tr = Vec::new();
+        match self {
+            &DeviceSelector::PreferGpu => {
+                tr = try!(DeviceSelector::Kind(DeviceKind::Gpu).select(platform, devices));
+                if tr.len() == 0 {
+                    tr = devices.to_vec();
+                }
+            }
+            &DeviceSelector::All => {
+                tr = devices.to_vec();
+            }
+            &DeviceSelector::Index(index) => {
+                if let Some(d) = devices.get(index) {
+                    tr.push(d.clone());
+                }
+            }
+            &DeviceSelector::Name(ref name) => {
+                let name = name.to_lowercase();
+                for d in devices.iter() {
+                    if try!(d.name()).to_lowercase().contains(&name) {
+                        tr.push(d.clone());
+                    }
+                }
+            }
+            &DeviceSelector::Vendor(ref vendor) => {
+                // proust doesn't expose vendors, so look them up by index and
+                // make sure the names agree
+                let vendor = vendor.to_lowercase();
+                let infos = try!(DeviceInfo::query_platform(platform)
+                                     .map_err(|_| EnvironmentError::DeviceInfo(platform)));
+                for (d, info) in devices.iter().zip(infos.iter()) {
+                    if info.name == try!(d.name()) &&
+                       info.vendor.to_lowercase().contains(&vendor) {
+                        tr.push(d.clone());
+                    }
+                }
+            }
+            &DeviceSelector::Kind(kind) => {
+                for d in devices.iter() {
+                    if try!(DeviceKind::of(d)) == kind {
+                        tr.push(d.clone());
+                    }
+                }
+            }
+        }
+        Ok(tr)
+    }
+}
+
+/// Builds an `Environment` from a platform and device selection
+///
+/// Without an explicit platform, the first platform with a matching device
+/// is used.  The `LIGHTFIELD_PLATFORM` and `LIGHTFIELD_DEVICE` environment
+/// variables override the builder's settings unless `ignore_env_vars` is set.
+#[derive(Clone, Debug)]
+pub struct EnvironmentBuilder {
+    platform: Option<usize>,
+    devices: DeviceSelector,
+    use_env_vars: bool,
+}
+
+impl EnvironmentBuilder {
+    pub fn new() -> Self {
+        EnvironmentBuilder {
+            platform: None,
+            devices: DeviceSelector::PreferGpu,
+            use_env_vars: true,
+        }
+    }
+
+    /// Only use the platform with the given index
+    pub fn platform(mut self: Self, index: usize) -> Self {
+        self.platform = Some(index);
+        self
+    }
+
+    /// Use the devices picked by `selector`
+    pub fn devices(mut self: Self, selector: DeviceSelector) -> Self {
+        self.devices = selector;
+        self
+    }
+
+    /// Do not let environment variables override this builder
+    pub fn ignore_env_vars(mut self: Self) -> Self {
+        self.use_env_vars = false;
+        self
+    }
+
+    /// Returns the platform index and device selector after applying
+    /// environment variable overrides
+    pub fn resolved(self: &Self) -> Result<(Option<usize>, DeviceSelector), EnvironmentError> {
+        let mut platform = self.platform;
+        let mut devices = self.devices.clone();
+        if self.use_env_vars {
+            if let Ok(s) = env::var(PLATFORM_VAR) {
+                platform = match s.trim().parse() {
+                    Ok(index) => Some(index),
+                    Err(_) => return Err(EnvironmentError::InvalidPlatformVar(s)),
+                };
+            }
+            if let Ok(s) = env::var(DEVICE_VAR) {
+                devices = DeviceSelector::parse(&s);
+            }
+        }
+        Ok((platform, devices))
+    }
+
+    /// Creates the environment
+    ///
+    /// The environment registers its context with the calling thread's
+    /// program cache, and its programs are dropped along with it.
+    pub fn build(self: &Self) -> Result<Environment, EnvironmentError> {
+        let (platform, selector) = try!(self.resolved());
+        let platforms = try!(Platform::platforms());
+        let candidates: Vec<(usize, &Platform)> = match platform {
+            Some(index) => {
+                match platforms.get(index) {
+                    Some(p) => vec![(index, p)],
+                    None => {
+                        return Err(EnvironmentError::NoPlatform {
+                            index: index,
+                            available: platforms.len(),
+                        })
+                    }
+                }
+            }
+            None => platforms.iter().enumerate().collect(),
+        };
+
+        // preferring GPUs means looking for GPUs on every platform before
+        // falling back to other devices
+        let passes = match selector {
+            DeviceSelector::PreferGpu => {
+                vec![DeviceSelector::Kind(DeviceKind::Gpu), DeviceSelector::All]
+            }
+            ref s => vec![s.clone()],
+        };
+
+        for pass in passes.iter() {
+            for &(ip, p) in candidates.iter() {
+                let devices = try!(pass.select(ip, &try!(p.devices())));
+                if devices.len() == 0 {
+                    continue;
+                }
+
+                let context = try!(Context::new(&devices));
+                let mut queues = Vec::new();
+                for d in devices.iter() {
+                    let q = try!(CommandQueue::new(context.clone(), d.clone()));
+                    queues.push(q);
+                }
+
+                let programs = try!(register_environment(&devices, &queues));
+                return Ok(Environment {
+                    ctx: context,
+                    devices: devices,
+                    queues: queues,
+                    _programs: programs,
+                });
+            }
+        }
+
+        Err(EnvironmentError::NoDevice {
+            platform: platform,
+            selector: selector,
+        })
+    }
+}
 
 /// OpenCL context and objects
 pub struct Environment {
     pub ctx: Context,
     pub devices: Vec<Device>,
     pub queues: Vec<CommandQueue>,
+
+    // keeps this context's programs cached while the environment lives
+    _programs: ProgramScope,
 }
 
 impl Environment {
-    /// Create an Environment with the first platform available
-    /// 
-    /// Only uses GPU devices by default.
-    pub fn new_easy() -> Result<Environment, Error> {
-        let platforms = try!(Platform::platforms());
-        let devices = try!(platforms[0].devices());
-        let mut gpu_devices: Vec<Device> = devices.iter().map(|d| d.clone())
-                                              .filter(|d| {
-                                                  if let DeviceType::GPU = d.device_type()
-                                                                            .unwrap() {
-                                                      true
-                                                  } else {
-                                                      false
-                                                  }
-                                              })
-                                              .collect();
-        if gpu_devices.len() == 0 {
-            gpu_devices = devices;
-        }
-
-        let context = try!(Context::new(&gpu_devices));
-        let mut queues = Vec::new();
-        for d in gpu_devices.iter() {
-            let q = try!(CommandQueue::new(context.clone(), d.clone()));
-            queues.push(q);
-        }
-
-        Ok(Environment {
-            ctx: context,
-            devices: gpu_devices,
-            queues: queues,
-        })
+    /// Create an Environment with the default selection
+    ///
+    /// Uses the GPUs of the first platform that has any, falling back to
+    /// every device of the first platform with devices.  Honors the
+    /// `LIGHTFIELD_PLATFORM` and `LIGHTFIELD_DEVICE` overrides; see
+    /// `EnvironmentBuilder`.
+    pub fn new_easy() -> Result<Environment, EnvironmentError> {
+        EnvironmentBuilder::new().build()
     }
 }
 
@@ -49,3 +311,55 @@ fn test_new_easy() {
     let env = Environment::new_easy().unwrap();
     assert!(env.devices.len() > 0);
 }
+
+#[test]
+fn test_device_selector_parse() {
+    assert_eq!(DeviceSelector::parse("all"), DeviceSelector::All);
+    assert_eq!(DeviceSelector::parse("GPU"), DeviceSelector::Kind(DeviceKind::Gpu));
+    assert_eq!(DeviceSelector::parse("cpu"), DeviceSelector::Kind(DeviceKind::Cpu));
+    assert_eq!(DeviceSelector::parse(" 1 "), DeviceSelector::Index(1));
+    assert_eq!(DeviceSelector::parse("GeForce"),
+               DeviceSelector::Name("GeForce".to_string()));
+    assert_eq!(DeviceSelector::parse("Vendor: NVIDIA"),
+               DeviceSelector::Vendor("NVIDIA".to_string()));
+}
+
+#[test]
+fn test_environment_builder() {
+    let env = EnvironmentBuilder::new()
+                  .devices(DeviceSelector::Index(0))
+                  .ignore_env_vars()
+                  .build()
+                  .unwrap();
+    assert_eq!(env.devices.len(), 1);
+
+    let name = env.devices[0].name().unwrap();
+    let by_name = EnvironmentBuilder::new()
+                      .devices(DeviceSelector::Name(name.clone()))
+                      .ignore_env_vars()
+                      .build()
+                      .unwrap();
+    assert!(by_name.devices.len() > 0);
+    assert_eq!(by_name.devices[0].name().unwrap(), name);
+
+    match EnvironmentBuilder::new().platform(1000).ignore_env_vars().build() {
+        Err(EnvironmentError::NoPlatform { index, .. }) => assert_eq!(index, 1000),
+        _ => panic!("Expected a missing platform error"),
+    }
+    match EnvironmentBuilder::new()
+              .devices(DeviceSelector::Name("no such device".to_string()))
+              .ignore_env_vars()
+              .build() {
+        Err(EnvironmentError::NoDevice { .. }) => (),
+        _ => panic!("Expected a missing device error"),
+    }
+
+    let infos = DeviceInfo::query_platform(0).unwrap();
+    let by_vendor = EnvironmentBuilder::new()
+                        .platform(0)
+                        .devices(DeviceSelector::Vendor(infos[0].vendor.to_uppercase()))
+                        .ignore_env_vars()
+                        .build()
+                        .unwrap();
+    assert!(by_vendor.devices.iter().any(|d| d.name().unwrap() == infos[0].name));
+}
@@ -1,49 +1,294 @@
 extern crate nalgebra;
 extern crate num;
 extern crate proust;
+extern crate time;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::nalgebra::BaseFloat;
 use light_volume::*;
 use imager::*;
 use vector_math::*;
 use self::proust::*;
+use self::time::precise_time_s;
 use geom::*;
 use potential_function::*;
 use cl_traits::*;
 use optics::*;
 use image_geom::*;
+use backend::*;
+use program_cache::*;
+use profiler::*;
+
+/// Backend that can run the FISTA image update
+///
+/// The update combines the data gradient with the regularizers, box
+/// constraints, mask and momentum step for every voxel of the volume.
+pub trait FistaBackend<F: Float>: Backend<F> {
+    /// State needed to run the update (compiled kernels, regularizers, etc.)
+    type Update;
+
+    /// Prepares the update for a volume and pair of regularizers
+    fn fista_update_new(queue: &Self::Queue,
+                        geom: &LightVolume<F>,
+                        sparsifying_regularizer: &Option<PotentialFunction<F>>,
+                        edge_preserving_regularizer: &Option<PotentialFunction<F>>)
+                        -> Result<Self::Update, Self::Error>;
+
+    /// Computes the new iterate `m` and momentum point `x` from the
+    /// previous iterate `x_off`
+    fn fista_update(update: &mut Self::Update,
+                    queue: &Self::Queue,
+                    geom: &LightVolume<F>,
+                    x: &mut Self::Buffer,
+                    denom: &Self::Buffer,
+                    data_gradient: &Self::Buffer,
+                    box_min: Option<F>,
+                    box_max: Option<F>,
+                    m: &mut Self::Buffer,
+                    t0: F,
+                    t1: F,
+                    mask3: &Self::Buffer,
+                    x_off: &Self::Buffer,
+                    wait_for: &[Self::Event])
+                    -> Result<Self::Event, Self::Error>;
+}
+
+/// OpenCL state for the FISTA update
+pub struct ClFistaUpdate {
+    update: Kernel,
+    sparsifying_buf: Option<Mem>,
+    edge_preserving_buf: Option<Mem>,
+    geom_buf: Mem,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaBackend<F> for ClBackend {
+    type Update = ClFistaUpdate;
+
+    fn fista_update_new(queue: &CommandQueue,
+                        geom: &LightVolume<F>,
+                        sparsifying_regularizer: &Option<PotentialFunction<F>>,
+                        edge_preserving_regularizer: &Option<PotentialFunction<F>>)
+                        -> Result<ClFistaUpdate, Error> {
+        // collect opencl sources
+        let sources = &[Optics::<F>::header(),
+                        ImageGeometry::<F>::header(),
+                        LightVolume::<F>::header(),
+                        PotentialFunction::<F>::header(),
+                        FistaVolumeSolver::<F>::header()];
+
+        // build opencl kernels
+        let built = try!(cached_program::<F>(queue, sources));
+
+        let update = try!(built.create_kernel("FistaVolumeSolver_update"));
+
+        // create sparsifying buffer
+        let sparsifying_buf = if let &Some(ref pf) = sparsifying_regularizer {
+            Some(try!(pf.as_cl_buffer(queue)))
+        } else {
+            None
+        };
+
+        // create edge-preserving buffer
+        let edge_preserving_buf = if let &Some(ref pf) = edge_preserving_regularizer {
+            Some(try!(pf.as_cl_buffer(queue)))
+        } else {
+            None
+        };
+
+        // create geometry buffer
+        let geom_buf = try!(geom.as_cl_buffer(queue));
+
+        Ok(ClFistaUpdate {
+            update: update,
+            sparsifying_buf: sparsifying_buf,
+            edge_preserving_buf: edge_preserving_buf,
+            geom_buf: geom_buf,
+        })
+    }
+
+    fn fista_update(update: &mut ClFistaUpdate,
+                    queue: &CommandQueue,
+                    geom: &LightVolume<F>,
+                    x: &mut Mem,
+                    denom: &Mem,
+                    data_gradient: &Mem,
+                    box_min: Option<F>,
+                    box_max: Option<F>,
+                    m: &mut Mem,
+                    t0: F,
+                    t1: F,
+                    mask3: &Mem,
+                    x_off: &Mem,
+                    wait_for: &[Event])
+                    -> Result<Event, Error> {
+        let kernel = &mut update.update;
+
+        // bind arguments
+        try!(kernel.bind(0, &update.geom_buf));
+        match &update.sparsifying_buf {
+            &Some(ref buf) => try!(kernel.bind(1, buf)),
+            &None => try!(kernel.bind_null(1)),
+        };
+        try!(kernel.bind_mut(2, x));
+        try!(kernel.bind(3, denom));
+        try!(kernel.bind(4, data_gradient));
+        match box_min {
+            Some(ref box_min) => try!(kernel.bind_scalar(5, box_min)),
+            None => try!(kernel.bind_scalar(5, &-F::infinity())),
+        };
+        match box_max {
+            Some(ref box_max) => try!(kernel.bind_scalar(6, box_max)),
+            None => try!(kernel.bind_scalar(6, &F::infinity())),
+        };
+        try!(kernel.bind_mut(7, m));
+        try!(kernel.bind_scalar(8, &t0));
+        try!(kernel.bind_scalar(9, &t1));
+        try!(kernel.bind(10, mask3));
+        match update.edge_preserving_buf {
+            Some(ref buf) => try!(kernel.bind(11, buf)),
+            None => try!(kernel.bind_null(11)),
+        };
+        try!(kernel.bind(12, x_off));
+
+        let local_size = (32, 8, 1);
+        let global_size = (geom.nx, geom.ny, geom.nz);
+
+        run_kernel(queue,
+                   kernel,
+                   "FistaVolumeSolver_update",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+}
+
+/// Host state for the FISTA update
+pub struct HostFistaUpdate<F: Float> {
+    sparsifying: Option<PotentialFunction<F>>,
+    edge_preserving: Option<PotentialFunction<F>>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaBackend<F> for HostBackend {
+    type Update = HostFistaUpdate<F>;
+
+    fn fista_update_new(_: &HostQueue,
+                        _: &LightVolume<F>,
+                        sparsifying_regularizer: &Option<PotentialFunction<F>>,
+                        edge_preserving_regularizer: &Option<PotentialFunction<F>>)
+                        -> Result<HostFistaUpdate<F>, ()> {
+        Ok(HostFistaUpdate {
+            sparsifying: sparsifying_regularizer.clone(),
+            edge_preserving: edge_preserving_regularizer.clone(),
+        })
+    }
+
+    fn fista_update(update: &mut HostFistaUpdate<F>,
+                    _: &HostQueue,
+                    geom: &LightVolume<F>,
+                    x: &mut Vec<F>,
+                    denom: &Vec<F>,
+                    data_gradient: &Vec<F>,
+                    box_min: Option<F>,
+                    box_max: Option<F>,
+                    m: &mut Vec<F>,
+                    t0: F,
+                    t1: F,
+                    mask3: &Vec<F>,
+                    x_off: &Vec<F>,
+                    _: &[HostEvent])
+                    -> Result<HostEvent, ()> {
+        let (nx, ny, nz) = (geom.nx, geom.ny, geom.nz);
+        let min_val = box_min.unwrap_or(F::neg_infinity());
+        let max_val = box_max.unwrap_or(F::infinity());
+
+        for iz in 0..nz {
+            for iy in 0..ny {
+                for ix in 0..nx {
+                    let idx = ix + nx * (iy + ny * iz);
+
+                    let xi = x_off[idx];
+                    let mut gi = data_gradient[idx];
+                    let mut di = denom[idx];
+                    let mi = m[idx];
+                    let m3i = if mask3[idx] > F::zero() {
+                        F::zero()
+                    } else {
+                        F::one()
+                    };
+
+                    if let Some(ref pf) = update.edge_preserving {
+                        for iiz in iz.saturating_sub(1)..(iz + 2).min(nz) {
+                            for iiy in iy.saturating_sub(1)..(iy + 2).min(ny) {
+                                for iix in ix.saturating_sub(1)..(ix + 2).min(nx) {
+                                    let xii = x_off[iix + nx * (iiy + ny * iiz)];
+                                    let h = pf.huber(xi - xii);
+                                    di = di + h;
+                                    gi = gi + h * (xi - xii);
+                                }
+                            }
+                        }
+                    }
+
+                    let mut new_val = if di == F::zero() {
+                        F::zero()
+                    } else {
+                        xi - gi / di
+                    };
+
+                    if let Some(ref pf) = update.sparsifying {
+                        new_val = pf.shrink(di, new_val);
+                    }
+
+                    if new_val < min_val {
+                        new_val = min_val;
+                    } else if new_val > max_val {
+                        new_val = max_val;
+                    }
+
+                    x[idx] = m3i * (new_val + (t0 - F::one()) / t1 * (new_val - mi));
+                    m[idx] = m3i * new_val;
+                }
+            }
+        }
+        Ok(HostEvent)
+    }
+}
 
 /// Translucent volume reconstruction via FISTA
-pub struct FistaVolumeSolver<F: Float + FromPrimitive + ToPrimitive + BaseFloat> {
+pub struct FistaVolumeSolver<F, B = ClBackend>
+    where F: Float + FromPrimitive + ToPrimitive + BaseFloat,
+          B: FistaBackend<F>
+{
     geom: LightVolume<F>,
-    imagers: Vec<Box<Imager<F, LightVolume<F>>>>,
+    imagers: Vec<Box<Imager<F, LightVolume<F>, B>>>,
+    projectors: Vec<Option<SubsetProjector<F, LightVolume<F>, B>>>,
     subsets: Vec<Vec<Vec<usize>>>,
-    vecmath: VectorMath<F>,
-
-    x: Mem,
-    m: Mem,
-    x_off: Mem,
-    denom: Mem,
-    mask3: Mem,
-    measurements: Vec<Mem>,
-    projections: Vec<Mem>,
-    tmp_buffers: Vec<Mem>,
+    vecmaths: Vec<B::VectorMath>, // one per device; device 0 holds the image
+
+    queues: Vec<B::Queue>,
+    camera_devices: Vec<usize>,
+    device_busy: Vec<f64>,
+    device_spans: Vec<f64>, // of the last gradient
+    gradient_time: f64,
+
+    x: B::Buffer,
+    m: B::Buffer,
+    x_off: B::Buffer,
+    denom: B::Buffer,
+    mask3: B::Buffer,
+    measurements: Vec<B::Buffer>,
+    projections: Vec<B::Buffer>,
+    tmp_buffers: Vec<B::Buffer>,
 
     camera_scales: Vec<F>,
     measurements_host: Vec<Vec<F>>,
     ynorm2s: Vec<F>,
 
-    update: Kernel,
-    sparsifying_buf: Option<Mem>,
-    edge_preserving_buf: Option<Mem>,
-    geom_buf: Mem,
+    update: B::Update,
     box_min: Option<F>,
     box_max: Option<F>,
 
     gain_estimation: bool,
 
-    queue: CommandQueue,
-
     t: F,
 }
 
@@ -53,9 +298,12 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> ClHeader for FistaVolum
     }
 }
 
-impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
+impl<F, B> FistaVolumeSolver<F, B>
+    where F: Float + FromPrimitive + ToPrimitive + BaseFloat,
+          B: FistaBackend<F>
+{
     pub fn new(geometry: LightVolume<F>,
-               imagers: Vec<Box<Imager<F, LightVolume<F>>>>,
+               imagers: Vec<Box<Imager<F, LightVolume<F>, B>>>,
                measurements: &[&[F]],
                initial_image: Option<&[F]>,
                sparsifying_regularizer: &Option<PotentialFunction<F>>,
@@ -64,27 +312,57 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
                box_min: Option<F>,
                box_max: Option<F>,
                gain_estimation: bool,
-               queue: CommandQueue)
-               -> Result<Self, Error> {
-        // get opencl objects
-        let context = try!(queue.context());
-        let device = try!(queue.device());
-        let sources = &[Optics::<F>::header(),
-                        ImageGeometry::<F>::header(),
-                        LightVolume::<F>::header(),
-                        PotentialFunction::<F>::header(),
-                        Self::header()];
-
-        // build opencl kernels
-        let unbuilt = try!(Program::new_from_source(context, sources));
-        let built = try!(unbuilt.build(&[device]));
-
-        let update = try!(built.create_kernel("FistaVolumeSolver_update"));
+               queue: B::Queue)
+               -> Result<Self, B::Error> {
+        let camera_devices = vec![0; imagers.len()];
+        Self::new_multi_device(geometry,
+                               imagers,
+                               measurements,
+                               initial_image,
+                               sparsifying_regularizer,
+                               edge_preserving_regularizer,
+                               num_subsets,
+                               box_min,
+                               box_max,
+                               gain_estimation,
+                               vec![queue],
+                               camera_devices)
+    }
 
-        // gather measurements onto gpu
+    /// Creates a solver whose cameras are spread over several devices
+    ///
+    /// `camera_devices[i]` is the index into `queues` of the device that
+    /// `imagers[i]` was created on.  Each camera's gradient is computed on
+    /// its own device; the gradients are reduced and the image is updated on
+    /// `queues[0]`.  For OpenCL, the queues must share a context (as the
+    /// queues of an `Environment` do) so buffers are visible to every device.
+    pub fn new_multi_device(geometry: LightVolume<F>,
+                            imagers: Vec<Box<Imager<F, LightVolume<F>, B>>>,
+                            measurements: &[&[F]],
+                            initial_image: Option<&[F]>,
+                            sparsifying_regularizer: &Option<PotentialFunction<F>>,
+                            edge_preserving_regularizer: &Option<PotentialFunction<F>>,
+                            num_subsets: usize,
+                            box_min: Option<F>,
+                            box_max: Option<F>,
+                            gain_estimation: bool,
+                            queues: Vec<B::Queue>,
+                            camera_devices: Vec<usize>)
+                            -> Result<Self, B::Error> {
+        assert!(queues.len() > 0);
+        assert_eq!(camera_devices.len(), imagers.len());
+        assert!(camera_devices.iter().all(|&d| d < queues.len()));
+
+        let queue = queues[0].clone();
+        let update = try!(B::fista_update_new(&queue,
+                                              &geometry,
+                                              sparsifying_regularizer,
+                                              edge_preserving_regularizer));
+
+        // gather measurements onto each camera's device
         let mut measurements_vec = Vec::new();
-        for &m in measurements.iter() {
-            let m_buf = try!(queue.create_buffer_from_slice(m));
+        for (&m, &device) in measurements.iter().zip(camera_devices.iter()) {
+            let m_buf = try!(B::create_buffer(&queues[device], m));
             measurements_vec.push(m_buf);
         }
 
@@ -100,65 +378,56 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
         // create projection buffers
         let mut projections = Vec::new();
         let mut tmp_buffers = Vec::new();
-        for imager in imagers.iter() {
+        for (imager, &device) in imagers.iter().zip(camera_devices.iter()) {
             let det_geom = imager.detector().image_geometry();
-            let proj_buf = try!(det_geom.zeros_buf(&queue));
+            let proj_buf = try!(B::create_buffer(&queues[device], &det_geom.zeros()));
             projections.push(proj_buf);
 
-            let tmp_buf = try!(geometry.zeros_buf(&queue));
+            let tmp_buf = try!(B::create_buffer(&queues[device], &geometry.zeros()));
             tmp_buffers.push(tmp_buf);
         }
 
-        // create vector math object
-        let vecmath = try!(VectorMath::new(queue.clone()));
+        // create vector math objects
+        let mut vecmaths = Vec::new();
+        for q in queues.iter() {
+            vecmaths.push(try!(B::vector_math(q)));
+        }
 
         // create blank x object
-        let (x, m, x_off) = match initial_image {
-            Some(x0) => {
-                let x = try!(queue.create_buffer_from_slice(x0));
-                let m = try!(queue.create_buffer_from_slice(x0));
-                let x_off = try!(queue.create_buffer_from_slice(x0));
-                (x, m, x_off)
-            }
-            None => {
-                let x = try!(geometry.zeros_buf(&queue));
-                let m = try!(geometry.zeros_buf(&queue));
-                let x_off = try!(geometry.zeros_buf(&queue));
-                (x, m, x_off)
-            }
-        };
+        let zeros = geometry.zeros();
+        let x0 = initial_image.unwrap_or(&zeros);
+        let x = try!(B::create_buffer(&queue, x0));
+        let m = try!(B::create_buffer(&queue, x0));
+        let x_off = try!(B::create_buffer(&queue, x0));
 
-        let denom = try!(geometry.zeros_buf(&queue));
-        let mask3 = try!(geometry.zeros_buf(&queue));
-
-        // create sparsifying buffer
-        let sparsifying_buf = if let &Some(ref pf) = sparsifying_regularizer {
-            Some(try!(pf.as_cl_buffer(&queue)))
-        } else {
-            None
-        };
-
-        // create edge-preserving buffer
-        let edge_preserving_buf = if let &Some(ref pf) = edge_preserving_regularizer {
-            Some(try!(pf.as_cl_buffer(&queue)))
-        } else {
-            None
-        };
+        let denom = try!(B::create_buffer(&queue, &zeros));
+        let mask3 = try!(B::create_buffer(&queue, &zeros));
 
+        // every subset needs at least one angle
         let mut subsets = Vec::new();
         for i in imagers.iter() {
+            assert!(num_subsets > 0 && num_subsets <= i.na(),
+                    "{} subsets requested, but a camera has {} angles",
+                    num_subsets,
+                    i.na());
             subsets.push(i.angular_plane().subsets_strided(num_subsets));
         }
+        let projectors = imagers.iter().map(|_| None).collect();
 
-        // create geometry buffer
-        let geom_buf = try!(geometry.as_cl_buffer(&queue));
-
+        let num_devices = queues.len();
         let mut volume_solver = FistaVolumeSolver {
             geom: geometry,
             imagers: imagers,
+            projectors: projectors,
             subsets: subsets,
 
-            vecmath: vecmath,
+            vecmaths: vecmaths,
+
+            queues: queues,
+            camera_devices: camera_devices,
+            device_busy: vec![0f64; num_devices],
+            device_spans: vec![0f64; num_devices],
+            gradient_time: 0f64,
 
             x: x,
             m: m,
@@ -171,9 +440,6 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
 
             update: update,
 
-            sparsifying_buf: sparsifying_buf,
-            edge_preserving_buf: edge_preserving_buf,
-            geom_buf: geom_buf,
             box_min: box_min,
             box_max: box_max,
 
@@ -183,8 +449,6 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
 
             gain_estimation: gain_estimation,
 
-            queue: queue,
-
             t: F::one(),
         };
         try!(volume_solver.compute_denominator());
@@ -193,7 +457,7 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
     }
 
     /// Compute a spherical mask
-    pub fn compute_mask3(self: &mut Self) -> Result<(), Error> {
+    pub fn compute_mask3(self: &mut Self) -> Result<(), B::Error> {
         let mut mask3 = self.geom.zeros();
         let c2 = F::one() + F::one();
 
@@ -215,30 +479,38 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
             }
         }
 
-        try!(try!(self.queue.write_buffer(&mut self.mask3, &mask3)).wait());
+        let evt = try!(B::write_buffer(&self.queues[0], &mut self.mask3, &mask3, &[]));
+        try!(B::wait(&evt));
 
         Ok(())
     }
 
     /// Computes data-fidelity term diagonal majorizer and camera normalization
     /// factors
-    fn compute_denominator(self: &mut Self) -> Result<(), Error> {
-        let ones = try!(self.geom.ones_buf(&self.queue));
-        let mut tmp = try!(self.geom.zeros_buf(&self.queue));
-        let mut denom_copy = self.denom.clone();
+    fn compute_denominator(self: &mut Self) -> Result<(), B::Error> {
+        // opaque volumes are backprojected through their compositing
+        // linearized about the last projection, so the ones go through the
+        // same linearization.  nothing has been projected yet, so this is
+        // the emission alone.
+        let ones = try!(B::create_buffer(&self.queues[0], &self.geom.ones()));
+        let mut tmp = try!(B::create_buffer(&self.queues[0], &self.geom.zeros()));
 
         let np_geom = self.geom.dimension();
 
+        // this runs once, so everything but the projections stays on the
+        // first device
+        let _scope = profile_scope("denominator");
+        let vecmath = &mut self.vecmaths[0];
         for (imager, proj_buf) in self.imagers.iter_mut().zip(self.projections.iter_mut()) {
             // clear tmp buf
-            let mut evt = try!(self.vecmath.set(np_geom, &mut tmp, F::zero(), &[]));
+            let mut evt = try!(vecmath.set(np_geom, &mut tmp, F::zero(), &[]));
 
             // clear proj buf
             let np_meas = imager.detector().ns * imager.detector().nt;
-            evt = try!(self.vecmath.set(np_meas, proj_buf, F::zero(), &[evt]));
+            evt = try!(vecmath.set(np_meas, proj_buf, F::zero(), &[evt]));
 
             // project and backproject volume of ones into tmp
-            evt = try!(imager.forw(&ones, proj_buf, &[evt]));
+            evt = try!(imager.forw_linearized(&ones, proj_buf, &[evt]));
 
             self.camera_scales.push(F::one());
 
@@ -247,21 +519,20 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
 
             // accumulate backprojected ones onto denom
             // note: we scale by camera_scale^2
-            evt = try!(self.vecmath.mix(np_geom,
-                                        &tmp,
-                                        &self.denom,
-                                        F::one(),
-                                        F::one(),
-                                        &mut denom_copy,
-                                        &[evt]));
-
-            try!(evt.wait());
+            evt = try!(vecmath.mix_inplace(np_geom,
+                                           &tmp,
+                                           F::one(),
+                                           F::one(),
+                                           &mut self.denom,
+                                           &[evt]));
+
+            try!(B::wait(&evt));
         }
 
         // keep all entries within 1000 of one another
         if false {
             let mut denom_host = self.geom.zeros();
-            try!(self.queue.read_buffer(&self.denom, &mut denom_host));
+            try!(B::read_buffer(&self.queues[0], &self.denom, &mut denom_host, &[]));
             let max_val = denom_host.iter().fold(F::one(), |l, &r| {
                 if l > r {
                     l
@@ -275,7 +546,8 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
                     *m = max_val / c1000;
                 }
             }
-            try!(self.queue.write_buffer(&mut self.denom, &denom_host));
+            let evt = try!(B::write_buffer(&self.queues[0], &mut self.denom, &denom_host, &[]));
+            try!(B::wait(&evt));
         }
 
         Ok(())
@@ -285,64 +557,127 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
     /// gradient in `self.tmp_buffers[0]`
     fn compute_data_gradient(self: &mut Self,
                              subset: usize,
-                             wait_for: &[Event])
-                             -> Result<Event, Error> {
+                             wait_for: &[B::Event])
+                             -> Result<B::Event, B::Error> {
         let num_cam = self.imagers.len();
         let np_obj = self.geom.dimension();
 
-        // loop through cameras and compute gradient for each
+        // loop through cameras and compute gradient for each on its device
+        let start = precise_time_s();
         let mut camera_events = Vec::new();
         for camera in 0..num_cam {
+            let _scope = profile_scope(&format!("camera {}", camera));
             let evt = try!(self.compute_camera_gradient(camera, subset, wait_for));
             camera_events.push(evt);
         }
 
         // accumulate gradients onto the tmp buffer for the first camera
+        let reduction_scope = profile_scope("reduction");
         let mut evts_iter = camera_events.iter();
         let mut tmp_iter = self.tmp_buffers.iter_mut();
         let mut evt = evts_iter.next().unwrap().clone();
         let tmp0 = tmp_iter.next().unwrap();
-        let mut tmp0_copy = tmp0.clone();
         for (evt_i, tmp_i) in evts_iter.zip(tmp_iter) {
             let wait = vec![evt, evt_i.clone()];
-            evt = try!(self.vecmath.mix(np_obj,
-                                        tmp0,
-                                        tmp_i,
-                                        F::one(),
-                                        F::one(),
-                                        &mut tmp0_copy,
-                                        &wait));
+            evt = try!(self.vecmaths[0].mix_inplace(np_obj,
+                                                    tmp_i,
+                                                    F::one(),
+                                                    F::one(),
+                                                    tmp0,
+                                                    &wait));
+        }
+        drop(reduction_scope);
+
+        if self.queues.len() > 1 {
+            try!(self.record_utilization(start, &camera_events));
         }
 
         Ok(evt)
     }
 
+    /// Projects a camera's subsets through further imagers
+    ///
+    /// `lanes` are more imagers for camera `camera`, built like the one
+    /// given to the constructor but each with a queue of its own on the same
+    /// device (and context).  The angles of each subset are then dealt over
+    /// the camera's imager and its lanes, which can run concurrently; see
+    /// `SubsetProjector`.
+    pub fn set_imager_lanes(self: &mut Self,
+                            camera: usize,
+                            lanes: Vec<Box<Imager<F, LightVolume<F>, B>>>)
+                            -> Result<(), B::Error> {
+        let queue = self.queues[self.camera_devices[camera]].clone();
+        let projector = try!(SubsetProjector::new(&*self.imagers[camera], lanes, queue));
+        self.projectors[camera] = Some(projector);
+        Ok(())
+    }
+
+    /// Adds how long each device took over the camera gradients started at
+    /// `start` to `device_busy`, and the longest of these to `gradient_time`
+    ///
+    /// Everything has been enqueued by now, so waiting here does not hold
+    /// back any device.  The devices are waited on in the order they
+    /// finished the last gradient, so that a device is rarely charged for
+    /// the time the host spends waiting on a slower one.
+    fn record_utilization(self: &mut Self,
+                          start: f64,
+                          camera_events: &[B::Event])
+                          -> Result<(), B::Error> {
+        let mut order: Vec<usize> = (0..self.queues.len()).collect();
+        order.sort_by(|&a, &b| self.device_spans[a].partial_cmp(&self.device_spans[b]).unwrap());
+
+        let mut longest = 0f64;
+        for &device in order.iter() {
+            for (evt, _) in camera_events.iter()
+                                         .zip(self.camera_devices.iter())
+                                         .filter(|&(_, &d)| d == device) {
+                try!(B::wait(evt));
+            }
+            let span = precise_time_s() - start;
+            self.device_spans[device] = span;
+            self.device_busy[device] += span;
+            longest = longest.max(span);
+        }
+        self.gradient_time += longest;
+        Ok(())
+    }
+
     fn compute_camera_gradient(self: &mut Self,
                                camera: usize,
                                subset: usize,
-                               wait_for: &[Event])
-                               -> Result<Event, Error> {
+                               wait_for: &[B::Event])
+                               -> Result<B::Event, B::Error> {
         let imager = &mut self.imagers[camera];
+        let projector = &mut self.projectors[camera];
         let tmp = &mut self.tmp_buffers[camera];
         let proj = &mut self.projections[camera];
-        let meas = &mut self.measurements[camera];
-        let mut proj_copy = proj.clone();
+        let meas = &self.measurements[camera];
         let subset_angles = &self.subsets[camera][subset];
+        let device = self.camera_devices[camera];
+        let queue = &self.queues[device];
+        let vecmath = &mut self.vecmaths[device];
 
         let np_obj = self.geom.dimension();
         let np_det = imager.detector().image_geometry().dimension();
 
         // clear proj buffer
-        let mut evt = try!(self.vecmath.set(np_det, proj, F::zero(), wait_for));
+        let mut evt = try!(vecmath.set(np_det, proj, F::zero(), wait_for));
 
         // project x
-        evt = try!(imager.forw_subset(&self.x, proj, subset_angles, &[evt]));
+        evt = {
+            let _scope = profile_scope("forw");
+            match *projector {
+                Some(ref mut p) => {
+                    try!(p.forw(&mut **imager, &self.x, proj, subset_angles, &[evt]))
+                }
+                None => try!(imager.forw_subset(&self.x, proj, subset_angles, &[evt])),
+            }
+        };
 
         if self.gain_estimation && camera > 0 {
             // for all cameras but the first, update the camera_scale
-            try!(evt.wait());
             let mut proj_host = vec![F::zero(); np_det];
-            try!(try!(self.queue.read_buffer(proj, &mut proj_host)).wait());
+            try!(B::read_buffer(queue, proj, &mut proj_host, &[evt.clone()]));
             let iprod = proj_host.iter()
                                  .zip(self.measurements_host[camera].iter())
                                  .fold(F::zero(), |l, (&a, &b)| l + a * b);
@@ -362,77 +697,72 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
         // the measurements:
         //          subset_gradient = scaling * A_subset' * ( scaling * A_subset * x - y )
         // this is a little bit different from x-ray ct
-        evt = try!(self.vecmath.mix(np_det,
-                                    proj,
-                                    meas,
-                                    scaling * scaling,
-                                    -scaling * self.camera_scales[camera],
-                                    &mut proj_copy,
-                                    &[evt]));
+        evt = try!(vecmath.mix_inplace(np_det,
+                                       meas,
+                                       -scaling * self.camera_scales[camera],
+                                       scaling * scaling,
+                                       proj,
+                                       &[evt]));
 
         // clear tmp
-        evt = try!(self.vecmath.set(np_obj, tmp, F::zero(), &[evt]));
+        evt = try!(vecmath.set(np_obj, tmp, F::zero(), &[evt]));
 
         // backproject residual into tmp
-        evt = try!(imager.back_subset(proj, tmp, subset_angles, &[evt]));
+        evt = {
+            let _scope = profile_scope("back");
+            match *projector {
+                Some(ref mut p) => {
+                    try!(p.back(&mut **imager, proj, tmp, subset_angles, &[evt]))
+                }
+                None => try!(imager.back_subset(proj, tmp, subset_angles, &[evt])),
+            }
+        };
 
         Ok(evt)
     }
 
-    fn update_image(self: &mut Self, wait_for: &[Event]) -> Result<Event, Error> {
+    fn update_image(self: &mut Self, wait_for: &[B::Event]) -> Result<B::Event, B::Error> {
+        let _scope = profile_scope("update");
+
         // update back-buffer x_off
         let np = self.geom.dimension();
-        let evt = try!(self.vecmath.mix(np,
-                                        &self.x,
-                                        &self.x,
-                                        F::one(),
-                                        F::zero(),
-                                        &mut self.x_off,
-                                        wait_for));
+        let evt = try!(self.vecmaths[0].mix(np,
+                                            &self.x,
+                                            &self.x,
+                                            F::one(),
+                                            F::zero(),
+                                            &mut self.x_off,
+                                            wait_for));
 
         // compute t1
         let c2 = F::from_f32(2f32).unwrap();
         let c4 = F::from_f32(4f32).unwrap();
         let t1 = (F::one() + (F::one() + c4 * self.t * self.t).sqrt()) / c2;
-
-        // bind arguments
-        try!(self.update.bind(0, &self.geom_buf));
-        match &self.sparsifying_buf {
-            &Some(ref buf) => try!(self.update.bind(1, buf)),
-            &None => try!(self.update.bind_null(1)),
-        };
-        try!(self.update.bind(2, &self.x));
-        try!(self.update.bind(3, &self.denom));
-        try!(self.update.bind(4, &self.tmp_buffers[0]));
-        match self.box_min {
-            Some(ref box_min) => try!(self.update.bind_scalar(5, &F::to_f32(box_min).unwrap())),
-            None => try!(self.update.bind_scalar(5, &-F::infinity())),
-        };
-        match self.box_max {
-            Some(ref box_max) => try!(self.update.bind_scalar(6, &F::to_f32(box_max).unwrap())),
-            None => try!(self.update.bind_scalar(6, &F::infinity())),
-        };
-        try!(self.update.bind_mut(7, &mut self.m));
-        try!(self.update.bind_scalar(8, &self.t));
-        try!(self.update.bind_scalar(9, &t1));
-        try!(self.update.bind(10, &self.mask3));
-        match self.edge_preserving_buf {
-            Some(ref buf) => try!(self.update.bind(11, buf)),
-            None => try!(self.update.bind_null(11)),
-        };
-        try!(self.update.bind(12, &self.x_off));
-
-        let local_size = (32, 8, 1);
-        let global_size = (self.geom.nx, self.geom.ny, self.geom.nz);
-
+        let t0 = self.t;
         self.t = t1;
 
-        self.queue.run_with_events(&mut self.update, local_size, global_size, &[evt])
+        B::fista_update(&mut self.update,
+                        &self.queues[0],
+                        &self.geom,
+                        &mut self.x,
+                        &self.denom,
+                        &self.tmp_buffers[0],
+                        self.box_min,
+                        self.box_max,
+                        &mut self.m,
+                        t0,
+                        t1,
+                        &self.mask3,
+                        &self.x_off,
+                        &[evt])
     }
 
     /// Run one subset of the FISTA iteration using the given subset of 
     /// angles to compute the data-fidelity gradients
-    pub fn run_subset(self: &mut Self, subset: usize, wait_for: &[Event]) -> Result<Event, Error> {
+    pub fn run_subset(self: &mut Self,
+                      subset: usize,
+                      wait_for: &[B::Event])
+                      -> Result<B::Event, B::Error> {
         // compute the data gradient into self.tmp_buffers[0]
         let evt = try!(self.compute_data_gradient(subset, wait_for));
 
@@ -440,7 +770,352 @@ impl<F: Float + FromPrimitive + ToPrimitive + BaseFloat> FistaVolumeSolver<F> {
         self.update_image(&[evt])
     }
 
-    pub fn image_buffer(self: &Self) -> Mem {
+    pub fn image_buffer(self: &Self) -> B::Buffer {
         self.m.clone()
     }
+
+    /// Returns the fraction of data-gradient time each device spent busy
+    ///
+    /// Entries are indexed like the queues given to `new_multi_device`.  A
+    /// device counts as busy from the start of each gradient until its last
+    /// camera gradient completes, and the gradient lasts until the slowest
+    /// device is done, so a device below 100% sat idle waiting for the
+    /// others.  Launches are not serialized for this; proust does not
+    /// expose OpenCL event profiling, so completion is timed on the host.
+    /// This is all zeros for a single device, which is never waited on.
+    pub fn device_utilization(self: &Self) -> Vec<f64> {
+        self.device_busy
+            .iter()
+            .map(|&b| if self.gradient_time > 0f64 {
+                b / self.gradient_time
+            } else {
+                0f64
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_host_fista_reduces_residual() {
+    use single_lens_imager::*;
+    use single_lens_camera::*;
+    use lens::*;
+    use detector::*;
+    use angular_plane::*;
+    use self::nalgebra::Vector3;
+
+    let vg = LightVolume {
+        nx: 8,
+        ny: 8,
+        nz: 4,
+        dx: 0.5,
+        dy: 0.5,
+        dz: 0.5,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    let camera = SingleLensCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 20f32,
+            focal_length_t: 20f32,
+        },
+        detector: Detector {
+            ns: 16,
+            nt: 16,
+            ds: 0.5,
+            dt: 0.5,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        distance_detector_lens: 25f32,
+        stop: None,
+        transfer: None,
+    };
+    let position = Vector3::new(0f32, 0f32, -100f32);
+
+    let mut imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                     camera.clone(),
+                                                     position,
+                                                     3,
+                                                     AngularBasis::Pillbox);
+    let x_true = vg.rands();
+    let y = imager.forw_host(&x_true, &HostQueue).unwrap();
+    let ynorm = y.iter().fold(0f32, |s, a| s + a * a).sqrt();
+
+    let imagers: Vec<Box<Imager<f32, LightVolume<f32>, HostBackend>>> =
+        vec![Box::new(imager)];
+    let mut solver = FistaVolumeSolver::<f32, HostBackend>::new(vg.clone(),
+                                                                imagers,
+                                                                &[&y],
+                                                                None,
+                                                                &None,
+                                                                &None,
+                                                                1,
+                                                                Some(0f32),
+                                                                None,
+                                                                false,
+                                                                HostQueue)
+                         .unwrap();
+    for _ in 0..10 {
+        solver.run_subset(0, &[]).unwrap();
+    }
+    let m = solver.image_buffer();
+
+    let mut check = HostSingleLensVolumeImager::new(vg.clone(),
+                                                    camera,
+                                                    position,
+                                                    3,
+                                                    AngularBasis::Pillbox);
+    let ym = check.forw_host(&m, &HostQueue).unwrap();
+    let rnorm = ym.iter().zip(y.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b)).sqrt();
+    println!("Host FISTA relative residual: {}", rnorm / ynorm);
+    assert!(rnorm < 0.5 * ynorm);
+}
+
+#[test]
+fn test_host_fista_multi_device() {
+    use single_lens_imager::*;
+    use single_lens_camera::*;
+    use lens::*;
+    use detector::*;
+    use angular_plane::*;
+    use self::nalgebra::Vector3;
+
+    let vg = LightVolume {
+        nx: 8,
+        ny: 8,
+        nz: 4,
+        dx: 0.5,
+        dy: 0.5,
+        dz: 0.5,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    let camera = SingleLensCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 20f32,
+            focal_length_t: 20f32,
+        },
+        detector: Detector {
+            ns: 16,
+            nt: 16,
+            ds: 0.5,
+            dt: 0.5,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        distance_detector_lens: 25f32,
+        stop: None,
+        transfer: None,
+    };
+    let positions = vec![Vector3::new(0f32, 0f32, -100f32), Vector3::new(1f32, 0f32, -90f32)];
+
+    let make_imagers = || -> Vec<Box<Imager<f32, LightVolume<f32>, HostBackend>>> {
+        positions.iter()
+                 .map(|&p| {
+                     Box::new(HostSingleLensVolumeImager::new(vg.clone(),
+                                                              camera.clone(),
+                                                              p,
+                                                              3,
+                                                              AngularBasis::Pillbox)) as
+                     Box<Imager<f32, LightVolume<f32>, HostBackend>>
+                 })
+                 .collect()
+    };
+
+    let x_true = vg.rands();
+    let mut ys = Vec::new();
+    for imager in make_imagers().iter_mut() {
+        ys.push(imager.forw_host(&x_true, &HostQueue).unwrap());
+    }
+    let y_slices: Vec<&[f32]> = ys.iter().map(|y| &y[..]).collect();
+
+    let mut single = FistaVolumeSolver::<f32, HostBackend>::new(vg.clone(),
+                                                                make_imagers(),
+                                                                &y_slices,
+                                                                None,
+                                                                &None,
+                                                                &None,
+                                                                1,
+                                                                None,
+                                                                None,
+                                                                false,
+                                                                HostQueue)
+                          .unwrap();
+    let mut multi = FistaVolumeSolver::<f32, HostBackend>::new_multi_device(vg.clone(),
+                                                                            make_imagers(),
+                                                                            &y_slices,
+                                                                            None,
+                                                                            &None,
+                                                                            &None,
+                                                                            1,
+                                                                            None,
+                                                                            None,
+                                                                            false,
+                                                                            vec![HostQueue,
+                                                                                 HostQueue],
+                                                                            vec![0, 1])
+                         .unwrap();
+    let mut laned = FistaVolumeSolver::<f32, HostBackend>::new(vg.clone(),
+                                                               make_imagers(),
+                                                               &y_slices,
+                                                               None,
+                                                               &None,
+                                                               &None,
+                                                               1,
+                                                               None,
+                                                               None,
+                                                               false,
+                                                               HostQueue)
+                         .unwrap();
+    for camera in 0..positions.len() {
+        let lanes = make_imagers().into_iter().skip(camera).take(1).collect();
+        laned.set_imager_lanes(camera, lanes).unwrap();
+    }
+    for _ in 0..3 {
+        single.run_subset(0, &[]).unwrap();
+        multi.run_subset(0, &[]).unwrap();
+        laned.run_subset(0, &[]).unwrap();
+    }
+    assert_eq!(single.image_buffer(), multi.image_buffer());
+
+    // lanes only change the order of summation
+    let (a, b) = (single.image_buffer(), laned.image_buffer());
+    let num = a.iter().zip(b.iter()).fold(0f32, |s, (x, y)| s + (x - y) * (x - y));
+    let den = a.iter().fold(0f32, |s, x| s + x * x);
+    assert!(den > 0f32);
+    assert!(num <= 1e-10 * den);
+
+    let utilization = multi.device_utilization();
+    assert_eq!(utilization.len(), 2);
+    assert!(utilization.iter().all(|&u| u >= 0f64 && u <= 1f64));
+}
+
+#[test]
+#[should_panic]
+fn test_host_fista_rejects_empty_subsets() {
+    use single_lens_imager::*;
+    use angular_plane::*;
+    use self::nalgebra::Vector3;
+
+    let vg = test_volume();
+    let imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                 test_camera(),
+                                                 Vector3::new(0f32, 0f32, -100f32),
+                                                 3,
+                                                 AngularBasis::Pillbox);
+    let y = imager.detector().image_geometry().zeros();
+
+    // more subsets than angles would leave some subsets empty
+    let num_subsets = imager.na() + 1;
+    let imagers: Vec<Box<Imager<f32, LightVolume<f32>, HostBackend>>> = vec![Box::new(imager)];
+    let _ = FistaVolumeSolver::<f32, HostBackend>::new(vg,
+                                                       imagers,
+                                                       &[&y[..]],
+                                                       None,
+                                                       &None,
+                                                       &None,
+                                                       num_subsets,
+                                                       None,
+                                                       None,
+                                                       false,
+                                                       HostQueue);
+}
+
+#[test]
+fn test_host_fista_opaque_reduces_residual() {
+    use single_lens_imager::*;
+    use single_lens_camera::*;
+    use lens::*;
+    use detector::*;
+    use angular_plane::*;
+    use self::nalgebra::Vector3;
+
+    let vg = LightVolume {
+        nx: 8,
+        ny: 8,
+        nz: 4,
+        dx: 0.5,
+        dy: 0.5,
+        dz: 0.5,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: true,
+        attenuation: 1.0,
+    };
+    let camera = SingleLensCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 20f32,
+            focal_length_t: 20f32,
+        },
+        detector: Detector {
+            ns: 16,
+            nt: 16,
+            ds: 0.5,
+            dt: 0.5,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        distance_detector_lens: 25f32,
+        stop: None,
+        transfer: None,
+    };
+    let position = Vector3::new(0f32, 0f32, -100f32);
+
+    let mut imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                     camera.clone(),
+                                                     position,
+                                                     3,
+                                                     AngularBasis::Pillbox);
+    let x_true = vg.rands();
+    let y = imager.forw_host(&x_true, &HostQueue).unwrap();
+    let ynorm = y.iter().fold(0f32, |s, a| s + a * a).sqrt();
+
+    let imagers: Vec<Box<Imager<f32, LightVolume<f32>, HostBackend>>> =
+        vec![Box::new(imager)];
+    let mut solver = FistaVolumeSolver::<f32, HostBackend>::new(vg.clone(),
+                                                                imagers,
+                                                                &[&y],
+                                                                None,
+                                                                &None,
+                                                                &None,
+                                                                1,
+                                                                Some(0f32),
+                                                                None,
+                                                                false,
+                                                                HostQueue)
+                         .unwrap();
+    for _ in 0..10 {
+        solver.run_subset(0, &[]).unwrap();
+    }
+    let m = solver.image_buffer();
+
+    let mut check = HostSingleLensVolumeImager::new(vg.clone(),
+                                                    camera,
+                                                    position,
+                                                    3,
+                                                    AngularBasis::Pillbox);
+    let ym = check.forw_host(&m, &HostQueue).unwrap();
+    let rnorm = ym.iter().zip(y.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b)).sqrt();
+    println!("Host FISTA relative residual for an opaque volume: {}", rnorm / ynorm);
+    assert!(rnorm < 0.5 * ynorm);
 }
@@ -0,0 +1,1163 @@
+extern crate num;
+
+use light_field_geom::*;
+use light_volume::*;
+use image_geom::*;
+use optics::*;
+use spline_kernel::*;
+use transport::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use std::cmp::max;
+
+/// Pixel bounds `(s0, s1, t0, t1)` of a transport
+pub type TransportBounds = (usize, usize, usize, usize);
+
+/// Clamps a (floored or ceiled) fractional pixel index to `[lo, hi]`
+fn clamp_index<F: Float + ToPrimitive>(x: F, lo: usize, hi: usize) -> usize {
+    if x.is_nan() || x <= F::zero() {
+        lo
+    } else {
+        let ix = x.to_usize().unwrap_or(hi);
+        max(lo, if ix > hi { hi } else { ix })
+    }
+}
+
+/// Host version of `transport_t_iprod` from `transport_f32.opencl`
+///
+/// Filters along `t` from `src_geom` onto the row `dst_it_offset` of
+/// `dst_geom` for source column `src_is`.
+fn transport_t_iprod<F>(src_is: usize,
+                        dst_it_offset: usize,
+                        src_geom: &ImageGeometry<F>,
+                        dst_geom: &ImageGeometry<F>,
+                        src_bounds: &TransportBounds,
+                        dst_bounds: &TransportBounds,
+                        kernel: &SplineKernel<F>,
+                        src: &[F])
+                        -> F
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let c2 = F::from_f32(2f32).unwrap();
+    let dst_t = dst_geom.it2t(dst_it_offset + dst_bounds.2);
+    let (tau_first, tau_last) = kernel.support(dst_t);
+
+    let itmin = clamp_index(src_geom.t2it(tau_first).floor(), src_bounds.2, src_bounds.3);
+    let itmax = clamp_index(src_geom.t2it(tau_last).ceil(), src_bounds.2, src_bounds.3);
+
+    let mut accum = F::zero();
+    for src_it in itmin..itmax {
+        let src_t = src_geom.it2t(src_it);
+        let w = kernel.integrate(dst_t,
+                                 src_t - src_geom.dt.abs() / c2,
+                                 src_t + src_geom.dt.abs() / c2);
+        accum = accum + w * src[src_is + src_geom.ns * src_it];
+    }
+    accum
+}
+
+/// Host version of `transport_s_iprod` from `transport_f32.opencl`
+///
+/// `tmp` is the `t`-filtered buffer written by the `t` pass.
+fn transport_s_iprod<F>(dst_it_offset: usize,
+                        dst_is_offset: usize,
+                        src_geom: &ImageGeometry<F>,
+                        dst_geom: &ImageGeometry<F>,
+                        src_bounds: &TransportBounds,
+                        dst_bounds: &TransportBounds,
+                        kernel: &SplineKernel<F>,
+                        tmp: &[F])
+                        -> F
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let c2 = F::from_f32(2f32).unwrap();
+    let dst_s = dst_geom.is2s(dst_is_offset + dst_bounds.0);
+    let (tau_first, tau_last) = kernel.support(dst_s);
+
+    let ismin = clamp_index(src_geom.s2is(tau_first).floor(), src_bounds.0, src_bounds.1);
+    let ismax = clamp_index(src_geom.s2is(tau_last).ceil(), src_bounds.0, src_bounds.1);
+
+    let dst_nt = dst_bounds.3 - dst_bounds.2;
+    let mut accum = F::zero();
+    for src_is in ismin..ismax {
+        let src_s = src_geom.is2s(src_is);
+        let w = kernel.integrate(dst_s,
+                                 src_s - src_geom.ds.abs() / c2,
+                                 src_s + src_geom.ds.abs() / c2);
+        accum = accum + w * tmp[dst_it_offset + dst_nt * (src_is - src_bounds.0)];
+    }
+    accum
+}
+
+/// Host version of the `Transport` object
+///
+/// This computes the same separable footprints as the OpenCL `Transport`
+/// entirely on the CPU, so it can be used for testing and debugging
+/// without an OpenCL device.
+pub struct HostTransport<F: Float> {
+    pub src: LightFieldGeometry<F>,
+    pub dst: LightFieldGeometry<F>,
+
+    pub overwrite_forw: bool,
+    pub overwrite_back: bool,
+
+    pub conservative_forw: bool,
+    pub conservative_back: bool,
+
+    pub onto_detector: bool,
+
+    src_bounds: TransportBounds,
+    dst_bounds: TransportBounds,
+
+    tmp: Vec<F>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> HostTransport<F> {
+    pub fn new_simple(src: LightFieldGeometry<F>, dst: LightFieldGeometry<F>) -> Self {
+        Self::new(src, dst, None, None, true, true, false, false, false)
+    }
+
+    pub fn new(src: LightFieldGeometry<F>,
+               dst: LightFieldGeometry<F>,
+               src_bounds: Option<TransportBounds>,
+               dst_bounds: Option<TransportBounds>,
+               overwrite_forw: bool,
+               overwrite_back: bool,
+               conservative_forw: bool,
+               conservative_back: bool,
+               onto_detector: bool)
+               -> Self {
+        let resolved_src_bounds = src_bounds.unwrap_or((0, src.geom.ns, 0, src.geom.nt));
+        let resolved_dst_bounds = dst_bounds.unwrap_or((0, dst.geom.ns, 0, dst.geom.nt));
+
+        let tmp_np = max(resolved_src_bounds.1 - resolved_src_bounds.0,
+                         resolved_dst_bounds.1 - resolved_dst_bounds.0) *
+                     max(resolved_src_bounds.3 - resolved_src_bounds.2,
+                         resolved_dst_bounds.3 - resolved_dst_bounds.2);
+
+        HostTransport {
+            src: src,
+            dst: dst,
+
+            overwrite_forw: overwrite_forw,
+            overwrite_back: overwrite_back,
+
+            conservative_forw: conservative_forw,
+            conservative_back: conservative_back,
+
+            onto_detector: onto_detector,
+
+            src_bounds: resolved_src_bounds,
+            dst_bounds: resolved_dst_bounds,
+
+            tmp: vec![F::zero(); tmp_np],
+        }
+    }
+
+    fn kernels(self: &Self, forw: bool, ia: usize) -> (SplineKernel<F>, SplineKernel<F>) {
+        transport_footprints(&self.src, &self.dst, ia, forw, self.onto_detector)
+    }
+
+    fn transport(self: &mut Self, forw: bool, src: &[F], dst: &mut [F], ia: usize) {
+        let (ks, kt) = self.kernels(forw, ia);
+        let (from_geom, into_geom, from_bounds, into_bounds, conservative, overwrite) = if forw {
+            (&self.src.geom,
+             &self.dst.geom,
+             &self.src_bounds,
+             &self.dst_bounds,
+             self.conservative_forw,
+             self.overwrite_forw)
+        } else {
+            (&self.dst.geom,
+             &self.src.geom,
+             &self.dst_bounds,
+             &self.src_bounds,
+             self.conservative_back,
+             self.overwrite_back)
+        };
+        let tmp = &mut self.tmp;
+
+        let from_ns = from_bounds.1 - from_bounds.0;
+        let into_ns = into_bounds.1 - into_bounds.0;
+        let into_nt = into_bounds.3 - into_bounds.2;
+
+        // filter along t
+        for from_is_offset in 0..from_ns {
+            for into_it_offset in 0..into_nt {
+                tmp[into_it_offset + into_nt * from_is_offset] =
+                    transport_t_iprod(from_is_offset + from_bounds.0,
+                                      into_it_offset,
+                                      from_geom,
+                                      into_geom,
+                                      from_bounds,
+                                      into_bounds,
+                                      &kt,
+                                      src);
+            }
+        }
+
+        // filter along s
+        for into_it_offset in 0..into_nt {
+            for into_is_offset in 0..into_ns {
+                let val = transport_s_iprod(into_it_offset,
+                                            into_is_offset,
+                                            from_geom,
+                                            into_geom,
+                                            from_bounds,
+                                            into_bounds,
+                                            &ks,
+                                            tmp);
+                let idx = (into_is_offset + into_bounds.0) +
+                          into_geom.ns * (into_it_offset + into_bounds.2);
+                if !conservative || val != F::zero() {
+                    if overwrite {
+                        dst[idx] = val;
+                    } else {
+                        dst[idx] = dst[idx] + val;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Transport from source to destination
+    pub fn forw(self: &mut Self, src: &[F], dst: &mut [F], ia: usize) {
+        self.transport(true, src, dst, ia)
+    }
+
+    /// Transport from destination to source
+    pub fn back(self: &mut Self, dst: &[F], src: &mut [F], ia: usize) {
+        self.transport(false, dst, src, ia)
+    }
+}
+
+/// Applies `volume_scale` from `light_volume_f32.opencl` on the host
+fn volume_scale<F>(dst: &LightFieldGeometry<F>,
+                   dst_to_obj: &Optics<F>,
+                   ia: usize,
+                   input: &[F],
+                   output: &mut [F],
+                   overwrite: bool)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let to_plane = &dst.to_plane;
+    let s_plane = dst.plane.s[ia];
+    let t_plane = dst.plane.t[ia];
+
+    for it in 0..dst.geom.nt {
+        let t = dst.geom.it2t(it);
+        let v = (t_plane - to_plane.tt * t - to_plane.t) / to_plane.tv;
+        let v_out = dst_to_obj.vt * t + dst_to_obj.vv * v + dst_to_obj.v;
+        for is in 0..dst.geom.ns {
+            let s = dst.geom.is2s(is);
+            let u = (s_plane - to_plane.ss * s - to_plane.s) / to_plane.su;
+            let u_out = dst_to_obj.us * s + dst_to_obj.uu * u + dst_to_obj.u;
+
+            let factor = (F::one() + u_out * u_out + v_out * v_out).sqrt();
+            let idx = is + dst.geom.ns * it;
+            if overwrite {
+                output[idx] = factor * input[idx];
+            } else {
+                output[idx] = output[idx] + factor * input[idx];
+            }
+        }
+    }
+}
+
+/// Host version of `volume_forw_t` and `volume_forw_s`
+///
+/// Projects `slice`, scaled by `scale`, onto `dst`.
+fn volume_forw_slice<F>(slice_geom: &ImageGeometry<F>,
+                        dst_geom: &ImageGeometry<F>,
+                        ks: &SplineKernel<F>,
+                        kt: &SplineKernel<F>,
+                        scale: F,
+                        slice: &[F],
+                        tmp: &mut [F],
+                        dst: &mut [F],
+                        overwrite: bool)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let slice_bounds = (0, slice_geom.ns, 0, slice_geom.nt);
+    let dst_bounds = (0, dst_geom.ns, 0, dst_geom.nt);
+
+    for src_is in 0..slice_geom.ns {
+        for dst_it in 0..dst_geom.nt {
+            tmp[dst_it + dst_geom.nt * src_is] = transport_t_iprod(src_is,
+                                                                   dst_it,
+                                                                   slice_geom,
+                                                                   dst_geom,
+                                                                   &slice_bounds,
+                                                                   &dst_bounds,
+                                                                   kt,
+                                                                   slice);
+        }
+    }
+
+    for dst_it in 0..dst_geom.nt {
+        for dst_is in 0..dst_geom.ns {
+            let val = scale *
+                      transport_s_iprod(dst_it,
+                                        dst_is,
+                                        slice_geom,
+                                        dst_geom,
+                                        &slice_bounds,
+                                        &dst_bounds,
+                                        ks,
+                                        tmp);
+            let idx = dst_is + dst_geom.ns * dst_it;
+            if overwrite {
+                dst[idx] = val;
+            } else {
+                dst[idx] = dst[idx] + val;
+            }
+        }
+    }
+}
+
+/// Host version of `volume_back_t` and `volume_back_s`
+///
+/// Backprojects `src`, scaled by `scale`, into `slice`.
+fn volume_back_slice<F>(slice_geom: &ImageGeometry<F>,
+                        dst_geom: &ImageGeometry<F>,
+                        ks: &SplineKernel<F>,
+                        kt: &SplineKernel<F>,
+                        scale: F,
+                        src: &[F],
+                        tmp: &mut [F],
+                        slice: &mut [F],
+                        overwrite: bool)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let slice_bounds = (0, slice_geom.ns, 0, slice_geom.nt);
+    let dst_bounds = (0, dst_geom.ns, 0, dst_geom.nt);
+
+    for dst_is in 0..dst_geom.ns {
+        for src_it in 0..slice_geom.nt {
+            tmp[src_it + slice_geom.nt * dst_is] = scale *
+                                                   transport_t_iprod(dst_is,
+                                                                     src_it,
+                                                                     dst_geom,
+                                                                     slice_geom,
+                                                                     &dst_bounds,
+                                                                     &slice_bounds,
+                                                                     kt,
+                                                                     src);
+        }
+    }
+
+    for src_it in 0..slice_geom.nt {
+        for src_is in 0..slice_geom.ns {
+            let val = transport_s_iprod(src_it,
+                                        src_is,
+                                        dst_geom,
+                                        slice_geom,
+                                        &dst_bounds,
+                                        &slice_bounds,
+                                        ks,
+                                        tmp);
+            let idx = src_is + slice_geom.ns * src_it;
+            if overwrite {
+                slice[idx] = val;
+            } else {
+                slice[idx] = slice[idx] + val;
+            }
+        }
+    }
+}
+
+/// Host version of `volume_composite` from `light_volume_f32.opencl`
+fn volume_composite<F: Float>(absorption: F,
+                              obliquity: &[F],
+                              projected: &[F],
+                              transmittance: &mut [F],
+                              light: &mut [F]) {
+    for idx in 0..projected.len() {
+        let v = projected[idx];
+        let trans = transmittance[idx];
+        light[idx] = light[idx] + trans * v;
+        transmittance[idx] = trans * (-absorption * obliquity[idx] * v.max(F::zero())).exp();
+    }
+}
+
+/// Host version of `volume_linearize` from `light_volume_f32.opencl`
+fn volume_linearize<F: Float>(absorption: F,
+                              obliquity: &[F],
+                              scaled: &[F],
+                              projected: &[F],
+                              total: &[F],
+                              transmittance: &mut [F],
+                              seen: &mut [F],
+                              weights: &mut [F]) {
+    for idx in 0..projected.len() {
+        let v = projected[idx];
+        let trans = transmittance[idx];
+        let behind = total[idx] - seen[idx] - trans * v;
+        let loss = if v > F::zero() {
+            absorption * obliquity[idx] * behind
+        } else {
+            F::zero()
+        };
+        weights[idx] = scaled[idx] * (trans - loss);
+        seen[idx] = seen[idx] + trans * v;
+        transmittance[idx] = trans * (-absorption * obliquity[idx] * v.max(F::zero())).exp();
+    }
+}
+
+/// Host version of `volume_tangent` from `light_volume_f32.opencl`
+fn volume_tangent<F: Float>(absorption: F,
+                            obliquity: &[F],
+                            direction: &[F],
+                            projected: &[F],
+                            total: &[F],
+                            transmittance: &mut [F],
+                            seen: &mut [F],
+                            tangent: &mut [F]) {
+    for idx in 0..projected.len() {
+        let v = projected[idx];
+        let trans = transmittance[idx];
+        let behind = total[idx] - seen[idx] - trans * v;
+        let loss = if v > F::zero() {
+            absorption * obliquity[idx] * behind
+        } else {
+            F::zero()
+        };
+        tangent[idx] = tangent[idx] + direction[idx] * (trans - loss);
+        seen[idx] = seen[idx] + trans * v;
+        transmittance[idx] = trans * (-absorption * obliquity[idx] * v.max(F::zero())).exp();
+    }
+}
+
+/// Host version of the `VolumeTransport` object
+///
+/// Opaque volumes use an emission-absorption model: each voxel emits in
+/// proportion to its value and attenuates light from the voxels behind it
+/// by `LightVolume::attenuation` per unit value and length.  Slices are
+/// composited front-to-back for each angle, and `back` applies the adjoint
+/// of this model linearized about the volume last passed to `forw`.
+pub struct HostVolumeTransport<F: Float> {
+    pub geom: LightVolume<F>,
+    pub dst: LightFieldGeometry<F>,
+
+    pub overwrite_forw: bool,
+    pub overwrite_back: bool,
+    pub onto_detector: bool,
+
+    slice_geom: ImageGeometry<F>,
+    dst_to_obj: Optics<F>,
+
+    // footprints for each slice and angle, indexed by na*iz + ia
+    forw_spline_kernels_s: Vec<SplineKernel<F>>,
+    forw_spline_kernels_t: Vec<SplineKernel<F>>,
+    back_spline_kernels_s: Vec<SplineKernel<F>>,
+    back_spline_kernels_t: Vec<SplineKernel<F>>,
+
+    // absorption for each slice and angle, indexed by na*iz + ia
+    absorption: Vec<F>,
+
+    tmp: Vec<F>,
+    scaled: Vec<F>,
+
+    // compositing buffers for opaque volumes
+    obliquity: Vec<F>,
+    transmittance: Vec<F>,
+    projected: Vec<F>,
+    direction: Vec<F>,
+    light: Vec<F>,
+    seen: Vec<F>,
+    weights: Vec<F>,
+    linearization: Option<Vec<F>>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> HostVolumeTransport<F> {
+    pub fn new_simple(src: LightVolume<F>,
+                      dst: LightFieldGeometry<F>,
+                      to_plane: Optics<F>)
+                      -> Self {
+        Self::new(src, dst, to_plane, true, true, false)
+    }
+
+    /// Create a new `HostVolumeTransport`
+    pub fn new(src: LightVolume<F>,
+               dst: LightFieldGeometry<F>,
+               to_plane: Optics<F>,
+               overwrite_forw: bool,
+               overwrite_back: bool,
+               onto_detector: bool)
+               -> Self {
+        let na = dst.plane.s.len();
+        let mut forw_spline_kernels_s = Vec::with_capacity(na * src.nz);
+        let mut forw_spline_kernels_t = Vec::with_capacity(na * src.nz);
+        let mut back_spline_kernels_s = Vec::with_capacity(na * src.nz);
+        let mut back_spline_kernels_t = Vec::with_capacity(na * src.nz);
+        for iz in 0..src.nz {
+            let slice_lfg = src.slice_light_field_geometry(iz, dst.plane.clone(), to_plane.clone());
+            for ia in 0..na {
+                let (forw_s, forw_t) = slice_lfg.transport_to(&dst, ia);
+                let (back_s, back_t) = dst.transport_to(&slice_lfg, ia);
+                forw_spline_kernels_s.push(forw_s);
+                forw_spline_kernels_t.push(forw_t);
+                back_spline_kernels_s.push(back_s);
+                back_spline_kernels_t.push(back_t);
+            }
+        }
+
+        let tmp_nx = max(src.nx, dst.geom.ns);
+        let tmp_ny = max(src.ny, dst.geom.nt);
+        let dst_np = dst.geom.ns * dst.geom.nt;
+        let opaque_np = if src.opaque {
+            dst_np
+        } else {
+            0
+        };
+
+        let mut tr = HostVolumeTransport {
+            slice_geom: src.transaxial_image_geometry(),
+            dst_to_obj: to_plane.invert().compose(&dst.to_plane),
+
+            forw_spline_kernels_s: forw_spline_kernels_s,
+            forw_spline_kernels_t: forw_spline_kernels_t,
+            back_spline_kernels_s: back_spline_kernels_s,
+            back_spline_kernels_t: back_spline_kernels_t,
+
+            absorption: Vec::with_capacity(na * src.nz),
+
+            tmp: vec![F::zero(); tmp_nx * tmp_ny],
+            scaled: vec![F::zero(); dst_np],
+
+            obliquity: vec![F::zero(); opaque_np],
+            transmittance: vec![F::zero(); opaque_np],
+            projected: vec![F::zero(); opaque_np],
+            direction: vec![F::zero(); opaque_np],
+            light: vec![F::zero(); opaque_np],
+            seen: vec![F::zero(); opaque_np],
+            weights: vec![F::zero(); opaque_np],
+            linearization: None,
+
+            geom: src,
+            dst: dst,
+
+            overwrite_forw: overwrite_forw,
+            overwrite_back: overwrite_back,
+            onto_detector: onto_detector,
+        };
+
+        for iz in 0..tr.geom.nz {
+            for ia in 0..na {
+                let beta = tr.geom.slice_absorption(&tr.forw_spline_kernels_s[na * iz + ia],
+                                                    &tr.forw_spline_kernels_t[na * iz + ia],
+                                                    tr.slice_scale(ia));
+                tr.absorption.push(beta);
+            }
+        }
+        tr
+    }
+
+    fn scale_factor(self: &Self, ia: usize) -> F {
+        if self.onto_detector {
+            self.geom.dz.abs() / self.dst.pixel_volume().sqrt() * self.dst.plane.w[ia]
+        } else {
+            self.geom.dz.abs() / self.dst.pixel_volume()
+        }
+    }
+
+    /// The OpenCL Dirac kernels do not apply the slice scale factor
+    /// inside the volume; we match them here.
+    fn slice_scale(self: &Self, ia: usize) -> F {
+        match self.forw_spline_kernels_s[0] {
+            SplineKernel::Rect(_, _, _) => F::one(),
+            _ => self.scale_factor(ia),
+        }
+    }
+
+    /// Computes the path length through a unit slab for each ray of angle `ia`
+    fn compute_obliquity(self: &mut Self, ia: usize) {
+        for x in self.transmittance.iter_mut() {
+            *x = F::one();
+        }
+        volume_scale(&self.dst,
+                     &self.dst_to_obj,
+                     ia,
+                     &self.transmittance,
+                     &mut self.obliquity,
+                     true);
+    }
+
+    /// Composites `vol` front-to-back into `light`, leaving the
+    /// transmittance behind the volume in `transmittance`
+    fn composite(self: &mut Self, vol: &[F], ia: usize) {
+        let na = self.dst.plane.s.len();
+        let slice_scale = self.slice_scale(ia);
+        let slice_np = self.slice_geom.ns * self.slice_geom.nt;
+
+        for x in self.light.iter_mut() {
+            *x = F::zero();
+        }
+        for x in self.transmittance.iter_mut() {
+            *x = F::one();
+        }
+
+        for iz in self.geom.slices_front_to_back() {
+            volume_forw_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.forw_spline_kernels_s[na * iz + ia],
+                              &self.forw_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &vol[slice_np * iz..slice_np * (iz + 1)],
+                              &mut self.tmp,
+                              &mut self.projected,
+                              true);
+            volume_composite(self.absorption[na * iz + ia],
+                             &self.obliquity,
+                             &self.projected,
+                             &mut self.transmittance,
+                             &mut self.light);
+        }
+    }
+
+    /// Project a volume onto the destination plane
+    pub fn forw(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        if self.geom.opaque {
+            return self.forw_opaque(vol, dst, ia);
+        }
+        self.forw_emission(vol, dst, ia)
+    }
+
+    /// Project the emission of a volume, ignoring any absorption
+    fn forw_emission(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        let na = self.dst.plane.s.len();
+        let slice_scale = self.slice_scale(ia);
+        let slice_np = self.slice_geom.ns * self.slice_geom.nt;
+
+        for x in self.scaled.iter_mut() {
+            *x = F::zero();
+        }
+
+        for iz in 0..self.geom.nz {
+            volume_forw_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.forw_spline_kernels_s[na * iz + ia],
+                              &self.forw_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &vol[slice_np * iz..slice_np * (iz + 1)],
+                              &mut self.tmp,
+                              &mut self.scaled,
+                              false);
+        }
+
+        volume_scale(&self.dst,
+                     &self.dst_to_obj,
+                     ia,
+                     &self.scaled,
+                     dst,
+                     self.overwrite_forw);
+    }
+
+    fn forw_opaque(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        // record the linearization point for `back`
+        match self.linearization {
+            Some(ref mut lin) => lin.copy_from_slice(vol),
+            None => self.linearization = Some(vol.to_vec()),
+        }
+
+        self.compute_obliquity(ia);
+        self.composite(vol, ia);
+
+        volume_scale(&self.dst,
+                     &self.dst_to_obj,
+                     ia,
+                     &self.light,
+                     dst,
+                     self.overwrite_forw);
+    }
+
+    /// Project a volume through the derivative of `forw`
+    ///
+    /// The derivative is taken at the same point as in `back`, so this is
+    /// the operator `back` is the adjoint of.  It is `forw` itself for
+    /// volumes that are not opaque.
+    pub fn forw_linearized(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        if self.geom.opaque && self.linearization.is_some() {
+            return self.forw_tangent(vol, dst, ia);
+        }
+        self.forw_emission(vol, dst, ia)
+    }
+
+    fn forw_tangent(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        let na = self.dst.plane.s.len();
+        let slice_scale = self.slice_scale(ia);
+        let slice_np = self.slice_geom.ns * self.slice_geom.nt;
+        let lin = self.linearization.take().unwrap();
+
+        self.compute_obliquity(ia);
+
+        // total light at the linearization point
+        self.composite(&lin, ia);
+
+        for x in self.seen.iter_mut() {
+            *x = F::zero();
+        }
+        for x in self.transmittance.iter_mut() {
+            *x = F::one();
+        }
+        for x in self.weights.iter_mut() {
+            *x = F::zero();
+        }
+
+        for iz in self.geom.slices_front_to_back() {
+            volume_forw_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.forw_spline_kernels_s[na * iz + ia],
+                              &self.forw_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &lin[slice_np * iz..slice_np * (iz + 1)],
+                              &mut self.tmp,
+                              &mut self.projected,
+                              true);
+            volume_forw_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.forw_spline_kernels_s[na * iz + ia],
+                              &self.forw_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &vol[slice_np * iz..slice_np * (iz + 1)],
+                              &mut self.tmp,
+                              &mut self.direction,
+                              true);
+            volume_tangent(self.absorption[na * iz + ia],
+                           &self.obliquity,
+                           &self.direction,
+                           &self.projected,
+                           &self.light,
+                           &mut self.transmittance,
+                           &mut self.seen,
+                           &mut self.weights);
+        }
+
+        volume_scale(&self.dst,
+                     &self.dst_to_obj,
+                     ia,
+                     &self.weights,
+                     dst,
+                     self.overwrite_forw);
+
+        self.linearization = Some(lin);
+    }
+
+    /// Backproject from the destination plane into a volume
+    ///
+    /// For opaque volumes this is the adjoint of the derivative of `forw`
+    /// at the volume last projected, or at zero (where the volume is
+    /// transparent) if nothing has been projected yet.
+    pub fn back(self: &mut Self, dst: &[F], vol: &mut [F], ia: usize) {
+        if self.geom.opaque && self.linearization.is_some() {
+            return self.back_opaque(dst, vol, ia);
+        }
+
+        let na = self.dst.plane.s.len();
+        let slice_scale = self.slice_scale(ia);
+        let slice_np = self.slice_geom.ns * self.slice_geom.nt;
+
+        volume_scale(&self.dst, &self.dst_to_obj, ia, dst, &mut self.scaled, true);
+
+        for iz in 0..self.geom.nz {
+            volume_back_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.back_spline_kernels_s[na * iz + ia],
+                              &self.back_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &self.scaled,
+                              &mut self.tmp,
+                              &mut vol[slice_np * iz..slice_np * (iz + 1)],
+                              self.overwrite_back);
+        }
+    }
+
+    fn back_opaque(self: &mut Self, dst: &[F], vol: &mut [F], ia: usize) {
+        let na = self.dst.plane.s.len();
+        let slice_scale = self.slice_scale(ia);
+        let slice_np = self.slice_geom.ns * self.slice_geom.nt;
+        let lin = self.linearization.take().unwrap();
+
+        volume_scale(&self.dst, &self.dst_to_obj, ia, dst, &mut self.scaled, true);
+        self.compute_obliquity(ia);
+
+        // total light at the linearization point
+        self.composite(&lin, ia);
+
+        for x in self.seen.iter_mut() {
+            *x = F::zero();
+        }
+        for x in self.transmittance.iter_mut() {
+            *x = F::one();
+        }
+
+        for iz in self.geom.slices_front_to_back() {
+            let lin_slice = &lin[slice_np * iz..slice_np * (iz + 1)];
+            volume_forw_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.forw_spline_kernels_s[na * iz + ia],
+                              &self.forw_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              lin_slice,
+                              &mut self.tmp,
+                              &mut self.projected,
+                              true);
+            volume_linearize(self.absorption[na * iz + ia],
+                             &self.obliquity,
+                             &self.scaled,
+                             &self.projected,
+                             &self.light,
+                             &mut self.transmittance,
+                             &mut self.seen,
+                             &mut self.weights);
+            volume_back_slice(&self.slice_geom,
+                              &self.dst.geom,
+                              &self.back_spline_kernels_s[na * iz + ia],
+                              &self.back_spline_kernels_t[na * iz + ia],
+                              slice_scale,
+                              &self.weights,
+                              &mut self.tmp,
+                              &mut vol[slice_np * iz..slice_np * (iz + 1)],
+                              self.overwrite_back);
+        }
+
+        self.linearization = Some(lin);
+    }
+}
+
+#[cfg(test)]
+fn test_lens() -> ::lens::Lens<f32> {
+    ::lens::Lens {
+        center_s: 1f32,
+        center_t: -1.5f32,
+        radius_s: 20f32,
+        radius_t: 15f32,
+        focal_length_s: 30f32,
+        focal_length_t: 35f32,
+    }
+}
+
+#[test]
+fn test_host_transport_adjoint() {
+    use angular_plane::*;
+    use geom::*;
+
+    let lens = test_lens();
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let plane = lens.as_angular_plane(basis, 5);
+
+        let src = LightFieldGeometry {
+            geom: ImageGeometry {
+                ns: 40,
+                nt: 50,
+                ds: 1.0,
+                dt: 1.1,
+                offset_s: 0.5,
+                offset_t: 2.9,
+            },
+            plane: plane.clone(),
+            to_plane: lens.optics().then(&Optics::translation(&500f32)).invert(),
+        };
+        let dst = LightFieldGeometry {
+            geom: ImageGeometry {
+                ns: 128,
+                nt: 192,
+                ds: 2e-1,
+                dt: 1.5e-1,
+                offset_s: -4.0,
+                offset_t: 2.1,
+            },
+            plane: plane.clone(),
+            to_plane: Optics::translation(&40f32),
+        };
+
+        let mut xport = HostTransport::new_simple(src.clone(), dst.clone());
+
+        let u = src.geom.rands();
+        let v = dst.geom.rands();
+        let mut proj_u = dst.geom.zeros();
+        let mut back_v = src.geom.zeros();
+        xport.forw(&u, &mut proj_u, 12);
+        xport.back(&v, &mut back_v, 12);
+
+        let v1 = proj_u.iter().zip(v.iter()).fold(0f32, |s, (ui, vi)| s + ui * vi);
+        let v2 = back_v.iter().zip(u.iter()).fold(0f32, |s, (vi, ui)| s + ui * vi);
+        let nrmse = (v1 - v2).abs() / v1.abs().max(v2.abs());
+
+        println!("Adjoint NRMSE for HostTransport: {}", nrmse);
+        assert!(nrmse < 1e-4);
+    }
+}
+
+#[test]
+fn test_host_transport_matches_opencl() {
+    use angular_plane::*;
+    use transport::*;
+    use geom::*;
+    use env::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let lens = test_lens();
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let plane = lens.as_angular_plane(basis, 5);
+
+        let src = LightFieldGeometry {
+            geom: ImageGeometry {
+                ns: 40,
+                nt: 50,
+                ds: 1.0,
+                dt: 1.1,
+                offset_s: 0.5,
+                offset_t: 2.9,
+            },
+            plane: plane.clone(),
+            to_plane: lens.optics().then(&Optics::translation(&500f32)).invert(),
+        };
+        let dst = LightFieldGeometry {
+            geom: ImageGeometry {
+                ns: 128,
+                nt: 192,
+                ds: 2e-1,
+                dt: 1.5e-1,
+                offset_s: -4.0,
+                offset_t: 2.1,
+            },
+            plane: plane.clone(),
+            to_plane: Optics::translation(&40f32),
+        };
+
+        let mut host = HostTransport::new_simple(src.clone(), dst.clone());
+        let mut cl = Transport::new_simple(src.clone(), dst.clone(), queue.clone()).unwrap();
+
+        let u = src.geom.rands();
+        let mut host_u = dst.geom.zeros();
+        let mut cl_u = dst.geom.zeros();
+        host.forw(&u, &mut host_u, 12);
+        cl.forw_host(&u, &mut cl_u, 12).unwrap();
+
+        let err = host_u.iter().zip(cl_u.iter()).fold(0f32, |s, (h, c)| s + (h - c) * (h - c));
+        let nrm = cl_u.iter().fold(0f32, |s, c| s + c * c);
+        let nrmse = (err / nrm).sqrt();
+
+        println!("NRMSE between HostTransport and Transport: {}", nrmse);
+        assert!(nrmse < 1e-4);
+    }
+}
+
+#[test]
+fn test_host_volume_transport_adjoint() {
+    use angular_plane::*;
+    use geom::*;
+
+    let lens = test_lens();
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let plane = lens.as_angular_plane(basis, 5);
+
+        let vg = LightVolume {
+            nx: 20,
+            ny: 30,
+            nz: 10,
+            dx: 1.0,
+            dy: 1.1,
+            dz: 1.0,
+            offset_x: 0.5,
+            offset_y: 2.9,
+            offset_z: 0.0,
+            opaque: false,
+            attenuation: 1.0,
+        };
+        let dst_geom = ImageGeometry {
+            ns: 64,
+            nt: 96,
+            ds: 2e-1,
+            dt: 1.5e-1,
+            offset_s: -4.0,
+            offset_t: 2.1,
+        };
+        let dst = LightFieldGeometry {
+            geom: dst_geom.clone(),
+            plane: plane,
+            to_plane: Optics::translation(&40f32),
+        };
+        let to_plane = lens.optics().then(&Optics::translation(&500f32)).invert();
+
+        let mut xport = HostVolumeTransport::new_simple(vg.clone(), dst, to_plane);
+
+        let x = vg.rands();
+        let y = dst_geom.rands();
+        let mut cx = dst_geom.zeros();
+        let mut cty = vg.zeros();
+        xport.forw(&x, &mut cx, 12);
+        xport.back(&y, &mut cty, 12);
+
+        let v1 = cx.iter().zip(y.iter()).fold(0f32, |s, (ui, vi)| s + ui * vi);
+        let v2 = cty.iter().zip(x.iter()).fold(0f32, |s, (ui, vi)| s + ui * vi);
+        let nrmse = (v1 - v2).abs() / v1.abs().max(v2.abs());
+
+        println!("Adjoint NRMSE for HostVolumeTransport: {}", nrmse);
+        assert!(nrmse < 1e-2);
+    }
+}
+
+#[test]
+fn test_host_volume_transport_occlusion() {
+    use angular_plane::*;
+    use geom::*;
+
+    let lens = test_lens();
+    let plane = lens.as_angular_plane(AngularBasis::Pillbox, 5);
+    let ia = 12;
+
+    let vg = LightVolume {
+        nx: 80,
+        ny: 80,
+        nz: 2,
+        dx: 1.0,
+        dy: 1.1,
+        dz: 1.0,
+        offset_x: 0.5,
+        offset_y: 2.9,
+        offset_z: 0.0,
+        opaque: true,
+        attenuation: 2.0,
+    };
+    let dst_geom = ImageGeometry {
+        ns: 64,
+        nt: 96,
+        ds: 2e-1,
+        dt: 1.5e-1,
+        offset_s: -4.0,
+        offset_t: 2.1,
+    };
+    let dst = LightFieldGeometry {
+        geom: dst_geom.clone(),
+        plane: plane,
+        to_plane: Optics::translation(&40f32),
+    };
+    let to_plane = lens.optics().then(&Optics::translation(&500f32)).invert();
+
+    let mut xport = HostVolumeTransport::new_simple(vg.clone(), dst, to_plane);
+
+    // a uniform slice in front of a uniform slice behind it
+    let slice_np = vg.nx * vg.ny;
+    let order = vg.slices_front_to_back();
+    let front_value = 0.5f32;
+    let mut front = vg.zeros();
+    let mut back = vg.zeros();
+    for i in 0..slice_np {
+        front[slice_np * order[0] + i] = front_value;
+        back[slice_np * order[1] + i] = 1f32;
+    }
+    let both: Vec<f32> = front.iter().zip(back.iter()).map(|(f, b)| f + b).collect();
+
+    let mut y_front = dst_geom.zeros();
+    let mut y_back = dst_geom.zeros();
+    let mut y_both = dst_geom.zeros();
+    xport.forw(&front, &mut y_front, ia);
+    xport.forw(&back, &mut y_back, ia);
+    xport.forw(&both, &mut y_both, ia);
+
+    // where rays cross the whole front slice, the back slice is dimmed by
+    // the attenuation along the path through it
+    let y_max = y_front.iter().fold(0f32, |m, &y| m.max(y));
+    let mut checked = 0;
+    for idx in 0..dst_geom.ns * dst_geom.nt {
+        if y_front[idx] < 0.999 * y_max || y_back[idx] <= 0f32 {
+            continue;
+        }
+        let trans = (-vg.attenuation * front_value * vg.dz * xport.obliquity[idx]).exp();
+        let expected = y_front[idx] + trans * y_back[idx];
+        assert!((y_both[idx] - expected).abs() < 1e-3 * expected);
+        assert!(y_both[idx] < y_front[idx] + y_back[idx]);
+        checked += 1;
+    }
+    println!("Checked occlusion at {} pixels", checked);
+    assert!(checked > 0);
+}
+
+#[test]
+fn test_host_volume_transport_linearized_adjoint() {
+    use angular_plane::*;
+    use lens::*;
+    use geom::*;
+
+    let lens = Lens {
+        center_s: 1f64,
+        center_t: -1.5f64,
+        radius_s: 20f64,
+        radius_t: 15f64,
+        focal_length_s: 30f64,
+        focal_length_t: 35f64,
+    };
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let plane = lens.as_angular_plane(basis, 5);
+
+        let vg = LightVolume {
+            nx: 20,
+            ny: 30,
+            nz: 4,
+            dx: 1.0,
+            dy: 1.1,
+            dz: 1.0,
+            offset_x: 0.5,
+            offset_y: 2.9,
+            offset_z: 0.0,
+            opaque: true,
+            attenuation: 0.5,
+        };
+        let dst_geom = ImageGeometry {
+            ns: 64,
+            nt: 96,
+            ds: 2e-1,
+            dt: 1.5e-1,
+            offset_s: -4.0,
+            offset_t: 2.1,
+        };
+        let dst = LightFieldGeometry {
+            geom: dst_geom.clone(),
+            plane: plane,
+            to_plane: Optics::translation(&40f64),
+        };
+        let to_plane = lens.optics().then(&Optics::translation(&500f64)).invert();
+
+        let mut xport = HostVolumeTransport::new_simple(vg.clone(), dst, to_plane);
+
+        let x = vg.rands();
+        let delta: Vec<f64> = vg.rands().iter().map(|d| d - 0.5).collect();
+        let r = dst_geom.rands();
+
+        // directional derivative by central differences
+        let h = 1e-4;
+        let x_plus: Vec<f64> = x.iter().zip(delta.iter()).map(|(a, d)| a + h * d).collect();
+        let x_minus: Vec<f64> = x.iter().zip(delta.iter()).map(|(a, d)| a - h * d).collect();
+        let mut y_plus = dst_geom.zeros();
+        let mut y_minus = dst_geom.zeros();
+        xport.forw(&x_plus, &mut y_plus, 12);
+        xport.forw(&x_minus, &mut y_minus, 12);
+
+        // linearized backprojection about x
+        let mut y = dst_geom.zeros();
+        let mut jtr = vg.zeros();
+        xport.forw(&x, &mut y, 12);
+        xport.back(&r, &mut jtr, 12);
+
+        let v1 = y_plus.iter()
+                       .zip(y_minus.iter())
+                       .zip(r.iter())
+                       .fold(0f64, |s, ((p, m), ri)| s + (p - m) / (2f64 * h) * ri);
+        let v2 = jtr.iter().zip(delta.iter()).fold(0f64, |s, (ji, di)| s + ji * di);
+        let nrmse = (v1 - v2).abs() / v1.abs().max(v2.abs());
+
+        println!("Linearized adjoint NRMSE for opaque HostVolumeTransport: {}", nrmse);
+        assert!(nrmse < 1e-4);
+
+        // the linearized projection is the operator back is the adjoint of
+        let mut jd = dst_geom.zeros();
+        xport.forw_linearized(&delta, &mut jd, 12);
+        let v3 = jd.iter().zip(r.iter()).fold(0f64, |s, (ji, ri)| s + ji * ri);
+        let nrmse = (v3 - v2).abs() / v3.abs().max(v2.abs());
+        println!("Tangent adjoint NRMSE for opaque HostVolumeTransport: {}", nrmse);
+        assert!(nrmse < 1e-10);
+    }
+}
@@ -225,12 +225,12 @@ impl<F: Float + ToPrimitive + FromPrimitive> ClBuffer for ImageGeometry<F> {
     fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
         buf.write_i32::<LittleEndian>(self.ns as i32).unwrap();
         buf.write_i32::<LittleEndian>(self.nt as i32).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.ds).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.dt).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.offset_s).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.offset_t).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.ws()).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.wt()).unwrap()).unwrap();
+        write_real(buf, &self.ds);
+        write_real(buf, &self.dt);
+        write_real(buf, &self.offset_s);
+        write_real(buf, &self.offset_t);
+        write_real(buf, &self.ws());
+        write_real(buf, &self.wt());
     }
 }
 
@@ -1,15 +1,21 @@
-extern crate proust;
 extern crate num;
+extern crate nalgebra;
 use self::num::{FromPrimitive, Float};
-use self::proust::*;
 use geom::*;
 use detector::*;
 use angular_plane::*;
+use backend::*;
+use vector_math::*;
+use std::cmp::min;
 
 /// Abstract type for a camera at a location that can image an object
-pub trait Imager<F, ObjectGeometry>
+///
+/// Buffers, events and queues come from the backend `B`, which defaults to
+/// OpenCL.
+pub trait Imager<F, ObjectGeometry, B = ClBackend>
 where F: Float + FromPrimitive,
-      ObjectGeometry: Geometry<F> {
+      ObjectGeometry: Geometry<F>,
+      B: Backend<F> {
     /// Number of angles in the imager's angular discretization
     fn na(self: &Self) -> usize;
 
@@ -24,86 +30,372 @@ where F: Float + FromPrimitive,
 
     /// Project a single angle out of the discretization
     fn forw_angle(self: &mut Self,
-                  object: &Mem,
-                  view: &mut Mem,
+                  object: &B::Buffer,
+                  view: &mut B::Buffer,
                   ia: usize,
-                  wait_for: &[Event])
-                  -> Result<Event, Error>;
+                  wait_for: &[B::Event])
+                  -> Result<B::Event, B::Error>;
 
     /// Backproject a single angle out of the discretization
     fn back_angle(self: &mut Self,
-                  view: &Mem,
-                  object: &mut Mem,
+                  view: &B::Buffer,
+                  object: &mut B::Buffer,
                   ia: usize,
-                  wait_for: &[Event])
-                  -> Result<Event, Error>;
+                  wait_for: &[B::Event])
+                  -> Result<B::Event, B::Error>;
+
+    /// Project a single angle through the derivative of `forw_angle`
+    ///
+    /// `back_angle` is the adjoint of this operator.  The two differ only
+    /// for objects whose projection is not linear, such as opaque volumes,
+    /// so by default this is `forw_angle`.
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &B::Buffer,
+                             view: &mut B::Buffer,
+                             ia: usize,
+                             wait_for: &[B::Event])
+                             -> Result<B::Event, B::Error> {
+        self.forw_angle(object, view, ia, wait_for)
+    }
 
     /// Project an object stored on the host
     ///
     /// This routine is provided for convenience for non-performant code.
-    fn forw_host(self: &mut Self, object: &[F], queue: &CommandQueue) -> Result<Vec<F>, Error> {
-        let obj = try!(queue.create_buffer_from_slice(object));
-        let det_ig = self.detector().image_geometry();
-        let mut img = try!(det_ig.zeros_buf(queue));
-        let mut img_host = det_ig.zeros();
-        try!(try!(self.forw(&obj, &mut img, &[])).wait());
-        try!(try!(queue.read_buffer(&img, &mut img_host)).wait());
+    fn forw_host(self: &mut Self, object: &[F], queue: &B::Queue) -> Result<Vec<F>, B::Error> {
+        let obj = try!(B::create_buffer(queue, object));
+        let mut img_host = self.detector().image_geometry().zeros();
+        let mut img = try!(B::create_buffer(queue, &img_host));
+        let evt = try!(self.forw(&obj, &mut img, &[]));
+        try!(B::read_buffer(queue, &img, &mut img_host, &[evt]));
         Ok(img_host)
     }
 
     /// Backproject an object stored on the host
     ///
     /// This routine is provided for convenience for non-performant code.
-    fn back_host(self: &mut Self, image: &[F], queue: &CommandQueue) -> Result<Vec<F>, Error> {
-        let img = try!(queue.create_buffer_from_slice(image));
-        let mut obj = try!(self.geometry().zeros_buf(queue));
+    fn back_host(self: &mut Self, image: &[F], queue: &B::Queue) -> Result<Vec<F>, B::Error> {
+        let img = try!(B::create_buffer(queue, image));
         let mut obj_host = self.geometry().zeros();
-        try!(try!(self.back(&img, &mut obj, &[])).wait());
-        try!(try!(queue.read_buffer(&obj, &mut obj_host)).wait());
+        let mut obj = try!(B::create_buffer(queue, &obj_host));
+        let evt = try!(self.back(&img, &mut obj, &[]));
+        try!(B::read_buffer(queue, &obj, &mut obj_host, &[evt]));
         Ok(obj_host)
     }
 
     /// Project all the angles in the discretization
     fn forw(self: &mut Self,
-            object: &Mem,
-            view: &mut Mem,
-            wait_for: &[Event])
-            -> Result<Event, Error> {
+            object: &B::Buffer,
+            view: &mut B::Buffer,
+            wait_for: &[B::Event])
+            -> Result<B::Event, B::Error> {
         let angles: Vec<usize> = (0..self.na()).collect();
         self.forw_subset(object, view, &angles, wait_for)
     }
 
+    /// Project all the angles through the derivative of `forw`
+    fn forw_linearized(self: &mut Self,
+                       object: &B::Buffer,
+                       view: &mut B::Buffer,
+                       wait_for: &[B::Event])
+                       -> Result<B::Event, B::Error> {
+        let mut evt = try!(self.forw_linearized_angle(object, view, 0, wait_for));
+        for ia in 1..self.na() {
+            evt = try!(self.forw_linearized_angle(object, view, ia, &[evt]));
+        }
+        Ok(evt)
+    }
+
     /// Backproject all of the angles in the discretization
-    fn back(&mut self, view: &Mem, object: &mut Mem, wait_for: &[Event]) -> Result<Event, Error> {
+    fn back(&mut self,
+            view: &B::Buffer,
+            object: &mut B::Buffer,
+            wait_for: &[B::Event])
+            -> Result<B::Event, B::Error> {
         let angles: Vec<usize> = (0..self.na()).collect();
         self.back_subset(view, object, &angles, wait_for)
     }
 
     /// Project a subset of the angles in the discretization
+    ///
+    /// Each angle in `angles` is accumulated onto `view` exactly once.  The
+    /// subset must not be empty.
     fn forw_subset(self: &mut Self,
-                   object: &Mem,
-                   view: &mut Mem,
+                   object: &B::Buffer,
+                   view: &mut B::Buffer,
                    angles: &[usize],
-                   wait_for: &[Event])
-                   -> Result<Event, Error> {
-        let mut evt = try!(self.forw_angle(object, view, 0, wait_for));
-        for &ia in angles.iter() {
+                   wait_for: &[B::Event])
+                   -> Result<B::Event, B::Error> {
+        let (&first, rest) = angles.split_first().expect("Empty angle subset");
+        let mut evt = try!(self.forw_angle(object, view, first, wait_for));
+        for &ia in rest.iter() {
             evt = try!(self.forw_angle(object, view, ia, &[evt]));
         }
         Ok(evt)
     }
 
     /// Backproject a subset of the angles in the discretization
+    ///
+    /// Each angle in `angles` is accumulated onto `object` exactly once.  The
+    /// subset must not be empty.
     fn back_subset(self: &mut Self,
-                   view: &Mem,
-                   object: &mut Mem,
+                   view: &B::Buffer,
+                   object: &mut B::Buffer,
                    angles: &[usize],
-                   wait_for: &[Event])
-                   -> Result<Event, Error> {
-        let mut evt = try!(self.back_angle(view, object, 0, wait_for));
-        for &ia in angles.iter() {
+                   wait_for: &[B::Event])
+                   -> Result<B::Event, B::Error> {
+        let (&first, rest) = angles.split_first().expect("Empty angle subset");
+        let mut evt = try!(self.back_angle(view, object, first, wait_for));
+        for &ia in rest.iter() {
             evt = try!(self.back_angle(view, object, ia, &[evt]));
         }
         Ok(evt)
     }
 }
+
+/// Projects angle subsets through partial buffers
+///
+/// `Imager::forw_subset` accumulates every angle onto one buffer, so each
+/// angle waits for the one before it.  A `SubsetProjector` instead deals the
+/// angles round-robin over several lanes, each an imager for the same camera
+/// with its own zeroed partial buffer and event chain.  The first lane is
+/// the imager passed to `forw` and `back`; the others are owned by the
+/// projector.  Imagers keep scratch buffers, so lanes never share an imager,
+/// and lanes created on queues of their own run concurrently wherever the
+/// device allows it.  The partials are then added onto the output in lane
+/// order, so the result does not depend on the order in which lanes finish.
+///
+/// Partials are allocated on first use.
+pub struct SubsetProjector<F, G, B = ClBackend>
+    where F: Float + FromPrimitive,
+          G: Geometry<F>,
+          B: Backend<F>
+{
+    queue: B::Queue,
+    vecmath: B::VectorMath,
+    lanes: Vec<Box<Imager<F, G, B>>>,
+    np_view: usize,
+    np_object: usize,
+    view_partials: Vec<B::Buffer>,
+    object_partials: Vec<B::Buffer>,
+}
+
+impl<F, G, B> SubsetProjector<F, G, B>
+    where F: Float + FromPrimitive,
+          G: Geometry<F>,
+          B: Backend<F>
+{
+    /// Creates a projector for `imager` and the further `lanes`
+    ///
+    /// Every lane must image the same camera with the same angular plane as
+    /// `imager`.  Partials live on, and are reduced on, `queue`.
+    pub fn new<I>(imager: &I,
+                  lanes: Vec<Box<Imager<F, G, B>>>,
+                  queue: B::Queue)
+                  -> Result<Self, B::Error>
+        where I: Imager<F, G, B> + ?Sized
+    {
+        assert!(lanes.iter().all(|l| l.na() == imager.na()),
+                "SubsetProjector lanes must share the imager's angles");
+        let vecmath = try!(B::vector_math(&queue));
+        Ok(SubsetProjector {
+            queue: queue,
+            vecmath: vecmath,
+            lanes: lanes,
+            np_view: imager.detector().image_geometry().dimension(),
+            np_object: imager.geometry().dimension(),
+            view_partials: Vec::new(),
+            object_partials: Vec::new(),
+        })
+    }
+
+    /// Number of lanes, counting the imager passed to `forw` and `back`
+    pub fn num_lanes(self: &Self) -> usize {
+        self.lanes.len() + 1
+    }
+
+    /// Accumulates the projection of `angles` onto `view`
+    pub fn forw(self: &mut Self,
+                imager: &mut Imager<F, G, B>,
+                object: &B::Buffer,
+                view: &mut B::Buffer,
+                angles: &[usize],
+                wait_for: &[B::Event])
+                -> Result<B::Event, B::Error> {
+        let num_used = self.num_used(angles);
+        while self.view_partials.len() < num_used {
+            let buf = try!(B::create_buffer(&self.queue, &vec![F::zero(); self.np_view]));
+            self.view_partials.push(buf);
+        }
+
+        let mut chains = Vec::new();
+        for (ip, partial) in self.view_partials[..num_used].iter_mut().enumerate() {
+            let lane: &mut Imager<F, G, B> = if ip == 0 {
+                imager
+            } else {
+                &mut *self.lanes[ip - 1]
+            };
+            let mut evt = try!(self.vecmath.set(self.np_view, partial, F::zero(), wait_for));
+            for (_, &ia) in angles.iter().enumerate().filter(|&(i, _)| i % num_used == ip) {
+                evt = try!(lane.forw_angle(object, partial, ia, &[evt]));
+            }
+            chains.push(evt);
+        }
+
+        reduce_partials::<F, B>(&mut self.vecmath,
+                                self.np_view,
+                                &self.view_partials[..num_used],
+                                &chains,
+                                view,
+                                wait_for)
+    }
+
+    /// Accumulates the backprojection of `angles` onto `object`
+    pub fn back(self: &mut Self,
+                imager: &mut Imager<F, G, B>,
+                view: &B::Buffer,
+                object: &mut B::Buffer,
+                angles: &[usize],
+                wait_for: &[B::Event])
+                -> Result<B::Event, B::Error> {
+        let num_used = self.num_used(angles);
+        while self.object_partials.len() < num_used {
+            let buf = try!(B::create_buffer(&self.queue, &vec![F::zero(); self.np_object]));
+            self.object_partials.push(buf);
+        }
+
+        let mut chains = Vec::new();
+        for (ip, partial) in self.object_partials[..num_used].iter_mut().enumerate() {
+            let lane: &mut Imager<F, G, B> = if ip == 0 {
+                imager
+            } else {
+                &mut *self.lanes[ip - 1]
+            };
+            let mut evt = try!(self.vecmath.set(self.np_object, partial, F::zero(), wait_for));
+            for (_, &ia) in angles.iter().enumerate().filter(|&(i, _)| i % num_used == ip) {
+                evt = try!(lane.back_angle(view, partial, ia, &[evt]));
+            }
+            chains.push(evt);
+        }
+
+        reduce_partials::<F, B>(&mut self.vecmath,
+                                self.np_object,
+                                &self.object_partials[..num_used],
+                                &chains,
+                                object,
+                                wait_for)
+    }
+
+    fn num_used(self: &Self, angles: &[usize]) -> usize {
+        assert!(angles.len() > 0, "Empty angle subset");
+        min(self.num_lanes(), angles.len())
+    }
+}
+
+/// Adds each partial onto `out` in order, once its chain has finished
+fn reduce_partials<F, B>(vecmath: &mut B::VectorMath,
+                         np: usize,
+                         partials: &[B::Buffer],
+                         chains: &[B::Event],
+                         out: &mut B::Buffer,
+                         wait_for: &[B::Event])
+                         -> Result<B::Event, B::Error>
+    where F: Float,
+          B: Backend<F>
+{
+    let mut wait = wait_for.to_vec();
+    for (partial, chain) in partials.iter().zip(chains.iter()) {
+        wait.push(chain.clone());
+        let evt = try!(vecmath.mix_inplace(np, partial, F::one(), F::one(), out, &wait));
+        wait = vec![evt];
+    }
+    Ok(wait.pop().unwrap())
+}
+
+#[cfg(test)]
+fn test_imager() -> Box<Imager<f32, ::light_volume::LightVolume<f32>, HostBackend>> {
+    use self::nalgebra::Vector3;
+    use single_lens_imager::*;
+
+    Box::new(HostSingleLensVolumeImager::new(test_volume(),
+                                             test_camera(),
+                                             Vector3::new(0f32, 0f32, -100f32),
+                                             5,
+                                             AngularBasis::Pillbox))
+}
+
+#[cfg(test)]
+fn assert_close(a: &[f32], b: &[f32]) {
+    let num = a.iter().zip(b.iter()).fold(0f32, |s, (x, y)| s + (x - y) * (x - y));
+    let den = a.iter().fold(0f32, |s, x| s + x * x);
+    assert!(den > 0f32);
+    assert!((num / den).sqrt() < 1e-5);
+}
+
+#[test]
+fn test_subset_sums_match_full() {
+    let mut imager = test_imager();
+    let vg = imager.geometry().clone();
+    let ig = imager.detector().image_geometry();
+    let x = vg.rands();
+    let y = ig.rands();
+
+    let mut full_view = ig.zeros();
+    imager.forw(&x, &mut full_view, &[]).unwrap();
+    let mut full_object = vg.zeros();
+    imager.back(&y, &mut full_object, &[]).unwrap();
+
+    // subsets partitioning the angles add up to the full projection
+    let subsets = imager.angular_plane().subsets_strided(2);
+    let mut subset_view = ig.zeros();
+    let mut subset_object = vg.zeros();
+    for angles in subsets.iter() {
+        imager.forw_subset(&x, &mut subset_view, angles, &[]).unwrap();
+        imager.back_subset(&y, &mut subset_object, angles, &[]).unwrap();
+    }
+    assert_close(&full_view, &subset_view);
+    assert_close(&full_object, &subset_object);
+
+    // a single-angle subset is just that angle
+    let mut angle_view = ig.zeros();
+    let mut single_view = ig.zeros();
+    imager.forw_angle(&x, &mut angle_view, 3, &[]).unwrap();
+    imager.forw_subset(&x, &mut single_view, &[3], &[]).unwrap();
+    assert_eq!(angle_view, single_view);
+}
+
+#[test]
+fn test_subset_projector() {
+    let mut imager = test_imager();
+    let vg = imager.geometry().clone();
+    let ig = imager.detector().image_geometry();
+    let x = vg.rands();
+    let y = ig.rands();
+    let angles = [4, 0, 2, 3];
+
+    let mut chained_view = ig.zeros();
+    imager.forw_subset(&x, &mut chained_view, &angles, &[]).unwrap();
+    let mut chained_object = vg.zeros();
+    imager.back_subset(&y, &mut chained_object, &angles, &[]).unwrap();
+
+    // three lanes, each with an imager (and scratch) of its own
+    let lanes = vec![test_imager(), test_imager()];
+    let mut projector = SubsetProjector::new(&*imager, lanes, HostQueue).unwrap();
+    assert_eq!(projector.num_lanes(), 3);
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        // projections accumulate onto existing contents
+        let mut view = ig.ones();
+        projector.forw(&mut *imager, &x, &mut view, &angles, &[]).unwrap();
+        for v in view.iter_mut() {
+            *v -= 1f32;
+        }
+        let mut object = vg.zeros();
+        projector.back(&mut *imager, &y, &mut object, &angles, &[]).unwrap();
+        assert_close(&chained_view, &view);
+        assert_close(&chained_object, &object);
+        runs.push((view, object));
+    }
+
+    // reduction order is fixed, so repeated runs agree exactly
+    assert_eq!(runs[0], runs[1]);
+}
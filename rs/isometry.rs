@@ -1,13 +1,12 @@
 extern crate num;
 extern crate toml;
 extern crate nalgebra;
-extern crate byteorder;
 use cl_traits::*;
 use serialize::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::toml::*;
 use self::nalgebra::{Isometry3, Vector3, Rotation3, Matrix3, BaseFloat, inverse, ApproxEq};
-use self::byteorder::*;
+use std::mem::size_of;
 
 /// Isometry for placing objects in space
 pub type Isometry<F> = Isometry3<F>;
@@ -243,7 +242,7 @@ impl<F> ClHeader for Isometry<F> {
 impl<F: ToPrimitive> ClBuffer for Isometry<F> {
     fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
         // NOTE!
-        // According to the OpenCL spec, float3 types (which is how we
+        // According to the OpenCL spec, real3 types (which is how we
         // define Isometry in `isometry_f32.opencl`) are stored as 4-element
         // vectors.  When packing data for OpenCL in this function, we
         // insert those extra spaces.
@@ -251,26 +250,26 @@ impl<F: ToPrimitive> ClBuffer for Isometry<F> {
         // https://www.khronos.org/registry/cl/sdk/1.2/docs/man/xhtml/dataTypes.html
 
         let rot = &self.rotation.submatrix();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m11).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m21).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m31).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(0f32).unwrap();
+        write_real(buf, &rot.m11);
+        write_real(buf, &rot.m21);
+        write_real(buf, &rot.m31);
+        buf.extend(vec![0u8; size_of::<F>()]);
 
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m12).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m22).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m32).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(0f32).unwrap();
+        write_real(buf, &rot.m12);
+        write_real(buf, &rot.m22);
+        write_real(buf, &rot.m32);
+        buf.extend(vec![0u8; size_of::<F>()]);
 
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m13).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m23).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&rot.m33).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(0f32).unwrap();
+        write_real(buf, &rot.m13);
+        write_real(buf, &rot.m23);
+        write_real(buf, &rot.m33);
+        buf.extend(vec![0u8; size_of::<F>()]);
 
         let xl = &self.translation;
-        buf.write_f32::<LittleEndian>(F::to_f32(&xl.x).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&xl.y).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&xl.z).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(0f32).unwrap();
+        write_real(buf, &xl.x);
+        write_real(buf, &xl.y);
+        write_real(buf, &xl.z);
+        buf.extend(vec![0u8; size_of::<F>()]);
     }
 }
 
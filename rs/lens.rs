@@ -7,8 +7,12 @@ use self::toml::*;
 use image_geom::*;
 use occluder::*;
 use bounding_geometry::*;
+use lens_prescription::*;
 
 /// Ideal thin lens
+///
+/// Tables describing a `LensPrescription` also load as a `Lens`; see
+/// `LensPrescription::thin_lens`.
 #[derive(Clone, Debug)]
 pub struct Lens<F: Float> {
     pub center_s: F,
@@ -349,7 +353,7 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for Lens<F> {
                     focal_length_t: F::from_f64(focal_length_t).unwrap(),
                 })
             }
-            _ => None,
+            _ => LensPrescription::from_map(map).map(|p| p.thin_lens()),
         }
     }
 
@@ -14,6 +14,99 @@ use optics::*;
 use vector_math::*;
 use std::cmp::min;
 use image_geom::ImageGeometry;
+use host_transport::*;
+use angular_plane::*;
+use spline_kernel::*;
+use cl_traits::*;
+use program_cache::*;
+use profiler::*;
+
+/// Placement of one microlens in a `LensArray`
+struct LensLayout<F: Float> {
+    /// Light field geometry on the detector behind the lens
+    detector_lfg: LightFieldGeometry<F>,
+
+    /// Pixels on the array plane covered by the lens
+    array_bounds: TransportBounds,
+
+    /// Pixels on the detector the lens can illuminate
+    detector_bounds: TransportBounds,
+}
+
+/// Rasterizes the microlens array mask and lays out each lens' transport
+fn lens_array_layout<F>(array_lfg: &LightFieldGeometry<F>,
+                        detector: &Detector<F>,
+                        distance_detector_array: F,
+                        lenses: &[Lens<F>])
+                        -> (Vec<F>, Vec<LensLayout<F>>)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let array_geometry = &array_lfg.geom;
+
+    let mut lens_mask = array_geometry.zeros();
+    let mut layouts = Vec::new();
+    for lens in lenses.iter() {
+        // get the pixels on the ulens plane that this lens touches
+        let lens_geom = lens.bounding_geometry(1, 1);
+        let (s0, s1, t0, t1) = lens_geom.spatial_bounds();
+        let (is0, is1, it0, it1) = array_geometry.region_pixels(s0, s1, t0, t1);
+
+        if is1 == is0 || it1 == it0 {
+            continue;
+        }
+
+        // update mask
+        for it in it0..it1 {
+            for is in is0..is1 {
+                let (ss0, ss1, tt0, tt1) = array_geometry.pixel_bounds(is, it);
+                let mask_val = F::one() - lens.rasterize(ss0, ss1, tt0, tt1, 10); // TODO magic number
+                let mask_index = is + array_geometry.ns * it;
+
+                // in case over overlap between two lenses, use the maximum
+                // mask value
+                let current_mask_value = lens_mask[mask_index];
+                if current_mask_value < mask_val {
+                    lens_mask[mask_index] = mask_val;
+                }
+            }
+        }
+
+        // light field geometry on the detector behind this lens
+        let lens_detector_lfg = LightFieldGeometry {
+            geom: detector.image_geometry(),
+            plane: array_lfg.plane.clone(),
+            to_plane: Optics::translation(&distance_detector_array)
+                          .then(&lens.optics())
+                          .then(&array_lfg.to_plane),
+        };
+
+        // TODO use a less magical dilation
+        let dilation_s = (is1 - is0) / 2;
+        let dilation_t = (it1 - it0) / 2;
+
+        let ds0 = if is0 < dilation_s {
+            0
+        } else {
+            is0 - dilation_s
+        };
+        let ds1 = min(is1 + dilation_s, array_geometry.ns);
+
+        let dt0 = if it0 < dilation_t {
+            0
+        } else {
+            it0 - dilation_t
+        };
+        let dt1 = min(it1 + dilation_t, array_geometry.nt);
+
+        layouts.push(LensLayout {
+            detector_lfg: lens_detector_lfg,
+            array_bounds: (is0, is1, it0, it1),
+            detector_bounds: (ds0, ds1, dt0, dt1),
+        });
+    }
+
+    (lens_mask, layouts)
+}
 
 /// Microlens array operation
 ///
@@ -67,67 +160,18 @@ impl<F: Float + FromPrimitive + ToPrimitive> LensArray<F> {
                queue: CommandQueue)
                -> Result<Self, Error> {
         let array_geometry = &array_lfg.geom;
+        let (lens_mask, layouts) = lens_array_layout(&array_lfg,
+                                                     &detector,
+                                                     distance_detector_array,
+                                                     lenses);
 
-        // create ulens array mask and transports for each ulens array
-        let mut lens_mask = array_geometry.zeros();
+        // create transports for each ulens
         let mut xports = Vec::new();
-        for lens in lenses.iter() {
-            // get the pixels on the ulens plane that this lens touches
-            let lens_geom = lens.bounding_geometry(1, 1);
-            let (s0, s1, t0, t1) = lens_geom.spatial_bounds();
-            let (is0, is1, it0, it1) = array_geometry.region_pixels(s0, s1, t0, t1);
-
-            if is1 == is0 || it1 == it0 {
-                continue;
-            }
-
-            // update mask
-            for it in it0..it1 {
-                for is in is0..is1 {
-                    let (ss0, ss1, tt0, tt1) = array_geometry.pixel_bounds(is, it);
-                    let mask_val = F::one() - lens.rasterize(ss0, ss1, tt0, tt1, 10); // TODO magic number
-                    let mask_index = is + array_geometry.ns * it;
-
-                    // in case over overlap between two lenses, use the maximum
-                    // mask value
-                    let current_mask_value = lens_mask[mask_index];
-                    if current_mask_value < mask_val {
-                        lens_mask[mask_index] = mask_val;
-                    }
-                }
-            }
-
-            // light field geometry on the detector behind this lens
-            let lens_detector_lfg = LightFieldGeometry {
-                geom: detector.image_geometry(),
-                plane: array_lfg.plane.clone(),
-                to_plane: Optics::translation(&distance_detector_array)
-                              .then(&lens.optics())
-                              .then(&array_lfg.to_plane),
-            };
-
-            // TODO use a less magical dilation
-            let dilation_s = (is1 - is0) / 2;
-            let dilation_t = (it1 - it0) / 2;
-
-            let ds0 = if is0 < dilation_s {
-                0
-            } else {
-                is0 - dilation_s
-            };
-            let ds1 = min(is1 + dilation_s, array_geometry.ns);
-
-            let dt0 = if it0 < dilation_t {
-                0
-            } else {
-                it0 - dilation_t
-            };
-            let dt1 = min(it1 + dilation_t, array_geometry.nt);
-
+        for layout in layouts.into_iter() {
             let xport = try!(Transport::new(array_lfg.clone(),
-                                            lens_detector_lfg,
-                                            Some((is0, is1, it0, it1)), // source bounds
-                                            Some((ds0, ds1, dt0, dt1)), // destination bounds
+                                            layout.detector_lfg,
+                                            Some(layout.array_bounds), // source bounds
+                                            Some(layout.detector_bounds), // destination bounds
                                             false, // overwrite forw
                                             true, // overwrite back
                                             true, // conservative forw,
@@ -191,3 +235,312 @@ impl<F: Float + FromPrimitive + ToPrimitive> LensArray<F> {
         self.mask.apply_mask(view, &evts)
     }
 }
+
+/// Returns the number of pixels of `geom` covered by each bounds, as
+/// row-compressed per-pixel lists of lens indices
+fn lens_lists<F: Float>(geom: &ImageGeometry<F>,
+                        bounds: &[TransportBounds])
+                        -> (Vec<i32>, Vec<i32>) {
+    let np = geom.ns * geom.nt;
+    let mut ptr = vec![0i32; np + 1];
+    for b in bounds.iter() {
+        for it in b.2..b.3 {
+            for is in b.0..b.1 {
+                ptr[is + geom.ns * it + 1] += 1;
+            }
+        }
+    }
+    for ip in 0..np {
+        ptr[ip + 1] += ptr[ip];
+    }
+
+    let mut fill: Vec<i32> = ptr[..np].to_owned();
+    let mut ids = vec![0i32; ptr[np] as usize];
+    for (lens, b) in bounds.iter().enumerate() {
+        for it in b.2..b.3 {
+            for is in b.0..b.1 {
+                let ip = is + geom.ns * it;
+                ids[fill[ip] as usize] = lens as i32;
+                fill[ip] += 1;
+            }
+        }
+    }
+
+    (ptr, ids)
+}
+
+/// Microlens array operation with all lenses batched into one launch
+///
+/// Computes the same operator as `LensArray`, but precomputes every lens'
+/// footprints and dispatches a single kernel per angle instead of two per
+/// microlens per angle.
+pub struct BatchedLensArray<F: Float + FromPrimitive> {
+    array_geom: ImageGeometry<F>,
+    detector_geom: ImageGeometry<F>,
+    na: usize,
+
+    queue: CommandQueue,
+    forw_kernel: Kernel,
+    back_kernel: Kernel,
+
+    array_geom_buf: Mem,
+    detector_geom_buf: Mem,
+    mask: Mem,
+
+    forw_lens_ptr: Mem, // [i32]*(detector pixels + 1)
+    forw_lens_ids: Mem,
+    back_lens_ptr: Mem, // [i32]*(array pixels + 1)
+    back_lens_ids: Mem,
+
+    array_bounds: Mem, // [i32; 4]*nlens
+    detector_bounds: Mem, // [i32; 4]*nlens
+
+    forw_ks: Mem, // [SplineKernel]*nlens*na
+    forw_kt: Mem, // [SplineKernel]*nlens*na
+    back_ks: Mem, // [SplineKernel]*nlens*na
+    back_kt: Mem, // [SplineKernel]*nlens*na
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> BatchedLensArray<F> {
+    pub fn new(array_lfg: LightFieldGeometry<F>,
+               detector: Detector<F>,
+               distance_detector_array: F,
+               lenses: &[Lens<F>],
+               queue: CommandQueue)
+               -> Result<Self, Error> {
+        let (lens_mask, layouts) = lens_array_layout(&array_lfg,
+                                                     &detector,
+                                                     distance_detector_array,
+                                                     lenses);
+        let na = array_lfg.plane.na();
+        let array_geom = array_lfg.geom.clone();
+        let detector_geom = detector.image_geometry();
+
+        // collect opencl sources
+        let prelude = match &array_lfg.plane.basis {
+            &AngularBasis::Dirac => {
+                "#define LensKernel struct RectSplineKernel\n\
+                 #define LensKernel_integrate RectSplineKernel_integrate\n\
+                 #define LensKernel_last_tau(k) ((k)->tau1)\n"
+            }
+            &AngularBasis::Pillbox => {
+                "#define LensKernel struct TrapezoidSplineKernel\n\
+                 #define LensKernel_integrate TrapezoidSplineKernel_integrate\n\
+                 #define LensKernel_last_tau(k) ((k)->tau3)\n"
+            }
+            &AngularBasis::Linear => {
+                "#define LensKernel struct QuadSplineKernel\n\
+                 #define LensKernel_integrate QuadSplineKernel_integrate\n\
+                 #define LensKernel_last_tau(k) ((k)->tau7)\n"
+            }
+        };
+        let sources = &[ImageGeometry::<F>::header(),
+                        SplineKernel::<F>::header(),
+                        prelude,
+                        include_str!("../cl/lens_array_f32.opencl")];
+
+        let program = try!(cached_program::<F>(&queue, sources));
+        let forw_kernel = try!(program.create_kernel("LensArray_forw"));
+        let back_kernel = try!(program.create_kernel("LensArray_back"));
+
+        // per-pixel lens lists
+        let array_bounds: Vec<TransportBounds> = layouts.iter().map(|l| l.array_bounds).collect();
+        let detector_bounds: Vec<TransportBounds> = layouts.iter()
+                                                           .map(|l| l.detector_bounds)
+                                                           .collect();
+        let (forw_ptr, forw_ids) = lens_lists(&detector_geom, &detector_bounds);
+        let (back_ptr, back_ids) = lens_lists(&array_geom, &array_bounds);
+
+        let flatten = |bounds: &[TransportBounds]| -> Vec<i32> {
+            bounds.iter()
+                  .flat_map(|b| vec![b.0 as i32, b.1 as i32, b.2 as i32, b.3 as i32])
+                  .collect()
+        };
+
+        // precompute footprints for every lens and angle
+        let mut forw_ks_buf: Vec<u8> = Vec::new();
+        let mut forw_kt_buf: Vec<u8> = Vec::new();
+        let mut back_ks_buf: Vec<u8> = Vec::new();
+        let mut back_kt_buf: Vec<u8> = Vec::new();
+        for layout in layouts.iter() {
+            for ia in 0..na {
+                let (forw_s, forw_t) =
+                    transport_footprints(&array_lfg, &layout.detector_lfg, ia, true, true);
+                let (back_s, back_t) =
+                    transport_footprints(&array_lfg, &layout.detector_lfg, ia, false, true);
+                forw_s.as_cl_bytes(&mut forw_ks_buf);
+                forw_t.as_cl_bytes(&mut forw_kt_buf);
+                back_s.as_cl_bytes(&mut back_ks_buf);
+                back_t.as_cl_bytes(&mut back_kt_buf);
+            }
+        }
+
+        // OpenCL rejects zero-sized buffers
+        let ids_or_dummy = |ids: Vec<i32>| if ids.len() == 0 { vec![0i32] } else { ids };
+
+        Ok(BatchedLensArray {
+            array_geom_buf: try!(array_geom.as_cl_buffer(&queue)),
+            detector_geom_buf: try!(detector_geom.as_cl_buffer(&queue)),
+            mask: try!(queue.create_buffer_from_slice(&lens_mask)),
+
+            forw_lens_ptr: try!(queue.create_buffer_from_slice(&forw_ptr)),
+            forw_lens_ids: try!(queue.create_buffer_from_slice(&ids_or_dummy(forw_ids))),
+            back_lens_ptr: try!(queue.create_buffer_from_slice(&back_ptr)),
+            back_lens_ids: try!(queue.create_buffer_from_slice(&ids_or_dummy(back_ids))),
+
+            array_bounds: try!(queue.create_buffer_from_slice(&ids_or_dummy(flatten(&array_bounds)))),
+            detector_bounds: try!(queue.create_buffer_from_slice(&ids_or_dummy(flatten(&detector_bounds)))),
+
+            forw_ks: try!(queue.create_buffer_from_slice(&forw_ks_buf)),
+            forw_kt: try!(queue.create_buffer_from_slice(&forw_kt_buf)),
+            back_ks: try!(queue.create_buffer_from_slice(&back_ks_buf)),
+            back_kt: try!(queue.create_buffer_from_slice(&back_kt_buf)),
+
+            array_geom: array_geom,
+            detector_geom: detector_geom,
+            na: na,
+
+            queue: queue,
+            forw_kernel: forw_kernel,
+            back_kernel: back_kernel,
+        })
+    }
+
+    /// Transport from the array plane to the detector, accumulating onto the
+    /// detector
+    pub fn forw(self: &mut Self,
+                view: &Mem,
+                det: &mut Mem,
+                ia: usize,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        try!(self.forw_kernel.bind(0, &self.array_geom_buf));
+        try!(self.forw_kernel.bind(1, &self.detector_geom_buf));
+        try!(self.forw_kernel.bind_scalar(2, &(self.na as i32)));
+        try!(self.forw_kernel.bind_scalar(3, &(ia as i32)));
+        try!(self.forw_kernel.bind(4, &self.forw_lens_ptr));
+        try!(self.forw_kernel.bind(5, &self.forw_lens_ids));
+        try!(self.forw_kernel.bind(6, &self.array_bounds));
+        try!(self.forw_kernel.bind(7, &self.forw_ks));
+        try!(self.forw_kernel.bind(8, &self.forw_kt));
+        try!(self.forw_kernel.bind(9, &self.mask));
+        try!(self.forw_kernel.bind(10, view));
+        try!(self.forw_kernel.bind_mut(11, det));
+
+        let local_size = (32, 8, 1);
+        let global_size = (self.detector_geom.ns, self.detector_geom.nt, 1);
+
+        run_kernel(&self.queue,
+                   &mut self.forw_kernel,
+                   "LensArray_forw",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+
+    /// Transport from the detector to the array plane, overwriting the array
+    /// plane
+    pub fn back(self: &mut Self,
+                det: &Mem,
+                view: &mut Mem,
+                ia: usize,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        try!(self.back_kernel.bind(0, &self.detector_geom_buf));
+        try!(self.back_kernel.bind(1, &self.array_geom_buf));
+        try!(self.back_kernel.bind_scalar(2, &(self.na as i32)));
+        try!(self.back_kernel.bind_scalar(3, &(ia as i32)));
+        try!(self.back_kernel.bind(4, &self.back_lens_ptr));
+        try!(self.back_kernel.bind(5, &self.back_lens_ids));
+        try!(self.back_kernel.bind(6, &self.detector_bounds));
+        try!(self.back_kernel.bind(7, &self.back_ks));
+        try!(self.back_kernel.bind(8, &self.back_kt));
+        try!(self.back_kernel.bind(9, &self.mask));
+        try!(self.back_kernel.bind(10, det));
+        try!(self.back_kernel.bind_mut(11, view));
+
+        let local_size = (32, 8, 1);
+        let global_size = (self.array_geom.ns, self.array_geom.nt, 1);
+
+        run_kernel(&self.queue,
+                   &mut self.back_kernel,
+                   "LensArray_back",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+}
+
+#[test]
+fn test_batched_lens_array_matches_per_lens() {
+    use env::*;
+    use serialize::*;
+    use scene::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let lenses: Vec<Lens<f32>> =
+        Vec::<Lens<f32>>::from_map(&table_from_file("cfg/lenses/test_array.toml").unwrap())
+            .unwrap();
+    let detector = Detector {
+        ns: 128,
+        nt: 128,
+        ds: 0.05,
+        dt: 0.05,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+    let main_lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 10f32,
+        radius_t: 10f32,
+        focal_length_s: 50f32,
+        focal_length_t: 50f32,
+    };
+
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let array_lfg = LightFieldGeometry {
+            geom: detector.image_geometry(),
+            plane: main_lens.as_angular_plane(basis, 3),
+            to_plane: Optics::translation(&60f32),
+        };
+        let mut per_lens = LensArray::new(array_lfg.clone(),
+                                          detector.clone(),
+                                          2f32,
+                                          &lenses,
+                                          queue.clone())
+                               .unwrap();
+        let mut batched = BatchedLensArray::new(array_lfg.clone(),
+                                                detector.clone(),
+                                                2f32,
+                                                &lenses,
+                                                queue.clone())
+                              .unwrap();
+
+        let ig = detector.image_geometry();
+        let x = queue.create_buffer_from_slice(&ig.rands()).unwrap();
+        for ia in 0..array_lfg.plane.na() {
+            let mut y0 = ig.zeros_buf(queue).unwrap();
+            let mut y1 = ig.zeros_buf(queue).unwrap();
+            per_lens.forw(&x, &mut y0, ia, &[]).unwrap().wait().unwrap();
+            batched.forw(&x, &mut y1, ia, &[]).unwrap().wait().unwrap();
+
+            let mut b0 = ig.zeros_buf(queue).unwrap();
+            let mut b1 = ig.zeros_buf(queue).unwrap();
+            per_lens.back(&y0, &mut b0, ia, &[]).unwrap().wait().unwrap();
+            batched.back(&y0, &mut b1, ia, &[]).unwrap().wait().unwrap();
+
+            for &(ref u, ref v) in [(&y0, &y1), (&b0, &b1)].iter() {
+                let mut uh = ig.zeros();
+                let mut vh = ig.zeros();
+                queue.read_buffer(u, &mut uh).unwrap().wait().unwrap();
+                queue.read_buffer(v, &mut vh).unwrap().wait().unwrap();
+                let num = uh.iter().zip(vh.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b));
+                let den = uh.iter().fold(0f32, |s, a| s + a * a);
+                assert!(num <= 1e-8 * den);
+            }
+        }
+    }
+}
@@ -0,0 +1,334 @@
+extern crate num;
+extern crate toml;
+use serialize::*;
+use optics::*;
+use lens::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
+use image_geom::*;
+use occluder::*;
+use bounding_geometry::*;
+
+/// One spherical refracting surface of a lens prescription
+#[derive(Clone, Debug)]
+pub struct LensSurface<F: Float> {
+    /// Curvature (reciprocal of the radius); positive when the center of
+    /// curvature is behind the surface, zero for flat surfaces
+    pub curvature: F,
+
+    /// Distance to the next surface
+    pub thickness: F,
+
+    /// Refractive index of the medium behind the surface
+    pub index: F,
+
+    /// Radius of the clear aperture
+    pub aperture: F,
+}
+
+/// Thick or multi-element lens described by its surfaces
+///
+/// Surfaces are listed in the order light crosses them, starting in air; the
+/// medium behind the last surface must be air again.  Everything is
+/// paraxial: the prescription behaves like a thin lens with the effective
+/// focal length placed at the principal planes, so distances to a camera's
+/// main lens are measured from the principal planes when a prescription is
+/// used in its place.
+#[derive(Clone, Debug)]
+pub struct LensPrescription<F: Float> {
+    pub center_s: F,
+    pub center_t: F,
+    pub surfaces: Vec<LensSurface<F>>,
+}
+
+impl<F: Float + FromPrimitive> LensPrescription<F> {
+    /// Returns the optical transformation from the first to the last vertex
+    pub fn vertex_optics(self: &Self) -> Optics<F> {
+        let mut tr = Optics::identity();
+        let mut n = F::one();
+        for (ix, surface) in self.surfaces.iter().enumerate() {
+            // n' u' = n u - (n' - n) c y
+            let power = (surface.index - n) * surface.curvature;
+            let mut refraction = Optics::identity();
+            refraction.us = -power / surface.index;
+            refraction.uu = n / surface.index;
+            refraction.vt = refraction.us;
+            refraction.vv = refraction.uu;
+            tr = tr.then(&refraction);
+
+            if ix + 1 < self.surfaces.len() {
+                tr = tr.then(&Optics::translation(&surface.thickness));
+            }
+            n = surface.index;
+        }
+        tr
+    }
+
+    /// Returns the effective focal length
+    pub fn effective_focal_length(self: &Self) -> F {
+        -F::one() / self.vertex_optics().us
+    }
+
+    /// Returns the positions of the front and back principal planes
+    ///
+    /// The front principal plane is measured from the first vertex and the
+    /// back principal plane from the last vertex, both positive in the
+    /// direction light travels.
+    pub fn principal_planes(self: &Self) -> (F, F) {
+        let optics = self.vertex_optics();
+        let f = -F::one() / optics.us;
+
+        // translation(front), then lens(f), then translation(-back)
+        let front = f * (F::one() - optics.uu);
+        let back = f * (optics.ss - F::one());
+        (front, back)
+    }
+
+    /// Returns the index of the surface acting as the aperture stop
+    ///
+    /// This is the surface whose clear aperture is the first to clip a
+    /// bundle of rays parallel to the axis.
+    pub fn aperture_stop(self: &Self) -> usize {
+        self.stop().0
+    }
+
+    /// Returns the radius of the entrance pupil for objects at infinity
+    pub fn entrance_pupil_radius(self: &Self) -> F {
+        self.stop().1
+    }
+
+    fn stop(self: &Self) -> (usize, F) {
+        let mut stop = (0, F::infinity());
+        let mut n = F::one();
+        let mut y = F::one();
+        let mut u = F::zero();
+        for (ix, surface) in self.surfaces.iter().enumerate() {
+            if y != F::zero() {
+                let r = (surface.aperture / y).abs();
+                if r < stop.1 {
+                    stop = (ix, r);
+                }
+            }
+
+            let power = (surface.index - n) * surface.curvature;
+            u = (n * u - power * y) / surface.index;
+            y = y + surface.thickness * u;
+            n = surface.index;
+        }
+        stop
+    }
+
+    /// Returns the equivalent ideal thin lens
+    ///
+    /// The thin lens has the effective focal length and the entrance pupil
+    /// as its aperture.
+    pub fn thin_lens(self: &Self) -> Lens<F> {
+        let f = self.effective_focal_length();
+        let r = self.entrance_pupil_radius();
+        Lens {
+            center_s: self.center_s,
+            center_t: self.center_t,
+            radius_s: r,
+            radius_t: r,
+            focal_length_s: f,
+            focal_length_t: f,
+        }
+    }
+}
+
+impl<F: Float + FromPrimitive> BoundingGeometry<F> for LensPrescription<F> {
+    fn bounding_geometry(self: &Self, ns: usize, nt: usize) -> ImageGeometry<F> {
+        self.thin_lens().bounding_geometry(ns, nt)
+    }
+}
+
+impl<F: Float + FromPrimitive> Occluder<F> for LensPrescription<F> {
+    /// The aperture stop, imaged into the plane of the equivalent thin lens
+    fn occludes(self: &Self, s: F, t: F) -> bool {
+        self.thin_lens().occludes(s, t)
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LensSurface<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let curvature = map.get("curvature");
+        let thickness = map.get("thickness");
+        let index = map.get("index");
+        let aperture = map.get("aperture");
+
+        match (curvature, thickness, index, aperture) {
+            (Some(&Value::Float(curvature)),
+             Some(&Value::Float(thickness)),
+             Some(&Value::Float(index)),
+             Some(&Value::Float(aperture))) => {
+                Some(LensSurface {
+                    curvature: F::from_f64(curvature).unwrap(),
+                    thickness: F::from_f64(thickness).unwrap(),
+                    index: F::from_f64(index).unwrap(),
+                    aperture: F::from_f64(aperture).unwrap(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        tr.insert("curvature".to_string(),
+                  Value::Float(F::to_f64(&self.curvature).unwrap()));
+        tr.insert("thickness".to_string(),
+                  Value::Float(F::to_f64(&self.thickness).unwrap()));
+        tr.insert("index".to_string(),
+                  Value::Float(F::to_f64(&self.index).unwrap()));
+        tr.insert("aperture".to_string(),
+                  Value::Float(F::to_f64(&self.aperture).unwrap()));
+        tr
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LensPrescription<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let center_s = map.get("center_s");
+        let center_t = map.get("center_t");
+        let surfaces = map.get("surfaces");
+
+        match (center_s, center_t, surfaces) {
+            (Some(&Value::Float(center_s)),
+             Some(&Value::Float(center_t)),
+             Some(&Value::Array(ref arr))) => {
+                let mut surfaces = Vec::new();
+                for it in arr.iter() {
+                    match it {
+                        &Value::Table(ref tab) => {
+                            match LensSurface::from_map(tab) {
+                                Some(surface) => surfaces.push(surface),
+                                None => return None,
+                            }
+                        }
+                        _ => return None,
+                    }
+                }
+
+                // light has to leave the lens in air
+                match surfaces.last() {
+                    Some(last) if last.index == F::one() => (),
+                    _ => return None,
+                }
+
+                Some(LensPrescription {
+                    center_s: F::from_f64(center_s).unwrap(),
+                    center_t: F::from_f64(center_t).unwrap(),
+                    surfaces: surfaces,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        tr.insert("center_s".to_string(),
+                  Value::Float(F::to_f64(&self.center_s).unwrap()));
+        tr.insert("center_t".to_string(),
+                  Value::Float(F::to_f64(&self.center_t).unwrap()));
+        let surfaces = self.surfaces.iter().map(|s| Value::Table(s.into_map())).collect();
+        tr.insert("surfaces".to_string(), Value::Array(surfaces));
+        tr
+    }
+}
+
+#[cfg(test)]
+fn test_biconvex() -> LensPrescription<f64> {
+    LensPrescription {
+        center_s: 1.0,
+        center_t: -2.0,
+        surfaces: vec![LensSurface {
+                           curvature: 1.0 / 50.0,
+                           thickness: 8.0,
+                           index: 1.5,
+                           aperture: 12.0,
+                       },
+                       LensSurface {
+                           curvature: -1.0 / 80.0,
+                           thickness: 0.0,
+                           index: 1.0,
+                           aperture: 10.0,
+                       }],
+    }
+}
+
+#[test]
+fn test_thick_lens() {
+    let lens = test_biconvex();
+    let (n, d, c1, c2) = (1.5, 8.0, 1.0 / 50.0, -1.0 / 80.0);
+
+    // lensmaker's equation for thick lenses
+    let f = 1.0 / ((n - 1.0) * (c1 - c2 + (n - 1.0) * d * c1 * c2 / n));
+    assert!((lens.effective_focal_length() - f).abs() < 1e-10);
+
+    let (front, back) = lens.principal_planes();
+    assert!((front + f * (n - 1.0) * d * c2 / n).abs() < 1e-10);
+    assert!((back + f * (n - 1.0) * d * c1 / n).abs() < 1e-10);
+
+    // a collimated ray reaches the back surface lower, so the rear
+    // aperture limits the bundle
+    assert_eq!(lens.aperture_stop(), 1);
+    let y1 = 1.0 - d * (n - 1.0) * c1 / n;
+    assert!((lens.entrance_pupil_radius() - 10.0 / y1).abs() < 1e-10);
+
+    // the thin lens equivalent reproduces the vertex optics between the
+    // principal planes
+    let thin = Optics::translation(&-front)
+                   .then(&lens.vertex_optics())
+                   .then(&Optics::translation(&back));
+    let ideal = lens.thin_lens();
+    let ideal_optics = Optics::lens(&0.0, &0.0, &ideal.focal_length_s);
+    assert!((thin.ss - ideal_optics.ss).abs() < 1e-10);
+    assert!((thin.su - ideal_optics.su).abs() < 1e-10);
+    assert!((thin.us - ideal_optics.us).abs() < 1e-10);
+    assert!((thin.uu - ideal_optics.uu).abs() < 1e-10);
+    assert!((thin.vt - ideal_optics.vt).abs() < 1e-10);
+}
+
+#[test]
+fn test_read_lens_prescription() {
+    let test = r#"
+    center_s = 1.0
+    center_t = -2.0
+
+    [[surfaces]]
+    curvature = 0.02
+    thickness = 8.0
+    index = 1.5
+    aperture = 12.0
+
+    [[surfaces]]
+    curvature = -0.0125
+    thickness = 0.0
+    index = 1.0
+    aperture = 10.0
+    "#;
+
+    let map = Parser::new(test).parse().unwrap();
+    let lens: LensPrescription<f64> = LensPrescription::from_map(&map).unwrap();
+    let expected = test_biconvex();
+    assert_eq!(lens.center_s, expected.center_s);
+    assert_eq!(lens.center_t, expected.center_t);
+    assert_eq!(lens.surfaces.len(), 2);
+    assert_eq!(lens.surfaces[0].curvature, 0.02);
+    assert_eq!(lens.surfaces[1].aperture, 10.0);
+
+    let roundtrip: LensPrescription<f64> = LensPrescription::from_map(&lens.into_map()).unwrap();
+    assert_eq!(roundtrip.surfaces[1].curvature, -0.0125);
+
+    // the lens has to end in air
+    let mut glass = lens.clone();
+    glass.surfaces[1].index = 1.5;
+    assert!(LensPrescription::<f64>::from_map(&glass.into_map()).is_none());
+
+    // prescriptions load wherever a thin lens does
+    let thin: Lens<f64> = Lens::from_map(&map).unwrap();
+    assert!((thin.focal_length_s - lens.effective_focal_length()).abs() < 1e-10);
+    assert!((thin.radius_t - lens.entrance_pupil_radius()).abs() < 1e-10);
+    assert_eq!(thin.center_t, -2.0);
+}
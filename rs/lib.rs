@@ -4,6 +4,9 @@
 mod optics;
 pub use optics::*;
 
+mod ray_transfer;
+pub use ray_transfer::*;
+
 mod serialize;
 pub use serialize::*;
 
@@ -13,6 +16,9 @@ pub use detector::*;
 mod lens;
 pub use lens::*;
 
+mod lens_prescription;
+pub use lens_prescription::*;
+
 mod isometry;
 pub use isometry::*;
 
@@ -28,6 +34,9 @@ pub use object::*;
 mod geom;
 pub use geom::*;
 
+mod backend;
+pub use backend::*;
+
 mod vector_math;
 pub use vector_math::*;
 
@@ -40,6 +49,12 @@ pub use bounding_geometry::*;
 mod occluder;
 pub use occluder::*;
 
+mod polygon;
+pub use polygon::*;
+
+mod aperture;
+pub use aperture::*;
+
 mod angular_plane;
 pub use angular_plane::*;
 
@@ -49,6 +64,18 @@ pub use spline_kernel::*;
 mod light_field_geom;
 pub use light_field_geom::*;
 
+mod light_field;
+pub use light_field::*;
+
+mod subaperture_grid;
+pub use subaperture_grid::*;
+
+mod refocus;
+pub use refocus::*;
+
+mod depth;
+pub use depth::*;
+
 mod light_volume;
 pub use light_volume::*;
 
@@ -61,21 +88,42 @@ pub use ellipsoid::*;
 mod phantom;
 pub use phantom::*;
 
+mod device_info;
+pub use device_info::*;
+
 mod env;
 pub use env::*;
 
 mod cl_traits;
 pub use cl_traits::*;
 
+mod program_cache;
+pub use program_cache::*;
+
+mod profiler;
+pub use profiler::*;
+
 mod transport;
 pub use transport::*;
 
+mod host_transport;
+pub use host_transport::*;
+
+mod skew_transport;
+pub use skew_transport::*;
+
+mod rebin;
+pub use rebin::*;
+
 mod scene;
 pub use scene::*;
 
 mod imager;
 pub use imager::*;
 
+mod adjoint;
+pub use adjoint::*;
+
 mod single_lens_imager;
 pub use single_lens_imager::*;
 
@@ -97,15 +145,27 @@ pub use plenoptic_camera::*;
 mod plenoptic_camera_calibration;
 pub use plenoptic_camera_calibration::*;
 
+mod plenoptic_decoder;
+pub use plenoptic_decoder::*;
+
+mod microlens_calibration;
+pub use microlens_calibration::*;
+
 mod plenoptic_imager;
 pub use plenoptic_imager::*;
 
+mod monte_carlo;
+pub use monte_carlo::*;
+
 mod volume_rotation;
 pub use volume_rotation::*;
 
 mod rotated_imager;
 pub use rotated_imager::*;
 
+mod slab_imager;
+pub use slab_imager::*;
+
 mod potential_function;
 pub use potential_function::*;
 
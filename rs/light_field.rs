@@ -0,0 +1,232 @@
+extern crate num;
+extern crate toml;
+extern crate avsfld;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
+use geom::*;
+use serialize::*;
+use image_geom::*;
+use light_field_geom::*;
+use scene::*;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::Write;
+
+/// Full 4d light field on one plane
+///
+/// Values are stored as `na` sub-aperture views, one for each angle on the
+/// plane, and each laid out like an image on `lfg.geom`.  The shape is
+/// `[ns, nt, na]` with `s` varying fastest, so the view for angle `ia` is
+/// the contiguous block returned by `view`; this is the same layout imagers
+/// use when they work one angle at a time.
+#[derive(Clone, Debug)]
+pub struct LightField<F: Float> {
+    pub lfg: LightFieldGeometry<F>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> LightField<F> {
+    pub fn new(lfg: LightFieldGeometry<F>) -> Self {
+        LightField { lfg: lfg }
+    }
+
+    /// Number of angles
+    pub fn na(self: &Self) -> usize {
+        self.lfg.plane.s.len()
+    }
+
+    /// Geometry of one sub-aperture view
+    pub fn view_geometry(self: &Self) -> ImageGeometry<F> {
+        self.lfg.geom.clone()
+    }
+
+    /// Returns the sub-aperture view for angle `ia`
+    pub fn view<'a>(self: &Self, buf: &'a [F], ia: usize) -> &'a [F] {
+        let np = self.lfg.geom.dimension();
+        &buf[np * ia..np * (ia + 1)]
+    }
+
+    /// Returns the sub-aperture view for angle `ia` for writing
+    pub fn view_mut<'a>(self: &Self, buf: &'a mut [F], ia: usize) -> &'a mut [F] {
+        let np = self.lfg.geom.dimension();
+        &mut buf[np * ia..np * (ia + 1)]
+    }
+
+    /// Writes a light field along with a description of its geometry
+    ///
+    /// `path` is a TOML file describing the geometry.  The values are saved
+    /// next to it in an AVS field file with the same name and an `fld`
+    /// extension, which the description refers to as `data_path`.
+    pub fn write<P: AsRef<Path>>(self: &Self, buf: &[F], path: P) -> Result<(), ()> {
+        let data_path = path.as_ref().with_extension("fld");
+        let data_name = match data_path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Err(()),
+        };
+
+        let mut desc = self.into_map();
+        desc.insert("data_path".to_string(), Value::String(data_name));
+        let mut file = if let Ok(f) = File::create(path.as_ref()) {
+            f
+        } else {
+            return Err(());
+        };
+        if write!(&mut file, "{}", encode_str(&desc)).is_err() {
+            return Err(());
+        }
+
+        self.save(buf, data_path)
+    }
+
+    /// Reads a light field written by `write`
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<F>), ()> {
+        let desc = match table_from_file(path.as_ref()) {
+            Some(desc) => desc,
+            None => return Err(()),
+        };
+        let lf = match LightField::from_map(&desc) {
+            Some(lf) => lf,
+            None => return Err(()),
+        };
+        let data_path = match desc.get("data_path") {
+            Some(&Value::String(ref data_path)) => {
+                let mut pb = PathBuf::from(path.as_ref());
+                pb.pop(); // pop off description file name
+                pb.push(data_path);
+                pb
+            }
+            _ => return Err(()),
+        };
+
+        let buf = try!(lf.load(data_path));
+        if buf.len() != lf.dimension() {
+            return Err(());
+        }
+        Ok((lf, buf))
+    }
+}
+
+impl<F: Float + FromPrimitive> Geometry<F> for LightField<F> {
+    fn shape(self: &Self) -> Vec<usize> {
+        vec![self.lfg.geom.ns, self.lfg.geom.nt, self.lfg.plane.s.len()]
+    }
+
+    fn save<P: AsRef<Path>>(self: &Self, buf: &[F], path: P) -> Result<(), ()> {
+        let mut file = if let Ok(f) = File::create(path) {
+            f
+        } else {
+            return Err(());
+        };
+        let sizes = self.shape();
+        match self::avsfld::AVSFile::write(&mut file, &sizes, buf) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn load<P: AsRef<Path>>(self: &Self, path: P) -> Result<Vec<F>, ()> {
+        let mut file = if let Ok(f) = self::avsfld::AVSFile::open(&path) {
+            f
+        } else {
+            return Err(());
+        };
+        match file.read() {
+            Ok(tr) => Ok(tr),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LightField<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        LightFieldGeometry::from_map(map).map(LightField::new)
+    }
+
+    fn into_map(self: &Self) -> Table {
+        self.lfg.into_map()
+    }
+}
+
+#[cfg(test)]
+fn test_light_field() -> LightField<f32> {
+    use angular_plane::*;
+    use optics::*;
+    use lens::*;
+
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 10f32,
+        radius_t: 10f32,
+        focal_length_s: 20f32,
+        focal_length_t: 20f32,
+    };
+    LightField::new(LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 12,
+            nt: 10,
+            ds: 0.5,
+            dt: 0.25,
+            offset_s: 0.5,
+            offset_t: -1.0,
+        },
+        plane: lens.as_angular_plane(AngularBasis::Linear, 4),
+        to_plane: Optics::translation(&25f32),
+    })
+}
+
+#[test]
+fn test_light_field_views() {
+    use host_transport::*;
+    use optics::*;
+
+    let lf = test_light_field();
+    assert_eq!(lf.shape(), vec![12, 10, lf.na()]);
+    assert_eq!(lf.dimension(), 120 * lf.na());
+
+    // views are the per-angle images a transport works on
+    let src = LightField::new(LightFieldGeometry {
+        to_plane: Optics::translation(&30f32),
+        ..lf.lfg.clone()
+    });
+    let x = src.rands();
+    let mut y = lf.zeros();
+    let mut xport = HostTransport::new_simple(src.lfg.clone(), lf.lfg.clone());
+    for ia in 0..lf.na() {
+        xport.forw(src.view(&x, ia), lf.view_mut(&mut y, ia), ia);
+    }
+
+    let ia = lf.na() / 2;
+    let mut expected = lf.view_geometry().zeros();
+    xport.forw(src.view(&x, ia), &mut expected, ia);
+    assert_eq!(lf.view(&y, ia), &expected[..]);
+}
+
+#[test]
+fn test_light_field_serialize() {
+    let lf = test_light_field();
+    let map = lf.into_map();
+    let roundtrip: LightField<f32> = LightField::from_map(&map).unwrap();
+    assert_eq!(roundtrip.shape(), lf.shape());
+    assert_eq!(roundtrip.lfg.plane.s, lf.lfg.plane.s);
+    assert_eq!(roundtrip.lfg.plane.w, lf.lfg.plane.w);
+    assert_eq!(roundtrip.lfg.geom.offset_t, -1.0);
+    assert_eq!(roundtrip.lfg.to_plane.su, 25.0);
+    match roundtrip.lfg.plane.basis {
+        ::angular_plane::AngularBasis::Linear => (),
+        _ => assert!(false),
+    }
+}
+
+#[test]
+fn test_light_field_io() {
+    use std::env::temp_dir;
+
+    let lf = test_light_field();
+    let x = lf.rands();
+    let path = temp_dir().join("lightfield_test_light_field_io.toml");
+    lf.write(&x, &path).unwrap();
+
+    let (read, y) = LightField::<f32>::read(&path).unwrap();
+    assert_eq!(read.shape(), lf.shape());
+    assert_eq!(x, y);
+}
@@ -1,9 +1,12 @@
 extern crate num;
-use self::num::{Float, FromPrimitive};
+extern crate toml;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
 use image_geom::*;
 use angular_plane::*;
 use optics::*;
 use spline_kernel::*;
+use serialize::*;
 use std::mem::swap;
 
 /// One plane in a light transport stack
@@ -21,7 +24,9 @@ impl<F: Float + FromPrimitive> LightFieldGeometry<F> {
     /// functions over their domain; see Table 1 in the PDF documentation.
     pub fn pixel_volume(self: &Self) -> F {
         match &self.plane.basis {
-            &AngularBasis::Dirac => {
+            // tents peak at one and integrate to the angular spacing, so
+            // they cover the same volume as a Dirac sample's cell
+            &AngularBasis::Dirac | &AngularBasis::Linear => {
                 let vs = (self.plane.ds / self.to_plane.su).abs() * self.geom.ds;
                 let vt = (self.plane.dt / self.to_plane.tv).abs() * self.geom.dt;
                 (vs * vt).abs()
@@ -78,8 +83,14 @@ impl<F: Float + FromPrimitive> LightFieldGeometry<F> {
                 let xt = self.transport_t_pillbox(dst, &src_to_dst, ia);
                 (xs, xt)
             }
+            (&AngularBasis::Linear, &AngularBasis::Linear) => {
+                let xs = self.transport_s_linear(dst, &src_to_dst, ia);
+                let xt = self.transport_t_linear(dst, &src_to_dst, ia);
+                (xs, xt)
+            }
             _ => {
-                panic!("Cannot transport between mismatched angular basis functions");
+                panic!("Cannot transport between mismatched angular basis functions; use a Rebin \
+                        first");
             }
         }
     }
@@ -191,4 +202,106 @@ impl<F: Float + FromPrimitive> LightFieldGeometry<F> {
 
         SplineKernel::Trapezoid(h, mag, taus_array)
     }
+
+    /// The tent around `s` is the convolution of two boxes as wide as the
+    /// angular spacing, so the footprint convolves three boxes: the
+    /// destination pixel and two copies of the pillbox's angular box.  The
+    /// height matches the pillbox's.
+    fn transport_s_linear(self: &Self,
+                          dst: &LightFieldGeometry<F>,
+                          src2dst: &Optics<F>,
+                          ia: usize)
+                          -> SplineKernel<F> {
+        let plane = &self.plane;
+        let s = plane.s[ia];
+        let c2 = F::one() + F::one();
+        let src2root = &self.to_plane;
+
+        let alpha = src2dst.ss - src2dst.su * src2root.ss / src2root.su;
+        let beta = src2dst.su / src2root.su;
+        let gamma = src2dst.s - src2dst.su * src2root.s / src2root.su;
+        let h = (plane.ds / src2root.su).abs().min((dst.geom.ds / src2dst.su).abs());
+
+        let taus = vec![
+            (dst.geom.ds/c2 - beta*(s + plane.ds) - gamma)/alpha,
+            (dst.geom.ds/c2 - beta*s - gamma)/alpha,
+            (dst.geom.ds/c2 - beta*s - gamma)/alpha,
+            (dst.geom.ds/c2 - beta*(s - plane.ds) - gamma)/alpha,
+            (-dst.geom.ds/c2 - beta*(s + plane.ds) - gamma)/alpha,
+            (-dst.geom.ds/c2 - beta*s - gamma)/alpha,
+            (-dst.geom.ds/c2 - beta*s - gamma)/alpha,
+            (-dst.geom.ds/c2 - beta*(s - plane.ds) - gamma)/alpha,
+        ];
+
+        let mag = F::one() / alpha;
+
+        SplineKernel::new_quad(h, mag, &taus)
+    }
+
+    fn transport_t_linear(self: &Self,
+                          dst: &LightFieldGeometry<F>,
+                          src2dst: &Optics<F>,
+                          ia: usize)
+                          -> SplineKernel<F> {
+        let plane = &self.plane;
+        let t = plane.t[ia];
+        let c2 = F::one() + F::one();
+        let src2root = &self.to_plane;
+
+        let alpha = src2dst.tt - src2dst.tv * src2root.tt / src2root.tv;
+        let beta = src2dst.tv / src2root.tv;
+        let gamma = src2dst.t - src2dst.tv * src2root.t / src2root.tv;
+        let h = (plane.dt / src2root.tv).abs().min((dst.geom.dt / src2dst.tv).abs());
+
+        let taus = vec![
+            (dst.geom.dt/c2 - beta*(t + plane.dt) - gamma)/alpha,
+            (dst.geom.dt/c2 - beta*t - gamma)/alpha,
+            (dst.geom.dt/c2 - beta*t - gamma)/alpha,
+            (dst.geom.dt/c2 - beta*(t - plane.dt) - gamma)/alpha,
+            (-dst.geom.dt/c2 - beta*(t + plane.dt) - gamma)/alpha,
+            (-dst.geom.dt/c2 - beta*t - gamma)/alpha,
+            (-dst.geom.dt/c2 - beta*t - gamma)/alpha,
+            (-dst.geom.dt/c2 - beta*(t - plane.dt) - gamma)/alpha,
+        ];
+
+        let mag = F::one() / alpha;
+
+        SplineKernel::new_quad(h, mag, &taus)
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LightFieldGeometry<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let geom = map.get("geom");
+        let plane = map.get("plane");
+        let to_plane = map.get("to_plane");
+
+        match (geom, plane, to_plane) {
+            (Some(&Value::Table(ref geom)),
+             Some(&Value::Table(ref plane)),
+             Some(&Value::Table(ref to_plane))) => {
+                match (ImageGeometry::from_map(geom),
+                       AngularPlane::from_map(plane),
+                       Optics::from_map(to_plane)) {
+                    (Some(geom), Some(plane), Some(to_plane)) => {
+                        Some(LightFieldGeometry {
+                            geom: geom,
+                            plane: plane,
+                            to_plane: to_plane,
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        tr.insert("geom".to_string(), Value::Table(self.geom.into_map()));
+        tr.insert("plane".to_string(), Value::Table(self.plane.into_map()));
+        tr.insert("to_plane".to_string(), Value::Table(self.to_plane.into_map()));
+        tr
+    }
 }
@@ -12,6 +12,7 @@ use image_geom::*;
 use optics::*;
 use light_field_geom::*;
 use angular_plane::*;
+use spline_kernel::*;
 use std::path::Path;
 use std::fs::File;
 
@@ -28,6 +29,12 @@ pub struct LightVolume<F: Float> {
     pub offset_y: F,
     pub offset_z: F,
     pub opaque: bool,
+
+    /// Attenuation per unit emission density and unit length
+    ///
+    /// Only used for `opaque` volumes, where each voxel absorbs light in
+    /// proportion to how much it emits.
+    pub attenuation: F,
 }
 
 impl<F: Float + FromPrimitive> LightVolume<F> {
@@ -106,6 +113,43 @@ impl<F: Float + FromPrimitive> LightVolume<F> {
             offset_z: self.offset_z,
 
             opaque: self.opaque,
+            attenuation: self.attenuation,
+        }
+    }
+
+    /// Returns the slice indices ordered from nearest to furthest from the
+    /// angular plane
+    ///
+    /// Slices with larger `z` sit closer to the plane (see `optics_to_z0`),
+    /// so this is the order in which an opaque volume is composited.
+    pub fn slices_front_to_back(self: &Self) -> Vec<usize> {
+        let mut tr: Vec<usize> = (0..self.nz).collect();
+        if self.dz > F::zero() {
+            tr.reverse();
+        }
+        tr
+    }
+
+    /// Returns the absorption of a projected slice per unit of projected value
+    ///
+    /// `ks` and `kt` are the footprints projecting the slice, and `scale`
+    /// the factor applied to them.  A slice of uniform emission density `c`
+    /// projects to `scale * c` times the integrals of both footprints, and
+    /// attenuates a ray crossing it along `z` by `exp(-attenuation * c * dz)`.
+    pub fn slice_absorption(self: &Self,
+                            ks: &SplineKernel<F>,
+                            kt: &SplineKernel<F>,
+                            scale: F)
+                            -> F {
+        let integral = |k: &SplineKernel<F>| {
+            let (l, r) = k.support(F::zero());
+            k.integrate(F::zero(), l, r)
+        };
+        let footprint = scale * integral(ks) * integral(kt);
+        if footprint > F::zero() {
+            self.attenuation * self.dz.abs() / footprint
+        } else {
+            F::zero()
         }
     }
 
@@ -113,6 +157,23 @@ impl<F: Float + FromPrimitive> LightVolume<F> {
     pub fn voxel_volume(self: &Self) -> F {
         (self.dx * self.dy * self.dz).abs()
     }
+
+    /// Returns the slices `iz0..iz1` of this volume as a volume of their own
+    ///
+    /// The offset is chosen so that slice `iz` of the slab sits at the same
+    /// position as slice `iz0 + iz` of this volume.
+    pub fn slab(self: &Self, iz0: usize, iz1: usize) -> Self {
+        assert!(iz0 < iz1 && iz1 <= self.nz);
+        let nz = iz1 - iz0;
+        let two = F::from_f32(2f32).unwrap();
+        let shift = (F::from_usize(self.nz).unwrap() - F::from_usize(nz).unwrap()) / two -
+                    F::from_usize(iz0).unwrap();
+        LightVolume {
+            nz: nz,
+            offset_z: self.offset_z + shift,
+            ..self.clone()
+        }
+    }
 }
 
 impl<F: Float + FromPrimitive> Geometry<F> for LightVolume<F> {
@@ -157,20 +218,24 @@ impl<F: Float + ToPrimitive + FromPrimitive> ClBuffer for LightVolume<F> {
         buf.write_i32::<LittleEndian>(self.nx as i32).unwrap();
         buf.write_i32::<LittleEndian>(self.ny as i32).unwrap();
         buf.write_i32::<LittleEndian>(self.nz as i32).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.dx).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.dy).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.dy).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.offset_x).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.offset_y).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.offset_z).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.wx()).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.wy()).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.wz()).unwrap()).unwrap();
+        align_real::<F>(buf);
+        write_real(buf, &self.dx);
+        write_real(buf, &self.dy);
+        write_real(buf, &self.dz);
+        write_real(buf, &self.offset_x);
+        write_real(buf, &self.offset_y);
+        write_real(buf, &self.offset_z);
+        write_real(buf, &self.wx());
+        write_real(buf, &self.wy());
+        write_real(buf, &self.wz());
         if self.opaque {
             buf.write_i32::<LittleEndian>(1i32).unwrap()
         } else {
             buf.write_i32::<LittleEndian>(0i32).unwrap()
         }
+        align_real::<F>(buf);
+        write_real(buf, &self.attenuation);
+        align_real::<F>(buf);
     }
 }
 
@@ -186,6 +251,11 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LightVolume<F> {
         let offset_y = map.get("offset_y");
         let offset_z = map.get("offset_z");
         let opaque = map.get("opaque");
+        let attenuation = match map.get("attenuation") {
+            Some(&Value::Float(attenuation)) => attenuation,
+            Some(_) => return None,
+            None => 1.0,
+        };
 
         match (nx, ny, nz, dx, dy, dz, offset_x, offset_y, offset_z, opaque) {
             (Some(&Value::Integer(nx)),
@@ -209,6 +279,7 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LightVolume<F> {
                     offset_y: F::from_f64(offset_y).unwrap(),
                     offset_z: F::from_f64(offset_z).unwrap(),
                     opaque: opaque,
+                    attenuation: F::from_f64(attenuation).unwrap(),
                 })
             }
             _ => None,
@@ -230,6 +301,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for LightVolume<F> {
         tr.insert("offset_z".to_string(),
                   Value::Float(F::to_f64(&self.offset_z).unwrap()));
         tr.insert("opaque".to_string(), Value::Boolean(self.opaque));
+        tr.insert("attenuation".to_string(),
+                  Value::Float(F::to_f64(&self.attenuation).unwrap()));
         tr
     }
 }
@@ -263,6 +336,7 @@ fn test_light_volume() {
     assert_eq!(v.offset_y, 8.0);
     assert_eq!(v.offset_z, 12.0);
     assert_eq!(v.opaque, false);
+    assert_eq!(v.attenuation, 1.0);
 
     let vv: LightVolume<f32> = LightVolume::from_map(&v.into_map()).unwrap();
 
@@ -276,4 +350,62 @@ fn test_light_volume() {
     assert_eq!(v.offset_y, vv.offset_y);
     assert_eq!(v.offset_z, vv.offset_z);
     assert_eq!(v.opaque, vv.opaque);
+    assert_eq!(v.attenuation, vv.attenuation);
+}
+
+#[test]
+fn test_light_volume_opaque() {
+    let test = r#"
+        nx = 10
+        ny = 20
+        nz = 30
+        dx = 1.0
+        dy = 1.0
+        dz = 0.5
+        offset_x = 0.0
+        offset_y = 0.0
+        offset_z = 0.0
+        opaque = true
+        attenuation = 0.25
+    "#;
+
+    let mut parser = Parser::new(test);
+    let map = parser.parse().unwrap();
+    let v: LightVolume<f32> = LightVolume::from_map(&map).unwrap();
+    assert_eq!(v.opaque, true);
+    assert_eq!(v.attenuation, 0.25);
+
+    let vv: LightVolume<f32> = LightVolume::from_map(&v.into_map()).unwrap();
+    assert_eq!(vv.attenuation, 0.25);
+
+    let order = v.slices_front_to_back();
+    assert_eq!(order.len(), v.nz);
+    for w in order.windows(2) {
+        assert!(v.iz2z(w[0]) > v.iz2z(w[1]));
+    }
+}
+
+#[test]
+fn test_light_volume_slab() {
+    let v = LightVolume {
+        nx: 4,
+        ny: 4,
+        nz: 10,
+        dx: 1f32,
+        dy: 1f32,
+        dz: 0.5f32,
+        offset_x: 0f32,
+        offset_y: 0f32,
+        offset_z: 1.5f32,
+        opaque: false,
+        attenuation: 1f32,
+    };
+
+    for &(iz0, iz1) in [(0, 10), (0, 3), (3, 6), (6, 10), (9, 10)].iter() {
+        let slab = v.slab(iz0, iz1);
+        assert_eq!(slab.nz, iz1 - iz0);
+        for iz in 0..slab.nz {
+            assert!((slab.iz2z(iz) - v.iz2z(iz0 + iz)).abs() < 1e-6);
+        }
+    }
 }
@@ -4,6 +4,8 @@ use self::num::{FromPrimitive, ToPrimitive, Float};
 use image_geom::*;
 use self::proust::*;
 use cl_traits::*;
+use program_cache::*;
+use profiler::*;
 
 /// Spatial mask operation
 pub struct Mask<F: Float> {
@@ -17,11 +19,8 @@ pub struct Mask<F: Float> {
 
 impl<F: Float + FromPrimitive + ToPrimitive> Mask<F> {
     pub fn new(geometry: ImageGeometry<F>, mask: &[F], queue: CommandQueue) -> Result<Self, Error> {
-        let context = try!(queue.context());
-        let device = try!(queue.device());
         let source = &[ImageGeometry::<F>::header()];
-        let unbuilt = try!(Program::new_from_source(context, source));
-        let built = try!(unbuilt.build(&[device]));
+        let built = try!(cached_program::<F>(&queue, source));
         let apply_mask = try!(built.create_kernel("apply_mask"));
         let apply_mask_to = try!(built.create_kernel("apply_mask_to"));
         let mask_buf = try!(queue.create_buffer_from_slice(mask));
@@ -46,7 +45,12 @@ impl<F: Float + FromPrimitive + ToPrimitive> Mask<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.geom.ns, self.geom.nt, 1);
 
-        self.queue.run_with_events(&mut self.apply_mask, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.apply_mask,
+                   "apply_mask",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     /// Apply mask out-of-place
@@ -63,6 +67,11 @@ impl<F: Float + FromPrimitive + ToPrimitive> Mask<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.geom.ns, self.geom.nt, 1);
 
-        self.queue.run_with_events(&mut self.apply_mask_to, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.apply_mask_to,
+                   "apply_mask_to",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 }
@@ -0,0 +1,846 @@
+extern crate num;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use std::f64::consts::PI;
+use image_geom::*;
+use lens::*;
+use plenoptic_camera::*;
+
+/// Arrangement of lenses in a microlens array, named as in `microlens_tool`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LensLayout {
+    /// Square grid
+    Quad,
+
+    /// Hexagonal grid with rows along s
+    Hex,
+
+    /// Hexagonal grid with columns along t
+    HexT,
+}
+
+impl LensLayout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quad" => Some(LensLayout::Quad),
+            "hex" => Some(LensLayout::Hex),
+            "hex_t" => Some(LensLayout::HexT),
+            _ => None,
+        }
+    }
+
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            &LensLayout::Quad => "quad",
+            &LensLayout::Hex => "hex",
+            &LensLayout::HexT => "hex_t",
+        }
+    }
+
+    /// Direction of the first lattice vector of an unrotated grid
+    fn base_angle<F: Float + FromPrimitive>(self: &Self) -> F {
+        match self {
+            &LensLayout::HexT => F::from_f64(PI / 2f64).unwrap(),
+            _ => F::zero(),
+        }
+    }
+
+    /// Angle from the first lattice vector to the second
+    fn lattice_angle<F: Float + FromPrimitive>(self: &Self) -> F {
+        match self {
+            &LensLayout::Quad => F::from_f64(PI / 2f64).unwrap(),
+            _ => F::from_f64(PI / 3f64).unwrap(),
+        }
+    }
+}
+
+/// Center of a microlens image found in a white image
+#[derive(Clone, Debug)]
+pub struct LensDetection<F: Float> {
+    pub center_s: F,
+    pub center_t: F,
+
+    /// Mean value of the lens image
+    pub brightness: F,
+
+    /// Grid site the detection was fitted to; `None` for outliers
+    pub index: Option<(i64, i64)>,
+
+    /// Distance from the center of the fitted grid site
+    pub residual: F,
+}
+
+/// Regular grid of microlens image centers on the detector
+#[derive(Clone, Debug)]
+pub struct MicrolensGrid<F: Float> {
+    pub layout: LensLayout,
+
+    /// Distance between neighbouring centers
+    pub pitch: F,
+
+    /// Counterclockwise rotation from the layout's orientation, in radians
+    pub rotation: F,
+
+    /// Center of the site at index `(0, 0)`
+    pub origin_s: F,
+    pub origin_t: F,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> MicrolensGrid<F> {
+    /// Returns the lattice vectors from a site to its neighbours along
+    /// each index
+    pub fn basis(self: &Self) -> ((F, F), (F, F)) {
+        let first = self.layout.base_angle::<F>() + self.rotation;
+        let second = first + self.layout.lattice_angle();
+        ((self.pitch * first.cos(), self.pitch * first.sin()),
+         (self.pitch * second.cos(), self.pitch * second.sin()))
+    }
+
+    /// Returns the center of site `(i, j)`
+    pub fn center(self: &Self, i: i64, j: i64) -> (F, F) {
+        let ((a1s, a1t), (a2s, a2t)) = self.basis();
+        let fi = F::from_i64(i).unwrap();
+        let fj = F::from_i64(j).unwrap();
+        (self.origin_s + fi * a1s + fj * a2s, self.origin_t + fi * a1t + fj * a2t)
+    }
+
+    /// Returns the fractional lattice coordinates of a point
+    pub fn coordinates(self: &Self, s: F, t: F) -> (F, F) {
+        let ((a1s, a1t), (a2s, a2t)) = self.basis();
+        let (ds, dt) = (s - self.origin_s, t - self.origin_t);
+        let det = a1s * a2t - a2s * a1t;
+        ((ds * a2t - dt * a2s) / det, (a1s * dt - a1t * ds) / det)
+    }
+
+    /// Returns the index of the site whose center is near a point
+    pub fn index(self: &Self, s: F, t: F) -> (i64, i64) {
+        let (fi, fj) = self.coordinates(s, t);
+        (fi.round().to_i64().unwrap(), fj.round().to_i64().unwrap())
+    }
+
+    /// Makes a rough estimate of the grid from detected centers
+    ///
+    /// The pitch is the median distance to the nearest neighbour, and the
+    /// rotation is the circular mean of the directions to all near
+    /// neighbours, taken modulo the grid's symmetry.  Unless `layout` is
+    /// given, grids whose lenses typically have six near neighbours are
+    /// hexagonal and the rest square.
+    pub fn estimate(detections: &[LensDetection<F>], layout: Option<LensLayout>) -> Option<Self> {
+        let n = detections.len();
+        if n < 3 {
+            return None;
+        }
+
+        let mut nearest: Vec<F> = detections.iter()
+                                            .enumerate()
+                                            .map(|(a, da)| {
+                                                detections.iter()
+                                                          .enumerate()
+                                                          .filter(|&(b, _)| a != b)
+                                                          .map(|(_, db)| distance(da, db))
+                                                          .fold(F::infinity(), |m, d| m.min(d))
+                                            })
+                                            .collect();
+        nearest.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let pitch = nearest[n / 2];
+
+        // directions to neighbours, as sums for 4- and 6-fold circular means
+        let reach = pitch * F::from_f32(1.25f32).unwrap();
+        let (c4, c6) = (F::from_f32(4f32).unwrap(), F::from_f32(6f32).unwrap());
+        let (mut sum4, mut sum6) = ((F::zero(), F::zero()), (F::zero(), F::zero()));
+        let mut counts = Vec::with_capacity(n);
+        for da in detections.iter() {
+            let mut count = 0;
+            for db in detections.iter() {
+                let d = distance(da, db);
+                if d > F::zero() && d < reach {
+                    let angle = (db.center_t - da.center_t).atan2(db.center_s - da.center_s);
+                    sum4 = (sum4.0 + (c4 * angle).cos(), sum4.1 + (c4 * angle).sin());
+                    sum6 = (sum6.0 + (c6 * angle).cos(), sum6.1 + (c6 * angle).sin());
+                    count += 1;
+                }
+            }
+            counts.push(count);
+        }
+        counts.sort();
+
+        let guessed = layout.is_none();
+        let layout = match layout {
+            Some(layout) => layout,
+            None if counts[n / 2] >= 5 => LensLayout::Hex,
+            None => LensLayout::Quad,
+        };
+        let rotation = match layout {
+            LensLayout::Quad => sum4.1.atan2(sum4.0) / c4,
+            _ => sum6.1.atan2(sum6.0) / c6,
+        };
+
+        // a hexagonal grid turned by 30 degrees is the transposed layout
+        let sixth = F::from_f64(PI / 6f64).unwrap();
+        let twelfth = F::from_f64(PI / 12f64).unwrap();
+        let (layout, rotation) = match layout {
+            LensLayout::Quad => (LensLayout::Quad, rotation),
+            LensLayout::Hex if !guessed || rotation.abs() < twelfth => (LensLayout::Hex, rotation),
+            _ if rotation > F::zero() => (LensLayout::HexT, rotation - sixth),
+            _ => (LensLayout::HexT, rotation + sixth),
+        };
+
+        // start from the lens nearest the middle of the detections
+        let mean_s = detections.iter().fold(F::zero(), |a, d| a + d.center_s) /
+                     F::from_usize(n).unwrap();
+        let mean_t = detections.iter().fold(F::zero(), |a, d| a + d.center_t) /
+                     F::from_usize(n).unwrap();
+        let seed = detections.iter()
+                             .min_by(|a, b| {
+                                 let da = (a.center_s - mean_s).hypot(a.center_t - mean_t);
+                                 let db = (b.center_s - mean_s).hypot(b.center_t - mean_t);
+                                 da.partial_cmp(&db).unwrap()
+                             })
+                             .unwrap();
+
+        Some(MicrolensGrid {
+            layout: layout,
+            pitch: pitch,
+            rotation: rotation,
+            origin_s: seed.center_s,
+            origin_t: seed.center_t,
+        })
+    }
+
+    /// Refines the grid by least squares, assigning detections to sites
+    ///
+    /// The fit starts with the detections near the origin and widens until
+    /// it covers all of them, so that small errors in the rough pitch do not
+    /// put distant lenses on the wrong sites.  Detections further than a
+    /// quarter pitch from their site are outliers and are left out.
+    pub fn fit(self: &Self, detections: &mut [LensDetection<F>]) -> Self {
+        let mut grid = self.clone();
+        let quarter = F::from_f32(0.25f32).unwrap();
+        let from_origin = |d: &LensDetection<F>| {
+            (d.center_s - self.origin_s).hypot(d.center_t - self.origin_t)
+        };
+        let extent = detections.iter().fold(F::zero(), |m, d| m.max(from_origin(d)));
+
+        let mut radius = self.pitch * F::from_f32(4f32).unwrap();
+        loop {
+            let done = radius > extent;
+            let mut pairs = Vec::new();
+            for d in detections.iter() {
+                if from_origin(d) > radius {
+                    continue;
+                }
+                let (i, j) = grid.index(d.center_s, d.center_t);
+                let (cs, ct) = grid.center(i, j);
+                if (d.center_s - cs).hypot(d.center_t - ct) < grid.pitch * quarter {
+                    pairs.push((i, j, d.center_s, d.center_t));
+                }
+            }
+            if pairs.len() >= 3 {
+                grid = grid.fit_sites(&pairs);
+            }
+            if done {
+                break;
+            }
+            radius = radius + radius;
+        }
+
+        for d in detections.iter_mut() {
+            let (i, j) = grid.index(d.center_s, d.center_t);
+            let (cs, ct) = grid.center(i, j);
+            d.residual = (d.center_s - cs).hypot(d.center_t - ct);
+            d.index = if d.residual < grid.pitch * quarter {
+                Some((i, j))
+            } else {
+                None
+            };
+        }
+        grid
+    }
+
+    /// Fits pitch, rotation and origin to centers at known sites
+    ///
+    /// The centers are a similarity transform of the unit lattice, which is
+    /// linear in the origin and in `pitch * (cos, sin)` of the rotation.
+    fn fit_sites(self: &Self, pairs: &[(i64, i64, F, F)]) -> Self {
+        let unit = MicrolensGrid {
+            layout: self.layout,
+            pitch: F::one(),
+            rotation: F::zero(),
+            origin_s: F::zero(),
+            origin_t: F::zero(),
+        };
+        let points: Vec<((F, F), (F, F))> = pairs.iter()
+                                                 .map(|&(i, j, s, t)| (unit.center(i, j), (s, t)))
+                                                 .collect();
+
+        let n = F::from_usize(points.len()).unwrap();
+        let (mut mus, mut mut_, mut mcs, mut mct) = (F::zero(), F::zero(), F::zero(), F::zero());
+        for &((us, ut), (cs, ct)) in points.iter() {
+            mus = mus + us;
+            mut_ = mut_ + ut;
+            mcs = mcs + cs;
+            mct = mct + ct;
+        }
+        let (mus, mut_, mcs, mct) = (mus / n, mut_ / n, mcs / n, mct / n);
+
+        let (mut norm, mut a, mut b) = (F::zero(), F::zero(), F::zero());
+        for &((us, ut), (cs, ct)) in points.iter() {
+            let (dus, dut, dcs, dct) = (us - mus, ut - mut_, cs - mcs, ct - mct);
+            norm = norm + dus * dus + dut * dut;
+            a = a + dus * dcs + dut * dct;
+            b = b + dus * dct - dut * dcs;
+        }
+        let (a, b) = (a / norm, b / norm);
+
+        MicrolensGrid {
+            layout: self.layout,
+            pitch: a.hypot(b),
+            rotation: b.atan2(a),
+            origin_s: mcs - a * mus + b * mut_,
+            origin_t: mct - b * mus - a * mut_,
+        }
+    }
+}
+
+fn distance<F: Float>(a: &LensDetection<F>, b: &LensDetection<F>) -> F {
+    (a.center_s - b.center_s).hypot(a.center_t - b.center_t)
+}
+
+/// Finds the centers of microlens images in a white image
+///
+/// Pixels brighter than halfway between the image's dark and bright levels
+/// are grouped into connected regions, and each region's center is its
+/// centroid weighted by how far its pixels rise above that level.  Regions
+/// touching the border of the image, or much smaller than is typical, are
+/// cut-off lens images and are dropped.
+///
+/// Returns `None` if the image is empty or does not match `geom`.
+pub fn detect_lens_centers<F>(geom: &ImageGeometry<F>,
+                              white: &[F])
+                              -> Option<Vec<LensDetection<F>>>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    if white.is_empty() || white.len() != geom.ns * geom.nt {
+        return None;
+    }
+
+    let mut sorted = white.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let margin = sorted.len() / 20;
+    let level = (sorted[margin] + sorted[sorted.len() - 1 - margin]) /
+                F::from_f32(2f32).unwrap();
+
+    // (weight, weighted s, weighted t, sum, pixels, touches border)
+    let mut regions = Vec::new();
+    let mut visited = vec![false; white.len()];
+    for start in 0..white.len() {
+        if visited[start] || white[start] <= level {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![start];
+        let (mut w, mut ws, mut wt, mut sum) = (F::zero(), F::zero(), F::zero(), F::zero());
+        let mut pixels = 0usize;
+        let mut border = false;
+        while let Some(ip) = stack.pop() {
+            let (is, it) = (ip % geom.ns, ip / geom.ns);
+            let (s, t) = geom.pixel_center(is, it);
+            let weight = white[ip] - level;
+            w = w + weight;
+            ws = ws + weight * s;
+            wt = wt + weight * t;
+            sum = sum + white[ip];
+            pixels += 1;
+            border = border || is == 0 || it == 0 || is + 1 == geom.ns || it + 1 == geom.nt;
+
+            let mut neighbours = Vec::with_capacity(4);
+            if is > 0 {
+                neighbours.push(ip - 1);
+            }
+            if is + 1 < geom.ns {
+                neighbours.push(ip + 1);
+            }
+            if it > 0 {
+                neighbours.push(ip - geom.ns);
+            }
+            if it + 1 < geom.nt {
+                neighbours.push(ip + geom.ns);
+            }
+            for &np in neighbours.iter() {
+                if !visited[np] && white[np] > level {
+                    visited[np] = true;
+                    stack.push(np);
+                }
+            }
+        }
+        if !border {
+            regions.push((w, ws, wt, sum, pixels));
+        }
+    }
+
+    let mut areas: Vec<usize> = regions.iter().map(|r| r.4).collect();
+    areas.sort();
+    let typical = if areas.len() > 0 {
+        areas[areas.len() / 2]
+    } else {
+        0
+    };
+
+    Some(regions.iter()
+                .filter(|r| 2 * r.4 >= typical)
+                .map(|&(w, ws, wt, sum, pixels)| {
+                    LensDetection {
+                        center_s: ws / w,
+                        center_t: wt / w,
+                        brightness: sum / F::from_usize(pixels).unwrap(),
+                        index: None,
+                        residual: F::zero(),
+                    }
+                })
+                .collect())
+}
+
+/// Microlens array estimated from a white image
+///
+/// A white image, taken through the main lens of an evenly lit scene,
+/// shows a bright image of the main lens' aperture behind each microlens.
+/// Those images are centered where the chief ray from the main lens' center
+/// through each microlens meets the detector, so their grid is the
+/// microlens grid magnified about the main lens' center.
+///
+/// Multi-focus arrays repeat their lens types in a pattern across the
+/// grid.  The types are told apart by their brightness in the white image,
+/// and each site of the repeating pattern takes the type most of its
+/// lenses were classified as, so lenses too close to the border to be
+/// detected still get a type.
+#[derive(Clone, Debug)]
+pub struct MicrolensCalibration<F: Float> {
+    /// Geometry of the white image
+    pub geom: ImageGeometry<F>,
+
+    /// Fitted grid of lens image centers on the detector
+    pub grid: MicrolensGrid<F>,
+
+    /// Every lens image found in the white image
+    pub detections: Vec<LensDetection<F>>,
+
+    /// Mean brightness of each lens type, in increasing order
+    pub type_brightness: Vec<F>,
+
+    /// Microlens array, for `PlenopticCamera::array`
+    pub lenses: Vec<Lens<F>>,
+
+    // type of each site of the repeating pattern
+    pattern: Vec<usize>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> MicrolensCalibration<F> {
+    /// Estimates the microlens array of `camera` from a white image
+    ///
+    /// `prototypes` holds one lens per lens type, in order of increasing
+    /// brightness in the white image.  Only their focal lengths are used;
+    /// centers and radii come from the fitted grid.  The layout is
+    /// detected unless it is given.  Returns `None` if `prototypes` or
+    /// `white` is empty, or if no grid fits the lenses found.
+    pub fn new(camera: &PlenopticCamera<F>,
+               white: &[F],
+               layout: Option<LensLayout>,
+               prototypes: &[Lens<F>])
+               -> Option<Self> {
+        if prototypes.is_empty() {
+            return None;
+        }
+        let geom = camera.detector.image_geometry();
+        let mut detections = match detect_lens_centers(&geom, white) {
+            Some(detections) => detections,
+            None => return None,
+        };
+        let grid = match MicrolensGrid::estimate(&detections, layout) {
+            Some(grid) => grid.fit(&mut detections),
+            None => return None,
+        };
+        if detections.iter().all(|d| d.index.is_none()) {
+            return None;
+        }
+
+        let mut tr = MicrolensCalibration {
+            geom: geom,
+            grid: grid,
+            detections: detections,
+            type_brightness: Vec::new(),
+            lenses: Vec::new(),
+            pattern: Vec::new(),
+        };
+        tr.classify(prototypes.len());
+        tr.lenses = tr.array(camera, prototypes);
+        Some(tr)
+    }
+
+    /// Returns the site of the repeating pattern that `(i, j)` falls on
+    fn pattern_site(self: &Self, i: i64, j: i64) -> usize {
+        let n = self.type_brightness.len() as i64;
+        let (pi, pj) = (((i % n) + n) % n, ((j % n) + n) % n);
+        (pi + n * pj) as usize
+    }
+
+    /// Returns the type of the lens at site `(i, j)` of the grid
+    pub fn lens_type(self: &Self, i: i64, j: i64) -> usize {
+        self.pattern[self.pattern_site(i, j)]
+    }
+
+    /// Sorts fitted lenses into `types` classes by brightness
+    fn classify(self: &mut Self, types: usize) {
+        let mut values: Vec<F> = self.detections
+                                     .iter()
+                                     .filter(|d| d.index.is_some())
+                                     .map(|d| d.brightness)
+                                     .collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        // one-dimensional k-means, started at evenly spaced quantiles
+        let mut centers: Vec<F> = (0..types)
+                                      .map(|k| values[(2 * k + 1) * values.len() / (2 * types)])
+                                      .collect();
+        for _ in 0..32 {
+            let mut sums = vec![(F::zero(), 0usize); types];
+            for &v in values.iter() {
+                let k = nearest_center(&centers, v);
+                sums[k] = (sums[k].0 + v, sums[k].1 + 1);
+            }
+            for (c, &(sum, count)) in centers.iter_mut().zip(sums.iter()) {
+                if count > 0 {
+                    *c = sum / F::from_usize(count).unwrap();
+                }
+            }
+        }
+        self.type_brightness = centers;
+
+        let sites = types * types;
+        let mut votes = vec![vec![0usize; types]; sites];
+        for d in self.detections.iter() {
+            if let Some((i, j)) = d.index {
+                let k = nearest_center(&self.type_brightness, d.brightness);
+                votes[self.pattern_site(i, j)][k] += 1;
+            }
+        }
+        self.pattern = votes.iter()
+                            .map(|v| {
+                                (0..types).fold(0, |best, k| if v[k] > v[best] { k } else { best })
+                            })
+                            .collect();
+    }
+
+    /// Places a lens at every grid site on the detector, moved back onto
+    /// the microlens array
+    fn array(self: &Self, camera: &PlenopticCamera<F>, prototypes: &[Lens<F>]) -> Vec<Lens<F>> {
+        let half = F::from_f32(0.5f32).unwrap();
+        let (mut s0, mut s1, mut t0, mut t1) = self.geom.spatial_bounds();
+        s0 = s0 - self.grid.pitch * half;
+        s1 = s1 + self.grid.pitch * half;
+        t0 = t0 - self.grid.pitch * half;
+        t1 = t1 + self.grid.pitch * half;
+
+        let corners = [self.grid.coordinates(s0, t0),
+                       self.grid.coordinates(s1, t0),
+                       self.grid.coordinates(s0, t1),
+                       self.grid.coordinates(s1, t1)];
+        let i0 = corners.iter().fold(F::infinity(), |m, c| m.min(c.0)).floor().to_i64().unwrap();
+        let i1 = corners.iter().fold(F::neg_infinity(), |m, c| m.max(c.0)).ceil().to_i64().unwrap();
+        let j0 = corners.iter().fold(F::infinity(), |m, c| m.min(c.1)).floor().to_i64().unwrap();
+        let j1 = corners.iter().fold(F::neg_infinity(), |m, c| m.max(c.1)).ceil().to_i64().unwrap();
+
+        // lens image centers are lens centers magnified about the main lens
+        let magnification = F::one() + camera.distance_detector_array / camera.distance_lens_array;
+        let radius = self.grid.pitch * half / magnification;
+
+        let mut tr = Vec::new();
+        for j in j0..j1 + 1 {
+            for i in i0..i1 + 1 {
+                let (s, t) = self.grid.center(i, j);
+                if s < s0 || s > s1 || t < t0 || t > t1 {
+                    continue;
+                }
+                let mut lens = prototypes[self.lens_type(i, j)].clone();
+                lens.center_s = camera.lens.center_s +
+                                (s - camera.lens.center_s) / magnification;
+                lens.center_t = camera.lens.center_t +
+                                (t - camera.lens.center_t) / magnification;
+                lens.radius_s = radius;
+                lens.radius_t = radius;
+                tr.push(lens);
+            }
+        }
+        tr
+    }
+
+    /// Returns the root mean square distance of fitted detections from
+    /// their grid sites
+    pub fn rms_residual(self: &Self) -> F {
+        let (sum, count) = self.detections
+                               .iter()
+                               .filter(|d| d.index.is_some())
+                               .fold((F::zero(), 0usize),
+                                     |(sum, count), d| (sum + d.residual * d.residual, count + 1));
+        if count > 0 {
+            (sum / F::from_usize(count).unwrap()).sqrt()
+        } else {
+            F::zero()
+        }
+    }
+
+    /// Returns the largest distance of a fitted detection from its grid site
+    pub fn max_residual(self: &Self) -> F {
+        self.detections
+            .iter()
+            .filter(|d| d.index.is_some())
+            .fold(F::zero(), |m, d| m.max(d.residual))
+    }
+
+    /// Describes the fit and its residuals
+    pub fn report(self: &Self) -> String {
+        let f = |x: F| F::to_f64(&x).unwrap();
+        let pixel = f((self.geom.ds + self.geom.dt) / F::from_f32(2f32).unwrap());
+        let fitted = self.detections.iter().filter(|d| d.index.is_some()).count();
+
+        let mut tr = String::new();
+        tr.push_str(&format!("Layout: {}\n", self.grid.layout.name()));
+        tr.push_str(&format!("Pitch on detector: {} ({} pixels)\n",
+                             f(self.grid.pitch),
+                             f(self.grid.pitch) / pixel));
+        tr.push_str(&format!("Rotation: {} degrees\n",
+                             f(self.grid.rotation) * 180f64 / PI));
+        tr.push_str(&format!("Origin on detector: ({}, {})\n",
+                             f(self.grid.origin_s),
+                             f(self.grid.origin_t)));
+        tr.push_str(&format!("Lens images found: {} ({} fitted, {} outliers)\n",
+                             self.detections.len(),
+                             fitted,
+                             self.detections.len() - fitted));
+        tr.push_str(&format!("Residuals: rms {} pixels, max {} pixels\n",
+                             f(self.rms_residual()) / pixel,
+                             f(self.max_residual()) / pixel));
+        for (k, &b) in self.type_brightness.iter().enumerate() {
+            let count = self.detections
+                            .iter()
+                            .filter(|d| d.index.is_some())
+                            .filter(|d| nearest_center(&self.type_brightness, d.brightness) == k)
+                            .count();
+            tr.push_str(&format!("Type {}: brightness {}, {} lens images\n", k, f(b), count));
+        }
+        tr.push_str(&format!("Microlenses: {}\n", self.lenses.len()));
+
+        let mut worst: Vec<&LensDetection<F>> = self.detections.iter().collect();
+        worst.sort_by(|a, b| b.residual.partial_cmp(&a.residual).unwrap());
+        for d in worst.iter().take(5) {
+            tr.push_str(&format!("  lens image at ({}, {}): residual {} pixels{}\n",
+                                 f(d.center_s),
+                                 f(d.center_t),
+                                 f(d.residual) / pixel,
+                                 if d.index.is_none() { " (outlier)" } else { "" }));
+        }
+        tr
+    }
+}
+
+fn nearest_center<F: Float>(centers: &[F], v: F) -> usize {
+    (0..centers.len()).fold(0, |best, k| {
+        if (centers[k] - v).abs() < (centers[best] - v).abs() {
+            k
+        } else {
+            best
+        }
+    })
+}
+
+/// Plenoptic camera with a 128 x 128 detector and no microlens array yet
+#[cfg(test)]
+fn test_calibration_camera() -> PlenopticCamera<f32> {
+    use detector::*;
+
+    PlenopticCamera {
+        lens: Lens {
+            center_s: 0.03f32,
+            center_t: -0.02f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 50f32,
+            focal_length_t: 50f32,
+        },
+        detector: Detector {
+            ns: 128,
+            nt: 128,
+            ds: 0.01f32,
+            dt: 0.01f32,
+            offset_s: 0f32,
+            offset_t: 0f32,
+        },
+        distance_lens_array: 100f32,
+        distance_detector_array: 2f32,
+        array_path: String::new(),
+        array: None,
+        stop: None,
+        transfer: None,
+    }
+}
+
+/// White image with a lens image at each site of `grid`
+///
+/// The lens at site `(i, j)` has type `(i - j) mod types`, which repeats
+/// like the multi-focus tesselations, and images of type `k` are brighter
+/// with `k`.
+#[cfg(test)]
+fn test_white_image(camera: &PlenopticCamera<f32>,
+                    grid: &MicrolensGrid<f32>,
+                    types: i64)
+                    -> Vec<f32> {
+    let geom = camera.detector.image_geometry();
+    let radius = 0.4f32 * grid.pitch;
+    let mut white = vec![0.05f32; geom.ns * geom.nt];
+    for it in 0..geom.nt {
+        for is in 0..geom.ns {
+            let (s, t) = geom.pixel_center(is, it);
+            let (i0, j0) = grid.index(s, t);
+
+            // supersample the edges of the lens images
+            for sub in 0..16 {
+                let ps = s + ((sub % 4) as f32 - 1.5f32) / 4f32 * geom.ds;
+                let pt = t + ((sub / 4) as f32 - 1.5f32) / 4f32 * geom.dt;
+                for j in j0 - 1..j0 + 2 {
+                    for i in i0 - 1..i0 + 2 {
+                        let (cs, ct) = grid.center(i, j);
+                        if (ps - cs).hypot(pt - ct) < radius {
+                            let k = (((i - j) % types) + types) % types;
+                            white[geom.address_linear(is, it)] += (0.6f32 + 0.2f32 * k as f32) /
+                                                                  16f32;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    white
+}
+
+#[test]
+fn test_fit_microlens_grid() {
+    let camera = test_calibration_camera();
+    let prototype = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 1f32,
+        radius_t: 1f32,
+        focal_length_s: 2.5f32,
+        focal_length_t: 2.5f32,
+    };
+    let magnification = 1.02f32;
+
+    for &(layout, rotation) in [(LensLayout::Quad, 0.03f32),
+                                (LensLayout::Hex, 0.04f32),
+                                (LensLayout::HexT, -0.03f32)]
+                                   .iter() {
+        let grid = MicrolensGrid {
+            layout: layout,
+            pitch: 0.093f32,
+            rotation: rotation,
+            origin_s: 0.012f32,
+            origin_t: -0.007f32,
+        };
+        let white = test_white_image(&camera, &grid, 1);
+        let cal = MicrolensCalibration::new(&camera, &white, None, &[prototype.clone()]).unwrap();
+        println!("{}", cal.report());
+
+        assert_eq!(cal.grid.layout, layout);
+        assert!((cal.grid.pitch / grid.pitch - 1f32).abs() < 2e-3);
+        assert!((cal.grid.rotation - rotation).abs() < 2e-3);
+        assert!(cal.rms_residual() < 0.1f32 * camera.detector.ds);
+        assert!(cal.detections.iter().all(|d| d.index.is_some()));
+
+        // lenses are found where they are on the array, and fill it
+        for j in -4..5 {
+            for i in -4..5 {
+                let (s, t) = grid.center(i, j);
+                let ms = camera.lens.center_s + (s - camera.lens.center_s) / magnification;
+                let mt = camera.lens.center_t + (t - camera.lens.center_t) / magnification;
+                let nearest = cal.lenses
+                                 .iter()
+                                 .map(|l| (l.center_s - ms).hypot(l.center_t - mt))
+                                 .fold(1f32, |m, d| m.min(d));
+                assert!(nearest < 0.01f32 * grid.pitch);
+            }
+        }
+        let radius = grid.pitch / 2f32 / magnification;
+        assert!(cal.lenses.iter().all(|l| (l.radius_s - radius).abs() < 1e-3 * radius));
+        assert_eq!(cal.lenses[0].focal_length_s, 2.5f32);
+    }
+}
+
+#[test]
+fn test_classify_lens_types() {
+    let camera = test_calibration_camera();
+    let prototypes: Vec<Lens<f32>> = [2f32, 2.5f32, 3f32]
+                                         .iter()
+                                         .map(|&f| {
+                                             Lens {
+                                                 center_s: 0f32,
+                                                 center_t: 0f32,
+                                                 radius_s: 1f32,
+                                                 radius_t: 1f32,
+                                                 focal_length_s: f,
+                                                 focal_length_t: f,
+                                             }
+                                         })
+                                         .collect();
+    let grid = MicrolensGrid {
+        layout: LensLayout::Hex,
+        pitch: 0.093f32,
+        rotation: 0.02f32,
+        origin_s: 0.012f32,
+        origin_t: -0.007f32,
+    };
+    let white = test_white_image(&camera, &grid, 3);
+    let cal = MicrolensCalibration::new(&camera, &white, Some(LensLayout::Hex), &prototypes)
+                  .unwrap();
+    println!("{}", cal.report());
+    assert!(cal.report().contains("Layout: hex\n"));
+
+    assert_eq!(cal.type_brightness.len(), 3);
+    assert!(cal.type_brightness[0] + 0.1f32 < cal.type_brightness[1]);
+    assert!(cal.type_brightness[1] + 0.1f32 < cal.type_brightness[2]);
+
+    // every lens has its type, including those cut off by the border
+    let magnification = 1.02f32;
+    for lens in cal.lenses.iter() {
+        let s = camera.lens.center_s + (lens.center_s - camera.lens.center_s) * magnification;
+        let t = camera.lens.center_t + (lens.center_t - camera.lens.center_t) * magnification;
+        let (i, j) = grid.index(s, t);
+        let k = (((i - j) % 3) + 3) % 3;
+        assert_eq!(lens.focal_length_s, prototypes[k as usize].focal_length_s);
+    }
+}
+
+#[test]
+fn test_calibration_rejects_empty_inputs() {
+    let camera = test_calibration_camera();
+    let prototype = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 1f32,
+        radius_t: 1f32,
+        focal_length_s: 2.5f32,
+        focal_length_t: 2.5f32,
+    };
+    let grid = MicrolensGrid {
+        layout: LensLayout::Quad,
+        pitch: 0.093f32,
+        rotation: 0f32,
+        origin_s: 0f32,
+        origin_t: 0f32,
+    };
+    let white = test_white_image(&camera, &grid, 1);
+    let geom = camera.detector.image_geometry();
+
+    assert!(detect_lens_centers(&geom, &[]).is_none());
+    assert!(detect_lens_centers(&geom, &white[1..]).is_none());
+    assert!(detect_lens_centers(&geom, &white).unwrap().len() > 0);
+    assert!(MicrolensCalibration::new(&camera, &white, None, &[]).is_none());
+    assert!(MicrolensCalibration::new(&camera, &[], None, &[prototype]).is_none());
+}
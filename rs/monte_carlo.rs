@@ -0,0 +1,670 @@
+extern crate num;
+extern crate nalgebra;
+extern crate rand;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::nalgebra::Vector3;
+use self::rand::Rng;
+use light_volume::*;
+use detector::*;
+use optics::*;
+use ray_transfer::*;
+use lens::*;
+use aperture::*;
+use occluder::*;
+use bounding_geometry::*;
+use image_geom::*;
+use single_lens_camera::*;
+use coded_aperture_camera::*;
+use plenoptic_camera::*;
+
+/// Something a ray crosses between the detector and the angular plane
+#[derive(Clone, Debug)]
+pub enum RayElement<F: Float> {
+    /// Rim of a lens
+    Lens(Lens<F>),
+
+    /// Aperture stop
+    Stop(Aperture<F>),
+
+    /// Grayscale transmission image, looked up at the nearest pixel
+    Mask(ImageGeometry<F>, Vec<F>),
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> RayElement<F> {
+    /// Returns the fraction of light passing the element at `(s, t)`
+    pub fn transmission(self: &Self, s: F, t: F) -> F {
+        match self {
+            &RayElement::Lens(ref lens) => {
+                if lens.occludes(s, t) {
+                    F::zero()
+                } else {
+                    F::one()
+                }
+            }
+            &RayElement::Stop(ref stop) => {
+                if stop.occludes(s, t) {
+                    F::zero()
+                } else {
+                    F::one()
+                }
+            }
+            &RayElement::Mask(ref geom, ref mask) => {
+                let is = geom.s2is(s).floor();
+                let it = geom.t2it(t).floor();
+                if is < F::zero() || it < F::zero() {
+                    return F::zero();
+                }
+                let is = is.to_usize().unwrap();
+                let it = it.to_usize().unwrap();
+                if is >= geom.ns || it >= geom.nt {
+                    F::zero()
+                } else {
+                    mask[is + geom.ns * it]
+                }
+            }
+        }
+    }
+}
+
+/// One way light can take from the angular plane to the detector
+#[derive(Clone, Debug)]
+pub struct RayPath<F: Float> {
+    /// Optics from the detector to the angular plane
+    pub to_plane: Optics<F>,
+
+    /// Elements the ray crosses, with the optics from the detector to each
+    pub elements: Vec<(Optics<F>, RayElement<F>)>,
+}
+
+/// Ray-traced Monte Carlo reference for the volume imagers
+///
+/// Rays are sampled uniformly by where they hit a detector pixel and where
+/// they cross the angular plane, traced back through each `RayPath` and
+/// summed through the volume slice by slice.  Each ray is weighted by the
+/// etendue of the sample, so a pixel estimates the radiance integrated over
+/// the pixel and the directions reaching it.  This matches the discretized
+/// imagers up to their angular normalization; see `compare_to_reference`.
+///
+/// The volume is treated as purely emissive; `opaque` is ignored.
+pub struct MonteCarloProjector<F: Float> {
+    /// Volume in the camera's optical frame
+    pub geom: LightVolume<F>,
+    pub detector: Detector<F>,
+    pub paths: Vec<RayPath<F>>,
+
+    /// Region of the angular plane rays are sampled from, `(s0, s1, t0, t1)`
+    pub plane_bounds: (F, F, F, F),
+
+    /// Ray transfer from the angular plane to the `z=0` plane of the
+    /// volume, through the camera's main lens transfer
+    pub plane_to_object: RayTransfer<F>,
+
+    pub rays_per_pixel: usize,
+}
+
+/// Applies separable optics to a ray
+fn trace<F: Float>(optics: &Optics<F>, s: F, u: F, t: F, v: F) -> (F, F, F, F) {
+    (optics.ss * s + optics.su * u + optics.s,
+     optics.us * s + optics.uu * u + optics.u,
+     optics.tt * t + optics.tv * v + optics.t,
+     optics.vt * t + optics.vv * v + optics.v)
+}
+
+/// Returns the volume in the optical frame of a camera at `position`
+fn camera_frame<F: Float>(geom: &LightVolume<F>, position: &Vector3<F>) -> LightVolume<F> {
+    let mut frame_geom = geom.clone();
+    frame_geom.offset_x = frame_geom.offset_x + position.x / geom.dx;
+    frame_geom.offset_y = frame_geom.offset_y + position.y / geom.dy;
+    frame_geom
+}
+
+/// Main lens rim and stop, both on the angular plane
+fn main_lens_elements<F: Float>(lens: &Lens<F>,
+                                stop: &Option<Aperture<F>>,
+                                to_plane: &Optics<F>)
+                                -> Vec<(Optics<F>, RayElement<F>)> {
+    let mut tr = vec![(to_plane.clone(), RayElement::Lens(lens.clone()))];
+    if let &Some(ref stop) = stop {
+        tr.push((to_plane.clone(), RayElement::Stop(stop.clone())));
+    }
+    tr
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> MonteCarloProjector<F> {
+    fn new(geom: &LightVolume<F>,
+           detector: &Detector<F>,
+           lens: &Lens<F>,
+           lens_transfer: &RayTransfer<F>,
+           position: &Vector3<F>,
+           paths: Vec<RayPath<F>>)
+           -> Self {
+        MonteCarloProjector {
+            geom: camera_frame(geom, position),
+            detector: detector.clone(),
+            paths: paths,
+            plane_bounds: lens.bounding_geometry(1, 1).spatial_bounds(),
+            plane_to_object: lens_transfer.then(&RayTransfer::translation(&-position.z)),
+            rays_per_pixel: 1000,
+        }
+    }
+
+    /// Reference for `SingleLensVolumeImager`
+    pub fn new_single_lens(geom: &LightVolume<F>,
+                           camera: &SingleLensCamera<F>,
+                           position: Vector3<F>)
+                           -> Self {
+        let to_plane = Optics::translation(&camera.distance_detector_lens);
+        let path = RayPath {
+            elements: main_lens_elements(&camera.lens, &camera.stop, &to_plane),
+            to_plane: to_plane,
+        };
+        Self::new(geom,
+                  &camera.detector,
+                  &camera.lens,
+                  &camera.lens_transfer(),
+                  &position,
+                  vec![path])
+    }
+
+    /// Reference for `CodedApertureVolumeImager`
+    pub fn new_coded_aperture(geom: &LightVolume<F>,
+                              camera: &CodedApertureCamera<F>,
+                              position: Vector3<F>)
+                              -> Self {
+        let mask = match camera.mask {
+            Some(ref v) => v.clone(),
+            None => panic!("MonteCarloProjector::new_coded_aperture called with unloaded mask"),
+        };
+
+        let to_mask = Optics::translation(&camera.distance_detector_mask);
+        let to_plane = to_mask.then(&Optics::translation(&camera.distance_lens_mask));
+        let mut elements = vec![(to_mask,
+                                 RayElement::Mask(camera.mask_geometry.clone(), mask))];
+        elements.extend(main_lens_elements(&camera.lens, &camera.stop, &to_plane));
+        let path = RayPath {
+            to_plane: to_plane,
+            elements: elements,
+        };
+        Self::new(geom,
+                  &camera.detector,
+                  &camera.lens,
+                  &camera.lens_transfer(),
+                  &position,
+                  vec![path])
+    }
+
+    /// Reference for `PlenopticVolumeImager`, with one path per microlens
+    pub fn new_plenoptic(geom: &LightVolume<F>,
+                         camera: &PlenopticCamera<F>,
+                         position: Vector3<F>)
+                         -> Self {
+        let lenses = match camera.array {
+            Some(ref v) => v,
+            None => panic!("MonteCarloProjector::new_plenoptic called with unloaded lenses"),
+        };
+
+        let to_array = Optics::translation(&camera.distance_detector_array);
+        let to_main_lens = Optics::translation(&camera.distance_lens_array);
+        let mut paths = Vec::with_capacity(lenses.len());
+        for ulens in lenses.iter() {
+            let to_plane = to_array.then(&ulens.optics()).then(&to_main_lens);
+            let mut elements = vec![(to_array.clone(), RayElement::Lens(ulens.clone()))];
+            elements.extend(main_lens_elements(&camera.lens, &camera.stop, &to_plane));
+            paths.push(RayPath {
+                to_plane: to_plane,
+                elements: elements,
+            });
+        }
+        Self::new(geom,
+                  &camera.detector,
+                  &camera.lens,
+                  &camera.lens_transfer(),
+                  &position,
+                  paths)
+    }
+
+    /// Sums the volume along a ray leaving the `z=0` plane
+    ///
+    /// Each slice contributes its thickness times the path length factor
+    /// through it, as in `volume_scale`.
+    fn line_integral(self: &Self, vol: &[F], x0: F, ux: F, y0: F, uy: F) -> F {
+        let geom = &self.geom;
+        let half = F::from_f32(0.5f32).unwrap();
+        let np = geom.nx * geom.ny;
+        let mut tr = F::zero();
+        for iz in 0..geom.nz {
+            let z = geom.iz2z(iz);
+            let ix = ((x0 + z * ux) / geom.dx + geom.wx() + half).floor();
+            let iy = ((y0 + z * uy) / geom.dy + geom.wy() + half).floor();
+            if ix < F::zero() || iy < F::zero() {
+                continue;
+            }
+            let ix = ix.to_usize().unwrap();
+            let iy = iy.to_usize().unwrap();
+            if ix < geom.nx && iy < geom.ny {
+                tr = tr + vol[ix + geom.nx * iy + np * iz];
+            }
+        }
+        tr * geom.dz.abs() * (F::one() + ux * ux + uy * uy).sqrt()
+    }
+
+    /// Returns the sum over all paths of the light reaching detector
+    /// position `(s, t)` through plane position `(ps, pt)`
+    fn trace_ray(self: &Self, vol: &[F], s: F, t: F, ps: F, pt: F) -> F {
+        let mut tr = F::zero();
+        for path in self.paths.iter() {
+            let to_plane = &path.to_plane;
+            let u = (ps - to_plane.ss * s - to_plane.s) / to_plane.su;
+            let v = (pt - to_plane.tt * t - to_plane.t) / to_plane.tv;
+
+            // change of variables from directions on the detector to
+            // positions on the plane
+            let mut weight = F::one() / (to_plane.su * to_plane.tv).abs();
+            for &(ref to_element, ref element) in path.elements.iter() {
+                let (es, _, et, _) = trace(to_element, s, u, t, v);
+                weight = weight * element.transmission(es, et);
+                if weight == F::zero() {
+                    break;
+                }
+            }
+            if weight == F::zero() {
+                continue;
+            }
+
+            let (_, pu, _, pv) = trace(to_plane, s, u, t, v);
+            let obj = self.plane_to_object.apply(&[ps, pu, pt, pv]);
+            tr = tr + weight * self.line_integral(vol, obj[0], obj[1], obj[2], obj[3]);
+        }
+        tr
+    }
+
+    /// Estimates the detector image of a volume
+    pub fn forw<R: Rng>(self: &Self, vol: &[F], rng: &mut R) -> Vec<F> {
+        let det = self.detector.image_geometry();
+        let (ps0, ps1, pt0, pt1) = self.plane_bounds;
+        let n = F::from_usize(self.rays_per_pixel).unwrap();
+        let measure = (det.ds * det.dt * (ps1 - ps0) * (pt1 - pt0)).abs() / n;
+
+        let mut tr = Vec::with_capacity(det.ns * det.nt);
+        for it in 0..det.nt {
+            for is in 0..det.ns {
+                let (s0, s1, t0, t1) = det.pixel_bounds(is, it);
+                let mut acc = F::zero();
+                for _ in 0..self.rays_per_pixel {
+                    let s = s0 + (s1 - s0) * F::from_f64(rng.next_f64()).unwrap();
+                    let t = t0 + (t1 - t0) * F::from_f64(rng.next_f64()).unwrap();
+                    let ps = ps0 + (ps1 - ps0) * F::from_f64(rng.next_f64()).unwrap();
+                    let pt = pt0 + (pt1 - pt0) * F::from_f64(rng.next_f64()).unwrap();
+                    acc = acc + self.trace_ray(vol, s, t, ps, pt);
+                }
+                tr.push(acc * measure);
+            }
+        }
+        tr
+    }
+}
+
+/// Compares a projection with a Monte Carlo reference
+///
+/// The imagers' normalization depends on the angular basis, so the
+/// reference is first scaled to fit `image` in the least squares sense.
+/// Returns the normalized RMS error and the fitted scale.
+pub fn compare_to_reference<F: Float>(reference: &[F], image: &[F]) -> (F, F) {
+    let (rr, ri, ii) = reference.iter()
+                                .zip(image.iter())
+                                .fold((F::zero(), F::zero(), F::zero()), |(rr, ri, ii), (&r, &i)| {
+                                    (rr + r * r, ri + r * i, ii + i * i)
+                                });
+    let scale = ri / rr;
+    let err = reference.iter()
+                       .zip(image.iter())
+                       .fold(F::zero(), |e, (&r, &i)| e + (i - scale * r) * (i - scale * r));
+    ((err / ii).sqrt(), scale)
+}
+
+#[cfg(test)]
+fn test_scene() -> (LightVolume<f32>, SingleLensCamera<f32>, Vector3<f32>, Vec<f32>) {
+    use single_lens_imager::*;
+    use self::rand::{SeedableRng, XorShiftRng};
+
+    // focused near the middle of the volume, which fills most of the
+    // detector; the near and far slices are out of focus
+    let mut camera = test_camera();
+    camera.distance_detector_lens = 60f32;
+    let vg = test_volume();
+    let position = Vector3::new(0.3f32, -0.2f32, -30f32);
+
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let x = (0..vg.nx * vg.ny * vg.nz).map(|_| rng.next_f32()).collect();
+    (vg, camera, position, x)
+}
+
+#[cfg(test)]
+fn test_coded_camera(rng: &mut self::rand::XorShiftRng) -> CodedApertureCamera<f32> {
+    let (_, camera, _, _) = test_scene();
+    let mask_geometry = camera.detector.image_geometry();
+    let mask = (0..mask_geometry.ns * mask_geometry.nt)
+                   .map(|_| if rng.next_f32() < 0.5 { 0f32 } else { 1f32 })
+                   .collect();
+    CodedApertureCamera {
+        lens: camera.lens,
+        detector: camera.detector,
+        mask_geometry: mask_geometry,
+        distance_lens_mask: 55f32,
+        distance_detector_mask: 5f32,
+        mask_path: String::new(),
+        mask: Some(mask),
+        stop: None,
+        transfer: None,
+    }
+}
+
+#[cfg(test)]
+fn test_plenoptic_camera() -> PlenopticCamera<f32> {
+    let (_, camera, _, _) = test_scene();
+    let ulens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 1f32,
+        radius_t: 1f32,
+        focal_length_s: 2f32,
+        focal_length_t: 2f32,
+    };
+    let detector = Detector {
+        ns: 64,
+        nt: 64,
+        ds: 0.25,
+        dt: 0.25,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+    let ig = detector.image_geometry();
+    PlenopticCamera {
+        lens: camera.lens,
+        detector: detector,
+        distance_lens_array: 60f32,
+        distance_detector_array: 0.8f32,
+        array_path: String::new(),
+        array: Some(Lens::tesselate_quad_1(-2f32, -2f32, &ig, &ulens)),
+        stop: None,
+        transfer: None,
+    }
+}
+
+#[cfg(test)]
+fn test_bases() -> Vec<(::angular_plane::AngularBasis, usize)> {
+    use angular_plane::*;
+
+    // Dirac samples need a finer plane to resolve the defocus blur
+    vec![(AngularBasis::Dirac, 15), (AngularBasis::Pillbox, 7), (AngularBasis::Linear, 7)]
+}
+
+/// Fitted scale an imager's projections should have against the Monte
+/// Carlo reference
+///
+/// Projecting onto the detector divides by the root of the pixel volume of
+/// `detector_lfg`.  The Dirac kernels skip that and the slice thickness,
+/// and count every sample's whole cell; when the volume is first
+/// transported onto `intermediate` (a mask or microlens array), the
+/// second transport applies the sample weights and divides by the root of
+/// the detector's pixel volume instead.
+#[cfg(test)]
+fn expected_scale(detector_lfg: &::light_field_geom::LightFieldGeometry<f32>,
+                  intermediate: Option<&::light_field_geom::LightFieldGeometry<f32>>,
+                  dz: f32)
+                  -> f32 {
+    use angular_plane::*;
+
+    let plane = &detector_lfg.plane;
+    match (&plane.basis, intermediate) {
+        (&AngularBasis::Dirac, None) => {
+            plane.na() as f32 / (dz.abs() * plane.w.iter().fold(0f32, |s, w| s + w))
+        }
+        (&AngularBasis::Dirac, Some(lfg)) => {
+            lfg.pixel_volume() / (dz.abs() * detector_lfg.pixel_volume().sqrt())
+        }
+        _ => 1f32 / detector_lfg.pixel_volume().sqrt(),
+    }
+}
+
+/// Projects `x` with `imager` and checks it against the Monte Carlo
+/// reference, both in shape and in the fitted scale
+#[cfg(test)]
+fn assert_matches_reference<I, B>(imager: &mut I,
+                                  reference: &[f32],
+                                  x: &[f32],
+                                  queue: &B::Queue,
+                                  expected: f32,
+                                  tolerance: f32)
+    where I: ::imager::Imager<f32, LightVolume<f32>, B> + ?Sized,
+          B: ::backend::Backend<f32>
+{
+    let img = imager.forw_host(x, queue).unwrap();
+    let (nrmse, scale) = compare_to_reference(reference, &img);
+    println!("{:?} with {} angles: NRMSE {} scale {} (expected {})",
+             imager.angular_plane().basis,
+             imager.na(),
+             nrmse,
+             scale,
+             expected);
+    assert!(nrmse < tolerance);
+    assert!((scale / expected - 1f32).abs() < tolerance);
+}
+
+/// Volume that is unchanged by rotations about the `z` axis through its
+/// center, up to interpolation
+#[cfg(test)]
+fn radial_phantom(vg: &LightVolume<f32>) -> Vec<f32> {
+    let mut tr = Vec::with_capacity(vg.nx * vg.ny * vg.nz);
+    for iz in 0..vg.nz {
+        for iy in 0..vg.ny {
+            for ix in 0..vg.nx {
+                let (x, y) = (vg.ix2x(ix), vg.iy2y(iy));
+                let r2 = (x * x + y * y) / 4f32;
+                tr.push((1f32 + iz as f32 / vg.nz as f32) * (-r2).exp());
+            }
+        }
+    }
+    tr
+}
+
+#[test]
+fn test_monte_carlo_matches_host_imager() {
+    use single_lens_imager::*;
+    use light_field_geom::*;
+    use imager::*;
+    use backend::*;
+    use self::rand::{SeedableRng, XorShiftRng};
+
+    let (vg, camera, position, x) = test_scene();
+    let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+    let reference = MonteCarloProjector::new_single_lens(&vg, &camera, position)
+                        .forw(&x, &mut rng);
+
+    for (basis, na) in test_bases().into_iter() {
+        let mut imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                         camera.clone(),
+                                                         position,
+                                                         na,
+                                                         basis.clone());
+        let detector_lfg = LightFieldGeometry {
+            geom: camera.detector.image_geometry(),
+            plane: imager.angular_plane().clone(),
+            to_plane: Optics::translation(&camera.distance_detector_lens),
+        };
+        let expected = expected_scale(&detector_lfg, None, vg.dz);
+        assert_matches_reference(&mut imager, &reference, &x, &HostQueue, expected, 0.05);
+    }
+}
+
+#[test]
+fn test_monte_carlo_traces_lens_transfer() {
+    use angular_plane::*;
+    use single_lens_imager::*;
+    use light_field_geom::*;
+    use imager::*;
+    use backend::*;
+    use self::rand::{SeedableRng, XorShiftRng};
+
+    // a cylindrical lens rotated by a twelfth of a turn couples s and t
+    let (vg, mut camera, position, x) = test_scene();
+    let mut cylinder = camera.lens.clone();
+    cylinder.focal_length_t = 24f32;
+    camera.transfer = Some(RayTransfer::from_optics(&cylinder.optics())
+                               .rotated(::std::f32::consts::PI / 6f32));
+    assert!(!camera.lens_transfer().is_separable());
+
+    let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+    let reference = MonteCarloProjector::new_single_lens(&vg, &camera, position)
+                        .forw(&x, &mut rng);
+
+    // the transfer changes the reference
+    let mut plain = camera.clone();
+    plain.transfer = None;
+    let plain_reference = MonteCarloProjector::new_single_lens(&vg, &plain, position)
+                              .forw(&x, &mut rng);
+    let (nrmse, _) = compare_to_reference(&plain_reference, &reference);
+    println!("NRMSE between plain and rotated lens references: {}", nrmse);
+    assert!(nrmse > 0.1);
+
+    let (basis, na) = (AngularBasis::Pillbox, 7);
+    let mut imager = HostSingleLensVolumeImager::new(vg.clone(),
+                                                     camera.clone(),
+                                                     position,
+                                                     na,
+                                                     basis.clone());
+    let detector_lfg = LightFieldGeometry {
+        geom: camera.detector.image_geometry(),
+        plane: imager.angular_plane().clone(),
+        to_plane: Optics::translation(&camera.distance_detector_lens),
+    };
+    let expected = expected_scale(&detector_lfg, None, vg.dz);
+    assert_matches_reference(&mut imager, &reference, &x, &HostQueue, expected, 0.05);
+}
+
+#[test]
+fn test_monte_carlo_matches_opencl_imagers() {
+    use env::*;
+    use camera::*;
+    use isometry::*;
+    use imager::*;
+    use light_field_geom::*;
+    use single_lens_imager::*;
+    use coded_aperture_imager::*;
+    use plenoptic_imager::*;
+    use self::rand::{SeedableRng, XorShiftRng};
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let (vg, camera, position, x) = test_scene();
+    let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+    let coded = test_coded_camera(&mut rng);
+    let plenoptic = test_plenoptic_camera();
+
+    let single_lens_reference = MonteCarloProjector::new_single_lens(&vg, &camera, position)
+                                    .forw(&x, &mut rng);
+    let coded_reference = MonteCarloProjector::new_coded_aperture(&vg, &coded, position)
+                              .forw(&x, &mut rng);
+    let plenoptic_reference = MonteCarloProjector::new_plenoptic(&vg, &plenoptic, position)
+                                  .forw(&x, &mut rng);
+
+    // rotating a radially symmetric volume about the optical axis leaves
+    // its projections alone
+    let radial = radial_phantom(&vg);
+    let on_axis = Vector3::new(0f32, 0f32, position.z);
+    let rotated_reference = MonteCarloProjector::new_single_lens(&vg, &camera, on_axis)
+                                .forw(&radial, &mut rng);
+    let config = CameraConfig::SingleLensCamera(camera.clone());
+
+    for (basis, na) in test_bases().into_iter() {
+        let mut single_lens = SingleLensVolumeImager::new(vg.clone(),
+                                                          camera.clone(),
+                                                          position,
+                                                          na,
+                                                          basis.clone(),
+                                                          queue.clone())
+                                  .unwrap();
+        let detector_lfg = LightFieldGeometry {
+            geom: camera.detector.image_geometry(),
+            plane: single_lens.angular_plane().clone(),
+            to_plane: Optics::translation(&camera.distance_detector_lens),
+        };
+        assert_matches_reference(&mut single_lens,
+                                 &single_lens_reference,
+                                 &x,
+                                 queue,
+                                 expected_scale(&detector_lfg, None, vg.dz),
+                                 0.05);
+
+        let mut coded_imager = CodedApertureVolumeImager::new(vg.clone(),
+                                                              coded.clone(),
+                                                              position,
+                                                              na,
+                                                              basis.clone(),
+                                                              queue.clone())
+                                   .unwrap();
+        let plane = coded_imager.angular_plane().clone();
+        let mask_lfg = LightFieldGeometry {
+            geom: coded.mask_geometry.clone(),
+            plane: plane.clone(),
+            to_plane: Optics::translation(&coded.distance_lens_mask),
+        };
+        let detector_lfg = LightFieldGeometry {
+            geom: coded.detector.image_geometry(),
+            plane: plane,
+            to_plane: Optics::translation(&(coded.distance_lens_mask +
+                                            coded.distance_detector_mask)),
+        };
+        assert_matches_reference(&mut coded_imager,
+                                 &coded_reference,
+                                 &x,
+                                 queue,
+                                 expected_scale(&detector_lfg, Some(&mask_lfg), vg.dz),
+                                 0.1);
+
+        let mut plenoptic_imager = PlenopticVolumeImager::new(vg.clone(),
+                                                              plenoptic.clone(),
+                                                              position,
+                                                              na,
+                                                              basis.clone(),
+                                                              queue.clone())
+                                       .unwrap();
+        // every microlens has the same optics, so the first stands for all
+        let array_lfg = plenoptic.array_light_field_geometry(basis.clone(), na);
+        let ulens = &plenoptic.array.as_ref().unwrap()[0];
+        let detector_lfg = LightFieldGeometry {
+            geom: plenoptic.detector.image_geometry(),
+            plane: array_lfg.plane.clone(),
+            to_plane: Optics::translation(&plenoptic.distance_detector_array)
+                          .then(&ulens.optics())
+                          .then(&array_lfg.to_plane),
+        };
+        assert_matches_reference(&mut plenoptic_imager,
+                                 &plenoptic_reference,
+                                 &x,
+                                 queue,
+                                 expected_scale(&detector_lfg, Some(&array_lfg), vg.dz),
+                                 0.1);
+
+        let mut rotated_imager = config.volume_imager(vg.clone(),
+                                                      on_axis,
+                                                      Some(rotation_z(30f32)),
+                                                      na,
+                                                      basis.clone(),
+                                                      queue.clone())
+                                       .unwrap();
+        let detector_lfg = LightFieldGeometry {
+            geom: camera.detector.image_geometry(),
+            plane: rotated_imager.angular_plane().clone(),
+            to_plane: Optics::translation(&camera.distance_detector_lens),
+        };
+        let dz = rotated_imager.geometry().dz;
+        assert_matches_reference(&mut *rotated_imager,
+                                 &rotated_reference,
+                                 &radial,
+                                 queue,
+                                 expected_scale(&detector_lfg, None, dz),
+                                 0.1);
+    }
+}
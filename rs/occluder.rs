@@ -7,10 +7,11 @@ pub trait Occluder<F: Float + FromPrimitive> {
     /// Returns `true` if the object occludes at this point, `false` if it does not
     fn occludes(self: &Self, s: F, t: F) -> bool;
 
-    /// Returns fraction of the rectangular region not occluded by this object
+    /// Returns fraction of the rectangular region occluded by this object
     ///
-    /// Complete occlusion is 0, partial occlusion between 0 and 1, and no
-    /// occlusion is 1.
+    /// No occlusion is 0, partial occlusion between 0 and 1, and complete
+    /// occlusion is 1.  The default samples `occludes` on a grid with
+    /// `discretization` points on each side.
     fn rasterize(self: &Self, s0: F, s1: F, t0: F, t1: F, discretization: usize) -> F {
         let mut tr = F::zero();
         let mut denom = F::zero();
@@ -1,12 +1,10 @@
 extern crate num;
 extern crate rand;
 extern crate toml;
-extern crate byteorder;
 use self::toml::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use serialize::*;
 use cl_traits::*;
-use self::byteorder::*;
 
 /// Affine optical transformation for light transport
 #[derive(Clone, Debug)]
@@ -290,20 +288,20 @@ impl<F: Float> ClHeader for Optics<F> {
 
 impl<F: Float + ToPrimitive> ClBuffer for Optics<F> {
     fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.ss).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.su).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.us).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.uu).unwrap()).unwrap();
-
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.tt).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.tv).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.vt).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.vv).unwrap()).unwrap();
-
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.s).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.t).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.u).unwrap()).unwrap();
-        buf.write_f32::<LittleEndian>(F::to_f32(&self.v).unwrap()).unwrap();
+        write_real(buf, &self.ss);
+        write_real(buf, &self.su);
+        write_real(buf, &self.us);
+        write_real(buf, &self.uu);
+
+        write_real(buf, &self.tt);
+        write_real(buf, &self.tv);
+        write_real(buf, &self.vt);
+        write_real(buf, &self.vv);
+
+        write_real(buf, &self.s);
+        write_real(buf, &self.t);
+        write_real(buf, &self.u);
+        write_real(buf, &self.v);
     }
 }
 
@@ -8,6 +8,8 @@ use ellipsoid::*;
 use optics::*;
 use light_volume::*;
 use cl_traits::*;
+use program_cache::*;
+use profiler::*;
 
 /// Renderer for phantoms
 pub struct PhantomRenderer<F: Float> {
@@ -35,10 +37,7 @@ impl<F: Float + FromPrimitive> PhantomRenderer<F> {
                         Self::header()];
 
         // compile opencl code
-        let context = try!(queue.context());
-        let device = try!(queue.device());
-        let unbuilt = try!(Program::new_from_source(context.clone(), sources));
-        let program = try!(unbuilt.build(&[device]));
+        let program = try!(cached_program::<F>(&queue, sources));
 
         // get kernels
         let render_ellipsoid = try!(program.create_kernel("render_ellipsoid"));
@@ -80,10 +79,12 @@ impl<F: Float + FromPrimitive> PhantomRenderer<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.geom.nx, self.geom.ny, self.geom.nz);
 
-        self.queue.run_with_events(&mut self.render_ellipsoid,
-                                   local_size,
-                                   global_size,
-                                   wait_for)
+        run_kernel(&self.queue,
+                   &mut self.render_ellipsoid,
+                   "render_ellipsoid",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 }
 
@@ -106,6 +107,7 @@ fn test_phantom_renderer() {
         offset_y: 2.0,
         offset_z: 3.0,
         opaque: false,
+        attenuation: 1.0,
     };
 
     let mut v_buf = vg.zeros_buf(&queue).unwrap();
@@ -4,10 +4,14 @@ use serialize::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::toml::*;
 use lens::*;
+use aperture::*;
+use angular_plane::*;
 use detector::*;
 use std::path::Path;
 use scene::*;
 use optics::*;
+use ray_transfer::*;
+use light_field_geom::*;
 
 #[derive(Clone, Debug)]
 pub struct PlenopticCamera<F: Float> {
@@ -17,33 +21,71 @@ pub struct PlenopticCamera<F: Float> {
     pub distance_detector_array: F,
     pub array_path: String,
     pub array: Option<Vec<Lens<F>>>,
+
+    /// Aperture stop replacing the main lens' aperture, if any
+    pub stop: Option<Aperture<F>>,
+
+    /// Ray transfer replacing the main lens' thin lens optics, if any
+    pub transfer: Option<RayTransfer<F>>,
 }
 
 impl<F: Float + FromPrimitive> PlenopticCamera<F> {
-    pub fn focus_at_distance(self: &mut Self, focus_distance: F) {
-        let pre_optics = Optics::identity();
-        let post_optics = self.lens.optics().then(&Optics::translation(&focus_distance));
-        let (distance_s, distance_t) = Optics::focus_at_distance(&pre_optics, &post_optics);
+    /// Returns the angular plane on the main lens
+    pub fn angular_plane(self: &Self, basis: AngularBasis, na: usize) -> AngularPlane<F> {
+        main_lens_angular_plane(&self.lens, &self.stop, basis, na)
+    }
+
+    /// Returns the ray transfer through the main lens
+    pub fn lens_transfer(self: &Self) -> RayTransfer<F> {
+        main_lens_transfer(&self.lens, &self.transfer)
+    }
+
+    /// Returns the light field geometry on the microlens array, with angles
+    /// on the main lens
+    pub fn array_light_field_geometry(self: &Self,
+                                      basis: AngularBasis,
+                                      na: usize)
+                                      -> LightFieldGeometry<F> {
+        LightFieldGeometry {
+            geom: self.detector.image_geometry(),
+            plane: self.angular_plane(basis, na),
+            to_plane: Optics::translation(&self.distance_lens_array),
+        }
+    }
+
+    /// Moves the microlens array so that the main lens focuses onto it at
+    /// `focus_distance`
+    ///
+    /// Returns `Err` if the main lens has no real focus.
+    pub fn focus_at_distance(self: &mut Self, focus_distance: F) -> Result<(), ()> {
+        let pre_optics = RayTransfer::identity();
+        let post_optics = self.lens_transfer().then(&RayTransfer::translation(&focus_distance));
+        let (distance_s, distance_t) = try!(RayTransfer::focus_at_distance(&pre_optics,
+                                                                            &post_optics));
         self.distance_lens_array = (distance_s + distance_t) / (F::one() + F::one());
+        Ok(())
     }
 
     pub fn describe(self: &Self) -> String {
-        let main_lens_optics = Optics::translation(&self.distance_lens_array).then(&self.lens.optics());
-        let (dms, dmt) = main_lens_optics.focused_distance();
+        let main_lens_optics = RayTransfer::translation(&self.distance_lens_array).then(&self.lens_transfer());
+        let (dms, dmt) = match main_lens_optics.focused_distance() {
+            Ok(focus) => focus,
+            Err(_) => return "Plenoptic camera with a main lens that does not focus".to_string(),
+        };
         let mut ulens_focus = Vec::new();
 
         if let Some(ref array) = self.array {
             for lens in array.iter() {
-                let ulens_optics = Optics::translation(&self.distance_detector_array).then(&lens.optics());
+                let ulens_optics = RayTransfer::translation(&self.distance_detector_array).then(&RayTransfer::from_optics(&lens.optics()));
                 let total_optics = ulens_optics.then(&main_lens_optics);
-                let (us, ut) = total_optics.focused_distance();
-                ulens_focus.push(F::to_f32(&us).unwrap());
-                ulens_focus.push(F::to_f32(&ut).unwrap());
+                if let Ok((us, ut)) = total_optics.focused_distance() {
+                    ulens_focus.push(F::to_f32(&us).unwrap());
+                    ulens_focus.push(F::to_f32(&ut).unwrap());
+                }
             }
         } else {
             panic!("Must call describe() with a loaded microlens array");
         }
-
         ulens_focus.sort_by(|a,b| a.partial_cmp(b).unwrap());
         ulens_focus.dedup();
 
@@ -54,6 +96,14 @@ impl<F: Float + FromPrimitive> PlenopticCamera<F> {
 
 impl<F: Float + FromPrimitive + ToPrimitive> Serialize for PlenopticCamera<F> {
     fn from_map(map: &Table) -> Option<Self> {
+        let stop = match stop_from_map(map) {
+            Ok(stop) => stop,
+            Err(_) => return None,
+        };
+        let transfer = match transfer_from_map(map) {
+            Ok(transfer) => transfer,
+            Err(_) => return None,
+        };
         let lens = map.get("lens");
         let detector = map.get("detector");
         let distance_lens_array = map.get("distance_lens_array");
@@ -79,6 +129,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for PlenopticCamera<F> {
                             distance_detector_array: F::from_f64(distance_detector_array).unwrap(),
                             array_path: array_path.clone(),
                             array: None,
+                            stop: stop,
+                            transfer: transfer,
                         })
                     }
                     _ => None,
@@ -99,10 +151,19 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for PlenopticCamera<F> {
                   Value::Float(F::to_f64(&self.distance_detector_array).unwrap()));
         tr.insert("array_path".to_string(),
                   Value::String(self.array_path.clone()));
+        if let Some(ref stop) = self.stop {
+            tr.insert("stop".to_string(), Value::Table(stop.into_map()));
+        }
+        if let Some(ref transfer) = self.transfer {
+            tr.insert("transfer".to_string(), Value::Table(transfer.into_map()));
+        }
         tr
     }
 
     fn load_assets<P: AsRef<Path>>(self: &mut Self, root_path: P) -> Result<(), ()> {
+        if let Some(ref mut stop) = self.stop {
+            try!(stop.load_assets(root_path.as_ref()));
+        }
         if self.array.is_none() {
             let mut pb = root_path.as_ref().to_path_buf();
             pb.pop(); // pop off configuration file name
@@ -0,0 +1,480 @@
+extern crate num;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use geom::*;
+use image_geom::*;
+use angular_plane::*;
+use light_field_geom::*;
+use light_field::*;
+use optics::*;
+use lens::*;
+use occluder::*;
+use plenoptic_camera::*;
+
+/// How raw detector values are read between pixel centers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Interpolation::Nearest),
+            "bilinear" => Some(Interpolation::Bilinear),
+            _ => None,
+        }
+    }
+
+    pub fn name(self: &Self) -> &'static str {
+        match self {
+            &Interpolation::Nearest => "nearest",
+            &Interpolation::Bilinear => "bilinear",
+        }
+    }
+}
+
+/// Correction for light lost towards the edges of each microlens image
+#[derive(Clone, Debug)]
+pub enum Vignetting<F: Float> {
+    /// Raw values are used as they are
+    None,
+
+    /// Raw values are divided by the fraction of their pixel the microlens
+    /// covers; pixels less than half covered are dropped
+    Coverage,
+
+    /// Raw values are divided by a flat-field ("white") capture taken with
+    /// the same camera settings
+    White(Vec<F>),
+}
+
+/// Resamples raw plenoptic captures into sub-aperture views
+///
+/// A detector pixel behind a microlens sees along the chief ray through the
+/// microlens' center, so it samples the light field on the microlens array
+/// at that center, from the point where the ray meets the main lens.  Each
+/// view is read from the detector where its angle on the main lens images
+/// to under every microlens; samples that fall outside a microlens' image
+/// are zero.
+///
+/// The decoded light field has a Dirac angular plane on the main lens, like
+/// `PlenopticCamera::array_light_field_geometry`, so it can be refocused
+/// or exported as a `SubapertureGrid` directly.
+#[derive(Clone, Debug)]
+pub struct PlenopticDecoder<F: Float> {
+    pub camera: PlenopticCamera<F>,
+
+    /// Geometry of the decoded light field
+    pub lfg: LightFieldGeometry<F>,
+
+    pub interpolation: Interpolation,
+    pub vignetting: Vignetting<F>,
+
+    // microlens nearest each spatial sample of `lfg`
+    lens_map: Vec<Option<usize>>,
+}
+
+/// Returns the lens whose center is nearest each pixel of `geom`
+///
+/// Pixels further than a lens radius and a pixel from every lens' bounding
+/// box have no lens.
+fn nearest_lens_map<F>(geom: &ImageGeometry<F>, lenses: &[Lens<F>]) -> Vec<Option<usize>>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let mut tr = vec![None; geom.dimension()];
+    let mut best = vec![F::infinity(); geom.dimension()];
+    for (lens_id, lens) in lenses.iter().enumerate() {
+        let rs = lens.radius_s + geom.ds.abs();
+        let rt = lens.radius_t + geom.dt.abs();
+        let (is0, is1, it0, it1) = geom.region_pixels(lens.center_s - rs,
+                                                      lens.center_s + rs,
+                                                      lens.center_t - rt,
+                                                      lens.center_t + rt);
+        for it in it0..it1 {
+            for is in is0..is1 {
+                let (s, t) = geom.pixel_center(is, it);
+                let d = ((s - lens.center_s) / lens.radius_s).powi(2) +
+                        ((t - lens.center_t) / lens.radius_t).powi(2);
+                let ip = geom.address_linear(is, it);
+                if d < best[ip] {
+                    best[ip] = d;
+                    tr[ip] = Some(lens_id);
+                }
+            }
+        }
+    }
+    tr
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> PlenopticDecoder<F> {
+    /// Creates a decoder for views on `geom` with `na` angles across the
+    /// main lens
+    ///
+    /// `camera` must have its microlens array loaded.
+    pub fn new(camera: PlenopticCamera<F>,
+               geom: ImageGeometry<F>,
+               na: usize,
+               interpolation: Interpolation,
+               vignetting: Vignetting<F>)
+               -> Self {
+        let lens_map = match camera.array {
+            Some(ref lenses) => nearest_lens_map(&geom, lenses),
+            None => panic!("PlenopticDecoder::new called with unloaded lenses"),
+        };
+        let lfg = LightFieldGeometry {
+            geom: geom,
+            plane: camera.angular_plane(AngularBasis::Dirac, na),
+            to_plane: Optics::translation(&camera.distance_lens_array),
+        };
+
+        PlenopticDecoder {
+            camera: camera,
+            lfg: lfg,
+            interpolation: interpolation,
+            vignetting: vignetting,
+            lens_map: lens_map,
+        }
+    }
+
+    /// Returns a geometry with about one pixel per microlens
+    ///
+    /// The pixel pitch is the diameter of the first microlens, and pixels
+    /// are centered on the span of microlens centers.
+    pub fn lens_grid_geometry(camera: &PlenopticCamera<F>) -> ImageGeometry<F> {
+        let lenses = match camera.array {
+            Some(ref lenses) => lenses,
+            None => panic!("lens_grid_geometry called with unloaded lenses"),
+        };
+        let c2 = F::from_f32(2f32).unwrap();
+        let (s0, s1, t0, t1) = lenses.iter().fold((F::infinity(),
+                                                   F::neg_infinity(),
+                                                   F::infinity(),
+                                                   F::neg_infinity()),
+                                                  |(s0, s1, t0, t1), lens| {
+                                                      (s0.min(lens.center_s),
+                                                       s1.max(lens.center_s),
+                                                       t0.min(lens.center_t),
+                                                       t1.max(lens.center_t))
+                                                  });
+        let ds = c2 * lenses[0].radius_s;
+        let dt = c2 * lenses[0].radius_t;
+        ImageGeometry {
+            ns: ((s1 - s0) / ds).round().to_usize().unwrap() + 1,
+            nt: ((t1 - t0) / dt).round().to_usize().unwrap() + 1,
+            ds: ds,
+            dt: dt,
+            offset_s: -(s0 + s1) / c2 / ds,
+            offset_t: -(t0 + t1) / c2 / dt,
+        }
+    }
+
+    /// Light field the decoder writes
+    pub fn light_field(self: &Self) -> LightField<F> {
+        LightField::new(self.lfg.clone())
+    }
+
+    /// Returns where the chief ray from `(s, t)` on the main lens through
+    /// `lens` meets the detector
+    fn detector_position(self: &Self, lens: &Lens<F>, s: F, t: F) -> (F, F) {
+        let scale = self.camera.distance_detector_array / self.camera.distance_lens_array;
+        (lens.center_s - (s - lens.center_s) * scale,
+         lens.center_t - (t - lens.center_t) * scale)
+    }
+
+    /// Returns a raw pixel corrected for vignetting, if `lens` images onto it
+    fn pixel(self: &Self, raw: &[F], lens: &Lens<F>, is: usize, it: usize) -> Option<F> {
+        let geom = self.camera.detector.image_geometry();
+        let (s, t) = geom.pixel_center(is, it);
+        if lens.occludes(s, t) {
+            return None;
+        }
+
+        let ip = geom.address_linear(is, it);
+        match self.vignetting {
+            Vignetting::None => Some(raw[ip]),
+            Vignetting::Coverage => {
+                let (s0, s1, t0, t1) = geom.pixel_bounds(is, it);
+                let coverage = F::one() - lens.rasterize(s0, s1, t0, t1, 10);
+                if coverage < F::from_f32(0.5f32).unwrap() {
+                    None
+                } else {
+                    Some(raw[ip] / coverage)
+                }
+            }
+            Vignetting::White(ref white) => {
+                if white[ip] > F::zero() {
+                    Some(raw[ip] / white[ip])
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Reads the raw capture at `(s, t)` on the detector, within `lens`' image
+    fn sample(self: &Self, raw: &[F], lens: &Lens<F>, s: F, t: F) -> Option<F> {
+        let geom = self.camera.detector.image_geometry();
+        let half = F::from_f32(0.5f32).unwrap();
+        let ns = F::from_usize(geom.ns).unwrap();
+        let nt = F::from_usize(geom.nt).unwrap();
+
+        // fractional index of the pixel centers around (s, t)
+        let x = geom.s2is(s) - half;
+        let y = geom.t2it(t) - half;
+
+        match self.interpolation {
+            Interpolation::Nearest => {
+                let (x, y) = (x.round(), y.round());
+                if x < F::zero() || y < F::zero() || x >= ns || y >= nt {
+                    return None;
+                }
+                self.pixel(raw, lens, x.to_usize().unwrap(), y.to_usize().unwrap())
+            }
+            Interpolation::Bilinear => {
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let mut accum = F::zero();
+                let mut total = F::zero();
+                for &(dx, wx) in [(F::zero(), F::one() - fx), (F::one(), fx)].iter() {
+                    for &(dy, wy) in [(F::zero(), F::one() - fy), (F::one(), fy)].iter() {
+                        let (xi, yi) = (x0 + dx, y0 + dy);
+                        let w = wx * wy;
+                        if w == F::zero() || xi < F::zero() || yi < F::zero() || xi >= ns ||
+                           yi >= nt {
+                            continue;
+                        }
+                        let is = xi.to_usize().unwrap();
+                        let it = yi.to_usize().unwrap();
+                        if let Some(v) = self.pixel(raw, lens, is, it) {
+                            accum = accum + w * v;
+                            total = total + w;
+                        }
+                    }
+                }
+                if total > F::zero() {
+                    Some(accum / total)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Decodes a raw capture into a buffer on `light_field()`
+    pub fn decode(self: &Self, raw: &[F]) -> Vec<F> {
+        let lenses = self.camera.array.as_ref().unwrap();
+        let geom = &self.lfg.geom;
+        let plane = &self.lfg.plane;
+        let np = geom.dimension();
+
+        let mut tr = vec![F::zero(); np * plane.na()];
+        for ia in 0..plane.na() {
+            for ip in 0..np {
+                if let Some(lens_id) = self.lens_map[ip] {
+                    let lens = &lenses[lens_id];
+                    let (s, t) = self.detector_position(lens, plane.s[ia], plane.t[ia]);
+                    if let Some(v) = self.sample(raw, lens, s, t) {
+                        tr[ip + np * ia] = v;
+                    }
+                }
+            }
+        }
+        tr
+    }
+}
+
+#[cfg(test)]
+fn test_decoder_camera() -> PlenopticCamera<f32> {
+    use detector::*;
+
+    let detector = Detector {
+        ns: 60,
+        nt: 60,
+        ds: 1f32,
+        dt: 1f32,
+        offset_s: 0f32,
+        offset_t: 0f32,
+    };
+    let ulens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 5f32,
+        radius_t: 5f32,
+        focal_length_s: 10f32,
+        focal_length_t: 10f32,
+    };
+    let array = Lens::tesselate_quad_1(0f32, 0f32, &detector.image_geometry(), &ulens);
+
+    PlenopticCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 40f32,
+            radius_t: 40f32,
+            focal_length_s: 50f32,
+            focal_length_t: 50f32,
+        },
+        detector: detector,
+        distance_lens_array: 100f32,
+        distance_detector_array: 10f32,
+        array_path: String::new(),
+        array: Some(array),
+        stop: None,
+        transfer: None,
+    }
+}
+
+/// Light field that is linear in position and angle on the main lens
+#[cfg(test)]
+fn test_radiance(s: f32, t: f32, u: f32, v: f32) -> f32 {
+    1f32 + 0.01 * s + 0.02 * t + 0.003 * u - 0.002 * v
+}
+
+/// Raw capture of `test_radiance`
+#[cfg(test)]
+fn test_linear_capture(camera: &PlenopticCamera<f32>) -> Vec<f32> {
+    let geom = camera.detector.image_geometry();
+    let lenses = camera.array.as_ref().unwrap();
+    let mut raw = geom.zeros();
+    for it in 0..geom.nt {
+        for is in 0..geom.ns {
+            let (s, t) = geom.pixel_center(is, it);
+            for lens in lenses.iter() {
+                if !lens.occludes(s, t) {
+                    let scale = camera.distance_lens_array / camera.distance_detector_array;
+                    let u = lens.center_s + (lens.center_s - s) * scale;
+                    let v = lens.center_t + (lens.center_t - t) * scale;
+                    raw[geom.address_linear(is, it)] = test_radiance(lens.center_s,
+                                                                     lens.center_t,
+                                                                     u,
+                                                                     v);
+                }
+            }
+        }
+    }
+    raw
+}
+
+#[test]
+fn test_lens_grid_geometry() {
+    let camera = test_decoder_camera();
+    let geom = PlenopticDecoder::lens_grid_geometry(&camera);
+    assert_eq!((geom.ns, geom.nt), (7, 7));
+
+    // one pixel centered on each microlens
+    let lenses = camera.array.as_ref().unwrap();
+    let lens_map = nearest_lens_map(&geom, lenses);
+    for it in 0..geom.nt {
+        for is in 0..geom.ns {
+            let (s, t) = geom.pixel_center(is, it);
+            let lens = &lenses[lens_map[geom.address_linear(is, it)].unwrap()];
+            assert!((lens.center_s - s).abs() < 1e-4);
+            assert!((lens.center_t - t).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_decode_linear_light_field() {
+    let camera = test_decoder_camera();
+    let raw = test_linear_capture(&camera);
+    let geom = PlenopticDecoder::lens_grid_geometry(&camera);
+
+    // bilinear reads are exact inside each microlens image; both
+    // interpolations lose accuracy where a read straddles a lens' edge
+    let mut medians = Vec::new();
+    for &interpolation in [Interpolation::Nearest, Interpolation::Bilinear].iter() {
+        let decoder = PlenopticDecoder::new(camera.clone(),
+                                            geom.clone(),
+                                            5,
+                                            interpolation,
+                                            Vignetting::None);
+        let lf = decoder.light_field();
+        let x = decoder.decode(&raw);
+        assert_eq!(x.len(), lf.dimension());
+
+        let plane = &decoder.lfg.plane;
+        let mut errors = Vec::new();
+        for ia in 0..lf.na() {
+            let view = lf.view(&x, ia);
+            for it in 0..geom.nt {
+                for is in 0..geom.ns {
+                    let v = view[geom.address_linear(is, it)];
+                    if v != 0f32 {
+                        let (s, t) = geom.pixel_center(is, it);
+                        let expected = test_radiance(s, t, plane.s[ia], plane.t[ia]);
+                        errors.push((v - expected).abs());
+                    }
+                }
+            }
+        }
+        errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = errors[errors.len() / 2];
+        let worst = errors[errors.len() - 1];
+        println!("{}: decoded {} of {} samples, median error {}, worst error {}",
+                 interpolation.name(),
+                 errors.len(),
+                 x.len(),
+                 median,
+                 worst);
+
+        // microlenses on the border of the detector are half cut off
+        assert!(errors.len() > x.len() / 3);
+        assert!(worst < 0.05);
+        medians.push(median);
+    }
+    assert!(medians[1] < 1e-4);
+    assert!(medians[1] < medians[0]);
+}
+
+#[test]
+fn test_decode_vignetting() {
+    let camera = test_decoder_camera();
+    let raw = test_linear_capture(&camera);
+    let geom = PlenopticDecoder::lens_grid_geometry(&camera);
+    let detector_geom = camera.detector.image_geometry();
+    let lenses = camera.array.as_ref().unwrap().clone();
+
+    // a white image divides out exactly
+    let white = detector_geom.rands().iter().map(|w| 0.5 + 0.5 * w).collect::<Vec<f32>>();
+    let shaded: Vec<f32> = raw.iter().zip(white.iter()).map(|(r, w)| r * w).collect();
+    for &interpolation in [Interpolation::Nearest, Interpolation::Bilinear].iter() {
+        let plain = PlenopticDecoder::new(camera.clone(),
+                                          geom.clone(),
+                                          5,
+                                          interpolation,
+                                          Vignetting::None);
+        let corrected = PlenopticDecoder::new(camera.clone(),
+                                              geom.clone(),
+                                              5,
+                                              interpolation,
+                                              Vignetting::White(white.clone()));
+        let x = plain.decode(&raw);
+        let y = corrected.decode(&shaded);
+        for (a, b) in x.iter().zip(y.iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    // a uniform scene seen through ideal microlenses loses light only to
+    // partial pixel coverage at the lens edges
+    let mut flat = detector_geom.zeros();
+    for it in 0..detector_geom.nt {
+        for is in 0..detector_geom.ns {
+            let (s0, s1, t0, t1) = detector_geom.pixel_bounds(is, it);
+            let coverage = lenses.iter().fold(0f32, |c, lens| {
+                c + 1f32 - lens.rasterize(s0, s1, t0, t1, 10)
+            });
+            flat[detector_geom.address_linear(is, it)] = coverage;
+        }
+    }
+    let decoder = PlenopticDecoder::new(camera.clone(),
+                                        geom.clone(),
+                                        5,
+                                        Interpolation::Bilinear,
+                                        Vignetting::Coverage);
+    for v in decoder.decode(&flat).into_iter() {
+        assert!(v == 0f32 || (v - 1f32).abs() < 1e-4);
+    }
+}
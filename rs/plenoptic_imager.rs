@@ -4,7 +4,7 @@ extern crate num;
 use imager::*;
 use self::proust::*;
 use light_volume::*;
-use self::num::{FromPrimitive, Float};
+use self::num::{FromPrimitive, Float, ToPrimitive};
 use self::nalgebra::Vector3;
 use angular_plane::*;
 use volume_transport::*;
@@ -14,17 +14,49 @@ use detector::*;
 use plenoptic_camera::*;
 use lens_array::*;
 use geom::*;
+use ray_transfer::*;
+
+/// Transport from the object to the microlens array, in the camera's frame
+fn object_transport<F>(geom: &LightVolume<F>,
+                       camera: &PlenopticCamera<F>,
+                       position: Vector3<F>,
+                       array_lfg: &LightFieldGeometry<F>,
+                       queue: &CommandQueue)
+                       -> Result<LensVolumeTransport<F>, Error>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    // geometry of the object in the camera's optical frame
+    let distance_to_object = -position.z;
+    let camera_ox = position.x / geom.dx;
+    let camera_oy = position.y / geom.dy;
+
+    // set up geometry of LightVolume in camera's reference frame
+    let mut frame_geom = geom.clone();
+    frame_geom.offset_x = frame_geom.offset_x + camera_ox;
+    frame_geom.offset_y = frame_geom.offset_y + camera_oy;
+
+    let to_object = RayTransfer::from_optics(&Optics::translation(&distance_to_object));
+    let object_to_plane = camera.lens_transfer().then(&to_object).invert();
+
+    LensVolumeTransport::new(frame_geom,
+                             array_lfg.clone(),
+                             object_to_plane,
+                             true, // overwrite_forw
+                             false, // overwrite_back
+                             false, // onto_detector
+                             queue.clone())
+}
 
 pub struct PlenopticVolumeImager<F: Float + FromPrimitive> {
     geom: LightVolume<F>,
-    xport: VolumeTransport<F>,
-    array: LensArray<F>,
+    xport: LensVolumeTransport<F>,
+    array: BatchedLensArray<F>,
     detector: Detector<F>,
     plane: AngularPlane<F>,
     tmp: Mem,
 }
 
-impl<F: Float + FromPrimitive> PlenopticVolumeImager<F> {
+impl<F: Float + FromPrimitive + ToPrimitive> PlenopticVolumeImager<F> {
     pub fn new(geom: LightVolume<F>,
                camera: PlenopticCamera<F>,
                position: Vector3<F>,
@@ -33,7 +65,7 @@ impl<F: Float + FromPrimitive> PlenopticVolumeImager<F> {
                queue: CommandQueue)
                -> Result<Self, Error> {
         // angular plane on main lens
-        let plane = camera.lens.as_angular_plane(basis, na);
+        let plane = camera.angular_plane(basis, na);
 
         // light field geometry on ulens array
         let array_lfg = LightFieldGeometry {
@@ -48,35 +80,12 @@ impl<F: Float + FromPrimitive> PlenopticVolumeImager<F> {
         };
 
         let tmp = try!(camera.detector.image_geometry().zeros_buf(&queue));
-
-        // geometry of the object in the camera's optical frame
-        let distance_to_object = -position.z;
-        let camera_ox = position.x / geom.dx;
-        let camera_oy = position.y / geom.dy;
-
-        // set up geometry of LightVolume in camera's reference frame
-        let mut frame_geom = geom.clone();
-        frame_geom.offset_x = frame_geom.offset_x + camera_ox;
-        frame_geom.offset_y = frame_geom.offset_y + camera_oy;
-
-        let optics_object_to_plane = camera.lens
-                                           .optics()
-                                           .then(&Optics::translation(&distance_to_object))
-                                           .invert();
-
-        let xport = try!(VolumeTransport::new(frame_geom,
-                                              array_lfg.clone(),
-                                              optics_object_to_plane,
-                                              true, // overwrite_forw
-                                              false, // overwrite_back
-                                              false, // onto_detector
-                                              queue.clone()));
-
-        let array = try!(LensArray::new(array_lfg,
-                                        camera.detector.clone(),
-                                        camera.distance_detector_array,
-                                        lenses,
-                                        queue.clone()));
+        let xport = try!(object_transport(&geom, &camera, position, &array_lfg, &queue));
+        let array = try!(BatchedLensArray::new(array_lfg,
+                                               camera.detector.clone(),
+                                               camera.distance_detector_array,
+                                               lenses,
+                                               queue.clone()));
 
         Ok(PlenopticVolumeImager {
             geom: geom,
@@ -89,7 +98,52 @@ impl<F: Float + FromPrimitive> PlenopticVolumeImager<F> {
     }
 }
 
-impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for PlenopticVolumeImager<F> {
+/// Light field on the microlens array of a plenoptic camera
+///
+/// Each view is the backprojection of the raw capture through the microlens
+/// array for one angle on the main lens, divided by the backprojection of a
+/// uniform capture so that vignetting by the microlenses cancels out.
+/// `array_lfg` is usually `camera.array_light_field_geometry`.
+pub fn backprojected_light_field<F>(camera: &PlenopticCamera<F>,
+                                    array_lfg: &LightFieldGeometry<F>,
+                                    raw: &[F],
+                                    queue: &CommandQueue)
+                                    -> Result<Vec<F>, Error>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let lenses = match camera.array {
+        Some(ref v) => v,
+        None => panic!("backprojected_light_field called with unloaded lenses"),
+    };
+    let mut array = try!(LensArray::new(array_lfg.clone(),
+                                        camera.detector.clone(),
+                                        camera.distance_detector_array,
+                                        lenses,
+                                        queue.clone()));
+
+    let geom = &array_lfg.geom;
+    let raw_buf = try!(queue.create_buffer_from_slice(raw));
+    let ones_buf = try!(queue.create_buffer_from_slice(&vec![F::one(); raw.len()]));
+    let mut view_buf = try!(geom.zeros_buf(queue));
+    let mut weight_buf = try!(geom.zeros_buf(queue));
+
+    let mut view = geom.zeros();
+    let mut weight = geom.zeros();
+    let mut tr = Vec::with_capacity(geom.dimension() * array_lfg.plane.na());
+    for ia in 0..array_lfg.plane.na() {
+        try!(try!(array.back(&raw_buf, &mut view_buf, ia, &[])).wait());
+        try!(try!(array.back(&ones_buf, &mut weight_buf, ia, &[])).wait());
+        try!(try!(queue.read_buffer(&view_buf, &mut view)).wait());
+        try!(try!(queue.read_buffer(&weight_buf, &mut weight)).wait());
+        for (v, w) in view.iter().zip(weight.iter()) {
+            tr.push(if *w > F::zero() { *v / *w } else { F::zero() });
+        }
+    }
+    Ok(tr)
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Imager<F, LightVolume<F>>
+    for PlenopticVolumeImager<F> {
     fn na(self: &Self) -> usize {
         self.plane.s.len()
     }
@@ -117,6 +171,17 @@ impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for PlenopticVolumeImag
         self.array.forw(&tmp_copy, view, ia, &[evt])
     }
 
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &Mem,
+                             view: &mut Mem,
+                             ia: usize,
+                             wait_for: &[Event])
+                             -> Result<Event, Error> {
+        let mut tmp_copy = self.tmp.clone();
+        let evt = try!(self.xport.forw_linearized(object, &mut tmp_copy, ia, wait_for));
+        self.array.forw(&tmp_copy, view, ia, &[evt])
+    }
+
     fn back_angle(self: &mut Self,
                   view: &Mem,
                   object: &mut Mem,
@@ -128,3 +193,98 @@ impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for PlenopticVolumeImag
         self.xport.back(&tmp_copy, object, ia, &[evt])
     }
 }
+
+#[test]
+fn test_plenoptic_imager_matches_per_lens_array() {
+    use env::*;
+    use lens::*;
+    use single_lens_imager::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let single_lens = test_camera();
+    let detector = Detector {
+        ns: 64,
+        nt: 64,
+        ds: 0.25,
+        dt: 0.25,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+    let ulens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 1f32,
+        radius_t: 1f32,
+        focal_length_s: 2f32,
+        focal_length_t: 2f32,
+    };
+    let camera = PlenopticCamera {
+        lens: single_lens.lens,
+        array: Some(Lens::tesselate_quad_1(-2f32, -2f32, &detector.image_geometry(), &ulens)),
+        detector: detector,
+        distance_lens_array: 25f32,
+        distance_detector_array: 0.8f32,
+        array_path: String::new(),
+        stop: None,
+        transfer: None,
+    };
+    let vg = test_volume();
+    let position = Vector3::new(0f32, 0f32, -30f32);
+
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let mut imager = PlenopticVolumeImager::new(vg.clone(),
+                                                    camera.clone(),
+                                                    position,
+                                                    3,
+                                                    basis.clone(),
+                                                    queue.clone())
+                             .unwrap();
+
+        // the same operator, with one transport per microlens
+        let array_lfg = LightFieldGeometry {
+            geom: camera.detector.image_geometry(),
+            plane: camera.angular_plane(basis, 3),
+            to_plane: Optics::translation(&camera.distance_lens_array),
+        };
+        let mut xport = object_transport(&vg, &camera, position, &array_lfg, queue).unwrap();
+        let mut array = LensArray::new(array_lfg.clone(),
+                                       camera.detector.clone(),
+                                       camera.distance_detector_array,
+                                       camera.array.as_ref().unwrap(),
+                                       queue.clone())
+                            .unwrap();
+
+        let ig = camera.detector.image_geometry();
+        let x = queue.create_buffer_from_slice(&vg.rands()).unwrap();
+        let y = queue.create_buffer_from_slice(&ig.rands()).unwrap();
+        let mut tmp = ig.zeros_buf(queue).unwrap();
+        for ia in 0..imager.na() {
+            let mut forw0 = ig.zeros_buf(queue).unwrap();
+            let mut forw1 = ig.zeros_buf(queue).unwrap();
+            imager.forw_angle(&x, &mut forw0, ia, &[]).unwrap().wait().unwrap();
+            let evt = xport.forw(&x, &mut tmp, ia, &[]).unwrap();
+            array.forw(&tmp, &mut forw1, ia, &[evt]).unwrap().wait().unwrap();
+
+            let mut back0 = vg.zeros_buf(queue).unwrap();
+            let mut back1 = vg.zeros_buf(queue).unwrap();
+            imager.back_angle(&y, &mut back0, ia, &[]).unwrap().wait().unwrap();
+            let evt = array.back(&y, &mut tmp, ia, &[]).unwrap();
+            xport.back(&tmp, &mut back1, ia, &[evt]).unwrap().wait().unwrap();
+
+            for &(ref u, ref v, n) in [(&forw0, &forw1, ig.dimension()),
+                                       (&back0, &back1, vg.dimension())]
+                                          .iter() {
+                let mut uh = vec![0f32; n];
+                let mut vh = vec![0f32; n];
+                queue.read_buffer(u, &mut uh).unwrap().wait().unwrap();
+                queue.read_buffer(v, &mut vh).unwrap().wait().unwrap();
+                let num = uh.iter().zip(vh.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b));
+                let den = uh.iter().fold(0f32, |s, a| s + a * a);
+                assert!(den > 0f32);
+                assert!(num <= 1e-8 * den);
+            }
+        }
+    }
+}
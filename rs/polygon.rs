@@ -0,0 +1,130 @@
+extern crate num;
+use self::num::Float;
+
+/// Clips a convex polygon against the half plane `sign * (p[axis] - bound) <= 0`
+fn clip_polygon<F: Float>(poly: &[(F, F)], axis: usize, bound: F, sign: F) -> Vec<(F, F)> {
+    let coord = |p: &(F, F)| if axis == 0 { p.0 } else { p.1 };
+    let inside = |p: &(F, F)| sign * (coord(p) - bound) <= F::zero();
+
+    let mut tr = Vec::with_capacity(poly.len() + 1);
+    for ix in 0..poly.len() {
+        let a = &poly[ix];
+        let b = &poly[(ix + 1) % poly.len()];
+        if inside(a) {
+            tr.push(*a);
+        }
+        if inside(a) != inside(b) {
+            let w = (bound - coord(a)) / (coord(b) - coord(a));
+            tr.push((a.0 + w * (b.0 - a.0), a.1 + w * (b.1 - a.1)));
+        }
+    }
+    tr
+}
+
+/// Area of a simple polygon
+pub fn polygon_area<F: Float>(poly: &[(F, F)]) -> F {
+    if poly.len() < 3 {
+        return F::zero();
+    }
+
+    let mut twice_area = F::zero();
+    for ix in 0..poly.len() {
+        let a = &poly[ix];
+        let b = &poly[(ix + 1) % poly.len()];
+        twice_area = twice_area + a.0 * b.1 - b.0 * a.1;
+    }
+    (twice_area / (F::one() + F::one())).abs()
+}
+
+/// Area of the intersection between a convex polygon and the box
+/// `[s0, s1] x [t0, t1]`
+pub fn polygon_box_area<F: Float>(poly: &[(F, F)], s0: F, s1: F, t0: F, t1: F) -> F {
+    let mut p = clip_polygon(poly, 0, s0, -F::one());
+    p = clip_polygon(&p, 0, s1, F::one());
+    p = clip_polygon(&p, 1, t0, -F::one());
+    p = clip_polygon(&p, 1, t1, F::one());
+    polygon_area(&p)
+}
+
+/// Area of the intersection between a circle of radius `r` around the
+/// origin and the box `[s0, s1] x [t0, t1]`
+pub fn circle_box_area<F: Float>(r: F, s0: F, s1: F, t0: F, t1: F) -> F {
+    let c2 = F::one() + F::one();
+    let s0 = s0.max(-r);
+    let s1 = s1.min(r);
+    if s1 <= s0 || t1 <= t0 {
+        return F::zero();
+    }
+
+    // half height of the circle and its antiderivative
+    let h = |s: F| (r * r - s * s).max(F::zero()).sqrt();
+    let g = |s: F| (s * h(s) + r * r * (s / r).max(-F::one()).min(F::one()).asin()) / c2;
+
+    // between these knots the box edges and the circle do not cross
+    let mut knots = vec![s0, s1];
+    for &t in [t0, t1].iter() {
+        if t.abs() < r {
+            let k = h(t);
+            for &x in [-k, k].iter() {
+                if x > s0 && x < s1 {
+                    knots.push(x);
+                }
+            }
+        }
+    }
+    knots.sort_by(|l, r| l.partial_cmp(r).unwrap());
+
+    let mut tr = F::zero();
+    for ix in 0..knots.len() - 1 {
+        let (a, b) = (knots[ix], knots[ix + 1]);
+        let mid = h((a + b) / c2);
+
+        // integrate min(t1, h) - max(t0, -h) over [a, b]
+        let (top_h, top_c) = if mid < t1 { (F::one(), F::zero()) } else { (F::zero(), t1) };
+        let (bot_h, bot_c) = if -mid > t0 { (-F::one(), F::zero()) } else { (F::zero(), t0) };
+        let len_h = top_h - bot_h;
+        let len_c = top_c - bot_c;
+        if len_h * mid + len_c > F::zero() {
+            tr = tr + len_h * (g(b) - g(a)) + len_c * (b - a);
+        }
+    }
+    tr
+}
+
+#[test]
+fn test_polygon_box_area() {
+    let square = vec![(0f64, 0f64), (2f64, 0f64), (2f64, 2f64), (0f64, 2f64)];
+    assert!((polygon_area(&square) - 4.0).abs() < 1e-12);
+    assert!((polygon_box_area(&square, 1.0, 3.0, -1.0, 0.5) - 0.5).abs() < 1e-12);
+    assert_eq!(polygon_box_area(&square, 3.0, 4.0, 0.0, 1.0), 0.0);
+
+    let diamond = vec![(1f64, 0f64), (0f64, 1f64), (-1f64, 0f64), (0f64, -1f64)];
+    assert!((polygon_box_area(&diamond, 0.0, 5.0, 0.0, 5.0) - 0.5).abs() < 1e-12);
+}
+
+#[test]
+fn test_circle_box_area() {
+    use std::f64::consts::PI;
+
+    let r = 1.5f64;
+    assert!((circle_box_area(r, -2.0, 2.0, -2.0, 2.0) - PI * r * r).abs() < 1e-12);
+    assert!((circle_box_area(r, 0.0, 2.0, 0.0, 2.0) - PI * r * r / 4.0).abs() < 1e-12);
+    assert!((circle_box_area(r, -0.5, 0.5, -0.5, 0.5) - 1.0).abs() < 1e-12);
+    assert_eq!(circle_box_area(r, 2.0, 3.0, -1.0, 1.0), 0.0);
+
+    // brute force for a box straddling the edge
+    let (s0, s1, t0, t1) = (0.3, 1.7, -0.4, 1.1);
+    let n = 2000;
+    let mut hits = 0;
+    for i in 0..n {
+        for j in 0..n {
+            let s = s0 + (s1 - s0) * (i as f64 + 0.5) / n as f64;
+            let t = t0 + (t1 - t0) * (j as f64 + 0.5) / n as f64;
+            if s * s + t * t < r * r {
+                hits += 1;
+            }
+        }
+    }
+    let brute = (s1 - s0) * (t1 - t0) * hits as f64 / (n * n) as f64;
+    assert!((circle_box_area(r, s0, s1, t0, t1) - brute).abs() < 1e-4);
+}
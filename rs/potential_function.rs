@@ -15,6 +15,32 @@ pub enum PotentialFunction<F: Float> {
     Fair(F, F),
 }
 
+impl<F: Float> PotentialFunction<F> {
+    /// Returns the minimizer of `mu/2 (x - y)^2 + Pf(x)`
+    ///
+    /// There is no closed form for the fair potential, so `y` is returned
+    /// unchanged.
+    pub fn shrink(self: &Self, mu: F, y: F) -> F {
+        match self {
+            &PotentialFunction::Quad(weight) => mu * y / (mu + weight),
+            &PotentialFunction::Abs(weight) => {
+                let wi = weight / mu;
+                y.signum() * (y.abs() - wi).max(F::zero())
+            }
+            &PotentialFunction::Fair(_, _) => y,
+        }
+    }
+
+    /// Returns the Huber curvature `Pf'(x) / x`
+    pub fn huber(self: &Self, x: F) -> F {
+        match self {
+            &PotentialFunction::Quad(weight) => weight,
+            &PotentialFunction::Abs(_) => F::one() / x.abs(),
+            &PotentialFunction::Fair(weight, delta) => weight / (F::one() + (x / delta).abs()),
+        }
+    }
+}
+
 impl<F: Float> ClHeader for PotentialFunction<F> {
     fn header() -> &'static str {
         include_str!("../cl/potential_function_f32.opencl")
@@ -26,16 +52,19 @@ impl<F: Float + ToPrimitive> ClBuffer for PotentialFunction<F> {
         match self {
             &PotentialFunction::Quad(ref weight) => {
                 buf.write_i32::<LittleEndian>(0i32).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(weight).unwrap()).unwrap();
+                align_real::<F>(buf);
+                write_real(buf, weight);
             }
             &PotentialFunction::Abs(ref weight) => {
                 buf.write_i32::<LittleEndian>(1i32).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(weight).unwrap()).unwrap();
+                align_real::<F>(buf);
+                write_real(buf, weight);
             }
             &PotentialFunction::Fair(ref weight, ref delta) => {
                 buf.write_i32::<LittleEndian>(2i32).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(weight).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(delta).unwrap()).unwrap();
+                align_real::<F>(buf);
+                write_real(buf, weight);
+                write_real(buf, delta);
             }
         }
     }
@@ -90,6 +119,21 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for PotentialFunction<F>
     }
 }
 
+#[test]
+fn test_potential_function_host() {
+    let quad = PotentialFunction::Quad(1f32);
+    assert_eq!(quad.shrink(1f32, 2f32), 1f32);
+    assert_eq!(quad.huber(5f32), 1f32);
+
+    let abs = PotentialFunction::Abs(2f32);
+    assert_eq!(abs.shrink(1f32, 3f32), 1f32);
+    assert_eq!(abs.shrink(1f32, -3f32), -1f32);
+    assert_eq!(abs.shrink(1f32, 1f32), 0f32);
+
+    let fair = PotentialFunction::Fair(3f32, 2f32);
+    assert_eq!(fair.huber(2f32), 1.5f32);
+}
+
 #[test]
 fn test_read_potential_function() {
     let test_quad = r#"
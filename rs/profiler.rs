@@ -0,0 +1,389 @@
+extern crate proust;
+extern crate time;
+use self::proust::*;
+use self::time::precise_time_s;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// A single timed kernel launch
+#[derive(Clone, Debug)]
+pub struct KernelRecord {
+    /// Name of the OpenCL kernel
+    pub kernel: &'static str,
+
+    /// Profiling scopes the launch happened in, joined with `/`; empty
+    /// outside of any scope
+    pub scope: String,
+
+    /// Seconds since profiling was enabled when the kernel started
+    pub start: f64,
+
+    /// Seconds since profiling was enabled when the kernel finished
+    pub end: f64,
+}
+
+/// Time spent in one kernel within one scope
+#[derive(Clone, Debug)]
+pub struct KernelTotal {
+    pub scope: String,
+    pub kernel: &'static str,
+    pub calls: usize,
+    pub seconds: f64,
+}
+
+struct Profiler {
+    epoch: f64,
+    scopes: Vec<String>,
+    records: Vec<KernelRecord>,
+}
+
+thread_local! {
+    static PROFILER: RefCell<Option<Profiler>> = RefCell::new(None);
+}
+
+/// Starts recording kernel launches on the calling thread
+///
+/// proust does not expose OpenCL event profiling, so while profiling is on
+/// every launch made through `run_kernel` waits for its dependencies, runs
+/// alone and is waited on.  The recorded times are host wall-clock times of
+/// these serialized launches; overall throughput drops accordingly.
+pub fn enable_profiling() {
+    PROFILER.with(|p| {
+        let mut p = p.borrow_mut();
+        if p.is_none() {
+            *p = Some(Profiler {
+                epoch: precise_time_s(),
+                scopes: Vec::new(),
+                records: Vec::new(),
+            });
+        }
+    });
+}
+
+/// Stops recording and returns everything recorded since the last `take_profile`
+pub fn disable_profiling() -> Profile {
+    let records = PROFILER.with(|p| {
+        match p.borrow_mut().take() {
+            Some(p) => p.records,
+            None => Vec::new(),
+        }
+    });
+    Profile { records: records }
+}
+
+/// Returns true if the calling thread is recording kernel launches
+pub fn profiling_enabled() -> bool {
+    PROFILER.with(|p| p.borrow().is_some())
+}
+
+/// Returns the launches recorded so far and keeps recording
+///
+/// Times stay relative to when profiling was enabled, so successive profiles
+/// can be concatenated.
+pub fn take_profile() -> Profile {
+    let records = PROFILER.with(|p| {
+        match *p.borrow_mut() {
+            Some(ref mut p) => p.records.drain(..).collect(),
+            None => Vec::new(),
+        }
+    });
+    Profile { records: records }
+}
+
+/// Returns the number of launches recorded so far; see `recorded_since`
+pub fn recorded_count() -> usize {
+    PROFILER.with(|p| p.borrow().as_ref().map_or(0, |p| p.records.len()))
+}
+
+/// Returns a copy of the launches recorded after the first `count`
+///
+/// Unlike `take_profile`, this keeps the records for the next profile.
+pub fn recorded_since(count: usize) -> Vec<KernelRecord> {
+    PROFILER.with(|p| {
+        match *p.borrow() {
+            Some(ref p) if count < p.records.len() => p.records[count..].to_vec(),
+            _ => Vec::new(),
+        }
+    })
+}
+
+/// Guard for a named profiling scope; see `profile_scope`
+pub struct ProfileScope {
+    active: bool,
+}
+
+/// Attributes kernel launches to `name` until the returned guard is dropped
+///
+/// Scopes nest, so launches inside `camera 1` and then `rotation` are
+/// recorded under `camera 1/rotation`.  Does nothing unless profiling is
+/// enabled.
+pub fn profile_scope(name: &str) -> ProfileScope {
+    let active = PROFILER.with(|p| {
+        match *p.borrow_mut() {
+            Some(ref mut p) => {
+                p.scopes.push(name.to_string());
+                true
+            }
+            None => false,
+        }
+    });
+    ProfileScope { active: active }
+}
+
+impl Drop for ProfileScope {
+    fn drop(self: &mut Self) {
+        if self.active {
+            PROFILER.with(|p| {
+                if let Some(ref mut p) = *p.borrow_mut() {
+                    p.scopes.pop();
+                }
+            });
+        }
+    }
+}
+
+fn record_kernel(kernel: &'static str, start: f64, end: f64) {
+    PROFILER.with(|p| {
+        if let Some(ref mut p) = *p.borrow_mut() {
+            let record = KernelRecord {
+                kernel: kernel,
+                scope: p.scopes.join("/"),
+                start: start - p.epoch,
+                end: end - p.epoch,
+            };
+            p.records.push(record);
+        }
+    });
+}
+
+/// Enqueues a kernel, timing it if profiling is enabled
+///
+/// `name` should be the kernel's name in the OpenCL source.
+pub fn run_kernel(queue: &CommandQueue,
+                  kernel: &mut Kernel,
+                  name: &'static str,
+                  local_size: (usize, usize, usize),
+                  global_size: (usize, usize, usize),
+                  wait_for: &[Event])
+                  -> Result<Event, Error> {
+    if !profiling_enabled() {
+        return queue.run_with_events(kernel, local_size, global_size, wait_for);
+    }
+
+    for evt in wait_for.iter() {
+        try!(evt.wait());
+    }
+    let start = precise_time_s();
+    let evt = try!(queue.run_with_events(kernel, local_size, global_size, wait_for));
+    try!(evt.wait());
+    record_kernel(name, start, precise_time_s());
+    Ok(evt)
+}
+
+/// Recorded kernel launches
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub records: Vec<KernelRecord>,
+}
+
+fn json_string(s: &str) -> String {
+    let mut tr = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => tr.push_str("\\\""),
+            '\\' => tr.push_str("\\\\"),
+            c if (c as u32) < 0x20 => tr.push_str(&format!("\\u{:04x}", c as u32)),
+            c => tr.push(c),
+        }
+    }
+    tr.push('"');
+    tr
+}
+
+impl Profile {
+    /// Appends the records of another profile
+    pub fn extend(self: &mut Self, other: Profile) {
+        self.records.extend(other.records);
+    }
+
+    /// Total kernel time
+    pub fn total_seconds(self: &Self) -> f64 {
+        self.records.iter().fold(0f64, |s, r| s + (r.end - r.start))
+    }
+
+    /// Time per scope and kernel, largest first
+    pub fn kernel_totals(self: &Self) -> Vec<KernelTotal> {
+        let mut totals: HashMap<(String, &'static str), (usize, f64)> = HashMap::new();
+        for r in self.records.iter() {
+            let entry = totals.entry((r.scope.clone(), r.kernel)).or_insert((0, 0f64));
+            entry.0 += 1;
+            entry.1 += r.end - r.start;
+        }
+
+        let mut tr: Vec<KernelTotal> = totals.into_iter()
+                                             .map(|((scope, kernel), (calls, seconds))| {
+                                                 KernelTotal {
+                                                     scope: scope,
+                                                     kernel: kernel,
+                                                     calls: calls,
+                                                     seconds: seconds,
+                                                 }
+                                             })
+                                             .collect();
+        tr.sort_by(|a, b| b.seconds.partial_cmp(&a.seconds).unwrap());
+        tr
+    }
+
+    /// Time per scope, largest first
+    pub fn scope_totals(self: &Self) -> Vec<(String, f64)> {
+        let mut totals: HashMap<String, f64> = HashMap::new();
+        for r in self.records.iter() {
+            *totals.entry(r.scope.clone()).or_insert(0f64) += r.end - r.start;
+        }
+        let mut tr: Vec<(String, f64)> = totals.into_iter().collect();
+        tr.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        tr
+    }
+
+    /// Returns a table of time spent per scope and per kernel
+    pub fn summary(self: &Self) -> String {
+        let total = self.total_seconds();
+        let percent = |s: f64| if total > 0f64 {
+            100f64 * s / total
+        } else {
+            0f64
+        };
+        let scope_name = |s: &str| if s.is_empty() {
+            "(none)".to_string()
+        } else {
+            s.to_string()
+        };
+
+        let mut tr = String::new();
+        tr.push_str(&format!("{:<32} {:>10} {:>7}\n", "scope", "total (s)", "%"));
+        for &(ref scope, seconds) in self.scope_totals().iter() {
+            tr.push_str(&format!("{:<32} {:>10.4} {:>7.1}\n",
+                                 scope_name(scope),
+                                 seconds,
+                                 percent(seconds)));
+        }
+        tr.push('\n');
+        tr.push_str(&format!("{:<32} {:<28} {:>7} {:>10} {:>10} {:>7}\n",
+                             "scope",
+                             "kernel",
+                             "calls",
+                             "total (s)",
+                             "mean (ms)",
+                             "%"));
+        for t in self.kernel_totals().iter() {
+            tr.push_str(&format!("{:<32} {:<28} {:>7} {:>10.4} {:>10.3} {:>7.1}\n",
+                                 scope_name(&t.scope),
+                                 t.kernel,
+                                 t.calls,
+                                 t.seconds,
+                                 1e3 * t.seconds / (t.calls as f64),
+                                 percent(t.seconds)));
+        }
+        tr
+    }
+
+    /// Writes the launches in the Chrome trace event format
+    ///
+    /// The output loads in `chrome://tracing` or Perfetto, with one row per
+    /// scope.
+    pub fn write_chrome_trace<W: Write>(self: &Self, out: &mut W) -> io::Result<()> {
+        let mut tids: HashMap<&str, usize> = HashMap::new();
+        let mut events = Vec::new();
+        for r in self.records.iter() {
+            let next_tid = tids.len();
+            let tid = *tids.entry(&r.scope).or_insert(next_tid);
+            if tid == next_tid {
+                events.push(format!("{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":0,\
+                                     \"tid\":{},\"args\":{{\"name\":{}}}}}",
+                                    tid,
+                                    json_string(&r.scope)));
+            }
+            events.push(format!("{{\"name\":{},\"cat\":\"kernel\",\"ph\":\"X\",\"pid\":0,\
+                                 \"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+                                json_string(r.kernel),
+                                tid,
+                                1e6 * r.start,
+                                1e6 * (r.end - r.start)));
+        }
+
+        try!(write!(out, "{{\"traceEvents\":[\n"));
+        try!(write!(out, "{}", events.join(",\n")));
+        try!(write!(out, "\n]}}\n"));
+        Ok(())
+    }
+
+    /// Saves the launches as a Chrome trace JSON file
+    pub fn save_chrome_trace<P: AsRef<Path>>(self: &Self, path: P) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        self.write_chrome_trace(&mut file)
+    }
+}
+
+#[test]
+fn test_profile_scopes() {
+    record_kernel("outside", 0f64, 1f64);
+    assert!(!profiling_enabled());
+
+    enable_profiling();
+    {
+        let _camera = profile_scope("camera 0");
+        record_kernel("volume_forw_t", 1f64, 2f64);
+        {
+            let _rotation = profile_scope("rotation");
+            record_kernel("rotate_filter_x", 2f64, 3f64);
+        }
+    }
+    record_kernel("VectorMath_mix", 3f64, 4f64);
+    assert_eq!(recorded_count(), 3);
+    assert_eq!(recorded_since(1)[0].kernel, "rotate_filter_x");
+    let profile = disable_profiling();
+
+    let scopes: Vec<&str> = profile.records.iter().map(|r| &r.scope[..]).collect();
+    assert_eq!(scopes, vec!["camera 0", "camera 0/rotation", ""]);
+    assert_eq!(profile.records[1].kernel, "rotate_filter_x");
+    assert!(take_profile().records.is_empty());
+}
+
+#[test]
+fn test_profile_summary() {
+    let record = |kernel, scope: &str, start, end| {
+        KernelRecord {
+            kernel: kernel,
+            scope: scope.to_string(),
+            start: start,
+            end: end,
+        }
+    };
+    let profile = Profile {
+        records: vec![record("volume_forw_t", "camera 0", 0f64, 1f64),
+                      record("volume_forw_t", "camera 0", 1f64, 2f64),
+                      record("volume_forw_s", "camera 0", 2f64, 2.5f64),
+                      record("volume_forw_t", "camera \"1\"", 2.5f64, 6f64)],
+    };
+
+    assert_eq!(profile.total_seconds(), 6f64);
+    let totals = profile.kernel_totals();
+    assert_eq!(totals[0].scope, "camera \"1\"");
+    assert_eq!(totals[1].kernel, "volume_forw_t");
+    assert_eq!(totals[1].calls, 2);
+    assert_eq!(totals[1].seconds, 2f64);
+    assert_eq!(profile.scope_totals()[1], ("camera 0".to_string(), 2.5f64));
+    assert!(profile.summary().contains("volume_forw_s"));
+
+    let mut trace = Vec::new();
+    profile.write_chrome_trace(&mut trace).unwrap();
+    let trace = String::from_utf8(trace).unwrap();
+    assert!(trace.starts_with("{\"traceEvents\":["));
+    assert_eq!(trace.matches("\"ph\":\"X\"").count(), 4);
+    assert_eq!(trace.matches("\"ph\":\"M\"").count(), 2);
+    assert!(trace.contains("\"camera \\\"1\\\"\""));
+}
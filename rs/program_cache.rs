@@ -0,0 +1,263 @@
+extern crate proust;
+use self::proust::*;
+use cl_traits::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Programs built in the context of one live `Environment`
+struct EnvironmentPrograms {
+    id: usize,
+    devices: Vec<Device>,
+    device_names: Vec<String>,
+
+    // one byte buffer in the environment's context, to tell its queues apart
+    probe: Option<Mem>,
+
+    // keyed by device name and sources
+    programs: HashMap<(String, String), Rc<Program>>,
+}
+
+thread_local! {
+    static ENVIRONMENTS: RefCell<Vec<EnvironmentPrograms>> = RefCell::new(Vec::new());
+    static NEXT_ENVIRONMENT_ID: Cell<usize> = Cell::new(0);
+}
+
+/// Registration of an `Environment` with the program cache
+///
+/// Each registered environment caches the programs built for queues in its
+/// context.  Dropping the registration drops its programs.
+pub struct ProgramScope {
+    id: usize,
+}
+
+/// Registers the devices and queues of a newly created context with the
+/// program cache
+pub fn register_environment(devices: &[Device],
+                            queues: &[CommandQueue])
+                            -> Result<ProgramScope, Error> {
+    let mut device_names = Vec::with_capacity(devices.len());
+    for d in devices.iter() {
+        device_names.push(try!(d.name()));
+    }
+    let probe = match queues.first() {
+        Some(queue) => Some(try!(queue.create_buffer_from_slice(&[0u8]))),
+        None => None,
+    };
+    let id = NEXT_ENVIRONMENT_ID.with(|n| {
+        let id = n.get();
+        n.set(id + 1);
+        id
+    });
+    ENVIRONMENTS.with(|e| {
+        e.borrow_mut().push(EnvironmentPrograms {
+            id: id,
+            devices: devices.to_vec(),
+            device_names: device_names,
+            probe: probe,
+            programs: HashMap::new(),
+        })
+    });
+    Ok(ProgramScope { id: id })
+}
+
+/// Returns true if `queue` belongs to the context `probe` was created in
+///
+/// proust cannot compare contexts, but OpenCL refuses to enqueue commands
+/// on buffers from another context with `CL_INVALID_CONTEXT`.
+fn same_context(queue: &CommandQueue, probe: &Mem) -> bool {
+    let mut probe = probe.clone();
+    match queue.write_buffer(&mut probe, &[0u8]) {
+        Ok(evt) => evt.wait().is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl Drop for ProgramScope {
+    fn drop(self: &mut Self) {
+        let id = self.id;
+        ENVIRONMENTS.with(|e| e.borrow_mut().retain(|env| env.id != id));
+    }
+}
+
+/// Returns a program built from `sources` for the queue's device
+///
+/// Programs are compiled on first use and then shared by every object built
+/// with the same source set on the same device, so constructing many
+/// transports (e.g., one per microlens) compiles each program once.
+///
+/// The sources are prefixed with `real_header::<F>()`, so `F` selects the
+/// single or double precision variant of each kernel.  Building a double
+/// precision program on a device without `cl_khr_fp64` returns the build
+/// error "unsupported precision"; see `supports_double` to check first.
+///
+/// Programs are cached for queues of the calling thread's live
+/// `Environment`s, separately for each context; see `ProgramScope`.
+pub fn cached_program<F>(queue: &CommandQueue, sources: &[&str]) -> Result<Rc<Program>, Error> {
+    let mut all_sources = vec![real_header::<F>()];
+    all_sources.extend(sources.iter().cloned());
+    build_cached(queue, &all_sources)
+}
+
+/// Returns true if the queue's device supports double precision
+///
+/// proust does not expose device extensions, so this runs a small probe
+/// kernel that reports whether the compiler defines `cl_khr_fp64`.  Errors
+/// building or running the probe are returned as such.
+pub fn supports_double(queue: &CommandQueue) -> Result<bool, Error> {
+    let probe = "kernel void fp64_probe(global int* x) {\n\
+                 #ifdef cl_khr_fp64\n\
+                 x[0] = 1;\n\
+                 #else\n\
+                 x[0] = 0;\n\
+                 #endif\n\
+                 }\n";
+    let program = try!(build_cached(queue, &[probe]));
+    let mut kernel = try!(program.create_kernel("fp64_probe"));
+
+    let mut x = [0i32];
+    let mut x_buf = try!(queue.create_buffer_from_slice(&x));
+    try!(kernel.bind_mut(0, &mut x_buf));
+    try!(try!(queue.run_with_events(&mut kernel, (1, 1, 1), (1, 1, 1), &[])).wait());
+    try!(try!(queue.read_buffer(&x_buf, &mut x[..])).wait());
+    Ok(x[0] != 0)
+}
+
+fn build_cached(queue: &CommandQueue, sources: &[&str]) -> Result<Rc<Program>, Error> {
+    let device = try!(queue.device());
+    let name = try!(device.name());
+    let key = (name.clone(), sources.concat());
+
+    // the cached program, or the environment the queue belongs to and the
+    // devices to build one for
+    let cached = ENVIRONMENTS.with(|e| {
+        for env in e.borrow().iter() {
+            match env.probe {
+                Some(ref probe) if same_context(queue, probe) => (),
+                _ => continue,
+            }
+            if let Some(program) = env.programs.get(&key) {
+                return Some(Ok(program.clone()));
+            }
+
+            // identical devices share a name, so build for all of them
+            let devices: Vec<Device> = env.devices
+                                          .iter()
+                                          .zip(env.device_names.iter())
+                                          .filter(|&(_, n)| *n == name)
+                                          .map(|(d, _)| d.clone())
+                                          .collect();
+            return Some(Err((env.id, devices)));
+        }
+        None
+    });
+
+    match cached {
+        Some(Ok(program)) => Ok(program),
+        Some(Err((id, devices))) => {
+            let context = try!(queue.context());
+            let unbuilt = try!(Program::new_from_source(context, sources));
+            let program = if devices.len() > 0 {
+                Rc::new(try!(unbuilt.build(&devices)))
+            } else {
+                Rc::new(try!(unbuilt.build(&[device])))
+            };
+            ENVIRONMENTS.with(|e| {
+                for env in e.borrow_mut().iter_mut().filter(|env| env.id == id) {
+                    env.programs.insert(key.clone(), program.clone());
+                }
+            });
+            Ok(program)
+        }
+        None => {
+            let context = try!(queue.context());
+            let unbuilt = try!(Program::new_from_source(context, sources));
+            Ok(Rc::new(try!(unbuilt.build(&[device]))))
+        }
+    }
+}
+
+/// Drops all cached programs for the calling thread
+pub fn clear_program_cache() {
+    ENVIRONMENTS.with(|e| {
+        for env in e.borrow_mut().iter_mut() {
+            env.programs.clear();
+        }
+    });
+}
+
+/// Returns the number of cached programs for the calling thread
+pub fn program_cache_len() -> usize {
+    ENVIRONMENTS.with(|e| e.borrow().iter().fold(0, |n, env| n + env.programs.len()))
+}
+
+#[test]
+fn test_program_cache() {
+    use env::*;
+    use vector_math::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    assert_eq!(program_cache_len(), 0);
+    VectorMath::<f32>::new(queue.clone()).unwrap();
+    VectorMath::<f32>::new(queue.clone()).unwrap();
+    assert_eq!(program_cache_len(), 1);
+
+    clear_program_cache();
+    assert_eq!(program_cache_len(), 0);
+}
+
+#[test]
+fn test_program_cache_per_environment() {
+    use env::*;
+    use vector_math::*;
+
+    let env = Environment::new_easy().unwrap();
+    VectorMath::<f32>::new(env.queues[0].clone()).unwrap();
+    assert_eq!(program_cache_len(), 1);
+
+    // each context keeps its own programs, even on the same device
+    let other = Environment::new_easy().unwrap();
+    VectorMath::<f32>::new(other.queues[0].clone()).unwrap();
+    assert_eq!(program_cache_len(), 2);
+    VectorMath::<f32>::new(env.queues[0].clone()).unwrap();
+    VectorMath::<f32>::new(other.queues[0].clone()).unwrap();
+    assert_eq!(program_cache_len(), 2);
+
+    drop(env);
+    assert_eq!(program_cache_len(), 1);
+    VectorMath::<f32>::new(other.queues[0].clone()).unwrap();
+    assert_eq!(program_cache_len(), 1);
+}
+
+#[test]
+fn test_double_precision_program() {
+    use env::*;
+    use vector_math::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+    if !supports_double(queue).unwrap() {
+        // unsupported precision is a build error, not a panic
+        assert!(VectorMath::<f64>::new(queue.clone()).is_err());
+        return;
+    }
+
+    // f32 and f64 variants are distinct programs
+    VectorMath::<f32>::new(queue.clone()).unwrap();
+    let mut vm = VectorMath::<f64>::new(queue.clone()).unwrap();
+    assert!(program_cache_len() >= 2);
+
+    let x: Vec<f64> = vec![1.0 + 1e-12; 16];
+    let mut y: Vec<f64> = vec![0.0; 16];
+    let x_buf = queue.create_buffer_from_slice(&x).unwrap();
+    let mut y_buf = queue.create_buffer_from_slice(&y).unwrap();
+    vm.mix(16, &x_buf, &x_buf, 1.0, -1.0, &mut y_buf, &[]).unwrap().wait().unwrap();
+    queue.read_buffer(&y_buf, &mut y).unwrap().wait().unwrap();
+    assert!(y.iter().all(|&v| v == 0.0));
+
+    vm.set(16, &mut y_buf, 1e-12, &[]).unwrap().wait().unwrap();
+    queue.read_buffer(&y_buf, &mut y).unwrap().wait().unwrap();
+    assert!(y.iter().all(|&v| v == 1e-12));
+}
@@ -0,0 +1,468 @@
+extern crate num;
+extern crate rand;
+extern crate toml;
+use self::toml::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use serialize::*;
+use cl_traits::*;
+use optics::*;
+use lens::*;
+
+// coordinates are ordered { s u t v }, as in `Optics_apply`
+const NAMES: [&'static str; 4] = ["s", "u", "t", "v"];
+
+/// General affine ray transfer
+///
+/// Unlike `Optics`, which keeps separate 2x2 blocks for `(s, u)` and
+/// `(t, v)`, a `RayTransfer` is a full 4x4 matrix and can couple the two,
+/// e.g., for cylindrical lenses rotated about the optical axis or for
+/// elements that are tilted relative to each other.
+///
+/// Rays are `[s, u, t, v]` and map to `m * ray + offset`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RayTransfer<F: Float> {
+    pub m: [[F; 4]; 4],
+    pub offset: [F; 4],
+}
+
+impl<F: Float> RayTransfer<F> {
+    /// Ray transfer that does nothing
+    pub fn identity() -> Self {
+        let mut m = [[F::zero(); 4]; 4];
+        for i in 0..4 {
+            m[i][i] = F::one();
+        }
+        RayTransfer {
+            m: m,
+            offset: [F::zero(); 4],
+        }
+    }
+
+    /// Free space propagation by `dist`
+    pub fn translation(dist: &F) -> Self {
+        RayTransfer::from_optics(&Optics::translation(dist))
+    }
+
+    /// Ray transfer equivalent to a separable `Optics`
+    pub fn from_optics(optics: &Optics<F>) -> Self {
+        let z = F::zero();
+        RayTransfer {
+            m: [[optics.ss, optics.su, z, z],
+                [optics.us, optics.uu, z, z],
+                [z, z, optics.tt, optics.tv],
+                [z, z, optics.vt, optics.vv]],
+            offset: [optics.s, optics.u, optics.t, optics.v],
+        }
+    }
+
+    /// Returns the equivalent `Optics` if `s` and `t` are not coupled
+    pub fn as_optics(self: &Self) -> Option<Optics<F>> {
+        if !self.is_separable() {
+            return None;
+        }
+        Some(Optics {
+            ss: self.m[0][0],
+            su: self.m[0][1],
+            us: self.m[1][0],
+            uu: self.m[1][1],
+
+            tt: self.m[2][2],
+            tv: self.m[2][3],
+            vt: self.m[3][2],
+            vv: self.m[3][3],
+
+            s: self.offset[0],
+            t: self.offset[2],
+            u: self.offset[1],
+            v: self.offset[3],
+        })
+    }
+
+    /// Returns true if the `(s, u)` and `(t, v)` blocks are not coupled
+    ///
+    /// Couplings count as zero if they are within roundoff of the entries
+    /// with the same units, so that, e.g., a cylindrical lens rotated by a
+    /// quarter turn is separable.
+    pub fn is_separable(self: &Self) -> bool {
+        let tol = F::epsilon() * (F::one() + F::one()).powi(6);
+        for &i in [0, 1].iter() {
+            for &j in [2, 3].iter() {
+                // the diagonal block entries in the same rows and columns
+                // as `m[i][j]` and `m[j][i]` have the same units
+                let scale = self.m[i][j - 2].abs() + self.m[i + 2][j].abs() +
+                            self.m[j - 2][i].abs() +
+                            self.m[j][i + 2].abs();
+                if self.m[i][j].abs() > tol * scale || self.m[j][i].abs() > tol * scale {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Rotation by `angle` radians about the optical axis
+    ///
+    /// Positions and directions rotate together.
+    pub fn rotation(angle: F) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let z = F::zero();
+        RayTransfer {
+            m: [[cos, z, -sin, z], [z, cos, z, -sin], [sin, z, cos, z], [z, sin, z, cos]],
+            offset: [z; 4],
+        }
+    }
+
+    /// Returns this transfer applied in a frame rotated by `angle` radians
+    /// about the optical axis
+    ///
+    /// For example, `RayTransfer::from_optics(&Optics::anisotropic_lens(..))
+    /// .rotated(angle)` is a cylindrical lens whose axes are rotated by
+    /// `angle`.
+    pub fn rotated(self: &Self, angle: F) -> Self {
+        RayTransfer::rotation(-angle).then(self).then(&RayTransfer::rotation(angle))
+    }
+
+    /// Applies this transfer to a ray `[s, u, t, v]`
+    pub fn apply(self: &Self, ray: &[F; 4]) -> [F; 4] {
+        let mut tr = self.offset;
+        for i in 0..4 {
+            for j in 0..4 {
+                tr[i] = tr[i] + self.m[i][j] * ray[j];
+            }
+        }
+        tr
+    }
+
+    /// Apply this transformation after the given one
+    pub fn compose(self: &Self, rhs: &Self) -> Self {
+        let mut m = [[F::zero(); 4]; 4];
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    m[i][j] = m[i][j] + self.m[i][k] * rhs.m[k][j];
+                }
+            }
+        }
+        let offset = self.apply(&rhs.offset);
+        RayTransfer {
+            m: m,
+            offset: offset,
+        }
+    }
+
+    /// Apply this transformation after the given one
+    pub fn then(self: &Self, then: &Self) -> Self {
+        then.compose(self)
+    }
+
+    /// Returns the inverse of this transformation
+    ///
+    /// Panics if the matrix is singular.
+    pub fn invert(self: &Self) -> Self {
+        // Gauss-Jordan elimination with partial pivoting
+        let mut a = self.m;
+        let mut inv = RayTransfer::<F>::identity().m;
+        for col in 0..4 {
+            let mut pivot = col;
+            for row in col + 1..4 {
+                if a[row][col].abs() > a[pivot][col].abs() {
+                    pivot = row;
+                }
+            }
+            assert!(a[pivot][col] != F::zero(), "Singular ray transfer");
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let p = a[col][col];
+            for j in 0..4 {
+                a[col][j] = a[col][j] / p;
+                inv[col][j] = inv[col][j] / p;
+            }
+            for row in 0..4 {
+                if row != col {
+                    let f = a[row][col];
+                    for j in 0..4 {
+                        a[row][j] = a[row][j] - f * a[col][j];
+                        inv[row][j] = inv[row][j] - f * inv[col][j];
+                    }
+                }
+            }
+        }
+
+        let mut tr = RayTransfer {
+            m: inv,
+            offset: [F::zero(); 4],
+        };
+        let shifted = tr.apply(&self.offset);
+        for i in 0..4 {
+            tr.offset[i] = -shifted[i];
+        }
+        tr
+    }
+
+    /// Returns the distances at which rays from a point before this
+    /// transfer converge after it, like `Optics::focused_distance`
+    ///
+    /// See `RayTransfer::focus_at_distance`.
+    pub fn focused_distance(self: &Self) -> Result<(F, F), ()> {
+        RayTransfer::focus_at_distance(self, &RayTransfer::identity())
+    }
+
+    /// Returns the translation distances between `pre_optics` and
+    /// `post_optics` that focus them, like `Optics::focus_at_distance`
+    ///
+    /// At these distances the position of a ray leaving `post_optics` does
+    /// not depend on its direction entering `pre_optics` along some axis.
+    /// Separable transfers are focused in `s` and `t`, in that order;
+    /// otherwise these are the two principal (astigmatic) focus distances in
+    /// increasing order.  Returns `Err` if they are not real.
+    pub fn focus_at_distance(pre_optics: &Self, post_optics: &Self) -> Result<(F, F), ()> {
+        if let (Some(pre), Some(post)) = (pre_optics.as_optics(), post_optics.as_optics()) {
+            return Ok(Optics::focus_at_distance(&pre, &post));
+        }
+
+        // translating by `d` makes the direction to position block
+        // `b0 + d * b1`, which is singular at the focus distances
+        let b0 = post_optics.compose(pre_optics).block(0, 1);
+        let pp = post_optics.block(0, 0);
+        let dd = pre_optics.block(1, 1);
+        let mut b1 = [[F::zero(); 2]; 2];
+        for i in 0..2 {
+            for j in 0..2 {
+                b1[i][j] = pp[i][0] * dd[0][j] + pp[i][1] * dd[1][j];
+            }
+        }
+
+        let a = b1[0][0] * b1[1][1] - b1[0][1] * b1[1][0];
+        let b = b0[0][0] * b1[1][1] + b1[0][0] * b0[1][1] - b0[0][1] * b1[1][0] -
+                b1[0][1] * b0[1][0];
+        let c = b0[0][0] * b0[1][1] - b0[0][1] * b0[1][0];
+        if a == F::zero() {
+            return Err(());
+        }
+
+        // rotated isotropic lenses have a double root, which roundoff can
+        // push slightly negative
+        let two = F::one() + F::one();
+        let mut disc = b * b - two * two * a * c;
+        if disc < F::zero() && -disc <= F::epsilon() * two.powi(6) * b * b {
+            disc = F::zero();
+        }
+        if disc < F::zero() {
+            return Err(());
+        }
+        let r0 = (-b - disc.sqrt()) / (two * a);
+        let r1 = (-b + disc.sqrt()) / (two * a);
+        Ok((r0.min(r1), r0.max(r1)))
+    }
+
+    /// Returns the 2x2 block mapping `from` coordinates onto `into`
+    /// coordinates, where both are `0` for positions and `1` for directions
+    pub fn block(self: &Self, into: usize, from: usize) -> [[F; 2]; 2] {
+        [[self.m[into][from], self.m[into][from + 2]],
+         [self.m[into + 2][from], self.m[into + 2][from + 2]]]
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for RayTransfer<F> {
+    /// Entries are named like `Optics`', e.g., `su` is the `(s, u)` entry
+    /// and `s` is the `s` offset; entries that are missing are zero, so an
+    /// `Optics` table loads as a `RayTransfer`.
+    fn from_map(map: &Table) -> Option<Self> {
+        let get = |key: String| {
+            match map.get(&key) {
+                Some(&Value::Float(x)) => F::from_f64(x),
+                Some(_) => None,
+                None => Some(F::zero()),
+            }
+        };
+
+        let mut tr = RayTransfer {
+            m: [[F::zero(); 4]; 4],
+            offset: [F::zero(); 4],
+        };
+        for i in 0..4 {
+            for j in 0..4 {
+                tr.m[i][j] = match get(format!("{}{}", NAMES[i], NAMES[j])) {
+                    Some(x) => x,
+                    None => return None,
+                };
+            }
+            tr.offset[i] = match get(NAMES[i].to_string()) {
+                Some(x) => x,
+                None => return None,
+            };
+        }
+        Some(tr)
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        for i in 0..4 {
+            for j in 0..4 {
+                tr.insert(format!("{}{}", NAMES[i], NAMES[j]),
+                          Value::Float(F::to_f64(&self.m[i][j]).unwrap()));
+            }
+            tr.insert(NAMES[i].to_string(),
+                      Value::Float(F::to_f64(&self.offset[i]).unwrap()));
+        }
+        tr
+    }
+}
+
+impl<F: Float + ToPrimitive> ClBuffer for RayTransfer<F> {
+    fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
+        for row in self.m.iter() {
+            for x in row.iter() {
+                write_real(buf, x);
+            }
+        }
+        for x in self.offset.iter() {
+            write_real(buf, x);
+        }
+    }
+}
+
+/// Reads the optional `transfer` of a camera configuration
+///
+/// Returns `Err` if there is a `transfer` table that cannot be read.
+pub fn transfer_from_map<F>(map: &Table) -> Result<Option<RayTransfer<F>>, ()>
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    match map.get("transfer") {
+        None => Ok(None),
+        Some(&Value::Table(ref tab)) => RayTransfer::from_map(tab).map(Some).ok_or(()),
+        Some(_) => Err(()),
+    }
+}
+
+/// Ray transfer through a camera's main lens, replaced by its `transfer` if
+/// it has one
+pub fn main_lens_transfer<F>(lens: &Lens<F>, transfer: &Option<RayTransfer<F>>) -> RayTransfer<F>
+    where F: Float + FromPrimitive
+{
+    match transfer {
+        &Some(ref transfer) => transfer.clone(),
+        &None => RayTransfer::from_optics(&lens.optics()),
+    }
+}
+
+#[test]
+fn test_ray_transfer_matches_optics() {
+    use self::rand::*;
+
+    let d0 = thread_rng().next_f64().abs();
+    let d1 = thread_rng().next_f64().abs();
+    let c_s = thread_rng().next_f64();
+    let c_t = thread_rng().next_f64();
+    let f_s = thread_rng().next_f64().abs();
+    let f_t = thread_rng().next_f64().abs();
+
+    let optics = Optics::translation(&d0)
+                     .then(&Optics::anisotropic_lens(&c_s, &c_t, &f_s, &f_t))
+                     .then(&Optics::translation(&d1));
+    let x = RayTransfer::from_optics(&Optics::translation(&d0))
+                .then(&RayTransfer::from_optics(&Optics::anisotropic_lens(&c_s, &c_t, &f_s, &f_t)))
+                .then(&RayTransfer::from_optics(&Optics::translation(&d1)));
+    assert!(x.is_separable());
+
+    let y = x.as_optics().unwrap();
+    assert!((y.ss - optics.ss).abs() < 1e-10);
+    assert!((y.su - optics.su).abs() < 1e-10);
+    assert!((y.us - optics.us).abs() < 1e-10);
+    assert!((y.vv - optics.vv).abs() < 1e-10);
+    assert!((y.u - optics.u).abs() < 1e-10);
+    assert!((y.v - optics.v).abs() < 1e-10);
+
+    let xi = RayTransfer::from_optics(&optics.invert());
+    let yi = x.invert();
+    for i in 0..4 {
+        for j in 0..4 {
+            assert!((xi.m[i][j] - yi.m[i][j]).abs() < 1e-8);
+        }
+        assert!((xi.offset[i] - yi.offset[i]).abs() < 1e-8);
+    }
+}
+
+#[test]
+fn test_rotated_ray_transfer() {
+    use self::rand::*;
+
+    let angle = thread_rng().next_f64();
+    let f_s = 1f64 + thread_rng().next_f64().abs();
+    let f_t = 1f64 + thread_rng().next_f64().abs();
+
+    let lens = RayTransfer::from_optics(&Optics::anisotropic_lens(&0f64, &0f64, &f_s, &f_t))
+                   .rotated(angle);
+    assert!(!lens.is_separable());
+
+    // a quarter turn swaps the axes up to roundoff, but other angles couple
+    // them
+    let cylindrical = RayTransfer::from_optics(&Optics::anisotropic_lens(&0f32,
+                                                                         &0f32,
+                                                                         &20f32,
+                                                                         &24f32));
+    assert!(cylindrical.rotated(::std::f32::consts::PI / 2f32).is_separable());
+    assert!(!cylindrical.rotated(::std::f32::consts::PI / 6f32).is_separable());
+
+    // a ray through the rotated s axis is bent by f_s only
+    let (sin, cos) = angle.sin_cos();
+    let out = lens.apply(&[cos, 0f64, sin, 0f64]);
+    assert!((out[1] + cos / f_s).abs() < 1e-10);
+    assert!((out[3] + sin / f_s).abs() < 1e-10);
+
+    // inverse composes to the identity
+    let x = lens.then(&RayTransfer::from_optics(&Optics::translation(&3f64)));
+    let xix = x.invert().compose(&x);
+    let id = RayTransfer::<f64>::identity();
+    for i in 0..4 {
+        for j in 0..4 {
+            assert!((xix.m[i][j] - id.m[i][j]).abs() < 1e-8);
+        }
+        assert!(xix.offset[i].abs() < 1e-8);
+    }
+
+    let y: RayTransfer<f64> = RayTransfer::from_map(&x.into_map()).unwrap();
+    assert_eq!(x, y);
+
+    let z: RayTransfer<f64> = RayTransfer::from_map(&Optics::translation(&3f64).into_map())
+                                  .unwrap();
+    assert_eq!(z, RayTransfer::from_optics(&Optics::translation(&3f64)));
+}
+
+#[test]
+fn test_ray_transfer_focus() {
+    use self::rand::*;
+
+    let f_s = 10f64 + thread_rng().next_f64().abs();
+    let f_t = 20f64 + thread_rng().next_f64().abs();
+    let z = 100f64 + thread_rng().next_f64().abs();
+    let lens = Optics::anisotropic_lens(&0f64, &0f64, &f_s, &f_t);
+
+    // separable transfers focus like `Optics`
+    let post = lens.then(&Optics::translation(&z));
+    let (ds, dt) = Optics::focus_at_distance(&Optics::identity(), &post);
+    let (rs, rt) = RayTransfer::focus_at_distance(&RayTransfer::identity(),
+                                                  &RayTransfer::from_optics(&post))
+                       .unwrap();
+    assert_eq!((ds, dt), (rs, rt));
+
+    // rotating the lens keeps its focus distances along its own axes
+    for &angle in [0.3f64, 1.2f64].iter() {
+        let rotated = RayTransfer::from_optics(&lens).rotated(angle);
+        let post = rotated.then(&RayTransfer::translation(&z));
+        let (r0, r1) = RayTransfer::focus_at_distance(&RayTransfer::identity(), &post).unwrap();
+        assert!((r0 - ds.min(dt)).abs() < 1e-8 * ds.abs());
+        assert!((r1 - ds.max(dt)).abs() < 1e-8 * ds.abs());
+
+        let (z0, z1) = RayTransfer::translation(&ds).then(&rotated).focused_distance().unwrap();
+        assert!((z0 - z).abs() < 1e-6 * z || (z1 - z).abs() < 1e-6 * z);
+    }
+
+    // an isotropic lens has a double root
+    let iso = RayTransfer::from_optics(&Optics::anisotropic_lens(&0f64, &0f64, &f_s, &f_s))
+                  .rotated(0.7f64)
+                  .then(&RayTransfer::translation(&z));
+    let (r0, r1) = RayTransfer::focus_at_distance(&RayTransfer::identity(), &iso).unwrap();
+    assert!((r0 - r1).abs() < 1e-6 * r0.abs());
+}
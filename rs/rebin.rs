@@ -0,0 +1,358 @@
+extern crate num;
+extern crate proust;
+
+use light_field_geom::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::proust::*;
+use program_cache::*;
+use profiler::*;
+
+/// Sparse row-compressed rebinning matrix between two angular planes
+#[derive(Clone, Debug)]
+struct RebinMatrix<F: Float> {
+    row_ptr: Vec<usize>,
+    cols: Vec<usize>,
+    vals: Vec<F>,
+}
+
+impl<F: Float> RebinMatrix<F> {
+    /// Builds a matrix with `nrows` rows from `(row, col, val)` triplets
+    fn from_triplets(nrows: usize, triplets: &[(usize, usize, F)]) -> Self {
+        let mut sorted = triplets.to_owned();
+        sorted.sort_by(|l, r| (l.0, l.1).cmp(&(r.0, r.1)));
+
+        let mut row_ptr = vec![0; nrows + 1];
+        for &(row, _, _) in sorted.iter() {
+            row_ptr[row + 1] += 1;
+        }
+        for irow in 0..nrows {
+            row_ptr[irow + 1] += row_ptr[irow];
+        }
+
+        RebinMatrix {
+            row_ptr: row_ptr,
+            cols: sorted.iter().map(|&(_, c, _)| c).collect(),
+            vals: sorted.iter().map(|&(_, _, v)| v).collect(),
+        }
+    }
+
+    fn nrows(self: &Self) -> usize {
+        self.row_ptr.len() - 1
+    }
+
+    /// Computes `y = A x` for light fields with `np` pixels per angle
+    fn apply(self: &Self, np: usize, x: &[F], y: &mut [F]) {
+        for irow in 0..self.nrows() {
+            let y_row = &mut y[np * irow..np * (irow + 1)];
+            for v in y_row.iter_mut() {
+                *v = F::zero();
+            }
+            for k in self.row_ptr[irow]..self.row_ptr[irow + 1] {
+                let x_col = &x[np * self.cols[k]..np * (self.cols[k] + 1)];
+                for (yi, &xi) in y_row.iter_mut().zip(x_col.iter()) {
+                    *yi = *yi + self.vals[k] * xi;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the forward and adjoint rebinning matrices between two light
+/// field geometries
+fn rebin_matrices<F: Float + FromPrimitive>(src: &LightFieldGeometry<F>,
+                                            dst: &LightFieldGeometry<F>)
+                                            -> (RebinMatrix<F>, RebinMatrix<F>) {
+    assert!(src.geom.ns == dst.geom.ns && src.geom.nt == dst.geom.nt,
+            "Rebin requires light fields with the same spatial geometry");
+    let triplets = src.plane.rebin_weights(&dst.plane);
+    let transposed: Vec<(usize, usize, F)> = triplets.iter()
+                                                      .map(|&(r, c, v)| (c, r, v))
+                                                      .collect();
+    (RebinMatrix::from_triplets(dst.plane.na(), &triplets),
+     RebinMatrix::from_triplets(src.plane.na(), &transposed))
+}
+
+/// Resamples a light field between two angular discretizations
+///
+/// Light fields are stored angle-major: the `ns*nt` image for angle `ia`
+/// starts at `ia*ns*nt`. Both geometries must share the same spatial
+/// geometry and plane; only the angular plane (basis and discretization)
+/// changes. Unlike `Transport`, the source and destination angular bases may
+/// differ.
+pub struct Rebin<F: Float> {
+    pub src: LightFieldGeometry<F>,
+    pub dst: LightFieldGeometry<F>,
+
+    queue: CommandQueue,
+    kernel: Kernel,
+
+    forw_row_ptr: Mem,
+    forw_cols: Mem,
+    forw_vals: Mem,
+
+    back_row_ptr: Mem,
+    back_cols: Mem,
+    back_vals: Mem,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Rebin<F> {
+    pub fn new(src: LightFieldGeometry<F>,
+               dst: LightFieldGeometry<F>,
+               queue: CommandQueue)
+               -> Result<Self, Error> {
+        let (forw, back) = rebin_matrices(&src, &dst);
+
+        // compile opencl code
+        let sources = &[include_str!("../cl/rebin_f32.opencl")];
+        let program = try!(cached_program::<F>(&queue, sources));
+        let kernel = try!(program.create_kernel("Rebin_apply"));
+
+        // upload matrices
+        let to_i32 = |v: &[usize]| -> Vec<i32> { v.iter().map(|&x| x as i32).collect() };
+        let forw_row_ptr = try!(queue.create_buffer_from_slice(&to_i32(&forw.row_ptr)));
+        let forw_cols = try!(queue.create_buffer_from_slice(&to_i32(&forw.cols)));
+        let forw_vals = try!(queue.create_buffer_from_slice(&forw.vals));
+        let back_row_ptr = try!(queue.create_buffer_from_slice(&to_i32(&back.row_ptr)));
+        let back_cols = try!(queue.create_buffer_from_slice(&to_i32(&back.cols)));
+        let back_vals = try!(queue.create_buffer_from_slice(&back.vals));
+
+        Ok(Rebin {
+            src: src,
+            dst: dst,
+
+            queue: queue,
+            kernel: kernel,
+
+            forw_row_ptr: forw_row_ptr,
+            forw_cols: forw_cols,
+            forw_vals: forw_vals,
+
+            back_row_ptr: back_row_ptr,
+            back_cols: back_cols,
+            back_vals: back_vals,
+        })
+    }
+
+    /// Rebin from source to destination, overwriting the destination
+    pub fn forw(self: &mut Self,
+                src: &Mem,
+                dst: &mut Mem,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        let np = self.src.geom.ns * self.src.geom.nt;
+        let nrows = self.dst.plane.na();
+
+        try!(self.kernel.bind_scalar(0, &(np as i32)));
+        try!(self.kernel.bind_scalar(1, &(nrows as i32)));
+        try!(self.kernel.bind(2, &self.forw_row_ptr));
+        try!(self.kernel.bind(3, &self.forw_cols));
+        try!(self.kernel.bind(4, &self.forw_vals));
+        try!(self.kernel.bind(5, src));
+        try!(self.kernel.bind_mut(6, dst));
+
+        let local_size = (256, 1, 1);
+        let global_size = (np, nrows, 1);
+
+        run_kernel(&self.queue, &mut self.kernel, "Rebin_apply", local_size, global_size, wait_for)
+    }
+
+    /// Adjoint rebin from destination to source, overwriting the source
+    pub fn back(self: &mut Self,
+                dst: &Mem,
+                src: &mut Mem,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        let np = self.src.geom.ns * self.src.geom.nt;
+        let nrows = self.src.plane.na();
+
+        try!(self.kernel.bind_scalar(0, &(np as i32)));
+        try!(self.kernel.bind_scalar(1, &(nrows as i32)));
+        try!(self.kernel.bind(2, &self.back_row_ptr));
+        try!(self.kernel.bind(3, &self.back_cols));
+        try!(self.kernel.bind(4, &self.back_vals));
+        try!(self.kernel.bind(5, dst));
+        try!(self.kernel.bind_mut(6, src));
+
+        let local_size = (256, 1, 1);
+        let global_size = (np, nrows, 1);
+
+        run_kernel(&self.queue, &mut self.kernel, "Rebin_apply", local_size, global_size, wait_for)
+    }
+
+    /// Rebin from source to destination (all-host utility function)
+    pub fn forw_host(self: &mut Self, src: &[F], dst: &mut [F]) -> Result<(), Error> {
+        let src_buf = try!(self.queue.create_buffer_from_slice(src));
+        let mut dst_buf = try!(self.queue.create_buffer_from_slice(dst));
+        try!(try!(self.forw(&src_buf, &mut dst_buf, &[])).wait());
+        try!(try!(self.queue.read_buffer(&dst_buf, dst)).wait());
+        Ok(())
+    }
+
+    /// Adjoint rebin from destination to source (all-host utility function)
+    pub fn back_host(self: &mut Self, dst: &[F], src: &mut [F]) -> Result<(), Error> {
+        let mut src_buf = try!(self.queue.create_buffer_from_slice(src));
+        let dst_buf = try!(self.queue.create_buffer_from_slice(dst));
+        try!(try!(self.back(&dst_buf, &mut src_buf, &[])).wait());
+        try!(try!(self.queue.read_buffer(&src_buf, src)).wait());
+        Ok(())
+    }
+}
+
+/// Host implementation of `Rebin`
+pub struct HostRebin<F: Float> {
+    pub src: LightFieldGeometry<F>,
+    pub dst: LightFieldGeometry<F>,
+
+    forw_matrix: RebinMatrix<F>,
+    back_matrix: RebinMatrix<F>,
+}
+
+impl<F: Float + FromPrimitive> HostRebin<F> {
+    pub fn new(src: LightFieldGeometry<F>, dst: LightFieldGeometry<F>) -> Self {
+        let (forw, back) = rebin_matrices(&src, &dst);
+        HostRebin {
+            src: src,
+            dst: dst,
+            forw_matrix: forw,
+            back_matrix: back,
+        }
+    }
+
+    /// Rebin from source to destination, overwriting the destination
+    pub fn forw(self: &Self, src: &[F], dst: &mut [F]) {
+        let np = self.src.geom.ns * self.src.geom.nt;
+        self.forw_matrix.apply(np, src, dst);
+    }
+
+    /// Adjoint rebin from destination to source, overwriting the source
+    pub fn back(self: &Self, dst: &[F], src: &mut [F]) {
+        let np = self.src.geom.ns * self.src.geom.nt;
+        self.back_matrix.apply(np, dst, src);
+    }
+}
+
+#[cfg(test)]
+fn test_rebin_geometries(src_basis: ::angular_plane::AngularBasis,
+                         src_na: usize,
+                         dst_basis: ::angular_plane::AngularBasis,
+                         dst_na: usize)
+                         -> (LightFieldGeometry<f32>, LightFieldGeometry<f32>) {
+    use lens::*;
+    use angular_plane::*;
+    use image_geom::*;
+    use optics::*;
+
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 10f32,
+        radius_t: 8f32,
+        focal_length_s: 30f32,
+        focal_length_t: 30f32,
+    };
+    let geom = ImageGeometry {
+        ns: 12,
+        nt: 10,
+        ds: 0.5,
+        dt: 0.5,
+        offset_s: 0.0,
+        offset_t: 0.0,
+    };
+    let to_plane = Optics::translation(&40f32);
+    let src = LightFieldGeometry {
+        geom: geom.clone(),
+        plane: lens.as_angular_plane(src_basis, src_na),
+        to_plane: to_plane.clone(),
+    };
+    let dst = LightFieldGeometry {
+        geom: geom,
+        plane: lens.as_angular_plane(dst_basis, dst_na),
+        to_plane: to_plane,
+    };
+    (src, dst)
+}
+
+#[test]
+fn test_host_rebin_adjoint() {
+    use angular_plane::*;
+    use geom::*;
+
+    let bases = vec![AngularBasis::Pillbox, AngularBasis::Dirac, AngularBasis::Linear];
+    for src_basis in bases.iter() {
+        for dst_basis in bases.iter() {
+            let (src, dst) = test_rebin_geometries(src_basis.clone(), 7, dst_basis.clone(), 4);
+            let rebin = HostRebin::new(src.clone(), dst.clone());
+
+            let np = src.geom.ns * src.geom.nt;
+            let x: Vec<f32> = (0..src.plane.na()).flat_map(|_| src.geom.rands()).collect();
+            let y: Vec<f32> = (0..dst.plane.na()).flat_map(|_| dst.geom.rands()).collect();
+            let mut ax = vec![0f32; np * dst.plane.na()];
+            let mut aty = vec![0f32; np * src.plane.na()];
+            rebin.forw(&x, &mut ax);
+            rebin.back(&y, &mut aty);
+
+            let v1 = ax.iter().zip(y.iter()).fold(0f32, |s, (a, b)| s + a * b);
+            let v2 = aty.iter().zip(x.iter()).fold(0f32, |s, (a, b)| s + a * b);
+            let nrmse = (v1 - v2).abs() / v1.abs().max(v2.abs());
+            println!("Rebin {:?} -> {:?} adjoint NRMSE: {}", src_basis, dst_basis, nrmse);
+            assert!(nrmse < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_host_rebin_constant_and_identity() {
+    use angular_plane::*;
+
+    // a constant light field should stay constant wherever the destination
+    // angles are fully covered by source angles
+    let (src, dst) = test_rebin_geometries(AngularBasis::Pillbox, 8, AngularBasis::Pillbox, 4);
+    let rebin = HostRebin::new(src.clone(), dst.clone());
+    let weights = src.plane.rebin_weights(&dst.plane);
+
+    let np = src.geom.ns * src.geom.nt;
+    let x = vec![1f32; np * src.plane.na()];
+    let mut y = vec![0f32; np * dst.plane.na()];
+    rebin.forw(&x, &mut y);
+
+    for ia in 0..dst.plane.na() {
+        let total = weights.iter()
+                           .filter(|&&(r, _, _)| r == ia)
+                           .fold(0f32, |s, &(_, _, w)| s + w);
+        assert!((y[np * ia] - total).abs() < 1e-5);
+        assert!(total <= 1f32 + 1e-5);
+    }
+
+    // Pillbox to Dirac on the same plane is the identity
+    let (src, dst) = test_rebin_geometries(AngularBasis::Pillbox, 5, AngularBasis::Dirac, 5);
+    let rebin = HostRebin::new(src.clone(), dst.clone());
+    let x: Vec<f32> = (0..np * src.plane.na()).map(|i| i as f32).collect();
+    let mut y = vec![0f32; np * dst.plane.na()];
+    rebin.forw(&x, &mut y);
+    assert_eq!(x, y);
+}
+
+#[test]
+fn test_rebin_matches_host() {
+    use angular_plane::*;
+    use env::*;
+    use geom::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let (src, dst) = test_rebin_geometries(AngularBasis::Pillbox, 7, AngularBasis::Dirac, 4);
+    let host = HostRebin::new(src.clone(), dst.clone());
+    let mut cl = Rebin::new(src.clone(), dst.clone(), queue.clone()).unwrap();
+
+    let np = src.geom.ns * src.geom.nt;
+    let x: Vec<f32> = (0..src.plane.na()).flat_map(|_| src.geom.rands()).collect();
+    let mut y_host = vec![0f32; np * dst.plane.na()];
+    let mut y_cl = vec![0f32; np * dst.plane.na()];
+    host.forw(&x, &mut y_host);
+    cl.forw_host(&x, &mut y_cl).unwrap();
+
+    for (a, b) in y_host.iter().zip(y_cl.iter()) {
+        assert!((a - b).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,326 @@
+extern crate num;
+extern crate proust;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::proust::*;
+use geom::*;
+use image_geom::*;
+use angular_plane::*;
+use light_field_geom::*;
+use optics::*;
+use aperture::*;
+use occluder::*;
+use transport::*;
+use host_transport::*;
+
+/// Angular samples used to render an image from a light field
+///
+/// The aperture only selects and weights samples that are already on the
+/// light field's angular plane; it cannot open wider than the capture.
+#[derive(Clone, Debug)]
+pub enum SyntheticAperture<F: Float> {
+    /// Every sample, weighted by its quadrature weight
+    Full,
+
+    /// Samples inside a stop, weighted by the fraction of their cell it
+    /// transmits
+    Stop(Aperture<F>),
+
+    /// Only the listed samples
+    Subset(Vec<usize>),
+
+    /// Explicit per-sample weights
+    Weights(Vec<F>),
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> SyntheticAperture<F> {
+    /// Returns the weight of each sample on `plane`
+    ///
+    /// Fails if a `Subset` lists an angle that is not on `plane` or if
+    /// `Weights` has the wrong length.
+    pub fn weights(self: &Self, plane: &AngularPlane<F>) -> Result<Vec<F>, ()> {
+        let c2 = F::from_f32(2f32).unwrap();
+        match self {
+            &SyntheticAperture::Full => Ok(plane.w.clone()),
+            &SyntheticAperture::Stop(ref stop) => {
+                Ok((0..plane.na())
+                    .map(|ia| {
+                        let (s, t) = (plane.s[ia], plane.t[ia]);
+                        let hs = plane.ds / c2;
+                        let ht = plane.dt / c2;
+                        let open = F::one() - stop.rasterize(s - hs, s + hs, t - ht, t + ht, 10);
+                        plane.w[ia] * open
+                    })
+                    .collect())
+            }
+            &SyntheticAperture::Subset(ref angles) => {
+                let mut tr = vec![F::zero(); plane.na()];
+                for &ia in angles.iter() {
+                    if ia >= plane.na() {
+                        return Err(());
+                    }
+                    tr[ia] = plane.w[ia];
+                }
+                Ok(tr)
+            }
+            &SyntheticAperture::Weights(ref w) => {
+                if w.len() != plane.na() {
+                    return Err(());
+                }
+                Ok(w.clone())
+            }
+        }
+    }
+}
+
+/// Renders refocused images from a light field
+///
+/// Each sub-aperture view is transported to the focal plane and the views
+/// are averaged with the synthetic aperture's weights, so a uniform light
+/// field renders as a uniform image of the same value wherever the views
+/// cover the focal plane.
+#[derive(Clone, Debug)]
+pub struct Refocuser<F: Float> {
+    pub lfg: LightFieldGeometry<F>,
+
+    /// Weight of each angular sample
+    pub weights: Vec<F>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Refocuser<F> {
+    /// Fails if `aperture` does not fit the light field's angular plane
+    pub fn new(lfg: LightFieldGeometry<F>, aperture: &SyntheticAperture<F>) -> Result<Self, ()> {
+        let weights = try!(aperture.weights(&lfg.plane));
+        Ok(Refocuser {
+            lfg: lfg,
+            weights: weights,
+        })
+    }
+
+    /// Returns the focal plane `distance` in front of the angular plane
+    ///
+    /// This is where a sensor would sit to be focused there, so for a
+    /// light field on the image side of a lens, `distance` is the image
+    /// distance rather than the object distance.
+    pub fn focal_plane(self: &Self, geom: ImageGeometry<F>, distance: F) -> LightFieldGeometry<F> {
+        LightFieldGeometry {
+            geom: geom,
+            plane: self.lfg.plane.clone(),
+            to_plane: Optics::translation(&distance),
+        }
+    }
+
+    /// Angles that contribute to the image
+    fn active_angles(self: &Self) -> Vec<usize> {
+        (0..self.weights.len()).filter(|&ia| self.weights[ia] != F::zero()).collect()
+    }
+
+    /// Averages views returned by `views_host` or `views`
+    pub fn combine(self: &Self,
+                   views: &[(usize, Vec<F>)],
+                   focus: &LightFieldGeometry<F>)
+                   -> Vec<F> {
+        let mut tr = focus.geom.zeros();
+        let mut total = F::zero();
+        for &(ia, ref view) in views.iter() {
+            let w = self.weights[ia];
+            for (i, v) in tr.iter_mut().zip(view.iter()) {
+                *i = *i + w * *v;
+            }
+            total = total + w;
+        }
+        if total != F::zero() {
+            for i in tr.iter_mut() {
+                *i = *i / total;
+            }
+        }
+        tr
+    }
+
+    /// Transports the views of `buf` with nonzero weight onto `focus` on the host
+    ///
+    /// Returns each view along with its angle.
+    pub fn views_host(self: &Self,
+                      buf: &[F],
+                      focus: &LightFieldGeometry<F>)
+                      -> Vec<(usize, Vec<F>)> {
+        let np = self.lfg.geom.dimension();
+        let mut xport = HostTransport::new_simple(self.lfg.clone(), focus.clone());
+        self.active_angles()
+            .into_iter()
+            .map(|ia| {
+                let mut view = focus.geom.zeros();
+                xport.forw(&buf[np * ia..np * (ia + 1)], &mut view, ia);
+                (ia, view)
+            })
+            .collect()
+    }
+
+    /// Transports the views of `buf` with nonzero weight onto `focus` using OpenCL
+    pub fn views(self: &Self,
+                 buf: &[F],
+                 focus: &LightFieldGeometry<F>,
+                 queue: &CommandQueue)
+                 -> Result<Vec<(usize, Vec<F>)>, Error> {
+        let np = self.lfg.geom.dimension();
+        let mut xport = try!(Transport::new_simple(self.lfg.clone(),
+                                                   focus.clone(),
+                                                   queue.clone()));
+        let mut tr = Vec::new();
+        for ia in self.active_angles().into_iter() {
+            let mut view = focus.geom.zeros();
+            try!(xport.forw_host(&buf[np * ia..np * (ia + 1)], &mut view, ia));
+            tr.push((ia, view));
+        }
+        Ok(tr)
+    }
+
+    /// Renders `buf`, a light field on `lfg`, onto `focus` on the host
+    pub fn refocus_host(self: &Self, buf: &[F], focus: &LightFieldGeometry<F>) -> Vec<F> {
+        self.combine(&self.views_host(buf, focus), focus)
+    }
+
+    /// Renders `buf`, a light field on `lfg`, onto `focus` using OpenCL
+    pub fn refocus(self: &Self,
+                   buf: &[F],
+                   focus: &LightFieldGeometry<F>,
+                   queue: &CommandQueue)
+                   -> Result<Vec<F>, Error> {
+        let views = try!(self.views(buf, focus, queue));
+        Ok(self.combine(&views, focus))
+    }
+
+    /// Renders a focal stack on the host, one image per distance
+    pub fn focal_stack_host(self: &Self,
+                            buf: &[F],
+                            geom: &ImageGeometry<F>,
+                            distances: &[F])
+                            -> Vec<Vec<F>> {
+        distances.iter()
+                 .map(|&d| self.refocus_host(buf, &self.focal_plane(geom.clone(), d)))
+                 .collect()
+    }
+}
+
+#[cfg(test)]
+fn test_refocus_lfg() -> LightFieldGeometry<f32> {
+    use lens::*;
+
+    let lens = Lens {
+        center_s: 0f32,
+        center_t: 0f32,
+        radius_s: 5f32,
+        radius_t: 5f32,
+        focal_length_s: 20f32,
+        focal_length_t: 20f32,
+    };
+    LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 32,
+            nt: 32,
+            ds: 0.25,
+            dt: 0.25,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        plane: lens.as_angular_plane(AngularBasis::Dirac, 7),
+        to_plane: Optics::translation(&20f32),
+    }
+}
+
+#[test]
+fn test_synthetic_aperture_weights() {
+    let lfg = test_refocus_lfg();
+    let plane = &lfg.plane;
+
+    let full = SyntheticAperture::Full.weights(plane).unwrap();
+    assert_eq!(full, plane.w);
+
+    let subset = SyntheticAperture::Subset(vec![0, 2]).weights(plane).unwrap();
+    assert_eq!(subset[0], plane.w[0]);
+    assert_eq!(subset[1], 0f32);
+    assert_eq!(subset[2], plane.w[2]);
+
+    // angles off the plane and mismatched weights are errors
+    assert!(SyntheticAperture::Subset(vec![0, plane.na()]).weights(plane).is_err());
+    assert!(SyntheticAperture::Weights(vec![1f32; plane.na() + 1]).weights(plane).is_err());
+    assert!(Refocuser::new(lfg.clone(), &SyntheticAperture::Subset(vec![plane.na()])).is_err());
+
+    // a small stop keeps only the samples near the center of the lens
+    let stop = SyntheticAperture::Stop(Aperture::Annulus(AnnularAperture {
+        center_s: 0f32,
+        center_t: 0f32,
+        outer_radius: 1f32,
+        inner_radius: 0f32,
+    }));
+    let w = stop.weights(plane).unwrap();
+    for ia in 0..plane.na() {
+        let r = (plane.s[ia] * plane.s[ia] + plane.t[ia] * plane.t[ia]).sqrt();
+        if r > 1f32 + plane.ds {
+            assert_eq!(w[ia], 0f32);
+        }
+        assert!(w[ia] <= plane.w[ia]);
+    }
+    assert!(w.iter().any(|&wi| wi > 0f32));
+}
+
+#[test]
+fn test_refocus_uniform() {
+    // a uniform light field stays uniform at any depth, away from the edges
+    let lfg = test_refocus_lfg();
+    let refocuser = Refocuser::new(lfg.clone(), &SyntheticAperture::Full).unwrap();
+    let x = vec![1f32; lfg.geom.dimension() * lfg.plane.na()];
+    let stack = refocuser.focal_stack_host(&x, &lfg.geom, &[15f32, 20f32, 25f32]);
+    for image in stack.iter() {
+        for it in 10..22 {
+            for is in 10..22 {
+                assert!((image[is + 32 * it] - 1f32).abs() < 1e-3);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_refocus_brings_point_into_focus() {
+    // light field of a point on the image plane, seen from the lens plane
+    let lfg = test_refocus_lfg();
+    let mut point = lfg.geom.zeros();
+    point[16 + 32 * 16] = 1f32;
+
+    // views of the point on a plane further from the lens are shifted
+    // copies of it; refocusing back to the original plane undoes the shift
+    let far = Refocuser::new(lfg.clone(), &SyntheticAperture::Full)
+                  .unwrap()
+                  .focal_plane(lfg.geom.clone(), 30f32);
+    let mut xport = HostTransport::new_simple(lfg.clone(), far.clone());
+    let np = lfg.geom.dimension();
+    let mut x = vec![0f32; np * lfg.plane.na()];
+    for ia in 0..lfg.plane.na() {
+        xport.forw(&point, &mut x[np * ia..np * (ia + 1)], ia);
+    }
+
+    let refocuser = Refocuser::new(far.clone(), &SyntheticAperture::Full).unwrap();
+    let sharpness = |image: &[f32]| {
+        image.iter().fold(0f32, |m, &v| m.max(v)) / image.iter().fold(0f32, |a, &v| a + v)
+    };
+    let in_focus = refocuser.refocus_host(&x, &lfg);
+    let defocused = refocuser.refocus_host(&x, &refocuser.focal_plane(lfg.geom.clone(), 25f32));
+    assert!(sharpness(&in_focus) > 0.5);
+    assert!(sharpness(&in_focus) > 4f32 * sharpness(&defocused));
+    assert_eq!(in_focus.iter()
+                       .enumerate()
+                       .fold((0, 0f32), |(im, m), (i, &v)| if v > m { (i, v) } else { (im, m) })
+                       .0,
+               16 + 32 * 16);
+
+    // stopping down the synthetic aperture deepens the depth of field
+    let pinhole = Refocuser::new(far.clone(),
+                                 &SyntheticAperture::Stop(Aperture::Annulus(AnnularAperture {
+                                     center_s: 0f32,
+                                     center_t: 0f32,
+                                     outer_radius: 1f32,
+                                     inner_radius: 0f32,
+                                 })))
+                      .unwrap();
+    let stopped = pinhole.refocus_host(&x, &pinhole.focal_plane(lfg.geom.clone(), 25f32));
+    assert!(sharpness(&stopped) > sharpness(&defocused));
+}
@@ -12,11 +12,17 @@ use volume_rotation::*;
 use vector_math::*;
 use angular_plane::*;
 
+/// Images a rotated volume
+///
+/// `tmp` holds the rotated object last projected, which opaque imagers
+/// linearize about until the matching backprojection, so backprojections
+/// and linearized projections rotate through `scratch` instead.
 pub struct RotatedVolumeImager<F: Float + FromPrimitive> {
     pub rotator: Option<VolumeRotation<F>>,
     pub imager: Box<Imager<F, LightVolume<F>>>,
     vecmath: VectorMath<F>,
     tmp: Option<Mem>,
+    scratch: Option<Mem>,
 }
 
 impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> RotatedVolumeImager<F> {
@@ -26,9 +32,11 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> RotatedVolumeImager<F>
                -> Result<Self, Error> {
         if let Some(rotator) = rotator {
             let tmp = try!(rotator.dst_geom.zeros_buf(&queue));
+            let scratch = try!(rotator.dst_geom.zeros_buf(&queue));
             Ok(RotatedVolumeImager {
                 rotator: Some(rotator),
                 tmp: Some(tmp),
+                scratch: Some(scratch),
                 imager: imager,
                 vecmath: try!(VectorMath::new(queue)),
             })
@@ -36,6 +44,7 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> RotatedVolumeImager<F>
             Ok(RotatedVolumeImager {
                 rotator: None,
                 tmp: None,
+                scratch: None,
                 imager: imager,
                 vecmath: try!(VectorMath::new(queue)),
             })
@@ -78,17 +87,34 @@ Imager<F, LightVolume<F>> for RotatedVolumeImager<F> {
         }
     }
 
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &Mem,
+                             view: &mut Mem,
+                             ia: usize,
+                             wait_for: &[Event]) -> Result<Event, Error> {
+        match (&mut self.rotator, &mut self.scratch) {
+            (&mut Some(ref mut rotator), &mut Some(ref mut scratch)) => {
+                let evt = try!(rotator.forw(object, scratch, wait_for));
+                self.imager.forw_linearized_angle(scratch, view, ia, &[evt])
+            },
+            (&mut None, &mut None) => {
+                self.imager.forw_linearized_angle(object, view, ia, wait_for)
+            },
+            _ => panic!("Unexpected state in RotatedVolumeImager")
+        }
+    }
+
     fn back_angle(self: &mut Self,
                   view: &Mem,
                   object: &mut Mem,
                   ia: usize,
                   wait_for: &[Event]) -> Result<Event, Error> {
-        match (&mut self.rotator, &mut self.tmp) {
-            (&mut Some(ref mut rotator), &mut Some(ref mut tmp)) => {
+        match (&mut self.rotator, &mut self.scratch) {
+            (&mut Some(ref mut rotator), &mut Some(ref mut scratch)) => {
                 let np = self.imager.geometry().dimension();
-                let mut evt = try!(self.vecmath.set(np, tmp, F::zero(), wait_for));
-                evt = try!(self.imager.back_angle(view, tmp, ia, &[evt]));
-                rotator.back(tmp, object, &[evt])
+                let mut evt = try!(self.vecmath.set(np, scratch, F::zero(), wait_for));
+                evt = try!(self.imager.back_angle(view, scratch, ia, &[evt]));
+                rotator.back(scratch, object, &[evt])
             },
             (&mut None, &mut None) => {
                 self.imager.back_angle(view, object, ia, wait_for)
@@ -119,12 +145,12 @@ Imager<F, LightVolume<F>> for RotatedVolumeImager<F> {
                   object: &mut Mem,
                   angles: &[usize],
                   wait_for: &[Event]) -> Result<Event, Error> {
-        match (&mut self.rotator, &mut self.tmp) {
-            (&mut Some(ref mut rotator), &mut Some(ref mut tmp)) => {
+        match (&mut self.rotator, &mut self.scratch) {
+            (&mut Some(ref mut rotator), &mut Some(ref mut scratch)) => {
                 let np = self.imager.geometry().dimension();
-                let mut evt = try!(self.vecmath.set(np, tmp, F::zero(), wait_for));
-                evt = try!(self.imager.back_subset(view, tmp, angles, &[evt]));
-                rotator.back(tmp, object, &[evt])
+                let mut evt = try!(self.vecmath.set(np, scratch, F::zero(), wait_for));
+                evt = try!(self.imager.back_subset(view, scratch, angles, &[evt]));
+                rotator.back(scratch, object, &[evt])
             },
             (&mut None, &mut None) => {
                 self.imager.back_subset(view, object, angles, wait_for)
@@ -4,8 +4,11 @@ use serialize::*;
 use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::toml::*;
 use lens::*;
+use aperture::*;
+use angular_plane::*;
 use detector::*;
-use optics::*;
+use ray_transfer::*;
+use std::path::Path;
 
 /// Single lens camera
 #[derive(Clone, Debug)]
@@ -13,26 +16,62 @@ pub struct SingleLensCamera<F: Float> {
     pub lens: Lens<F>,
     pub detector: Detector<F>,
     pub distance_detector_lens: F,
+
+    /// Aperture stop replacing the main lens' aperture, if any
+    pub stop: Option<Aperture<F>>,
+
+    /// Ray transfer replacing the main lens' thin lens optics, if any
+    ///
+    /// This models lenses that `Lens` cannot, e.g., cylindrical lenses
+    /// rotated about the optical axis.  The lens still bounds the aperture.
+    pub transfer: Option<RayTransfer<F>>,
 }
 
 impl<F: Float + FromPrimitive + ToPrimitive> SingleLensCamera<F> {
-    pub fn focus_at_distance(self: &mut Self, focus_distance: F) {
-        let pre_optics = Optics::identity();
-        let post_optics = self.lens.optics().then(&Optics::translation(&focus_distance));
-        let (distance_s, distance_t) = Optics::focus_at_distance(&pre_optics, &post_optics);
+    /// Returns the angular plane on the main lens
+    pub fn angular_plane(self: &Self, basis: AngularBasis, na: usize) -> AngularPlane<F> {
+        main_lens_angular_plane(&self.lens, &self.stop, basis, na)
+    }
+
+    /// Returns the ray transfer through the main lens
+    pub fn lens_transfer(self: &Self) -> RayTransfer<F> {
+        main_lens_transfer(&self.lens, &self.transfer)
+    }
+
+    /// Moves the detector so that the main lens focuses at `focus_distance`
+    ///
+    /// Returns `Err` if the main lens has no real focus.
+    pub fn focus_at_distance(self: &mut Self, focus_distance: F) -> Result<(), ()> {
+        let pre_optics = RayTransfer::identity();
+        let post_optics = self.lens_transfer().then(&RayTransfer::translation(&focus_distance));
+        let (distance_s, distance_t) = try!(RayTransfer::focus_at_distance(&pre_optics,
+                                                                            &post_optics));
         self.distance_detector_lens = (distance_s + distance_t) / (F::one() + F::one());
+        Ok(())
     }
 
     pub fn describe(self: &Self) -> String {
-        let optics = Optics::translation(&self.distance_detector_lens).then(&self.lens.optics());
-        let (ds, dt) = optics.focused_distance();
-        format!("Single Lens Camera focused at ({}, {})", 
-                F::to_f32(&ds).unwrap(), F::to_f32(&dt).unwrap())
+        let optics = RayTransfer::translation(&self.distance_detector_lens).then(&self.lens_transfer());
+        match optics.focused_distance() {
+            Ok((ds, dt)) => {
+                format!("Single Lens Camera focused at ({}, {})", 
+                        F::to_f32(&ds).unwrap(), F::to_f32(&dt).unwrap())
+            }
+            Err(_) => "Single Lens Camera with a main lens that does not focus".to_string(),
+        }
     }
 }
 
 impl<F: Float + FromPrimitive + ToPrimitive> Serialize for SingleLensCamera<F> {
     fn from_map(map: &Table) -> Option<Self> {
+        let stop = match stop_from_map(map) {
+            Ok(stop) => stop,
+            Err(_) => return None,
+        };
+        let transfer = match transfer_from_map(map) {
+            Ok(transfer) => transfer,
+            Err(_) => return None,
+        };
         let lens = map.get("lens");
         let detector = map.get("detector");
         let distance_detector_lens = map.get("distance_detector_lens");
@@ -47,6 +86,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for SingleLensCamera<F> {
                             lens: lens,
                             detector: det,
                             distance_detector_lens: F::from_f64(distance_detector_lens).unwrap(),
+                            stop: stop,
+                            transfer: transfer,
                         })
                     }
                     _ => None,
@@ -63,8 +104,21 @@ impl<F: Float + FromPrimitive + ToPrimitive> Serialize for SingleLensCamera<F> {
                   Value::Table(self.detector.into_map()));
         tr.insert("distance_detector_lens".to_string(),
                   Value::Float(F::to_f64(&self.distance_detector_lens).unwrap()));
+        if let Some(ref stop) = self.stop {
+            tr.insert("stop".to_string(), Value::Table(stop.into_map()));
+        }
+        if let Some(ref transfer) = self.transfer {
+            tr.insert("transfer".to_string(), Value::Table(transfer.into_map()));
+        }
         tr
     }
+
+    fn load_assets<P: AsRef<Path>>(self: &mut Self, root_path: P) -> Result<(), ()> {
+        match self.stop {
+            Some(ref mut stop) => stop.load_assets(root_path),
+            None => Ok(()),
+        }
+    }
 }
 
 #[test]
@@ -108,3 +162,119 @@ fn test_read_camera() {
     assert_eq!(camera.lens.focal_length_s, 12.0);
     assert_eq!(camera.lens.focal_length_t, 24.0);
 }
+
+#[test]
+fn test_read_camera_with_stop() {
+    let test = r#"
+    distance_detector_lens = 32.0
+
+    [detector]
+    ns = 64
+    nt = 64
+    ds = 5e-2
+    dt = 5e-2
+    offset_s = 0.0
+    offset_t = 0.0
+
+    [lens]
+    center_s = 0.0
+    center_t = 0.0
+    radius_s = 4.0
+    radius_t = 4.0
+    focal_length_s = 12.0
+    focal_length_t = 12.0
+
+    [stop]
+    shape = "polygon"
+    center_s = 0.0
+    center_t = 0.0
+    radius = 2.0
+    sides = 5
+    "#;
+
+    let map = Parser::new(test).parse().unwrap();
+    let camera: SingleLensCamera<f64> = SingleLensCamera::from_map(&map).unwrap();
+    match camera.stop {
+        Some(Aperture::Polygon(ref p)) => {
+            assert_eq!(p.sides, 5);
+            assert_eq!(p.radius, 2.0);
+        }
+        _ => panic!("Expected a polygonal stop"),
+    }
+
+    // the plane covers the stop instead of the lens
+    let plane = camera.angular_plane(AngularBasis::Pillbox, 10);
+    assert!((plane.ds - 0.4).abs() < 1e-12);
+    let area = plane.w.iter().fold(0.0, |s, w| s + w) * plane.ds * plane.dt;
+    let pentagon = 2.5 * 4.0 * (2.0 * ::std::f64::consts::PI / 5.0).sin();
+    assert!((area - pentagon).abs() < 1e-10);
+
+    let roundtrip: SingleLensCamera<f64> = SingleLensCamera::from_map(&camera.into_map()).unwrap();
+    assert!(roundtrip.stop.is_some());
+
+    let mut bad = map.clone();
+    if let Some(&mut Value::Table(ref mut stop)) = bad.get_mut("stop") {
+        stop.insert("shape".to_string(), Value::String("blob".to_string()));
+    }
+    assert!(SingleLensCamera::<f64>::from_map(&bad).is_none());
+}
+
+#[test]
+fn test_read_camera_with_transfer() {
+    use optics::*;
+
+    let test = r#"
+    distance_detector_lens = 32.0
+
+    [detector]
+    ns = 64
+    nt = 64
+    ds = 5e-2
+    dt = 5e-2
+    offset_s = 0.0
+    offset_t = 0.0
+
+    [lens]
+    center_s = 0.0
+    center_t = 0.0
+    radius_s = 4.0
+    radius_t = 4.0
+    focal_length_s = 12.0
+    focal_length_t = 12.0
+
+    [transfer]
+    ss = 1.0
+    us = -0.05
+    uu = 1.0
+    tt = 1.0
+    vt = -0.05
+    vs = -0.02
+    uv = -0.02
+    vv = 1.0
+    "#;
+
+    let map = Parser::new(test).parse().unwrap();
+    let camera: SingleLensCamera<f64> = SingleLensCamera::from_map(&map).unwrap();
+    let transfer = camera.lens_transfer();
+    assert!(!transfer.is_separable());
+    assert_eq!(transfer.m[3][0], -0.02);
+    assert_eq!(transfer.m[1][3], -0.02);
+
+    let roundtrip: SingleLensCamera<f64> = SingleLensCamera::from_map(&camera.into_map()).unwrap();
+    assert_eq!(roundtrip.transfer.unwrap().m, transfer.m);
+
+    // without a transfer, the camera uses its thin lens
+    let mut plain = camera.clone();
+    plain.transfer = None;
+    assert_eq!(plain.lens_transfer().as_optics().unwrap().us,
+               plain.lens.optics().us);
+
+    // a rotated cylindrical lens with powers 0.07 and 0.03 along its axes
+    // puts the detector halfway between their images
+    let mut focused = plain.clone();
+    let cylindrical = Optics::anisotropic_lens(&0.0, &0.0, &(1.0 / 0.07), &(1.0 / 0.03));
+    focused.transfer = Some(RayTransfer::from_optics(&cylindrical).rotated(0.4));
+    focused.focus_at_distance(1000.0).unwrap();
+    let expected = (1.0 / (0.07 - 1e-3) + 1.0 / (0.03 - 1e-3)) / 2.0;
+    assert!((focused.distance_detector_lens - expected).abs() < 1e-8);
+}
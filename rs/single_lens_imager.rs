@@ -4,7 +4,7 @@ extern crate num;
 use imager::*;
 use self::proust::*;
 use light_volume::*;
-use self::num::{FromPrimitive, Float};
+use self::num::{FromPrimitive, ToPrimitive, Float};
 use self::nalgebra::Vector3;
 use angular_plane::*;
 use volume_transport::*;
@@ -12,16 +12,56 @@ use optics::*;
 use light_field_geom::*;
 use single_lens_camera::*;
 use detector::*;
+use host_transport::*;
+use skew_transport::*;
+use ray_transfer::*;
+use backend::*;
+
+/// Geometry of the transport from a volume onto a single lens camera's
+/// detector
+///
+/// Returns the volume in the camera's optical frame, the detector light
+/// field geometry and the ray transfer from the object to the lens plane.
+fn single_lens_transport_geometry<F: Float + FromPrimitive>
+    (geom: &LightVolume<F>,
+     camera: &SingleLensCamera<F>,
+     position: Vector3<F>,
+     plane: &AngularPlane<F>)
+     -> (LightVolume<F>, LightFieldGeometry<F>, RayTransfer<F>) {
+    // light field geometry on detector
+    let detector_lfg = LightFieldGeometry {
+        geom: camera.detector.image_geometry(),
+        plane: plane.clone(),
+        to_plane: Optics::translation(&camera.distance_detector_lens),
+    };
+
+    // geometry of the object in the camera's optical frame
+    let distance_to_object = -position.z;
+    let camera_ox = position.x / geom.dx;
+    let camera_oy = position.y / geom.dy;
+
+    let mut frame_geom = geom.clone();
+    frame_geom.offset_x = frame_geom.offset_x + camera_ox;
+    frame_geom.offset_y = frame_geom.offset_y + camera_oy;
+
+    let to_object = RayTransfer::from_optics(&Optics::translation(&distance_to_object));
+    let object_to_plane = camera.lens_transfer().then(&to_object).invert();
+
+    (frame_geom, detector_lfg, object_to_plane)
+}
 
 /// Implementation of an imager for a volume
+///
+/// If the camera's lens transfer couples `s` and `t`, the projections run
+/// on the host through a `SkewVolumeTransport`, which is much slower.
 pub struct SingleLensVolumeImager<F: Float + FromPrimitive> {
     geom: LightVolume<F>,
-    xport: VolumeTransport<F>,
+    xport: LensVolumeTransport<F>,
     plane: AngularPlane<F>,
     detector: Detector<F>,
 }
 
-impl<F: Float + FromPrimitive> SingleLensVolumeImager<F> {
+impl<F: Float + FromPrimitive + ToPrimitive> SingleLensVolumeImager<F> {
     pub fn new(geom: LightVolume<F>,
                camera: SingleLensCamera<F>,
                position: Vector3<F>,
@@ -30,37 +70,18 @@ impl<F: Float + FromPrimitive> SingleLensVolumeImager<F> {
                queue: CommandQueue)
                -> Result<Self, Error> {
         // angular plane on main lens
-        let plane = camera.lens.as_angular_plane(basis, na);
-
-        // light field geometry on detector
-        let detector_lfg = LightFieldGeometry {
-            geom: camera.detector.image_geometry(),
-            plane: plane.clone(),
-            to_plane: Optics::translation(&camera.distance_detector_lens),
-        };
-
-        // geometry of the object in the camera's optical frame
-        let distance_to_object = -position.z;
-        let camera_ox = position.x / geom.dx;
-        let camera_oy = position.y / geom.dy;
-
-        let mut frame_geom = geom.clone();
-        frame_geom.offset_x = frame_geom.offset_x + camera_ox;
-        frame_geom.offset_y = frame_geom.offset_y + camera_oy;
-
-        let optics_object_to_plane = camera.lens
-                                           .optics()
-                                           .then(&Optics::translation(&distance_to_object))
-                                           .invert();
+        let plane = camera.angular_plane(basis, na);
+        let (frame_geom, detector_lfg, object_to_plane) =
+            single_lens_transport_geometry(&geom, &camera, position, &plane);
 
         // transport from object to detector
-        let xport = try!(VolumeTransport::new(frame_geom,
-                                              detector_lfg,
-                                              optics_object_to_plane,
-                                              false, // overwrite_forw
-                                              false, // overwrite_back
-                                              true, // onto_detector
-                                              queue.clone()));
+        let xport = try!(LensVolumeTransport::new(frame_geom,
+                                                  detector_lfg,
+                                                  object_to_plane,
+                                                  false, // overwrite_forw
+                                                  false, // overwrite_back
+                                                  true, // onto_detector
+                                                  queue));
 
         Ok(SingleLensVolumeImager {
             geom: geom,
@@ -72,7 +93,8 @@ impl<F: Float + FromPrimitive> SingleLensVolumeImager<F> {
 }
 
 
-impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for SingleLensVolumeImager<F> {
+impl<F: Float + FromPrimitive + ToPrimitive> Imager<F, LightVolume<F>>
+    for SingleLensVolumeImager<F> {
     fn na(self: &Self) -> usize {
         self.plane.s.len()
     }
@@ -98,6 +120,15 @@ impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for SingleLensVolumeIma
         self.xport.forw(object, view, ia, wait_for)
     }
 
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &Mem,
+                             view: &mut Mem,
+                             ia: usize,
+                             wait_for: &[Event])
+                             -> Result<Event, Error> {
+        self.xport.forw_linearized(object, view, ia, wait_for)
+    }
+
     fn back_angle(self: &mut Self,
                   view: &Mem,
                   object: &mut Mem,
@@ -107,3 +138,351 @@ impl<F: Float + FromPrimitive> Imager<F, LightVolume<F>> for SingleLensVolumeIma
         self.xport.back(view, object, ia, wait_for)
     }
 }
+
+/// Transport from a volume onto a single lens camera's detector on the host
+enum HostSingleLensTransport<F: Float> {
+    Separable(HostVolumeTransport<F>),
+    Skew(SkewVolumeTransport<F>),
+}
+
+/// Implementation of an imager for a volume on the host backend
+pub struct HostSingleLensVolumeImager<F: Float + FromPrimitive> {
+    geom: LightVolume<F>,
+    xport: HostSingleLensTransport<F>,
+    plane: AngularPlane<F>,
+    detector: Detector<F>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> HostSingleLensVolumeImager<F> {
+    pub fn new(geom: LightVolume<F>,
+               camera: SingleLensCamera<F>,
+               position: Vector3<F>,
+               na: usize,
+               basis: AngularBasis)
+               -> Self {
+        // angular plane on main lens
+        let plane = camera.angular_plane(basis, na);
+        let (frame_geom, detector_lfg, object_to_plane) =
+            single_lens_transport_geometry(&geom, &camera, position, &plane);
+
+        // transport from object to detector
+        let xport = match object_to_plane.as_optics() {
+            Some(optics_object_to_plane) => {
+                HostSingleLensTransport::Separable(HostVolumeTransport::new(frame_geom,
+                                                                            detector_lfg,
+                                                                            optics_object_to_plane,
+                                                                            false, // overwrite_forw
+                                                                            false, // overwrite_back
+                                                                            true)) // onto_detector
+            }
+            None => {
+                HostSingleLensTransport::Skew(SkewVolumeTransport::new(frame_geom,
+                                                                       detector_lfg,
+                                                                       object_to_plane,
+                                                                       false, // overwrite_forw
+                                                                       false, // overwrite_back
+                                                                       true)) // onto_detector
+            }
+        };
+
+        HostSingleLensVolumeImager {
+            geom: geom,
+            xport: xport,
+            plane: plane,
+            detector: camera.detector,
+        }
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Imager<F, LightVolume<F>, HostBackend>
+    for HostSingleLensVolumeImager<F> {
+    fn na(self: &Self) -> usize {
+        self.plane.s.len()
+    }
+
+    fn detector(self: &Self) -> &Detector<F> {
+        &self.detector
+    }
+
+    fn geometry(self: &Self) -> &LightVolume<F> {
+        &self.geom
+    }
+
+    fn angular_plane(self: &Self) -> &AngularPlane<F> {
+        &self.plane
+    }
+
+    fn forw_angle(self: &mut Self,
+                  object: &Vec<F>,
+                  view: &mut Vec<F>,
+                  ia: usize,
+                  _: &[HostEvent])
+                  -> Result<HostEvent, ()> {
+        match self.xport {
+            HostSingleLensTransport::Separable(ref mut xport) => xport.forw(object, view, ia),
+            HostSingleLensTransport::Skew(ref mut xport) => xport.forw(object, view, ia),
+        }
+        Ok(HostEvent)
+    }
+
+    fn forw_linearized_angle(self: &mut Self,
+                             object: &Vec<F>,
+                             view: &mut Vec<F>,
+                             ia: usize,
+                             _: &[HostEvent])
+                             -> Result<HostEvent, ()> {
+        match self.xport {
+            HostSingleLensTransport::Separable(ref mut xport) => {
+                xport.forw_linearized(object, view, ia)
+            }
+            HostSingleLensTransport::Skew(ref mut xport) => xport.forw(object, view, ia),
+        }
+        Ok(HostEvent)
+    }
+
+    fn back_angle(self: &mut Self,
+                  view: &Vec<F>,
+                  object: &mut Vec<F>,
+                  ia: usize,
+                  _: &[HostEvent])
+                  -> Result<HostEvent, ()> {
+        match self.xport {
+            HostSingleLensTransport::Separable(ref mut xport) => xport.back(view, object, ia),
+            HostSingleLensTransport::Skew(ref mut xport) => xport.back(view, object, ia),
+        }
+        Ok(HostEvent)
+    }
+}
+
+#[cfg(test)]
+pub fn test_camera() -> SingleLensCamera<f32> {
+    use lens::*;
+    SingleLensCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 20f32,
+            focal_length_t: 20f32,
+        },
+        detector: Detector {
+            ns: 32,
+            nt: 32,
+            ds: 0.5,
+            dt: 0.5,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        distance_detector_lens: 25f32,
+        stop: None,
+        transfer: None,
+    }
+}
+
+#[cfg(test)]
+pub fn test_volume() -> LightVolume<f32> {
+    LightVolume {
+        nx: 16,
+        ny: 16,
+        nz: 8,
+        dx: 0.5,
+        dy: 0.5,
+        dz: 0.5,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    }
+}
+
+#[test]
+fn test_host_single_lens_imager_matches_opencl() {
+    use env::*;
+    use geom::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let vg = test_volume();
+    let position = Vector3::new(0f32, 0f32, -100f32);
+
+    let mut host = HostSingleLensVolumeImager::new(vg.clone(),
+                                                   test_camera(),
+                                                   position,
+                                                   5,
+                                                   AngularBasis::Pillbox);
+    let mut cl = SingleLensVolumeImager::new(vg.clone(),
+                                             test_camera(),
+                                             position,
+                                             5,
+                                             AngularBasis::Pillbox,
+                                             queue.clone())
+                     .unwrap();
+
+    let x = vg.rands();
+    let host_img = host.forw_host(&x, &HostQueue).unwrap();
+    let cl_img = cl.forw_host(&x, queue).unwrap();
+
+    let num = host_img.iter().zip(cl_img.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b));
+    let den = cl_img.iter().fold(0f32, |s, a| s + a * a);
+    let nrmse = (num / den).sqrt();
+    println!("Host vs OpenCL single lens imager NRMSE: {}", nrmse);
+    assert!(nrmse < 1e-4);
+}
+
+#[test]
+fn test_single_lens_imager_rotated_lens() {
+    use env::*;
+    use geom::*;
+    use lens::*;
+
+    let vg = test_volume();
+    let position = Vector3::new(0f32, 0f32, -100f32);
+
+    // a cylindrical lens rotated a quarter turn swaps its focal lengths, up
+    // to roundoff that `is_separable` ignores
+    let mut plain = test_camera();
+    plain.lens.focal_length_t = 24f32;
+    let lens = Lens {
+        focal_length_s: 24f32,
+        focal_length_t: 20f32,
+        ..plain.lens.clone()
+    };
+    let mut rotated = plain.clone();
+    rotated.transfer = Some(RayTransfer::from_optics(&lens.optics())
+                                .rotated(::std::f32::consts::PI / 2f32));
+    assert!(rotated.lens_transfer().is_separable());
+
+    let mut host_plain = HostSingleLensVolumeImager::new(vg.clone(),
+                                                         plain.clone(),
+                                                         position,
+                                                         3,
+                                                         AngularBasis::Dirac);
+    let mut host_rotated = HostSingleLensVolumeImager::new(vg.clone(),
+                                                           rotated.clone(),
+                                                           position,
+                                                           3,
+                                                           AngularBasis::Dirac);
+
+    let x = vg.rands();
+    let expected = host_plain.forw_host(&x, &HostQueue).unwrap();
+    let nrmse = |img: &[f32]| {
+        let num = img.iter().zip(expected.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b));
+        let den = expected.iter().fold(0f32, |s, a| s + a * a);
+        (num / den).sqrt()
+    };
+    let host_img = host_rotated.forw_host(&x, &HostQueue).unwrap();
+    println!("Rotated vs plain lens NRMSE (host): {}", nrmse(&host_img));
+    assert!(nrmse(&host_img) < 1e-4);
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+    let mut cl_rotated = SingleLensVolumeImager::new(vg.clone(),
+                                                     rotated,
+                                                     position,
+                                                     3,
+                                                     AngularBasis::Dirac,
+                                                     queue.clone())
+                             .unwrap();
+    let cl_img = cl_rotated.forw_host(&x, queue).unwrap();
+    println!("Rotated vs plain lens NRMSE (OpenCL): {}", nrmse(&cl_img));
+    assert!(nrmse(&cl_img) < 1e-4);
+}
+
+#[test]
+fn test_single_lens_imager_skewed_lens() {
+    use env::*;
+
+    let vg = test_volume();
+    let position = Vector3::new(0f32, 0f32, -100f32);
+    let angle = ::std::f32::consts::PI / 6f32;
+
+    // a cylindrical lens rotated by a twelfth of a turn couples s and t
+    let mut plain = test_camera();
+    plain.lens.focal_length_t = 21f32;
+    plain.detector.ns = 64;
+    plain.detector.nt = 64;
+    plain.detector.ds = 0.0625;
+    plain.detector.dt = 0.0625;
+    let mut rotated = plain.clone();
+    rotated.lens.focal_length_t = 20f32;
+    rotated.transfer = Some(RayTransfer::from_optics(&plain.lens.optics()).rotated(angle));
+    assert!(!rotated.lens_transfer().is_separable());
+
+    // the volume is symmetric about the optical axis, so the skewed lens'
+    // image is the plain lens' image rotated by the same angle
+    let mut x = Vec::with_capacity(vg.nx * vg.ny * vg.nz);
+    for iz in 0..vg.nz {
+        for iy in 0..vg.ny {
+            for ix in 0..vg.nx {
+                let (px, py) = (vg.ix2x(ix), vg.iy2y(iy));
+                let r2 = (px * px + py * py) / 4f32;
+                x.push((1f32 + iz as f32 / vg.nz as f32) * (-r2).exp());
+            }
+        }
+    }
+
+    let mut host_plain = HostSingleLensVolumeImager::new(vg.clone(),
+                                                         plain.clone(),
+                                                         position,
+                                                         3,
+                                                         AngularBasis::Pillbox);
+    let mut host_rotated = HostSingleLensVolumeImager::new(vg.clone(),
+                                                           rotated.clone(),
+                                                           position,
+                                                           3,
+                                                           AngularBasis::Pillbox);
+    let plain_img = host_plain.forw_host(&x, &HostQueue).unwrap();
+    let host_img = host_rotated.forw_host(&x, &HostQueue).unwrap();
+
+    // NRMSE against the plain image rotated by `rot`, with bilinear
+    // interpolation
+    let ig = plain.detector.image_geometry();
+    let rotated_nrmse = |img: &[f32], rot: f32| {
+        let (sin, cos) = rot.sin_cos();
+        let mut num = 0f32;
+        let mut den = 0f32;
+        for it in 0..ig.nt - 1 {
+            for is in 0..ig.ns - 1 {
+                let (s, t) = (ig.is2s(is), ig.it2t(it));
+                let fs = (cos * s + sin * t - ig.is2s(0)) / ig.ds;
+                let ft = (-sin * s + cos * t - ig.it2t(0)) / ig.dt;
+                if fs < 0f32 || ft < 0f32 || fs >= (ig.ns - 1) as f32 ||
+                   ft >= (ig.nt - 1) as f32 {
+                    continue;
+                }
+                let (i0, j0) = (fs.floor() as usize, ft.floor() as usize);
+                let (a, b) = (fs - fs.floor(), ft - ft.floor());
+                let p = |i: usize, j: usize| plain_img[j * ig.ns + i];
+                let expected = (1f32 - a) * (1f32 - b) * p(i0, j0) +
+                               a * (1f32 - b) * p(i0 + 1, j0) +
+                               (1f32 - a) * b * p(i0, j0 + 1) +
+                               a * b * p(i0 + 1, j0 + 1);
+                let y = img[it * ig.ns + is];
+                num = num + (y - expected) * (y - expected);
+                den = den + expected * expected;
+            }
+        }
+        (num / den).sqrt()
+    };
+    println!("Skewed vs rotated plain lens NRMSE (host): {}",
+             rotated_nrmse(&host_img, angle));
+    assert!(rotated_nrmse(&host_img, angle) < 0.05);
+    assert!(rotated_nrmse(&host_img, -angle) > 0.1);
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+    let mut cl_rotated = SingleLensVolumeImager::new(vg.clone(),
+                                                     rotated,
+                                                     position,
+                                                     3,
+                                                     AngularBasis::Pillbox,
+                                                     queue.clone())
+                             .unwrap();
+    let cl_img = cl_rotated.forw_host(&x, queue).unwrap();
+    for (a, b) in host_img.iter().zip(cl_img.iter()) {
+        assert!((a - b).abs() <= 1e-4 * (1f32 + a.abs()));
+    }
+}
@@ -0,0 +1,589 @@
+extern crate num;
+
+use angular_plane::*;
+use image_geom::*;
+use light_field_geom::*;
+use light_volume::*;
+use ray_transfer::*;
+use polygon::*;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use std::mem::swap;
+
+type Mat2<F> = [[F; 2]; 2];
+
+fn det2<F: Float>(a: &Mat2<F>) -> F {
+    a[0][0] * a[1][1] - a[0][1] * a[1][0]
+}
+
+fn inv2<F: Float>(a: &Mat2<F>) -> Mat2<F> {
+    let det = det2(a);
+    [[a[1][1] / det, -a[0][1] / det], [-a[1][0] / det, a[0][0] / det]]
+}
+
+fn mul2<F: Float>(a: &Mat2<F>, b: &Mat2<F>) -> Mat2<F> {
+    [[a[0][0] * b[0][0] + a[0][1] * b[1][0], a[0][0] * b[0][1] + a[0][1] * b[1][1]],
+     [a[1][0] * b[0][0] + a[1][1] * b[1][0], a[1][0] * b[0][1] + a[1][1] * b[1][1]]]
+}
+
+fn apply2<F: Float>(a: &Mat2<F>, x: (F, F)) -> (F, F) {
+    (a[0][0] * x.0 + a[0][1] * x.1, a[1][0] * x.0 + a[1][1] * x.1)
+}
+
+/// Transport between two planes related by non-separable optics
+///
+/// `Transport` factors each footprint into an `s` and a `t` `SplineKernel`,
+/// which only works when the optics between the planes are separable.  A
+/// `SkewTransport` takes `RayTransfer`s instead and computes 2d footprints by
+/// intersecting each source pixel with the preimage of each destination
+/// pixel, so it handles rotated anisotropic lenses and skewed optical
+/// trains.
+///
+/// Dirac footprints are exact; Pillbox and Linear footprints integrate the
+/// Dirac footprint over the angular basis function with an
+/// `angular_samples` x `angular_samples` midpoint rule.  The forward and
+/// back projections use the same weights, so they are exact adjoints.
+///
+/// This runs on the host; there is no OpenCL version yet.
+pub struct SkewTransport<F: Float> {
+    pub src_geom: ImageGeometry<F>,
+    pub src_to_plane: RayTransfer<F>,
+    pub dst_geom: ImageGeometry<F>,
+    pub dst_to_plane: RayTransfer<F>,
+    pub plane: AngularPlane<F>,
+
+    pub overwrite_forw: bool,
+    pub overwrite_back: bool,
+    pub onto_detector: bool,
+
+    pub angular_samples: usize,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> SkewTransport<F> {
+    /// Transport between two separable `LightFieldGeometry`s
+    ///
+    /// This is mostly useful for checking `SkewTransport` against
+    /// `Transport`.
+    pub fn new_simple(src: &LightFieldGeometry<F>, dst: &LightFieldGeometry<F>) -> Self {
+        Self::new(src.geom.clone(),
+                  RayTransfer::from_optics(&src.to_plane),
+                  dst.geom.clone(),
+                  RayTransfer::from_optics(&dst.to_plane),
+                  dst.plane.clone(),
+                  true,
+                  true,
+                  false)
+    }
+
+    pub fn new(src_geom: ImageGeometry<F>,
+               src_to_plane: RayTransfer<F>,
+               dst_geom: ImageGeometry<F>,
+               dst_to_plane: RayTransfer<F>,
+               plane: AngularPlane<F>,
+               overwrite_forw: bool,
+               overwrite_back: bool,
+               onto_detector: bool)
+               -> Self {
+        SkewTransport {
+            src_geom: src_geom,
+            src_to_plane: src_to_plane,
+            dst_geom: dst_geom,
+            dst_to_plane: dst_to_plane,
+            plane: plane,
+
+            overwrite_forw: overwrite_forw,
+            overwrite_back: overwrite_back,
+            onto_detector: onto_detector,
+
+            angular_samples: 4,
+        }
+    }
+
+    /// Returns the 4d volume of a destination pixel
+    ///
+    /// This is the same for all angular bases; for separable optics it
+    /// equals `LightFieldGeometry::pixel_volume`.
+    pub fn pixel_volume(self: &Self) -> F {
+        let b = self.dst_to_plane.block(0, 1);
+        (self.plane.ds * self.plane.dt * self.dst_geom.ds * self.dst_geom.dt / det2(&b)).abs()
+    }
+
+    /// Returns plane points and weights integrating the angular basis
+    /// function of `ia`
+    ///
+    /// The weights include the area of the angular cell.
+    fn angular_quadrature(self: &Self, ia: usize) -> Vec<((F, F), F)> {
+        let plane = &self.plane;
+        let (s, t) = (plane.s[ia], plane.t[ia]);
+        let area = (plane.ds * plane.dt).abs();
+        let c2 = F::one() + F::one();
+
+        // samples span the cell for Pillbox and both neighbouring cells
+        // for Linear, whose tent reaches zero at the next sample
+        let (n, reach) = match &plane.basis {
+            &AngularBasis::Dirac => return vec![((s, t), area)],
+            &AngularBasis::Pillbox => (self.angular_samples, F::one() / c2),
+            &AngularBasis::Linear => (2 * self.angular_samples, F::one()),
+        };
+
+        let nf = F::from_usize(n).unwrap();
+        let mut tr = Vec::with_capacity(n * n);
+        let mut total = F::zero();
+        for i in 0..n {
+            let fi = reach * (c2 * (F::from_usize(i).unwrap() + F::one() / c2) / nf - F::one());
+            for j in 0..n {
+                let fj = reach *
+                         (c2 * (F::from_usize(j).unwrap() + F::one() / c2) / nf - F::one());
+                let w = match &plane.basis {
+                    &AngularBasis::Linear => (F::one() - fi.abs()) * (F::one() - fj.abs()),
+                    _ => F::one(),
+                };
+                total = total + w;
+                tr.push(((s + fi * plane.ds, t + fj * plane.dt), w));
+            }
+        }
+
+        // the basis functions integrate to the cell area
+        for x in tr.iter_mut() {
+            x.1 = x.1 * area / total;
+        }
+        tr
+    }
+
+    /// Calls `f(dst_idx, src_idx, weight)` for each nonzero entry of the
+    /// transport matrix for angle `ia`
+    fn footprints<G>(self: &Self, ia: usize, mut f: G)
+        where G: FnMut(usize, usize, F)
+    {
+        let src_to_dst = self.dst_to_plane.invert().compose(&self.src_to_plane);
+
+        // with the plane point p fixed, a source position x reaches the
+        // destination at y = m x + b(p)
+        let a_p = self.src_to_plane.block(0, 0);
+        let b_p_inv = inv2(&self.src_to_plane.block(0, 1));
+        let a_q = src_to_dst.block(0, 0);
+        let b_q = src_to_dst.block(0, 1);
+        let b_qp = mul2(&b_q, &b_p_inv);
+        let m_qp = mul2(&b_qp, &a_p);
+        let m = [[a_q[0][0] - m_qp[0][0], a_q[0][1] - m_qp[0][1]],
+                 [a_q[1][0] - m_qp[1][0], a_q[1][1] - m_qp[1][1]]];
+        let m_inv = inv2(&m);
+
+        let pv = self.pixel_volume();
+        let height = if self.onto_detector {
+            self.plane.w[ia] / pv.sqrt()
+        } else {
+            F::one() / pv
+        };
+        let scale = height / det2(&self.src_to_plane.block(0, 1)).abs();
+
+        let src_geom = &self.src_geom;
+        let dst_geom = &self.dst_geom;
+        for ((ps, pt), pw) in self.angular_quadrature(ia) {
+            let p = (ps - self.src_to_plane.offset[0], pt - self.src_to_plane.offset[2]);
+            let bp = apply2(&b_qp, p);
+            let b = (bp.0 + src_to_dst.offset[0], bp.1 + src_to_dst.offset[2]);
+
+            for dst_it in 0..dst_geom.nt {
+                for dst_is in 0..dst_geom.ns {
+                    // preimage of the destination pixel in source coordinates
+                    let (s0, s1, t0, t1) = dst_geom.pixel_bounds(dst_is, dst_it);
+                    let poly: Vec<(F, F)> = [(s0, t0), (s1, t0), (s1, t1), (s0, t1)]
+                                                .iter()
+                                                .map(|&(s, t)| {
+                                                    apply2(&m_inv, (s - b.0, t - b.1))
+                                                })
+                                                .collect();
+
+                    let mut lo = poly[0];
+                    let mut hi = poly[0];
+                    for x in poly.iter() {
+                        lo = (lo.0.min(x.0), lo.1.min(x.1));
+                        hi = (hi.0.max(x.0), hi.1.max(x.1));
+                    }
+                    let (mut is0, mut is1, mut it0, mut it1) =
+                        src_geom.region_pixels(lo.0, hi.0, lo.1, hi.1);
+                    if is0 > is1 {
+                        swap(&mut is0, &mut is1);
+                    }
+                    if it0 > it1 {
+                        swap(&mut it0, &mut it1);
+                    }
+
+                    let dst_idx = dst_is + dst_geom.ns * dst_it;
+                    for src_it in it0..(it1 + 1).min(src_geom.nt) {
+                        for src_is in is0..(is1 + 1).min(src_geom.ns) {
+                            let (a0, a1, c0, c1) = src_geom.pixel_bounds(src_is, src_it);
+                            let area = polygon_box_area(&poly,
+                                                        a0.min(a1),
+                                                        a0.max(a1),
+                                                        c0.min(c1),
+                                                        c0.max(c1));
+                            if area > F::zero() {
+                                f(dst_idx, src_is + src_geom.ns * src_it, pw * scale * area);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Transport from source to destination
+    pub fn forw(self: &Self, src: &[F], dst: &mut [F], ia: usize) {
+        if self.overwrite_forw {
+            for x in dst.iter_mut() {
+                *x = F::zero();
+            }
+        }
+        self.footprints(ia, |dst_idx, src_idx, w| dst[dst_idx] = dst[dst_idx] + w * src[src_idx]);
+    }
+
+    /// Transport from destination to source
+    pub fn back(self: &Self, dst: &[F], src: &mut [F], ia: usize) {
+        if self.overwrite_back {
+            for x in src.iter_mut() {
+                *x = F::zero();
+            }
+        }
+        self.footprints(ia, |dst_idx, src_idx, w| src[src_idx] = src[src_idx] + w * dst[dst_idx]);
+    }
+}
+
+/// Applies the path length factor of `volume_scale` for non-separable
+/// optics from the destination to the volume
+fn skew_volume_scale<F>(dst: &LightFieldGeometry<F>,
+                        dst_to_obj: &RayTransfer<F>,
+                        ia: usize,
+                        input: &[F],
+                        output: &mut [F],
+                        overwrite: bool)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let to_plane = &dst.to_plane;
+    let s_plane = dst.plane.s[ia];
+    let t_plane = dst.plane.t[ia];
+
+    for it in 0..dst.geom.nt {
+        let t = dst.geom.it2t(it);
+        let v = (t_plane - to_plane.tt * t - to_plane.t) / to_plane.tv;
+        for is in 0..dst.geom.ns {
+            let s = dst.geom.is2s(is);
+            let u = (s_plane - to_plane.ss * s - to_plane.s) / to_plane.su;
+            let ray = dst_to_obj.apply(&[s, u, t, v]);
+
+            let factor = (F::one() + ray[1] * ray[1] + ray[3] * ray[3]).sqrt();
+            let idx = is + dst.geom.ns * it;
+            if overwrite {
+                output[idx] = factor * input[idx];
+            } else {
+                output[idx] = output[idx] + factor * input[idx];
+            }
+        }
+    }
+}
+
+/// Transport from a volume onto a light field through non-separable optics
+///
+/// The `SkewTransport` counterpart of `HostVolumeTransport`: each slice is
+/// projected with its own `SkewTransport` and scaled the same way, so the
+/// two agree for separable optics.  Only transparent volumes are
+/// supported.
+pub struct SkewVolumeTransport<F: Float> {
+    pub geom: LightVolume<F>,
+    pub dst: LightFieldGeometry<F>,
+
+    pub overwrite_forw: bool,
+    pub overwrite_back: bool,
+    pub onto_detector: bool,
+
+    slices: Vec<SkewTransport<F>>,
+    dst_to_obj: RayTransfer<F>,
+
+    tmp: Vec<F>,
+    slice_tmp: Vec<F>,
+    scaled: Vec<F>,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> SkewVolumeTransport<F> {
+    /// Create a new `SkewVolumeTransport`
+    ///
+    /// `to_plane` is the ray transfer from the `z=0` plane of the volume to
+    /// the angular plane of `dst`.  Panics if the volume is opaque.
+    pub fn new(src: LightVolume<F>,
+               dst: LightFieldGeometry<F>,
+               to_plane: RayTransfer<F>,
+               overwrite_forw: bool,
+               overwrite_back: bool,
+               onto_detector: bool)
+               -> Self {
+        assert!(!src.opaque,
+                "SkewVolumeTransport does not support opaque volumes");
+
+        let slice_geom = src.transaxial_image_geometry();
+        let dst_to_plane = RayTransfer::from_optics(&dst.to_plane);
+        let slices = (0..src.nz)
+                         .map(|iz| {
+                             let to_z0 = RayTransfer::from_optics(&src.optics_to_z0(iz));
+                             SkewTransport::new(slice_geom.clone(),
+                                                to_plane.compose(&to_z0),
+                                                dst.geom.clone(),
+                                                dst_to_plane.clone(),
+                                                dst.plane.clone(),
+                                                true,
+                                                true,
+                                                false)
+                         })
+                         .collect();
+
+        let dst_np = dst.geom.ns * dst.geom.nt;
+        SkewVolumeTransport {
+            slices: slices,
+            dst_to_obj: to_plane.invert().compose(&dst_to_plane),
+
+            tmp: vec![F::zero(); dst_np],
+            slice_tmp: vec![F::zero(); src.nx * src.ny],
+            scaled: vec![F::zero(); dst_np],
+
+            geom: src,
+            dst: dst,
+
+            overwrite_forw: overwrite_forw,
+            overwrite_back: overwrite_back,
+            onto_detector: onto_detector,
+        }
+    }
+
+    /// Scale turning the slice footprints, which are normalized by the
+    /// destination pixel volume, into those of `HostVolumeTransport`
+    fn slice_scale(self: &Self, ia: usize) -> F {
+        let pv = self.dst.pixel_volume();
+        match self.dst.plane.basis {
+            // the OpenCL Dirac kernels do not apply the slice scale factor
+            AngularBasis::Dirac => pv,
+            _ if self.onto_detector => self.geom.dz.abs() * pv.sqrt() * self.dst.plane.w[ia],
+            _ => self.geom.dz.abs(),
+        }
+    }
+
+    /// Transport from the volume to the destination
+    pub fn forw(self: &mut Self, vol: &[F], dst: &mut [F], ia: usize) {
+        let scale = self.slice_scale(ia);
+        let slice_np = self.geom.nx * self.geom.ny;
+
+        for x in self.scaled.iter_mut() {
+            *x = F::zero();
+        }
+        for (iz, slice) in self.slices.iter().enumerate() {
+            slice.forw(&vol[slice_np * iz..slice_np * (iz + 1)], &mut self.tmp, ia);
+            for (y, &x) in self.scaled.iter_mut().zip(self.tmp.iter()) {
+                *y = *y + scale * x;
+            }
+        }
+
+        skew_volume_scale(&self.dst,
+                          &self.dst_to_obj,
+                          ia,
+                          &self.scaled,
+                          dst,
+                          self.overwrite_forw);
+    }
+
+    /// Transport from the destination to the volume
+    pub fn back(self: &mut Self, dst: &[F], vol: &mut [F], ia: usize) {
+        let scale = self.slice_scale(ia);
+        let slice_np = self.geom.nx * self.geom.ny;
+
+        skew_volume_scale(&self.dst, &self.dst_to_obj, ia, dst, &mut self.scaled, true);
+
+        for (iz, slice) in self.slices.iter().enumerate() {
+            slice.back(&self.scaled, &mut self.slice_tmp, ia);
+            let vol_slice = &mut vol[slice_np * iz..slice_np * (iz + 1)];
+            for (y, &x) in vol_slice.iter_mut().zip(self.slice_tmp.iter()) {
+                if self.overwrite_back {
+                    *y = scale * x;
+                } else {
+                    *y = *y + scale * x;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_geometries(basis: ::angular_plane::AngularBasis)
+                   -> (::light_field_geom::LightFieldGeometry<f64>,
+                       ::light_field_geom::LightFieldGeometry<f64>) {
+    use lens::*;
+    use optics::*;
+
+    let lens = Lens {
+        center_s: 0.5,
+        center_t: -0.3,
+        radius_s: 10.0,
+        radius_t: 12.0,
+        focal_length_s: 20.0,
+        focal_length_t: 25.0,
+    };
+    let plane = lens.as_angular_plane(basis, 5);
+
+    let src = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 20,
+            nt: 24,
+            ds: 1.0,
+            dt: 1.1,
+            offset_s: 0.5,
+            offset_t: -0.9,
+        },
+        plane: plane.clone(),
+        to_plane: lens.optics().then(&Optics::translation(&50f64)).invert(),
+    };
+    let dst = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 32,
+            nt: 30,
+            ds: 0.5,
+            dt: 0.6,
+            offset_s: -1.0,
+            offset_t: 2.1,
+        },
+        plane: plane,
+        to_plane: Optics::translation(&40f64),
+    };
+    (src, dst)
+}
+
+#[test]
+fn test_skew_transport_matches_host_transport() {
+    use host_transport::*;
+    use geom::*;
+
+    let (src, dst) = test_geometries(AngularBasis::Dirac);
+    let skew = SkewTransport::new_simple(&src, &dst);
+    let mut xport = HostTransport::new_simple(src.clone(), dst.clone());
+
+    let u = src.geom.rands();
+    for ia in vec![0, 12, 20] {
+        let mut proj_skew = dst.geom.zeros();
+        let mut proj_host = dst.geom.zeros();
+        skew.forw(&u, &mut proj_skew, ia);
+        xport.forw(&u, &mut proj_host, ia);
+
+        let err = proj_skew.iter()
+                           .zip(proj_host.iter())
+                           .fold(0f64, |s, (a, b)| s + (a - b).powi(2));
+        let norm = proj_host.iter().fold(0f64, |s, a| s + a * a);
+        println!("NRMSE for SkewTransport vs HostTransport: {}", (err / norm).sqrt());
+        assert!(norm > 0f64);
+        assert!((err / norm).sqrt() < 1e-6);
+    }
+}
+
+#[test]
+fn test_skew_transport_adjoint() {
+    use optics::*;
+    use geom::*;
+
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let (src, dst) = test_geometries(basis);
+
+        // rotate the lens' axes so the footprints do not separate
+        let lens = RayTransfer::from_optics(&Optics::anisotropic_lens(&0.5, &-0.3, &20.0, &25.0))
+                       .rotated(0.4);
+        let src_to_plane = lens.then(&RayTransfer::from_optics(&Optics::translation(&50f64)))
+                               .invert();
+        assert!(!src_to_plane.is_separable());
+
+        let skew = SkewTransport::new(src.geom.clone(),
+                                      src_to_plane,
+                                      dst.geom.clone(),
+                                      RayTransfer::from_optics(&dst.to_plane),
+                                      dst.plane.clone(),
+                                      true,
+                                      true,
+                                      false);
+
+        let u = src.geom.rands();
+        let v = dst.geom.rands();
+        let mut proj_u = dst.geom.zeros();
+        let mut back_v = src.geom.zeros();
+        skew.forw(&u, &mut proj_u, 12);
+        skew.back(&v, &mut back_v, 12);
+
+        let v1 = proj_u.iter().zip(v.iter()).fold(0f64, |s, (ui, vi)| s + ui * vi);
+        let v2 = back_v.iter().zip(u.iter()).fold(0f64, |s, (vi, ui)| s + ui * vi);
+        let nrmse = (v1 - v2).abs() / v1.abs().max(v2.abs());
+
+        println!("Adjoint NRMSE for SkewTransport: {}", nrmse);
+        assert!(v1 != 0f64);
+        assert!(nrmse < 1e-8);
+    }
+}
+
+#[test]
+fn test_skew_volume_transport_matches_host_volume_transport() {
+    use host_transport::*;
+    use geom::*;
+    use optics::*;
+
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox] {
+        let (_, dst) = test_geometries(basis.clone());
+        let vg = LightVolume {
+            nx: 12,
+            ny: 10,
+            nz: 4,
+            dx: 1.5,
+            dy: 1.6,
+            dz: 2.0,
+            offset_x: 0.5,
+            offset_y: -0.5,
+            offset_z: 0.0,
+            opaque: false,
+            attenuation: 1.0,
+        };
+        let lens = Optics::anisotropic_lens(&0.5, &-0.3, &20.0, &25.0);
+        let to_plane = lens.then(&Optics::translation(&60f64)).invert();
+
+        let mut skew = SkewVolumeTransport::new(vg.clone(),
+                                                dst.clone(),
+                                                RayTransfer::from_optics(&to_plane),
+                                                false,
+                                                false,
+                                                true);
+        let mut xport = HostVolumeTransport::new(vg.clone(),
+                                                 dst.clone(),
+                                                 to_plane,
+                                                 false,
+                                                 false,
+                                                 true);
+
+        let x = vg.rands();
+        let y = dst.geom.rands();
+        for ia in vec![0, 12, 20] {
+            let mut proj_skew = dst.geom.zeros();
+            let mut proj_host = dst.geom.zeros();
+            skew.forw(&x, &mut proj_skew, ia);
+            xport.forw(&x, &mut proj_host, ia);
+
+            let mut back_skew = vg.zeros();
+            let mut back_host = vg.zeros();
+            skew.back(&y, &mut back_skew, ia);
+            xport.back(&y, &mut back_host, ia);
+
+            for &(a, b) in [(&proj_skew, &proj_host), (&back_skew, &back_host)].iter() {
+                let err = a.iter().zip(b.iter()).fold(0f64, |s, (a, b)| s + (a - b).powi(2));
+                let norm = b.iter().fold(0f64, |s, b| s + b * b);
+                let nrmse = (err / norm).sqrt();
+                println!("NRMSE for SkewVolumeTransport vs HostVolumeTransport ({:?}): {}",
+                         basis,
+                         nrmse);
+                assert!(norm > 0f64);
+                // Pillbox footprints are integrated with a midpoint rule
+                match basis {
+                    AngularBasis::Dirac => assert!(nrmse < 1e-6),
+                    _ => assert!(nrmse < 2e-2),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+extern crate num;
+extern crate nalgebra;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use geom::*;
+use detector::*;
+use angular_plane::*;
+use backend::*;
+use imager::*;
+use light_volume::*;
+use std::cmp::{max, min};
+use std::mem::size_of;
+
+/// Returns the number of slices per slab that fit in a device memory budget
+///
+/// `budget` is in bytes and only counts the resident slab; the detector
+/// buffer and whatever the per-slab imagers allocate come on top of it.
+/// Always returns at least one slice.
+pub fn slab_depth<F: Float>(geom: &LightVolume<F>, budget: usize) -> usize {
+    let slice_bytes = geom.nx * geom.ny * size_of::<F>();
+    max(1, min(geom.nz, budget / slice_bytes))
+}
+
+/// Imager for volumes that do not fit in device memory
+///
+/// The volume lives on the host and is cut into z-slabs.  Each slab gets an
+/// imager of its own on the device backend `B`; projecting uploads one slab
+/// at a time and accumulates its projection onto the detector, and
+/// backprojecting streams each slab of the result back to the host.  This
+/// relies on the projection being a sum over slices, so the volume must not
+/// be opaque, and the per-slab imagers must image the slab in place (a
+/// rotated camera resamples each slab separately, which is not the same as
+/// resampling the whole volume).
+///
+/// The imager itself runs on the host backend, so a `FistaVolumeSolver`
+/// built from `SlabImager`s keeps its image-sized state in host memory and
+/// only the slabs visit the device.  The host backend's errors carry no
+/// information, so when a projection through the `Imager` interface fails,
+/// the device backend's error is kept for `take_error`; `forw_slabs` and
+/// `back_slabs` return it directly.
+pub struct SlabImager<F: Float + FromPrimitive, B: Backend<F>> {
+    geom: LightVolume<F>,
+    slabs: Vec<(usize, usize)>,
+    imagers: Vec<Box<Imager<F, LightVolume<F>, B>>>,
+    queue: B::Queue,
+    slab_buf: B::Buffer,
+    view_buf: B::Buffer,
+    error: Option<B::Error>,
+}
+
+impl<F, B> SlabImager<F, B>
+    where F: Float + FromPrimitive + ToPrimitive,
+          B: Backend<F>
+{
+    /// Creates an imager streaming slabs of at most `depth` slices
+    ///
+    /// `new_imager` is called with the geometry of each slab, in order of
+    /// increasing `z`, and returns an imager for that slab on `queue`.
+    pub fn new<G>(geom: LightVolume<F>,
+                  depth: usize,
+                  queue: B::Queue,
+                  mut new_imager: G)
+                  -> Result<Self, B::Error>
+        where G: FnMut(LightVolume<F>) -> Result<Box<Imager<F, LightVolume<F>, B>>, B::Error>
+    {
+        assert!(!geom.opaque, "Slab streaming requires a non-opaque volume");
+        assert!(depth > 0);
+
+        let mut slabs = Vec::new();
+        let mut imagers: Vec<Box<Imager<F, LightVolume<F>, B>>> = Vec::new();
+        let mut iz0 = 0;
+        while iz0 < geom.nz {
+            let iz1 = min(geom.nz, iz0 + depth);
+            let imager = try!(new_imager(geom.slab(iz0, iz1)));
+            if let Some(first) = imagers.first() {
+                assert_eq!(first.na(), imager.na());
+            }
+            slabs.push((iz0, iz1));
+            imagers.push(imager);
+            iz0 = iz1;
+        }
+
+        // the last slab may be thinner; it only uses the front of the buffer
+        let slab_np = geom.nx * geom.ny * min(geom.nz, depth);
+        let slab_buf = try!(B::create_buffer(&queue, &vec![F::zero(); slab_np]));
+        let view_buf = try!(B::create_buffer(&queue,
+                                             &imagers[0].detector().image_geometry().zeros()));
+
+        Ok(SlabImager {
+            geom: geom,
+            slabs: slabs,
+            imagers: imagers,
+            queue: queue,
+            slab_buf: slab_buf,
+            view_buf: view_buf,
+            error: None,
+        })
+    }
+
+    /// Creates an imager whose slabs fit in `budget` bytes; see `slab_depth`
+    pub fn with_budget<G>(geom: LightVolume<F>,
+                          budget: usize,
+                          queue: B::Queue,
+                          new_imager: G)
+                          -> Result<Self, B::Error>
+        where G: FnMut(LightVolume<F>) -> Result<Box<Imager<F, LightVolume<F>, B>>, B::Error>
+    {
+        let depth = slab_depth(&geom, budget);
+        Self::new(geom, depth, queue, new_imager)
+    }
+
+    /// Number of slabs the volume is streamed in
+    pub fn num_slabs(self: &Self) -> usize {
+        self.slabs.len()
+    }
+
+    /// Returns the device error behind the last failed projection or
+    /// backprojection through the `Imager` interface, if any
+    pub fn take_error(self: &mut Self) -> Option<B::Error> {
+        self.error.take()
+    }
+
+    /// Projects the angles in `angles` of `object` onto `view`
+    ///
+    /// Like `Imager::forw_subset`, this adds to `view`.
+    pub fn forw_slabs(self: &mut Self,
+                      object: &[F],
+                      view: &mut [F],
+                      angles: &[usize])
+                      -> Result<(), B::Error> {
+        let slice_np = self.geom.nx * self.geom.ny;
+
+        // projections accumulate onto the view, so start from its contents
+        let mut evt = try!(B::write_buffer(&self.queue, &mut self.view_buf, view, &[]));
+        for (&(iz0, iz1), imager) in self.slabs.iter().zip(self.imagers.iter_mut()) {
+            evt = try!(B::write_buffer(&self.queue,
+                                       &mut self.slab_buf,
+                                       &object[slice_np * iz0..slice_np * iz1],
+                                       &[evt]));
+            for &ia in angles.iter() {
+                evt = try!(imager.forw_angle(&self.slab_buf, &mut self.view_buf, ia, &[evt]));
+            }
+        }
+        B::read_buffer(&self.queue, &self.view_buf, view, &[evt])
+    }
+
+    /// Backprojects the angles in `angles` of `view` into `object`
+    ///
+    /// Like `Imager::back_subset`, this adds to `object`.
+    pub fn back_slabs(self: &mut Self,
+                      view: &[F],
+                      object: &mut [F],
+                      angles: &[usize])
+                      -> Result<(), B::Error> {
+        let slice_np = self.geom.nx * self.geom.ny;
+
+        let mut evt = try!(B::write_buffer(&self.queue, &mut self.view_buf, view, &[]));
+        for (&(iz0, iz1), imager) in self.slabs.iter().zip(self.imagers.iter_mut()) {
+            // backprojections accumulate too, so upload the slab first
+            let slab = &mut object[slice_np * iz0..slice_np * iz1];
+            evt = try!(B::write_buffer(&self.queue, &mut self.slab_buf, slab, &[evt]));
+            for &ia in angles.iter() {
+                evt = try!(imager.back_angle(&self.view_buf, &mut self.slab_buf, ia, &[evt]));
+            }
+            try!(B::read_buffer(&self.queue, &self.slab_buf, slab, &[evt.clone()]));
+        }
+        Ok(())
+    }
+}
+
+impl<F, B> Imager<F, LightVolume<F>, HostBackend> for SlabImager<F, B>
+    where F: Float + FromPrimitive + ToPrimitive,
+          B: Backend<F>
+{
+    fn na(self: &Self) -> usize {
+        self.imagers[0].na()
+    }
+
+    fn detector(self: &Self) -> &Detector<F> {
+        self.imagers[0].detector()
+    }
+
+    fn geometry(self: &Self) -> &LightVolume<F> {
+        &self.geom
+    }
+
+    fn angular_plane(self: &Self) -> &AngularPlane<F> {
+        self.imagers[0].angular_plane()
+    }
+
+    fn forw_angle(self: &mut Self,
+                  object: &Vec<F>,
+                  view: &mut Vec<F>,
+                  ia: usize,
+                  wait_for: &[HostEvent])
+                  -> Result<HostEvent, ()> {
+        self.forw_subset(object, view, &[ia], wait_for)
+    }
+
+    fn back_angle(self: &mut Self,
+                  view: &Vec<F>,
+                  object: &mut Vec<F>,
+                  ia: usize,
+                  wait_for: &[HostEvent])
+                  -> Result<HostEvent, ()> {
+        self.back_subset(view, object, &[ia], wait_for)
+    }
+
+    fn forw_subset(self: &mut Self,
+                   object: &Vec<F>,
+                   view: &mut Vec<F>,
+                   angles: &[usize],
+                   _: &[HostEvent])
+                   -> Result<HostEvent, ()> {
+        match self.forw_slabs(object, view, angles) {
+            Ok(()) => Ok(HostEvent),
+            Err(e) => {
+                self.error = Some(e);
+                Err(())
+            }
+        }
+    }
+
+    fn back_subset(self: &mut Self,
+                   view: &Vec<F>,
+                   object: &mut Vec<F>,
+                   angles: &[usize],
+                   _: &[HostEvent])
+                   -> Result<HostEvent, ()> {
+        match self.back_slabs(view, object, angles) {
+            Ok(()) => Ok(HostEvent),
+            Err(e) => {
+                self.error = Some(e);
+                Err(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_slab_imager_matches_whole_volume() {
+    use lens::*;
+    use single_lens_camera::*;
+    use single_lens_imager::*;
+    use self::nalgebra::Vector3;
+
+    let camera = SingleLensCamera {
+        lens: Lens {
+            center_s: 0f32,
+            center_t: 0f32,
+            radius_s: 10f32,
+            radius_t: 10f32,
+            focal_length_s: 20f32,
+            focal_length_t: 20f32,
+        },
+        detector: Detector {
+            ns: 32,
+            nt: 32,
+            ds: 0.5,
+            dt: 0.5,
+            offset_s: 0.0,
+            offset_t: 0.0,
+        },
+        distance_detector_lens: 25f32,
+        stop: None,
+        transfer: None,
+    };
+    let vg = LightVolume {
+        nx: 16,
+        ny: 16,
+        nz: 8,
+        dx: 0.5,
+        dy: 0.5,
+        dz: 0.5,
+        offset_x: 0.0,
+        offset_y: 0.0,
+        offset_z: 0.0,
+        opaque: false,
+        attenuation: 1.0,
+    };
+    let position = Vector3::new(0f32, 0f32, -100f32);
+    let na = 3;
+
+    let mut whole = HostSingleLensVolumeImager::new(vg.clone(),
+                                                    camera.clone(),
+                                                    position,
+                                                    na,
+                                                    AngularBasis::Pillbox);
+
+    // three slices per slab leaves a thinner slab at the end
+    let budget = 3 * vg.nx * vg.ny * size_of::<f32>();
+    let mut slabs = SlabImager::<f32, HostBackend>::with_budget(vg.clone(),
+                                                               budget,
+                                                               HostQueue,
+                                                               |slab_geom| {
+        let imager: Box<Imager<f32, LightVolume<f32>, HostBackend>> =
+            Box::new(HostSingleLensVolumeImager::new(slab_geom,
+                                                     camera.clone(),
+                                                     position,
+                                                     na,
+                                                     AngularBasis::Pillbox));
+        Ok(imager)
+    })
+                        .unwrap();
+    assert_eq!(slabs.num_slabs(), 3);
+
+    let x = vg.rands();
+    let y = camera.detector.image_geometry().rands();
+    for ia in 0..na {
+        let mut whole_img = camera.detector.image_geometry().zeros();
+        let mut slab_img = camera.detector.image_geometry().zeros();
+        whole.forw_angle(&x, &mut whole_img, ia, &[]).unwrap();
+        slabs.forw_angle(&x, &mut slab_img, ia, &[]).unwrap();
+        for (a, b) in whole_img.iter().zip(slab_img.iter()) {
+            assert!((a - b).abs() <= 1e-4 * (1f32 + a.abs()));
+        }
+
+        let mut whole_vol = vg.zeros();
+        let mut slab_vol = vg.zeros();
+        whole.back_angle(&y, &mut whole_vol, ia, &[]).unwrap();
+        slabs.back_angle(&y, &mut slab_vol, ia, &[]).unwrap();
+        for (a, b) in whole_vol.iter().zip(slab_vol.iter()) {
+            assert!((a - b).abs() <= 1e-4 * (1f32 + a.abs()));
+        }
+
+        let mut direct_img = camera.detector.image_geometry().zeros();
+        slabs.forw_slabs(&x, &mut direct_img, &[ia]).unwrap();
+        assert_eq!(direct_img, slab_img);
+    }
+    assert!(slabs.take_error().is_none());
+}
@@ -1,10 +1,13 @@
 extern crate num;
-extern crate byteorder;
-use self::num::{Float, ToPrimitive};
+use self::num::{Float, FromPrimitive, ToPrimitive};
 use cl_traits::*;
-use self::byteorder::*;
 
 /// Kernel of a Toeplitz-like operation
+///
+/// `Rect` and `Trapezoid` are a box and the convolution of two boxes, scaled
+/// to a unit peak.  `Quad` is the convolution of three unit boxes divided by
+/// the product of the two narrowest widths, so it also peaks at one when it
+/// has a flat top; its eight taus are the sorted knots of the convolution.
 #[derive(Clone, Debug)]
 pub enum SplineKernel<F: Float> {
     Rect(F, F, [F; 2]),
@@ -46,6 +49,162 @@ impl<F: Float> SplineKernel<F> {
             &SplineKernel::Quad(_, ref mag, _) => mag.clone(),
         }
     }
+
+    /// Returns a copy of this kernel with a different height
+    pub fn with_height(self: &Self, height: F) -> Self {
+        match self {
+            &SplineKernel::Rect(_, mag, taus) => SplineKernel::Rect(height, mag, taus),
+            &SplineKernel::Trapezoid(_, mag, taus) => SplineKernel::Trapezoid(height, mag, taus),
+            &SplineKernel::Quad(_, mag, taus) => SplineKernel::Quad(height, mag, taus),
+        }
+    }
+
+    /// Returns the (first, last) knots of the kernel shifted by `loc`
+    pub fn support(self: &Self, loc: F) -> (F, F) {
+        let mag = self.magnification();
+        match self {
+            &SplineKernel::Rect(_, _, ref taus) => (taus[0] + loc * mag, taus[1] + loc * mag),
+            &SplineKernel::Trapezoid(_, _, ref taus) => (taus[0] + loc * mag, taus[3] + loc * mag),
+            &SplineKernel::Quad(_, _, ref taus) => (taus[0] + loc * mag, taus[7] + loc * mag),
+        }
+    }
+}
+
+fn clamp<F: Float>(x: F, lo: F, hi: F) -> F {
+    x.max(lo).min(hi)
+}
+
+/// Integral of `max(x - k, 0)^2 / 2` over `[l, r]`
+fn quad_integral<F: Float + FromPrimitive>(k: F, l: F, r: F) -> F {
+    let c6 = F::from_f32(6f32).unwrap();
+    let a = l.max(k);
+    if r <= a {
+        F::zero()
+    } else {
+        let (ra, aa) = (r - k, a - k);
+        (r - a) * (ra * ra + ra * aa + aa * aa) / c6
+    }
+}
+
+impl<F: Float + FromPrimitive> SplineKernel<F> {
+    /// Integrates the kernel, shifted by `loc`, over `[l, r]`
+    ///
+    /// This mirrors the `*SplineKernel_integrate` functions in
+    /// `spline_kernel_f32.opencl` and is used by the host transports.
+    pub fn integrate(self: &Self, loc: F, l: F, r: F) -> F {
+        let mag = self.magnification();
+        let c2 = F::from_f32(2f32).unwrap();
+        let c6 = F::from_f32(6f32).unwrap();
+        match self {
+            &SplineKernel::Rect(h, _, ref taus) => {
+                let tau0 = taus[0] + loc * mag;
+                let tau1 = taus[1] + loc * mag;
+                h * (clamp(r, tau0, tau1) - clamp(l, tau0, tau1))
+            }
+            &SplineKernel::Trapezoid(h, _, ref taus) => {
+                let tau0 = taus[0] + loc * mag;
+                let tau1 = taus[1] + loc * mag;
+                let tau2 = taus[2] + loc * mag;
+                let tau3 = taus[3] + loc * mag;
+                let mut accum = F::zero();
+
+                if tau1 > tau0 {
+                    let ll = clamp(l, tau0, tau1);
+                    let rr = clamp(r, tau0, tau1);
+                    accum = accum +
+                            ((rr - tau0) * (rr - tau0) - (ll - tau0) * (ll - tau0)) /
+                            (c2 * (tau1 - tau0));
+                }
+
+                accum = accum + clamp(r, tau1, tau2) - clamp(l, tau1, tau2);
+
+                if tau3 > tau2 {
+                    let ll = clamp(l, tau2, tau3);
+                    let rr = clamp(r, tau2, tau3);
+                    accum = accum +
+                            ((ll - tau3) * (ll - tau3) - (rr - tau3) * (rr - tau3)) /
+                            (c2 * (tau3 - tau2));
+                }
+
+                h * accum
+            }
+            &SplineKernel::Quad(h, _, ref taus) => {
+                let t0 = taus[0] + loc * mag;
+                let t1 = taus[1] + loc * mag;
+                let t2 = taus[2] + loc * mag;
+                let t3 = taus[3] + loc * mag;
+                let t4 = taus[4] + loc * mag;
+                let t5 = taus[5] + loc * mag;
+                let t6 = taus[6] + loc * mag;
+                let t7 = taus[7] + loc * mag;
+                let pow2 = |x: F| x * x;
+                let pow3 = |x: F| x * x * x;
+
+                // widths of the three boxes, narrowest first
+                let w1 = t1 - t0;
+                let w2 = t2 - t0;
+                let w3 = t7 - t2 - t1 + t0;
+                if w3 < w1 + w2 {
+                    // no flat top: sum the knots' truncated quadratics, with
+                    // signs from the sorted knot order in this regime
+                    let l = clamp(l, t0, t7);
+                    let r = clamp(r, t0, t7);
+                    let accum = quad_integral(t0, l, r) - quad_integral(t1, l, r) -
+                                quad_integral(t2, l, r) -
+                                quad_integral(t3, l, r) +
+                                quad_integral(t4, l, r) +
+                                quad_integral(t5, l, r) +
+                                quad_integral(t6, l, r) -
+                                quad_integral(t7, l, r);
+                    return h * accum / (w1 * w2);
+                }
+
+                let mut accum = F::zero();
+                let c1 = F::one() / ((t1 - t0) * ((t1 - t0) / c2 + t2 - t1 + (t3 - t2) / c2));
+
+                let (a, b) = (l.max(t0), r.min(t1));
+                if b > a {
+                    accum = accum + c1 * (pow3(b - t0) - pow3(a - t0)) / c6;
+                }
+
+                let (a, b) = (l.max(t1), r.min(t2));
+                if b > a {
+                    accum = accum + c1 * (t1 - t0) * (pow2(b - t1) - pow2(a - t1)) / c2;
+                    accum = accum + c1 * pow2(t1 - t0) * (b - a) / c2;
+                }
+
+                let (a, b) = (l.max(t2), r.min(t3));
+                if b > a {
+                    accum = accum + (b - a);
+                    accum = accum -
+                            c1 * (t1 - t0) * (pow3(b - t3) - pow3(a - t3)) / (c6 * (t3 - t2));
+                }
+
+                let (a, b) = (l.max(t3), r.min(t4));
+                accum = accum + (b - a).max(F::zero());
+
+                let (a, b) = (l.max(t4), r.min(t5));
+                if b > a {
+                    accum = accum + (b - a);
+                    accum = accum -
+                            c1 * (t1 - t0) * (pow3(b - t4) - pow3(a - t4)) / (c6 * (t3 - t2));
+                }
+
+                let (a, b) = (l.max(t5), r.min(t6));
+                if b > a {
+                    accum = accum + c1 * pow2(t1 - t0) / c2 * (b - a);
+                    accum = accum - c1 * (t1 - t0) / c2 * (pow2(b - t6) - pow2(a - t6));
+                }
+
+                let (a, b) = (l.max(t6), r.min(t7));
+                if b > a {
+                    accum = accum + c1 * (pow3(b - t7) - pow3(a - t7)) / c6;
+                }
+
+                h * accum
+            }
+        }
+    }
 }
 
 impl<F: Float> ClHeader for SplineKernel<F> {
@@ -58,31 +217,87 @@ impl<F: Float + ToPrimitive> ClBuffer for SplineKernel<F> {
     fn as_cl_bytes(self: &Self, buf: &mut Vec<u8>) -> () {
         match self {
             &SplineKernel::Rect(ref height, ref mag, ref taus) => {
-                buf.write_f32::<LittleEndian>(F::to_f32(height).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(mag).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[0]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[1]).unwrap()).unwrap();
+                write_real(buf, height);
+                write_real(buf, mag);
+                write_real(buf, &taus[0]);
+                write_real(buf, &taus[1]);
             }
             &SplineKernel::Trapezoid(ref height, ref mag, ref taus) => {
-                buf.write_f32::<LittleEndian>(F::to_f32(height).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(mag).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[0]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[1]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[2]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[3]).unwrap()).unwrap();
+                write_real(buf, height);
+                write_real(buf, mag);
+                write_real(buf, &taus[0]);
+                write_real(buf, &taus[1]);
+                write_real(buf, &taus[2]);
+                write_real(buf, &taus[3]);
             }
             &SplineKernel::Quad(ref height, ref mag, ref taus) => {
-                buf.write_f32::<LittleEndian>(F::to_f32(height).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(mag).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[0]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[1]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[2]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[3]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[4]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[5]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[6]).unwrap()).unwrap();
-                buf.write_f32::<LittleEndian>(F::to_f32(&taus[7]).unwrap()).unwrap();
+                write_real(buf, height);
+                write_real(buf, mag);
+                write_real(buf, &taus[0]);
+                write_real(buf, &taus[1]);
+                write_real(buf, &taus[2]);
+                write_real(buf, &taus[3]);
+                write_real(buf, &taus[4]);
+                write_real(buf, &taus[5]);
+                write_real(buf, &taus[6]);
+                write_real(buf, &taus[7]);
             }
         }
     }
 }
+
+#[test]
+fn test_spline_kernel_integrate() {
+    let rect = SplineKernel::Rect(2f32, 1f32, [-0.5f32, 0.5f32]);
+    assert!((rect.integrate(0f32, -10f32, 10f32) - 2f32).abs() < 1e-6);
+    assert!((rect.integrate(1f32, 0f32, 1f32) - 1f32).abs() < 1e-6);
+
+    let trap = SplineKernel::new_trapezoid(1f32, 1f32, &[-2f32, -1f32, 1f32, 2f32]);
+    assert!((trap.integrate(0f32, -10f32, 10f32) - 3f32).abs() < 1e-6);
+    assert!((trap.integrate(0f32, -2f32, -1f32) - 0.5f32).abs() < 1e-6);
+    assert!((trap.integrate(0f32, 0f32, 10f32) - 1.5f32).abs() < 1e-6);
+}
+
+#[test]
+fn test_spline_kernel_quad() {
+    // a box of width a convolved with a tent of half-width b, sampled at x
+    let box_tent = |a: f64, b: f64, x: f64| {
+        let n = 2000;
+        let mut accum = 0f64;
+        for k in 0..n {
+            let u = x - a / 2f64 + a * (k as f64 + 0.5f64) / (n as f64);
+            accum += (1f64 - u.abs() / b).max(0f64);
+        }
+        accum * a / (n as f64)
+    };
+
+    // flat top, no flat top with a linear part, and no linear part
+    for &a in [3f64, 1.5f64, 0.5f64].iter() {
+        let b = 1f64;
+        let (w1, w2) = if a < b { (a, b) } else { (b, b) };
+        let taus: Vec<f32> = [-1f64, 1f64]
+                                 .iter()
+                                 .flat_map(|&ea| {
+                                     [-2f64, 0f64, 0f64, 2f64]
+                                         .iter()
+                                         .map(|&eb| (ea * a / 2f64 + eb * b / 2f64) as f32)
+                                         .collect::<Vec<f32>>()
+                                 })
+                                 .collect();
+        let quad = SplineKernel::new_quad(1f32, 1f32, &taus);
+
+        let total = quad.integrate(0f32, -10f32, 10f32);
+        assert!((total as f64 - a * b / (w1 * w2)).abs() < 1e-4);
+
+        let dx = 0.1f64;
+        let mut x = -3f64;
+        while x < 3f64 {
+            let expected = (0..10).fold(0f64, |s, k| {
+                s + box_tent(a, b, x + dx * (k as f64 + 0.5f64) / 10f64) * dx / 10f64
+            }) / (w1 * w2);
+            let actual = quad.integrate(0.5f32, (x + 0.5f64) as f32, (x + 0.5f64 + dx) as f32);
+            assert!((actual as f64 - expected).abs() < 1e-4);
+            x += dx;
+        }
+    }
+}
@@ -0,0 +1,444 @@
+extern crate num;
+extern crate toml;
+extern crate image;
+use self::num::{Float, FromPrimitive, ToPrimitive};
+use self::toml::*;
+use self::image::{ImageBuffer, Luma};
+use serialize::*;
+use geom::*;
+use image_geom::*;
+use optics::*;
+use angular_plane::*;
+use light_field_geom::*;
+use light_field::*;
+use std::path::{Path, PathBuf};
+
+/// Light field stored as a grid of sub-aperture images
+///
+/// This is how camera arrays and many public light field datasets are
+/// distributed: one image per camera position, named by the position's row
+/// and column in the grid.  Columns step along `s` and rows along `t` on the
+/// angular plane; either spacing may be negative if the dataset counts the
+/// other way.  Each grid position becomes one Dirac sample on the plane.
+#[derive(Clone, Debug)]
+pub struct SubapertureGrid<F: Float> {
+    pub rows: usize,
+    pub cols: usize,
+
+    /// Distance between neighbouring columns on the angular plane
+    pub spacing_s: F,
+
+    /// Distance between neighbouring rows on the angular plane
+    pub spacing_t: F,
+
+    /// Position of the center of the grid on the angular plane
+    pub center_s: F,
+    pub center_t: F,
+
+    /// Geometry of each sub-aperture image
+    pub geom: ImageGeometry<F>,
+
+    /// Optics from the image plane to the angular plane
+    pub to_plane: Optics<F>,
+
+    /// Image file names, with `{row}` and `{col}` standing in for the
+    /// position in the grid
+    pub pattern: String,
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> SubapertureGrid<F> {
+    /// Returns the smallest grid holding every sample on `lfg`'s plane
+    ///
+    /// The grid spacing is the plane's sample spacing.  Grid positions
+    /// without a sample, e.g. outside a round aperture, are exported as
+    /// black images.
+    pub fn from_light_field_geometry(lfg: &LightFieldGeometry<F>) -> Self {
+        let plane = &lfg.plane;
+        assert!(plane.s.len() > 0, "Empty angular plane");
+        let (s0, s1) = plane.s.iter().fold((plane.s[0], plane.s[0]), |(lo, hi), &s| {
+            (lo.min(s), hi.max(s))
+        });
+        let (t0, t1) = plane.t.iter().fold((plane.t[0], plane.t[0]), |(lo, hi), &t| {
+            (lo.min(t), hi.max(t))
+        });
+        let two = F::from_f32(2f32).unwrap();
+
+        SubapertureGrid {
+            rows: ((t1 - t0) / plane.dt).round().to_usize().unwrap() + 1,
+            cols: ((s1 - s0) / plane.ds).round().to_usize().unwrap() + 1,
+            spacing_s: plane.ds,
+            spacing_t: plane.dt,
+            center_s: (s0 + s1) / two,
+            center_t: (t0 + t1) / two,
+            geom: lfg.geom.clone(),
+            to_plane: lfg.to_plane.clone(),
+            pattern: "{row}_{col}.png".to_string(),
+        }
+    }
+
+    /// Number of sub-aperture images
+    pub fn na(self: &Self) -> usize {
+        self.rows * self.cols
+    }
+
+    /// Returns the position of a grid point on the angular plane
+    pub fn position(self: &Self, row: usize, col: usize) -> (F, F) {
+        let two = F::from_f32(2f32).unwrap();
+        let ws = (F::from_usize(self.cols).unwrap() - F::one()) / two;
+        let wt = (F::from_usize(self.rows).unwrap() - F::one()) / two;
+        (self.center_s + (F::from_usize(col).unwrap() - ws) * self.spacing_s,
+         self.center_t + (F::from_usize(row).unwrap() - wt) * self.spacing_t)
+    }
+
+    /// Returns the `(row, col)` of the grid point at `(s, t)`, if any
+    pub fn grid_index(self: &Self, s: F, t: F) -> Option<(usize, usize)> {
+        let two = F::from_f32(2f32).unwrap();
+        let tolerance = F::from_f32(0.25f32).unwrap();
+        let ws = (F::from_usize(self.cols).unwrap() - F::one()) / two;
+        let wt = (F::from_usize(self.rows).unwrap() - F::one()) / two;
+        let col = (s - self.center_s) / self.spacing_s + ws;
+        let row = (t - self.center_t) / self.spacing_t + wt;
+        if (col - col.round()).abs() > tolerance || (row - row.round()).abs() > tolerance {
+            return None;
+        }
+        match (row.round().to_isize(), col.round().to_isize()) {
+            (Some(row), Some(col)) if row >= 0 && col >= 0 &&
+                                      (row as usize) < self.rows &&
+                                      (col as usize) < self.cols => {
+                Some((row as usize, col as usize))
+            }
+            _ => None,
+        }
+    }
+
+    /// Dirac angular plane with one sample per image, in row-major order
+    pub fn angular_plane(self: &Self) -> AngularPlane<F> {
+        let mut s = Vec::with_capacity(self.na());
+        let mut t = Vec::with_capacity(self.na());
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let (sk, tk) = self.position(row, col);
+                s.push(sk);
+                t.push(tk);
+            }
+        }
+
+        AngularPlane {
+            ds: self.spacing_s.abs(),
+            dt: self.spacing_t.abs(),
+            basis: AngularBasis::Dirac,
+            s: s,
+            t: t,
+            w: vec![F::one(); self.na()],
+        }
+    }
+
+    pub fn light_field_geometry(self: &Self) -> LightFieldGeometry<F> {
+        LightFieldGeometry {
+            geom: self.geom.clone(),
+            plane: self.angular_plane(),
+            to_plane: self.to_plane.clone(),
+        }
+    }
+
+    /// Light field holding the grid's images as its views
+    pub fn light_field(self: &Self) -> LightField<F> {
+        LightField::new(self.light_field_geometry())
+    }
+
+    /// Returns the file name of the image at `(row, col)`
+    pub fn file_name(self: &Self, row: usize, col: usize) -> String {
+        self.pattern
+            .replace("{row}", &row.to_string())
+            .replace("{col}", &col.to_string())
+    }
+
+    /// Reads the images in directory `root` into a buffer on `light_field()`
+    ///
+    /// Eight-bit intensities are scaled to `[0, 1]`.
+    pub fn import<P: AsRef<Path>>(self: &Self, root: P) -> Result<Vec<F>, ()> {
+        let scale = F::from_u8(255).unwrap();
+        let np = self.geom.dimension();
+        let mut tr = Vec::with_capacity(np * self.na());
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let path = root.as_ref().join(self.file_name(row, col));
+                let view = try!(self.geom.load(&path));
+                if view.len() != np {
+                    return Err(());
+                }
+                tr.extend(view.into_iter().map(|v| v / scale));
+            }
+        }
+        Ok(tr)
+    }
+
+    /// Writes the views of a light field as images in directory `root`
+    ///
+    /// Each sample on `lf`'s plane goes to the grid point at its position;
+    /// samples off the grid are an error.  All images share one intensity
+    /// scale, with the brightest pixel of the light field written as white
+    /// and negative values as black.
+    pub fn export<P: AsRef<Path>>(self: &Self,
+                                  lf: &LightField<F>,
+                                  buf: &[F],
+                                  root: P)
+                                  -> Result<(), ()> {
+        let geom = &lf.lfg.geom;
+        if geom.ns != self.geom.ns || geom.nt != self.geom.nt {
+            return Err(());
+        }
+
+        let mut views: Vec<Option<usize>> = vec![None; self.na()];
+        for ia in 0..lf.na() {
+            match self.grid_index(lf.lfg.plane.s[ia], lf.lfg.plane.t[ia]) {
+                Some((row, col)) => views[col + self.cols * row] = Some(ia),
+                None => return Err(()),
+            }
+        }
+
+        let max_val = buf.iter().fold(F::zero(), |m, &v| m.max(v));
+        let scale = if max_val > F::zero() {
+            F::from_u8(255).unwrap() / max_val
+        } else {
+            F::zero()
+        };
+
+        let black = vec![F::zero(); geom.dimension()];
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let view = match views[col + self.cols * row] {
+                    Some(ia) => lf.view(buf, ia),
+                    None => &black[..],
+                };
+                let path = root.as_ref().join(self.file_name(row, col));
+                try!(save_png(geom, view, scale, path));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Saves an image with a fixed intensity scale
+fn save_png<F, P>(geom: &ImageGeometry<F>, buf: &[F], scale: F, path: P) -> Result<(), ()>
+    where F: Float + FromPrimitive + ToPrimitive,
+          P: AsRef<Path>
+{
+    let white = F::from_u8(255).unwrap();
+    let bytes = buf.iter()
+                   .map(|&v| {
+                       let v = (v * scale).max(F::zero()).min(white);
+                       F::to_u8(&v.round()).unwrap()
+                   })
+                   .collect();
+    let image: ImageBuffer<Luma<u8>, Vec<u8>> = ImageBuffer::from_raw(geom.ns as u32,
+                                                                      geom.nt as u32,
+                                                                      bytes)
+                                                    .expect("logic error -- buffer not big \
+                                                             enough");
+    match image.save(PathBuf::from(path.as_ref())) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(()),
+    }
+}
+
+impl<F: Float + FromPrimitive + ToPrimitive> Serialize for SubapertureGrid<F> {
+    fn from_map(map: &Table) -> Option<Self> {
+        let rows = map.get("rows");
+        let cols = map.get("cols");
+        let spacing_s = map.get("spacing_s");
+        let spacing_t = map.get("spacing_t");
+        let center_s = match map.get("center_s") {
+            Some(&Value::Float(v)) => v,
+            None => 0f64,
+            _ => return None,
+        };
+        let center_t = match map.get("center_t") {
+            Some(&Value::Float(v)) => v,
+            None => 0f64,
+            _ => return None,
+        };
+        let geom = match map.get("geom") {
+            Some(&Value::Table(ref tab)) => ImageGeometry::from_map(tab),
+            _ => None,
+        };
+        let pattern = match map.get("pattern") {
+            Some(&Value::String(ref pattern)) => pattern.clone(),
+            None => "{row}_{col}.png".to_string(),
+            _ => return None,
+        };
+
+        // either full optics or just the distance to the camera plane
+        let to_plane = match (map.get("to_plane"), map.get("distance")) {
+            (Some(&Value::Table(ref tab)), _) => Optics::from_map(tab),
+            (None, Some(&Value::Float(distance))) => {
+                Some(Optics::translation(&F::from_f64(distance).unwrap()))
+            }
+            _ => None,
+        };
+
+        match (rows, cols, spacing_s, spacing_t, geom, to_plane) {
+            (Some(&Value::Integer(rows)),
+             Some(&Value::Integer(cols)),
+             Some(&Value::Float(spacing_s)),
+             Some(&Value::Float(spacing_t)),
+             Some(geom),
+             Some(to_plane)) => {
+                Some(SubapertureGrid {
+                    rows: rows as usize,
+                    cols: cols as usize,
+                    spacing_s: F::from_f64(spacing_s).unwrap(),
+                    spacing_t: F::from_f64(spacing_t).unwrap(),
+                    center_s: F::from_f64(center_s).unwrap(),
+                    center_t: F::from_f64(center_t).unwrap(),
+                    geom: geom,
+                    to_plane: to_plane,
+                    pattern: pattern,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn into_map(self: &Self) -> Table {
+        let mut tr = Table::new();
+        tr.insert("rows".to_string(), Value::Integer(self.rows as i64));
+        tr.insert("cols".to_string(), Value::Integer(self.cols as i64));
+        tr.insert("spacing_s".to_string(),
+                  Value::Float(F::to_f64(&self.spacing_s).unwrap()));
+        tr.insert("spacing_t".to_string(),
+                  Value::Float(F::to_f64(&self.spacing_t).unwrap()));
+        tr.insert("center_s".to_string(),
+                  Value::Float(F::to_f64(&self.center_s).unwrap()));
+        tr.insert("center_t".to_string(),
+                  Value::Float(F::to_f64(&self.center_t).unwrap()));
+        tr.insert("geom".to_string(), Value::Table(self.geom.into_map()));
+        tr.insert("to_plane".to_string(), Value::Table(self.to_plane.into_map()));
+        tr.insert("pattern".to_string(), Value::String(self.pattern.clone()));
+        tr
+    }
+}
+
+#[test]
+fn test_read_subaperture_grid() {
+    let test = r#"
+        rows = 3
+        cols = 5
+        spacing_s = 2.0
+        spacing_t = -1.5
+        distance = 40.0
+        pattern = "view_{row}_{col}.png"
+
+        [geom]
+        ns = 64
+        nt = 48
+        ds = 0.1
+        dt = 0.1
+        offset_s = 0.0
+        offset_t = 0.0
+    "#;
+
+    let map = Parser::new(test).parse().unwrap();
+    let grid: SubapertureGrid<f32> = SubapertureGrid::from_map(&map).unwrap();
+    assert_eq!(grid.na(), 15);
+    assert_eq!(grid.to_plane.su, 40.0);
+    assert_eq!(grid.file_name(2, 4), "view_2_4.png");
+
+    // row-major samples centered on the plane, rows counting down
+    let plane = grid.angular_plane();
+    assert_eq!(plane.s.len(), 15);
+    assert_eq!((plane.s[0], plane.t[0]), (-4.0, 1.5));
+    assert_eq!((plane.s[7], plane.t[7]), (0.0, 0.0));
+    assert_eq!((plane.s[14], plane.t[14]), (4.0, -1.5));
+    assert_eq!(plane.dt, 1.5);
+    for ia in 0..15 {
+        assert_eq!(grid.grid_index(plane.s[ia], plane.t[ia]),
+                   Some((ia / 5, ia % 5)));
+    }
+    assert_eq!(grid.grid_index(1.0, 0.0), None);
+    assert_eq!(grid.grid_index(6.0, 0.0), None);
+
+    let roundtrip: SubapertureGrid<f32> = SubapertureGrid::from_map(&grid.into_map()).unwrap();
+    assert_eq!(roundtrip.pattern, grid.pattern);
+    assert_eq!(roundtrip.spacing_t, -1.5);
+    assert_eq!(roundtrip.to_plane.tv, 40.0);
+}
+
+#[test]
+fn test_grid_from_lens_plane() {
+    use lens::*;
+
+    // a round aperture drops the corners of its bounding grid
+    let lens = Lens {
+        center_s: 1f32,
+        center_t: -2f32,
+        radius_s: 10f32,
+        radius_t: 10f32,
+        focal_length_s: 20f32,
+        focal_length_t: 20f32,
+    };
+    let lfg = LightFieldGeometry {
+        geom: ImageGeometry {
+            ns: 8,
+            nt: 8,
+            ds: 1f32,
+            dt: 1f32,
+            offset_s: 0f32,
+            offset_t: 0f32,
+        },
+        plane: lens.as_angular_plane(AngularBasis::Dirac, 7),
+        to_plane: Optics::translation(&25f32),
+    };
+    assert!(lfg.plane.s.len() < 49);
+
+    let grid = SubapertureGrid::from_light_field_geometry(&lfg);
+    assert_eq!((grid.rows, grid.cols), (7, 7));
+    assert!((grid.center_s - 1f32).abs() < 1e-5);
+    assert!((grid.center_t + 2f32).abs() < 1e-5);
+
+    // every sample of the lens plane is a grid point
+    let grid_plane = grid.angular_plane();
+    for ia in 0..lfg.plane.s.len() {
+        let (row, col) = grid.grid_index(lfg.plane.s[ia], lfg.plane.t[ia]).unwrap();
+        let ig = col + grid.cols * row;
+        assert!((grid_plane.s[ig] - lfg.plane.s[ia]).abs() < 1e-4);
+        assert!((grid_plane.t[ig] - lfg.plane.t[ia]).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_subaperture_grid_io() {
+    use std::env::temp_dir;
+    use std::fs::create_dir_all;
+
+    let grid = SubapertureGrid {
+        rows: 2,
+        cols: 3,
+        spacing_s: 1f32,
+        spacing_t: 1f32,
+        center_s: 0f32,
+        center_t: 0f32,
+        geom: ImageGeometry {
+            ns: 16,
+            nt: 8,
+            ds: 1f32,
+            dt: 1f32,
+            offset_s: 0f32,
+            offset_t: 0f32,
+        },
+        to_plane: Optics::translation(&10f32),
+        pattern: "{row}_{col}.png".to_string(),
+    };
+    let root = temp_dir().join("lightfield_test_subaperture_grid_io");
+    create_dir_all(&root).unwrap();
+
+    // values on the 8-bit grid survive the trip
+    let lf = grid.light_field();
+    let x: Vec<f32> = (0..lf.dimension()).map(|i| (i % 256) as f32 / 255f32).collect();
+    let x_max = x.iter().fold(0f32, |m, &v| m.max(v));
+    grid.export(&lf, &x, &root).unwrap();
+    let y = grid.import(&root).unwrap();
+    for (a, b) in x.iter().zip(y.iter()) {
+        assert!((a / x_max - b).abs() < 1e-6);
+    }
+}
@@ -8,8 +8,37 @@ use self::proust::*;
 use image_geom::*;
 use cl_traits::*;
 use optics::*;
-use std::mem::{size_of, swap};
+use spline_kernel::*;
+use std::mem::size_of;
 use std::cmp::max;
+use program_cache::*;
+use profiler::*;
+
+/// Returns the `(s, t)` footprints of a `Transport` from `src` to `dst`
+///
+/// `forw` selects the forward or adjoint direction. The light field
+/// normalization used by `Transport` is folded into the `t` footprint.
+pub fn transport_footprints<F>(src: &LightFieldGeometry<F>,
+                               dst: &LightFieldGeometry<F>,
+                               ia: usize,
+                               forw: bool,
+                               onto_detector: bool)
+                               -> (SplineKernel<F>, SplineKernel<F>)
+    where F: Float + FromPrimitive + ToPrimitive
+{
+    let (ks, kt) = if forw {
+        src.transport_to(dst, ia)
+    } else {
+        dst.transport_to(src, ia)
+    };
+    let pv = dst.pixel_volume();
+    let ht = if onto_detector {
+        kt.height() * dst.plane.w[ia] / pv.sqrt()
+    } else {
+        kt.height() / pv
+    };
+    (ks, kt.with_height(ht))
+}
 
 /// Transport between two planes in a light transport stack
 pub struct Transport<F: Float> {
@@ -42,8 +71,10 @@ pub struct Transport<F: Float> {
     dst_geom_buf: Mem,
     tmp_buf: Mem,
 
-    src_to_dst: Optics<F>,
-    dst_to_src: Optics<F>,
+    forw_splines_s: Mem,
+    forw_splines_t: Mem,
+    back_splines_s: Mem,
+    back_splines_t: Mem,
 }
 
 impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
@@ -70,12 +101,23 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
             (&AngularBasis::Pillbox, &AngularBasis::Pillbox) => {
                 [ImageGeometry::<F>::header(),
                  Optics::<F>::header(),
-                 include_str!("../cl/transport_pillbox_f32.opencl")]
+                 SplineKernel::<F>::header(),
+                 include_str!("../cl/transport_pillbox_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl")]
             }
             (&AngularBasis::Dirac, &AngularBasis::Dirac) => {
                 [ImageGeometry::<F>::header(),
                  Optics::<F>::header(),
-                 include_str!("../cl/transport_dirac_f32.opencl")]
+                 SplineKernel::<F>::header(),
+                 include_str!("../cl/transport_dirac_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl")]
+            }
+            (&AngularBasis::Linear, &AngularBasis::Linear) => {
+                [ImageGeometry::<F>::header(),
+                 Optics::<F>::header(),
+                 SplineKernel::<F>::header(),
+                 include_str!("../cl/transport_linear_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl")]
             }
             _ => {
                 panic!("Cannot transport between light fields with different bases; use a rebin \
@@ -84,10 +126,7 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
         };
 
         // compile opencl code
-        let context = try!(queue.context());
-        let device = try!(queue.device());
-        let unbuilt = try!(Program::new_from_source(context.clone(), &sources));
-        let program = try!(unbuilt.build(&[device]));
+        let program = try!(cached_program::<F>(&queue, &sources));
 
         // build opencl kernels
         let kernel_s = try!(program.create_kernel("transport_s"));
@@ -116,6 +155,26 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
         let src_geom_buf = try!(src.geom.as_cl_buffer(&queue));
         let dst_geom_buf = try!(dst.geom.as_cl_buffer(&queue));
 
+        // precompute the footprints for each angle, as `VolumeTransport`
+        // does for each angle and slice
+        let mut forw_splines_s_buf: Vec<u8> = Vec::new();
+        let mut forw_splines_t_buf: Vec<u8> = Vec::new();
+        let mut back_splines_s_buf: Vec<u8> = Vec::new();
+        let mut back_splines_t_buf: Vec<u8> = Vec::new();
+        for ia in 0..src.plane.s.len() {
+            let (forw_s, forw_t) = transport_footprints(&src, &dst, ia, true, onto_detector);
+            let (back_s, back_t) = transport_footprints(&src, &dst, ia, false, onto_detector);
+
+            forw_s.as_cl_bytes(&mut forw_splines_s_buf);
+            forw_t.as_cl_bytes(&mut forw_splines_t_buf);
+            back_s.as_cl_bytes(&mut back_splines_s_buf);
+            back_t.as_cl_bytes(&mut back_splines_t_buf);
+        }
+        let forw_splines_s = try!(queue.create_buffer_from_slice(&forw_splines_s_buf));
+        let forw_splines_t = try!(queue.create_buffer_from_slice(&forw_splines_t_buf));
+        let back_splines_s = try!(queue.create_buffer_from_slice(&back_splines_s_buf));
+        let back_splines_t = try!(queue.create_buffer_from_slice(&back_splines_t_buf));
+
         Ok(Transport {
             overwrite_forw: overwrite_forw,
             overwrite_back: overwrite_back,
@@ -144,8 +203,10 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
             src_geom_buf: src_geom_buf,
             dst_geom_buf: dst_geom_buf,
 
-            src_to_dst: dst.to_plane.invert().compose(&src.to_plane),
-            dst_to_src: src.to_plane.invert().compose(&dst.to_plane),
+            forw_splines_s: forw_splines_s,
+            forw_splines_t: forw_splines_t,
+            back_splines_s: back_splines_s,
+            back_splines_t: back_splines_t,
 
             src: src,
             dst: dst,
@@ -184,219 +245,24 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
         Ok(())
     }
 
-    #[allow(non_snake_case)] // allow us to break style guide to match docs
-    fn transport_dirac_t(self: &mut Self,
-                         forw: bool,
-                         src: &Mem,
-                         ia: usize,
-                         wait_for: &[Event])
-                         -> Result<Event, Error> {
-        let Rqp = if forw {
-            &self.src_to_dst
-        } else {
-            &self.dst_to_src
-        };
-        let Rp = if forw {
-            &self.src.to_plane
-        } else {
-            &self.dst.to_plane
-        };
-        let into_geom = if forw {
-            &self.dst.geom
-        } else {
-            &self.src.geom
-        };
-        let plane = &self.src.plane;
-
-        let t = plane.t[ia];
-        let c2 = F::from_f32(2f32).unwrap();
-
-        let alpha = Rqp.tt - Rp.tt * Rqp.tv / Rp.tv;
-        let beta = Rqp.t + Rqp.tv * (t - Rp.t) / Rp.tv;
-        let mut h = (plane.dt / Rp.tv).abs();
-        if !self.onto_detector {
-            h = h / self.dst.pixel_volume();
-        } else {
-            h = h * self.dst.plane.w[ia] / self.dst.pixel_volume().sqrt();
-        }
-
-        let mut tau0 = (-into_geom.dt / c2 - beta) / alpha;
-        let mut tau1 = (into_geom.dt / c2 - beta) / alpha;
-        if tau0 > tau1 {
-            swap(&mut tau0, &mut tau1);
-        }
-
+    fn transport_t(self: &mut Self,
+                   forw: bool,
+                   src: &Mem,
+                   ia: usize,
+                   wait_for: &[Event])
+                   -> Result<Event, Error> {
         let mut kernel = self.kernel_t.clone();
         try!(self.bind_common_args(forw, &mut kernel));
 
-        try!(kernel.bind_scalar(10, &F::to_f32(&(F::one() / alpha)).unwrap()));
-        try!(kernel.bind_scalar(11, &F::to_f32(&tau0).unwrap()));
-        try!(kernel.bind_scalar(12, &F::to_f32(&tau1).unwrap()));
-        try!(kernel.bind_scalar(13, &F::to_f32(&h).unwrap()));
-
-        try!(kernel.bind(14, src));
-        try!(kernel.bind_mut(15, &mut self.tmp_buf));
-
-        let local_size = (32usize, 8usize, 1usize);
-        let global_size = if forw {
-            (self.src_s1 - self.src_s0, self.dst_t1 - self.dst_t0, 1)
-        } else {
-            (self.dst_s1 - self.dst_s0, self.src_t1 - self.src_t0, 1)
-        };
-
-        self.queue.run_with_events(&mut kernel, local_size, global_size, wait_for)
-    }
-
-    #[allow(non_snake_case)] // allow us to break style guide to match docs
-    fn transport_dirac_s(self: &mut Self,
-                         forw: bool,
-                         dst: &mut Mem,
-                         ia: usize,
-                         wait_for: &[Event])
-                         -> Result<Event, Error> {
-        let Rqp = if forw {
-            &self.src_to_dst
-        } else {
-            &self.dst_to_src
-        };
-        let Rp = if forw {
-            &self.src.to_plane
-        } else {
-            &self.dst.to_plane
-        };
-        let into_geom = if forw {
-            &self.dst.geom
-        } else {
-            &self.src.geom
-        };
-        let conservative_flag = match (forw, self.conservative_forw, self.conservative_back) {
-            (true, true, _) => 1u32,
-            (true, false, _) => 0u32,
-            (false, _, true) => 1u32,
-            (false, _, false) => 0u32,
-        };
-        let overwrite_flag = match (forw, self.overwrite_forw, self.overwrite_back) {
-            (true, true, _) => 1u32,
-            (true, false, _) => 0u32,
-            (false, _, true) => 1u32,
-            (false, _, false) => 0u32,
-        };
-        let plane = &self.src.plane;
-
-        let s = plane.s[ia];
-        let c2 = F::from_f32(2f32).unwrap();
-
-        let alpha = Rqp.ss - Rp.ss * Rqp.su / Rp.su;
-        let beta = Rqp.s + Rqp.su * (s - Rp.s) / Rp.su;
-        let h = (plane.ds / Rp.su).abs();
-
-        let mut tau0 = (-into_geom.ds / c2 - beta) / alpha;
-        let mut tau1 = (into_geom.ds / c2 - beta) / alpha;
-        if tau0 > tau1 {
-            swap(&mut tau0, &mut tau1);
-        }
-
-        let mut kernel = self.kernel_s.clone();
-        try!(self.bind_common_args(forw, &mut kernel));
-
-        try!(kernel.bind_scalar(10, &F::to_f32(&(F::one() / alpha)).unwrap()));
-        try!(kernel.bind_scalar(11, &F::to_f32(&tau0).unwrap()));
-        try!(kernel.bind_scalar(12, &F::to_f32(&tau1).unwrap()));
-        try!(kernel.bind_scalar(13, &F::to_f32(&h).unwrap()));
-
-        try!(kernel.bind(14, &self.tmp_buf));
-        try!(kernel.bind_mut(15, dst));
-
-        try!(kernel.bind_scalar(16, &conservative_flag));
-        try!(kernel.bind_scalar(17, &overwrite_flag));
-
-        let local_size = (32usize, 8usize, 1usize);
-        let global_size = if forw {
-            (self.dst_t1 - self.dst_t0, self.dst_s1 - self.dst_s0, 1)
-        } else {
-            (self.src_t1 - self.src_t0, self.src_s1 - self.src_s0, 1)
-        };
-
-        self.queue.run_with_events(&mut kernel, local_size, global_size, wait_for)
-    }
-
-    fn forw_dirac(self: &mut Self,
-                  src: &Mem,
-                  dst: &mut Mem,
-                  ia: usize,
-                  wait_for: &[Event])
-                  -> Result<Event, Error> {
-        let done_t = try!(self.transport_dirac_t(true, src, ia, wait_for));
-        self.transport_dirac_s(true, dst, ia, &[done_t])
-    }
-
-    fn back_dirac(self: &mut Self,
-                  dst: &Mem,
-                  src: &mut Mem,
-                  ia: usize,
-                  wait_for: &[Event])
-                  -> Result<Event, Error> {
-        let done_t = try!(self.transport_dirac_t(false, dst, ia, wait_for));
-        self.transport_dirac_s(false, src, ia, &[done_t])
-    }
-
-    #[allow(non_snake_case)] // allow us to break style guide to match docs
-    fn transport_pillbox_t(self: &mut Self,
-                           forw: bool,
-                           src: &Mem,
-                           ia: usize,
-                           wait_for: &[Event])
-                           -> Result<Event, Error> {
-        let Rqp = if forw {
-            &self.src_to_dst
-        } else {
-            &self.dst_to_src
-        };
-        let Rp = if forw {
-            &self.src.to_plane
-        } else {
-            &self.dst.to_plane
-        };
-        let into_geom = if forw {
-            &self.dst.geom
-        } else {
-            &self.src.geom
-        };
-        let plane = &self.src.plane;
-
-        let t = plane.t[ia];
-        let c2 = F::from_f32(2f32).unwrap();
-
-        let alpha = Rqp.tt - Rqp.tv * Rp.tt / Rp.tv;
-        let beta = Rqp.tv / Rp.tv;
-        let gamma = Rqp.t - Rqp.tv * Rp.t / Rp.tv;
-        let mut h = (plane.dt / Rp.tv).abs().min((into_geom.dt / Rqp.tv).abs());
-        if !self.onto_detector {
-            h = h / self.dst.pixel_volume();
+        if forw {
+            try!(kernel.bind(10, &self.forw_splines_t));
         } else {
-            h = h / self.dst.pixel_volume().sqrt() * self.dst.plane.w[ia];
+            try!(kernel.bind(10, &self.back_splines_t));
         }
+        try!(kernel.bind_scalar(11, &(ia as i32)));
 
-        let mut taus = vec![
-            (into_geom.dt/c2 - beta*(t + plane.dt/c2) - gamma)/alpha,
-            (into_geom.dt/c2 - beta*(t - plane.dt/c2) - gamma)/alpha,
-            (-into_geom.dt/c2 - beta*(t + plane.dt/c2) - gamma)/alpha,
-            (-into_geom.dt/c2 - beta*(t - plane.dt/c2) - gamma)/alpha,
-        ];
-        taus.sort_by(|l, r| l.partial_cmp(r).unwrap());
-
-        let mut kernel = self.kernel_t.clone();
-        try!(self.bind_common_args(forw, &mut kernel));
-
-        try!(kernel.bind_scalar(10, &F::to_f32(&(F::one() / alpha)).unwrap()));
-        try!(kernel.bind_scalar(11, &F::to_f32(&taus[0]).unwrap()));
-        try!(kernel.bind_scalar(12, &F::to_f32(&taus[1]).unwrap()));
-        try!(kernel.bind_scalar(13, &F::to_f32(&taus[2]).unwrap()));
-        try!(kernel.bind_scalar(14, &F::to_f32(&taus[3]).unwrap()));
-        try!(kernel.bind_scalar(15, &F::to_f32(&h).unwrap()));
-
-        try!(kernel.bind(16, src));
-        try!(kernel.bind_mut(17, &mut self.tmp_buf));
+        try!(kernel.bind(12, src));
+        try!(kernel.bind_mut(13, &mut self.tmp_buf));
 
         let local_size = (32usize, 8usize, 1usize);
         let global_size = if forw {
@@ -405,31 +271,20 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
             (self.dst_s1 - self.dst_s0, self.src_t1 - self.src_t0, 1)
         };
 
-        self.queue.run_with_events(&mut kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut kernel,
+                   "transport_t",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
-    #[allow(non_snake_case)] // allow us to break style guide to match docs
-    fn transport_pillbox_s(self: &mut Self,
-                           forw: bool,
-                           dst: &mut Mem,
-                           ia: usize,
-                           wait_for: &[Event])
-                           -> Result<Event, Error> {
-        let Rqp = if forw {
-            &self.src_to_dst
-        } else {
-            &self.dst_to_src
-        };
-        let Rp = if forw {
-            &self.src.to_plane
-        } else {
-            &self.dst.to_plane
-        };
-        let into_geom = if forw {
-            &self.dst.geom
-        } else {
-            &self.src.geom
-        };
+    fn transport_s(self: &mut Self,
+                   forw: bool,
+                   dst: &mut Mem,
+                   ia: usize,
+                   wait_for: &[Event])
+                   -> Result<Event, Error> {
         let conservative_flag = match (forw, self.conservative_forw, self.conservative_back) {
             (true, true, _) => 1u32,
             (true, false, _) => 0u32,
@@ -442,39 +297,22 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
             (false, _, true) => 1u32,
             (false, _, false) => 0u32,
         };
-        let plane = &self.src.plane;
-
-        let s = plane.s[ia];
-        let c2 = F::from_f32(2f32).unwrap();
-
-        let alpha = Rqp.ss - Rqp.su * Rp.ss / Rp.su;
-        let beta = Rqp.su / Rp.su;
-        let gamma = Rqp.s - Rqp.su * Rp.s / Rp.su;
-        let h = (plane.ds / Rp.su).abs().min((into_geom.ds / Rqp.su).abs());
-
-        let mut taus = vec![
-            (into_geom.ds/c2 - beta*(s + plane.ds/c2) - gamma)/alpha,
-            (into_geom.ds/c2 - beta*(s - plane.ds/c2) - gamma)/alpha,
-            (-into_geom.ds/c2 - beta*(s + plane.ds/c2) - gamma)/alpha,
-            (-into_geom.ds/c2 - beta*(s - plane.ds/c2) - gamma)/alpha,
-        ];
-        taus.sort_by(|l, r| l.partial_cmp(r).unwrap());
 
         let mut kernel = self.kernel_s.clone();
         try!(self.bind_common_args(forw, &mut kernel));
 
-        try!(kernel.bind_scalar(10, &F::to_f32(&(F::one() / alpha)).unwrap()));
-        try!(kernel.bind_scalar(11, &F::to_f32(&taus[0]).unwrap()));
-        try!(kernel.bind_scalar(12, &F::to_f32(&taus[1]).unwrap()));
-        try!(kernel.bind_scalar(13, &F::to_f32(&taus[2]).unwrap()));
-        try!(kernel.bind_scalar(14, &F::to_f32(&taus[3]).unwrap()));
-        try!(kernel.bind_scalar(15, &F::to_f32(&h).unwrap()));
+        if forw {
+            try!(kernel.bind(10, &self.forw_splines_s));
+        } else {
+            try!(kernel.bind(10, &self.back_splines_s));
+        }
+        try!(kernel.bind_scalar(11, &(ia as i32)));
 
-        try!(kernel.bind(16, &self.tmp_buf));
-        try!(kernel.bind_mut(17, dst));
+        try!(kernel.bind(12, &self.tmp_buf));
+        try!(kernel.bind_mut(13, dst));
 
-        try!(kernel.bind_scalar(18, &conservative_flag));
-        try!(kernel.bind_scalar(19, &overwrite_flag));
+        try!(kernel.bind_scalar(14, &conservative_flag));
+        try!(kernel.bind_scalar(15, &overwrite_flag));
 
         let local_size = (32usize, 8usize, 1usize);
         let global_size = if forw {
@@ -483,27 +321,12 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
             (self.src_t1 - self.src_t0, self.src_s1 - self.src_s0, 1)
         };
 
-        self.queue.run_with_events(&mut kernel, local_size, global_size, wait_for)
-    }
-
-    fn forw_pillbox(self: &mut Self,
-                    src: &Mem,
-                    dst: &mut Mem,
-                    ia: usize,
-                    wait_for: &[Event])
-                    -> Result<Event, Error> {
-        let done_t = try!(self.transport_pillbox_t(true, src, ia, wait_for));
-        self.transport_pillbox_s(true, dst, ia, &[done_t])
-    }
-
-    fn back_pillbox(self: &mut Self,
-                    dst: &Mem,
-                    src: &mut Mem,
-                    ia: usize,
-                    wait_for: &[Event])
-                    -> Result<Event, Error> {
-        let done_t = try!(self.transport_pillbox_t(false, dst, ia, wait_for));
-        self.transport_pillbox_s(false, src, ia, &[done_t])
+        run_kernel(&self.queue,
+                   &mut kernel,
+                   "transport_s",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     /// Transport from source to destination, overwriting on the destination plane
@@ -513,10 +336,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
                 ia: usize,
                 wait_for: &[Event])
                 -> Result<Event, Error> {
-        match &self.src.plane.basis {
-            &AngularBasis::Dirac => self.forw_dirac(src, dst, ia, wait_for),
-            &AngularBasis::Pillbox => self.forw_pillbox(src, dst, ia, wait_for),
-        }
+        let done_t = try!(self.transport_t(true, src, ia, wait_for));
+        self.transport_s(true, dst, ia, &[done_t])
     }
 
     /// Transport from destination to source, overwriting on the destination plane
@@ -526,10 +347,8 @@ impl<F: Float + FromPrimitive + ToPrimitive> Transport<F> {
                 ia: usize,
                 wait_for: &[Event])
                 -> Result<Event, Error> {
-        match &self.src.plane.basis {
-            &AngularBasis::Dirac => self.back_dirac(dst, src, ia, wait_for),
-            &AngularBasis::Pillbox => self.back_pillbox(dst, src, ia, wait_for),
-        }
+        let done_t = try!(self.transport_t(false, dst, ia, wait_for));
+        self.transport_s(false, src, ia, &[done_t])
     }
 
     /// Transport from source to destination (all-host utility function)
@@ -4,6 +4,50 @@ use self::num::{Float, ToPrimitive, FromPrimitive};
 use self::proust::*;
 use std::marker::PhantomData;
 use cl_traits::*;
+use backend::*;
+use program_cache::*;
+use profiler::*;
+
+/// Vector operations over the buffers of a compute backend
+pub trait VectorOps<F, B: Backend<F>> {
+    /// Implements `vec[i] = val`
+    fn set(self: &mut Self,
+           np: usize,
+           vec: &mut B::Buffer,
+           val: F,
+           wait_for: &[B::Event])
+           -> Result<B::Event, B::Error>;
+
+    /// Implements `out[i] = ax*x[i] + ay*y[i]`
+    fn mix(self: &mut Self,
+           np: usize,
+           x: &B::Buffer,
+           y: &B::Buffer,
+           ax: F,
+           ay: F,
+           out: &mut B::Buffer,
+           wait_for: &[B::Event])
+           -> Result<B::Event, B::Error>;
+
+    /// Implements `y[i] = ax*x[i] + ay*y[i]`
+    fn mix_inplace(self: &mut Self,
+                   np: usize,
+                   x: &B::Buffer,
+                   ax: F,
+                   ay: F,
+                   y: &mut B::Buffer,
+                   wait_for: &[B::Event])
+                   -> Result<B::Event, B::Error>;
+
+    /// Implements `out[i] = x[i] / y[i]`
+    fn div(self: &mut Self,
+           np: usize,
+           x: &B::Buffer,
+           y: &B::Buffer,
+           out: &mut B::Buffer,
+           wait_for: &[B::Event])
+           -> Result<B::Event, B::Error>;
+}
 
 /// Common vector operations
 pub struct VectorMath<F: Float + ToPrimitive + FromPrimitive> {
@@ -22,14 +66,9 @@ impl<F: Float + ToPrimitive + FromPrimitive> ClHeader for VectorMath<F> {
 
 impl<F: Float + ToPrimitive + FromPrimitive> VectorMath<F> {
     pub fn new(queue: CommandQueue) -> Result<Self, Error> {
-        // get OpenCL objects
-        let context = try!(queue.context());
-        let device = try!(queue.device());
-
         // build program
         let sources = &[Self::header()];
-        let unbuilt = try!(Program::new_from_source(context, sources));
-        let built = try!(unbuilt.build(&[device]));
+        let built = try!(cached_program::<F>(&queue, sources));
 
         // create kernels
         let set = try!(built.create_kernel("VectorMath_set"));
@@ -54,12 +93,12 @@ impl<F: Float + ToPrimitive + FromPrimitive> VectorMath<F> {
                -> Result<Event, Error> {
         try!(self.set.bind_scalar(0, &(np as i32)));
         try!(self.set.bind_mut(1, vec));
-        try!(self.set.bind_scalar(2, &F::to_f32(&val).unwrap()));
+        try!(self.set.bind_scalar(2, &val));
 
         let local_size = (256, 1, 1);
         let global_size = (np, 1, 1);
 
-        self.queue.run_with_events(&mut self.set, local_size, global_size, wait_for)
+        run_kernel(&self.queue, &mut self.set, "VectorMath_set", local_size, global_size, wait_for)
     }
 
     /// Implements `out[i] = ax*x[i] + ay*y[i]`
@@ -75,14 +114,14 @@ impl<F: Float + ToPrimitive + FromPrimitive> VectorMath<F> {
         try!(self.mix.bind_scalar(0, &(np as i32)));
         try!(self.mix.bind(1, x));
         try!(self.mix.bind(2, y));
-        try!(self.mix.bind_scalar(3, &F::to_f32(&ax).unwrap()));
-        try!(self.mix.bind_scalar(4, &F::to_f32(&ay).unwrap()));
+        try!(self.mix.bind_scalar(3, &ax));
+        try!(self.mix.bind_scalar(4, &ay));
         try!(self.mix.bind_mut(5, out));
 
         let local_size = (256, 1, 1);
         let global_size = (np, 1, 1);
 
-        self.queue.run_with_events(&mut self.mix, local_size, global_size, wait_for)
+        run_kernel(&self.queue, &mut self.mix, "VectorMath_mix", local_size, global_size, wait_for)
     }
 
     /// Implements `out[i] = x[i] / y[i]`
@@ -101,10 +140,141 @@ impl<F: Float + ToPrimitive + FromPrimitive> VectorMath<F> {
         let local_size = (256, 1, 1);
         let global_size = (np, 1, 1);
 
-        self.queue.run_with_events(&mut self.div, local_size, global_size, wait_for)
+        run_kernel(&self.queue, &mut self.div, "VectorMath_div", local_size, global_size, wait_for)
+    }
+}
+
+impl<F: Float + ToPrimitive + FromPrimitive> VectorOps<F, ClBackend> for VectorMath<F> {
+    fn set(self: &mut Self,
+           np: usize,
+           vec: &mut Mem,
+           val: F,
+           wait_for: &[Event])
+           -> Result<Event, Error> {
+        VectorMath::set(self, np, vec, val, wait_for)
+    }
+
+    fn mix(self: &mut Self,
+           np: usize,
+           x: &Mem,
+           y: &Mem,
+           ax: F,
+           ay: F,
+           out: &mut Mem,
+           wait_for: &[Event])
+           -> Result<Event, Error> {
+        VectorMath::mix(self, np, x, y, ax, ay, out, wait_for)
+    }
+
+    fn mix_inplace(self: &mut Self,
+                   np: usize,
+                   x: &Mem,
+                   ax: F,
+                   ay: F,
+                   y: &mut Mem,
+                   wait_for: &[Event])
+                   -> Result<Event, Error> {
+        // the kernel reads y[i] before writing out[i], so aliasing is safe
+        let y_copy = y.clone();
+        VectorMath::mix(self, np, x, &y_copy, ax, ay, y, wait_for)
+    }
+
+    fn div(self: &mut Self,
+           np: usize,
+           x: &Mem,
+           y: &Mem,
+           out: &mut Mem,
+           wait_for: &[Event])
+           -> Result<Event, Error> {
+        VectorMath::div(self, np, x, y, out, wait_for)
     }
 }
 
+/// Common vector operations on host vectors
+pub struct HostVectorMath<F: Float + ToPrimitive + FromPrimitive> {
+    m_: PhantomData<F>,
+}
+
+impl<F: Float + ToPrimitive + FromPrimitive> HostVectorMath<F> {
+    pub fn new() -> Self {
+        HostVectorMath { m_: PhantomData }
+    }
+}
+
+impl<F: Float + ToPrimitive + FromPrimitive> VectorOps<F, HostBackend> for HostVectorMath<F> {
+    fn set(self: &mut Self,
+           np: usize,
+           vec: &mut Vec<F>,
+           val: F,
+           _: &[HostEvent])
+           -> Result<HostEvent, ()> {
+        for v in vec[..np].iter_mut() {
+            *v = val;
+        }
+        Ok(HostEvent)
+    }
+
+    fn mix(self: &mut Self,
+           np: usize,
+           x: &Vec<F>,
+           y: &Vec<F>,
+           ax: F,
+           ay: F,
+           out: &mut Vec<F>,
+           _: &[HostEvent])
+           -> Result<HostEvent, ()> {
+        for i in 0..np {
+            out[i] = ax * x[i] + ay * y[i];
+        }
+        Ok(HostEvent)
+    }
+
+    fn mix_inplace(self: &mut Self,
+                   np: usize,
+                   x: &Vec<F>,
+                   ax: F,
+                   ay: F,
+                   y: &mut Vec<F>,
+                   _: &[HostEvent])
+                   -> Result<HostEvent, ()> {
+        for i in 0..np {
+            y[i] = ax * x[i] + ay * y[i];
+        }
+        Ok(HostEvent)
+    }
+
+    fn div(self: &mut Self,
+           np: usize,
+           x: &Vec<F>,
+           y: &Vec<F>,
+           out: &mut Vec<F>,
+           _: &[HostEvent])
+           -> Result<HostEvent, ()> {
+        for i in 0..np {
+            out[i] = x[i] / y[i];
+        }
+        Ok(HostEvent)
+    }
+}
+
+#[test]
+fn test_host_vector_math() {
+    let mut vm = HostVectorMath::<f32>::new();
+    let x = vec![1f32, 2f32, 3f32];
+    let mut y = vec![0f32; 3];
+    let mut out = vec![0f32; 3];
+
+    VectorOps::<f32, HostBackend>::set(&mut vm, 3, &mut y, 2f32, &[]).unwrap();
+    VectorOps::<f32, HostBackend>::mix(&mut vm, 3, &x, &y, 2f32, -1f32, &mut out, &[]).unwrap();
+    assert_eq!(out, vec![0f32, 2f32, 4f32]);
+
+    VectorOps::<f32, HostBackend>::mix_inplace(&mut vm, 3, &x, 1f32, 0.5f32, &mut y, &[]).unwrap();
+    assert_eq!(y, vec![2f32, 3f32, 4f32]);
+
+    VectorOps::<f32, HostBackend>::div(&mut vm, 3, &x, &y, &mut out, &[]).unwrap();
+    assert_eq!(out, vec![0.5f32, 2f32 / 3f32, 0.75f32]);
+}
+
 #[test]
 fn test_vector_math() {
     use env::*;
@@ -11,6 +11,8 @@ use geom::*;
 use optics::*;
 use image_geom::*;
 use spline_kernel::*;
+use program_cache::*;
+use profiler::*;
 
 /// Rotates a LightVolume
 pub struct VolumeRotation<F: Float> {
@@ -79,9 +81,7 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
                src_geom: LightVolume<F>,
                queue: CommandQueue)
                -> Result<Self, Error> {
-        // get OpenCL objects and source code
-        let context = try!(queue.context());
-        let device = try!(queue.device());
+        // collect OpenCL source code
         let sources = &[Optics::<F>::header(),
                         ImageGeometry::<F>::header(),
                         LightVolume::<F>::header(),
@@ -89,8 +89,7 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
                         Self::header()];
 
         // compile program
-        let unbuilt = try!(Program::new_from_source(context.clone(), sources));
-        let program = try!(unbuilt.build(&[device]));
+        let program = try!(cached_program::<F>(&queue, sources));
 
         // get opencl kernels
         let filter_x = try!(program.create_kernel("rotate_filter_x"));
@@ -279,7 +278,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_z, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_z,
+                   "rotate_filter_z",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn forw_x(self: &mut Self, out: &Mem, wait_for: &[Event]) -> Result<Event, Error> {
@@ -291,7 +295,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_x, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_x,
+                   "rotate_filter_x",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn forw_y(self: &mut Self, out: &mut Mem, wait_for: &[Event]) -> Result<Event, Error> {
@@ -303,7 +312,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_y, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_y,
+                   "rotate_filter_y",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     pub fn forw(self: &mut Self,
@@ -329,7 +343,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_y, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_y,
+                   "rotate_filter_y",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn back_x(self: &mut Self, out: &Mem, wait_for: &[Event]) -> Result<Event, Error> {
@@ -341,7 +360,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_x, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_x,
+                   "rotate_filter_x",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn back_z(self: &mut Self, out: &mut Mem, wait_for: &[Event]) -> Result<Event, Error> {
@@ -353,7 +377,12 @@ impl<F: Float + BaseFloat + ApproxEq<F> + FromPrimitive> VolumeRotation<F> {
         let local_size = (32, 8, 1);
         let global_size = (self.dst_geom.nx, self.dst_geom.ny, self.dst_geom.nz);
 
-        self.queue.run_with_events(&mut self.filter_z, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.filter_z,
+                   "rotate_filter_z",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     pub fn back(self: &mut Self,
@@ -386,6 +415,7 @@ fn test_volume_rotation() {
         offset_y: 0.0,
         offset_z: 0.0,
         opaque: false,
+        attenuation: 1.0,
     };
 
     let mut rotator = VolumeRotation::new(&rot, src_geom.clone(), queue.clone()).unwrap();
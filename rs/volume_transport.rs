@@ -1,6 +1,6 @@
 extern crate num;
 extern crate proust;
-use self::num::{Float, FromPrimitive};
+use self::num::{Float, FromPrimitive, ToPrimitive};
 use self::proust::*;
 
 use light_volume::*;
@@ -8,13 +8,42 @@ use light_field_geom::*;
 use optics::*;
 use angular_plane::*;
 use image_geom::*;
+use geom::*;
 use cl_traits::*;
 use spline_kernel::*;
+use ray_transfer::*;
+use skew_transport::*;
 
 use std::cmp::max;
 use std::mem::size_of;
+use program_cache::*;
+use profiler::*;
+
+/// Compositing buffers for opaque volumes
+///
+/// `linearizations[ia]` is the volume last projected at angle `ia`, and
+/// `lights[ia]` the light composited from it, so `back` and
+/// `forw_linearized` reuse the compositing of `forw` rather than redo it.
+#[derive(Clone)]
+struct OpaqueBuffers {
+    ones: Mem, // ImageGeometry
+    obliquity: Mem, // ImageGeometry
+    transmittance: Mem, // ImageGeometry
+    projected: Mem, // ImageGeometry
+    direction: Mem, // ImageGeometry
+    seen: Mem, // ImageGeometry
+    weights: Mem, // ImageGeometry
+    lights: Vec<Mem>, // [ImageGeometry]*na
+    linearizations: Vec<Option<Mem>>, // [LightVolume]*na
+}
 
 /// Transport for `LightVolume` objects
+///
+/// Opaque volumes are composited front-to-back for each angle, and `back`
+/// applies the adjoint of the compositing linearized about the volume last
+/// passed to `forw`; see `HostVolumeTransport`.  The volume is not copied:
+/// the transport keeps a handle to the buffer passed to `forw`, which must
+/// not change until the matching `back`.
 pub struct VolumeTransport<F: Float> {
     pub geom: LightVolume<F>,
     pub dst: LightFieldGeometry<F>,
@@ -30,6 +59,9 @@ pub struct VolumeTransport<F: Float> {
     back_s_kernel: Kernel,
     scale_kernel: Kernel,
     zero_kernel: Kernel,
+    composite_kernel: Kernel,
+    linearize_kernel: Kernel,
+    tangent_kernel: Kernel,
 
     tmp: Mem, // half-filtered volume
     scaled: Mem, // scaled light field
@@ -43,6 +75,9 @@ pub struct VolumeTransport<F: Float> {
     forw_spline_kernels_t: Mem, // [SplineKernel]*nz*na
     back_spline_kernels_s: Mem, // [SplineKernel]*nz*na
     back_spline_kernels_t: Mem, // [SplineKernel]*nz*na
+
+    absorption: Vec<F>, // nz*na
+    opaque: Option<OpaqueBuffers>,
 }
 
 impl<F> VolumeTransport<F> where F: Float + FromPrimitive
@@ -72,6 +107,7 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
                  LightVolume::<F>::header(),
                  SplineKernel::<F>::header(),
                  include_str!("../cl/transport_pillbox_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl"),
                  include_str!("../cl/volume_transport_pillbox_f32.opencl")]
             }
             &AngularBasis::Dirac => {
@@ -80,15 +116,22 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
                  LightVolume::<F>::header(),
                  SplineKernel::<F>::header(),
                  include_str!("../cl/transport_dirac_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl"),
                  include_str!("../cl/volume_transport_dirac_f32.opencl")]
             }
+            &AngularBasis::Linear => {
+                [ImageGeometry::<F>::header(),
+                 Optics::<F>::header(),
+                 LightVolume::<F>::header(),
+                 SplineKernel::<F>::header(),
+                 include_str!("../cl/transport_linear_f32.opencl"),
+                 include_str!("../cl/transport_f32.opencl"),
+                 include_str!("../cl/volume_transport_pillbox_f32.opencl")]
+            }
         };
 
         // compile opencl code
-        let context = try!(queue.context());
-        let device = try!(queue.device());
-        let unbuilt = try!(Program::new_from_source(context.clone(), &sources));
-        let program = try!(unbuilt.build(&[device]));
+        let program = try!(cached_program::<F>(&queue, &sources));
 
         // get opencl kernels
         let forw_t_kernel = try!(program.create_kernel("volume_forw_t"));
@@ -97,6 +140,9 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let back_s_kernel = try!(program.create_kernel("volume_back_s"));
         let scale_kernel = try!(program.create_kernel("volume_scale"));
         let zero_kernel = try!(program.create_kernel("image_zero"));
+        let composite_kernel = try!(program.create_kernel("volume_composite"));
+        let linearize_kernel = try!(program.create_kernel("volume_linearize"));
+        let tangent_kernel = try!(program.create_kernel("volume_tangent"));
 
         // size of temporary buffers
         let tmp_nx = max(src.nx, dst.geom.ns);
@@ -111,6 +157,28 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let dst_to_obj = try!(to_plane.invert().compose(&dst.to_plane).as_cl_buffer(&queue));
         let scaled = try!(queue.create_buffer(size_of::<F>() * dst.geom.ns * dst.geom.nt));
 
+        let opaque = if src.opaque {
+            let dst_bytes = size_of::<F>() * dst.geom.ns * dst.geom.nt;
+            let na = dst.plane.s.len();
+            let mut lights = Vec::with_capacity(na);
+            for _ in 0..na {
+                lights.push(try!(queue.create_buffer(dst_bytes)));
+            }
+            Some(OpaqueBuffers {
+                ones: try!(dst.geom.ones_buf(&queue)),
+                obliquity: try!(queue.create_buffer(dst_bytes)),
+                transmittance: try!(queue.create_buffer(dst_bytes)),
+                projected: try!(queue.create_buffer(dst_bytes)),
+                direction: try!(queue.create_buffer(dst_bytes)),
+                seen: try!(queue.create_buffer(dst_bytes)),
+                weights: try!(queue.create_buffer(dst_bytes)),
+                lights: lights,
+                linearizations: vec![None; na],
+            })
+        } else {
+            None
+        };
+
         // slice buffers
         //
         // we precompute the footprints for each angle and slice.  this takes
@@ -122,12 +190,20 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let mut forw_spline_kernels_t_buf: Vec<u8> = Vec::new();
         let mut back_spline_kernels_s_buf: Vec<u8> = Vec::new();
         let mut back_spline_kernels_t_buf: Vec<u8> = Vec::new();
+        let mut absorption = Vec::with_capacity(src.nz * dst.plane.s.len());
         for iz in 0..src.nz {
             let slice_lfg = src.slice_light_field_geometry(iz, dst.plane.clone(), to_plane.clone());
             for ia in 0..dst.plane.s.len() {
                 let (forw_s, forw_t) = slice_lfg.transport_to(&dst, ia);
                 let (back_s, back_t) = dst.transport_to(&slice_lfg, ia);
 
+                // the Dirac kernels do not apply the slice scale factor
+                let slice_scale = match forw_s {
+                    SplineKernel::Rect(_, _, _) => F::one(),
+                    _ => scale_factor(&src, &dst, ia, onto_detector),
+                };
+                absorption.push(src.slice_absorption(&forw_s, &forw_t, slice_scale));
+
                 forw_s.as_cl_bytes(&mut forw_spline_kernels_s_buf);
                 forw_t.as_cl_bytes(&mut forw_spline_kernels_t_buf);
                 back_s.as_cl_bytes(&mut back_spline_kernels_s_buf);
@@ -160,6 +236,9 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
             back_s_kernel: back_s_kernel,
             scale_kernel: scale_kernel,
             zero_kernel: zero_kernel,
+            composite_kernel: composite_kernel,
+            linearize_kernel: linearize_kernel,
+            tangent_kernel: tangent_kernel,
 
             tmp: tmp,
             volume_geom: volume_geom,
@@ -173,6 +252,9 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
             forw_spline_kernels_t: forw_spline_kernels_t,
             back_spline_kernels_s: back_spline_kernels_s,
             back_spline_kernels_t: back_spline_kernels_t,
+
+            absorption: absorption,
+            opaque: opaque,
         })
     }
 
@@ -193,8 +275,8 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         try!(self.forw_t_kernel.bind(3, &self.forw_spline_kernels_t));
         try!(self.forw_t_kernel.bind_scalar(4, &(ia as i32)));
         try!(self.forw_t_kernel.bind_scalar(5, &(na as i32)));
-        try!(self.forw_t_kernel.bind_scalar(6, &F::to_f32(&u).unwrap()));
-        try!(self.forw_t_kernel.bind_scalar(7, &F::to_f32(&v).unwrap()));
+        try!(self.forw_t_kernel.bind_scalar(6, &u));
+        try!(self.forw_t_kernel.bind_scalar(7, &v));
         try!(self.forw_t_kernel.bind_scalar(8, &(iz as i32)));
         try!(self.forw_t_kernel.bind(9, vol));
         try!(self.forw_t_kernel.bind_mut(10, &mut self.tmp));
@@ -202,22 +284,29 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let local_size = (32, 8, 1);
         let global_size = (self.geom.nx, self.dst.geom.nt, 1);
 
-        self.queue.run_with_events(&mut self.forw_t_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.forw_t_kernel,
+                   "volume_forw_t",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn forw_s(self: &mut Self,
               dst: &mut Mem,
               ia: usize,
               iz: usize,
+              overwrite: bool,
               wait_for: &[Event])
               -> Result<Event, Error> {
         let na = self.dst.plane.s.len();
         let u = self.dst.plane.s[ia];
         let v = self.dst.plane.t[ia];
-        let scale = if self.onto_detector {
-            self.geom.dz.abs() / self.dst.pixel_volume().sqrt() * self.dst.plane.w[ia]
+        let scale = scale_factor(&self.geom, &self.dst, ia, self.onto_detector);
+        let overwrite_flag = if overwrite {
+            1u32
         } else {
-            self.geom.dz.abs() / self.dst.pixel_volume()
+            0u32
         };
 
         // bind arguments
@@ -227,17 +316,23 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         try!(self.forw_s_kernel.bind(3, &self.forw_spline_kernels_s));
         try!(self.forw_s_kernel.bind_scalar(4, &(ia as i32)));
         try!(self.forw_s_kernel.bind_scalar(5, &(na as i32)));
-        try!(self.forw_s_kernel.bind_scalar(6, &F::to_f32(&u).unwrap()));
-        try!(self.forw_s_kernel.bind_scalar(7, &F::to_f32(&v).unwrap()));
+        try!(self.forw_s_kernel.bind_scalar(6, &u));
+        try!(self.forw_s_kernel.bind_scalar(7, &v));
         try!(self.forw_s_kernel.bind_scalar(8, &(iz as i32)));
         try!(self.forw_s_kernel.bind_scalar(9, &scale));
         try!(self.forw_s_kernel.bind(10, &self.tmp));
         try!(self.forw_s_kernel.bind_mut(11, dst));
+        try!(self.forw_s_kernel.bind_scalar(12, &overwrite_flag));
 
         let local_size = (32, 8, 1);
         let global_size = (self.dst.geom.nt, self.dst.geom.ns, 1);
 
-        self.queue.run_with_events(&mut self.forw_s_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.forw_s_kernel,
+                   "volume_forw_s",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn back_t(self: &mut Self,
@@ -249,11 +344,7 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let na = self.dst.plane.s.len();
         let u = self.dst.plane.s[ia];
         let v = self.dst.plane.t[ia];
-        let scale = if self.onto_detector {
-            self.geom.dz.abs() / self.dst.pixel_volume().sqrt() * self.dst.plane.w[ia]
-        } else {
-            self.geom.dz.abs() / self.dst.pixel_volume()
-        };
+        let scale = scale_factor(&self.geom, &self.dst, ia, self.onto_detector);
 
         // bind arguments
         try!(self.back_t_kernel.bind(0, &self.volume_geom));
@@ -262,8 +353,8 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         try!(self.back_t_kernel.bind(3, &self.back_spline_kernels_t));
         try!(self.back_t_kernel.bind_scalar(4, &(ia as i32)));
         try!(self.back_t_kernel.bind_scalar(5, &(na as i32)));
-        try!(self.back_t_kernel.bind_scalar(6, &F::to_f32(&u).unwrap()));
-        try!(self.back_t_kernel.bind_scalar(7, &F::to_f32(&v).unwrap()));
+        try!(self.back_t_kernel.bind_scalar(6, &u));
+        try!(self.back_t_kernel.bind_scalar(7, &v));
         try!(self.back_t_kernel.bind_scalar(8, &(iz as i32)));
         try!(self.back_t_kernel.bind_scalar(9, &scale));
         try!(self.back_t_kernel.bind(10, dst));
@@ -272,7 +363,12 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let local_size = (32, 8, 1);
         let global_size = (self.dst.geom.ns, self.geom.ny, 1);
 
-        self.queue.run_with_events(&mut self.back_t_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.back_t_kernel,
+                   "volume_back_t",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn back_s(self: &mut Self,
@@ -297,8 +393,8 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         try!(self.back_s_kernel.bind(3, &self.back_spline_kernels_s));
         try!(self.back_s_kernel.bind_scalar(4, &(ia as i32)));
         try!(self.back_s_kernel.bind_scalar(5, &(na as i32)));
-        try!(self.back_s_kernel.bind_scalar(6, &F::to_f32(&u).unwrap()));
-        try!(self.back_s_kernel.bind_scalar(7, &F::to_f32(&v).unwrap()));
+        try!(self.back_s_kernel.bind_scalar(6, &u));
+        try!(self.back_s_kernel.bind_scalar(7, &v));
         try!(self.back_s_kernel.bind_scalar(8, &(iz as i32)));
         try!(self.back_s_kernel.bind(9, &self.tmp));
         try!(self.back_s_kernel.bind_mut(10, vol));
@@ -307,7 +403,12 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let local_size = (32, 8, 1);
         let global_size = (self.geom.ny, self.geom.nx, 1);
 
-        self.queue.run_with_events(&mut self.back_s_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.back_s_kernel,
+                   "volume_back_s",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn scale(self: &mut Self,
@@ -329,8 +430,8 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         try!(self.scale_kernel.bind(0, &self.dst_geom));
         try!(self.scale_kernel.bind(1, &self.dst_to_root));
         try!(self.scale_kernel.bind(2, &self.dst_to_obj));
-        try!(self.scale_kernel.bind_scalar(3, &F::to_f32(&s).unwrap()));
-        try!(self.scale_kernel.bind_scalar(4, &F::to_f32(&t).unwrap()));
+        try!(self.scale_kernel.bind_scalar(3, &s));
+        try!(self.scale_kernel.bind_scalar(4, &t));
         try!(self.scale_kernel.bind(5, input));
         try!(self.scale_kernel.bind_mut(6, output));
         try!(self.scale_kernel.bind_scalar(7, &overwrite_flag));
@@ -338,7 +439,12 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let local_size = (32, 8, 1);
         let global_size = (self.dst.geom.ns, self.dst.geom.nt, 1);
 
-        self.queue.run_with_events(&mut self.scale_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.scale_kernel,
+                   "volume_scale",
+                   local_size,
+                   global_size,
+                   wait_for)
     }
 
     fn zero(self: &mut Self, img: &mut Mem, wait_for: &[Event]) -> Result<Event, Error> {
@@ -348,7 +454,162 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let local_size = (32, 8, 1);
         let global_size = (self.dst.geom.ns, self.dst.geom.nt, 1);
 
-        self.queue.run_with_events(&mut self.zero_kernel, local_size, global_size, wait_for)
+        run_kernel(&self.queue,
+                   &mut self.zero_kernel,
+                   "image_zero",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+
+    fn composite(self: &mut Self,
+                 buffers: &mut OpaqueBuffers,
+                 ia: usize,
+                 iz: usize,
+                 first: bool,
+                 wait_for: &[Event])
+                 -> Result<Event, Error> {
+        let na = self.dst.plane.s.len();
+        let first_flag = if first {
+            1u32
+        } else {
+            0u32
+        };
+
+        // bind arguments
+        try!(self.composite_kernel.bind(0, &self.dst_geom));
+        try!(self.composite_kernel.bind_scalar(1, &self.absorption[na * iz + ia]));
+        try!(self.composite_kernel.bind(2, &buffers.obliquity));
+        try!(self.composite_kernel.bind(3, &buffers.projected));
+        try!(self.composite_kernel.bind_mut(4, &mut buffers.transmittance));
+        try!(self.composite_kernel.bind_mut(5, &mut buffers.lights[ia]));
+        try!(self.composite_kernel.bind_scalar(6, &first_flag));
+
+        let local_size = (32, 8, 1);
+        let global_size = (self.dst.geom.ns, self.dst.geom.nt, 1);
+
+        run_kernel(&self.queue,
+                   &mut self.composite_kernel,
+                   "volume_composite",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+
+    fn linearize(self: &mut Self,
+                 buffers: &mut OpaqueBuffers,
+                 scaled: &Mem,
+                 ia: usize,
+                 iz: usize,
+                 first: bool,
+                 wait_for: &[Event])
+                 -> Result<Event, Error> {
+        let na = self.dst.plane.s.len();
+        let first_flag = if first {
+            1u32
+        } else {
+            0u32
+        };
+
+        // bind arguments
+        try!(self.linearize_kernel.bind(0, &self.dst_geom));
+        try!(self.linearize_kernel.bind_scalar(1, &self.absorption[na * iz + ia]));
+        try!(self.linearize_kernel.bind(2, &buffers.obliquity));
+        try!(self.linearize_kernel.bind(3, scaled));
+        try!(self.linearize_kernel.bind(4, &buffers.projected));
+        try!(self.linearize_kernel.bind(5, &buffers.lights[ia]));
+        try!(self.linearize_kernel.bind_mut(6, &mut buffers.transmittance));
+        try!(self.linearize_kernel.bind_mut(7, &mut buffers.seen));
+        try!(self.linearize_kernel.bind_mut(8, &mut buffers.weights));
+        try!(self.linearize_kernel.bind_scalar(9, &first_flag));
+
+        let local_size = (32, 8, 1);
+        let global_size = (self.dst.geom.ns, self.dst.geom.nt, 1);
+
+        run_kernel(&self.queue,
+                   &mut self.linearize_kernel,
+                   "volume_linearize",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+
+    /// Accumulates the tangent along `buffers.direction` into
+    /// `buffers.weights`
+    fn tangent(self: &mut Self,
+               buffers: &mut OpaqueBuffers,
+               ia: usize,
+               iz: usize,
+               first: bool,
+               wait_for: &[Event])
+               -> Result<Event, Error> {
+        let na = self.dst.plane.s.len();
+        let first_flag = if first {
+            1u32
+        } else {
+            0u32
+        };
+
+        // bind arguments
+        try!(self.tangent_kernel.bind(0, &self.dst_geom));
+        try!(self.tangent_kernel.bind_scalar(1, &self.absorption[na * iz + ia]));
+        try!(self.tangent_kernel.bind(2, &buffers.obliquity));
+        try!(self.tangent_kernel.bind(3, &buffers.direction));
+        try!(self.tangent_kernel.bind(4, &buffers.projected));
+        try!(self.tangent_kernel.bind(5, &buffers.lights[ia]));
+        try!(self.tangent_kernel.bind_mut(6, &mut buffers.transmittance));
+        try!(self.tangent_kernel.bind_mut(7, &mut buffers.seen));
+        try!(self.tangent_kernel.bind_mut(8, &mut buffers.weights));
+        try!(self.tangent_kernel.bind_scalar(9, &first_flag));
+
+        let local_size = (32, 8, 1);
+        let global_size = (self.dst.geom.ns, self.dst.geom.nt, 1);
+
+        run_kernel(&self.queue,
+                   &mut self.tangent_kernel,
+                   "volume_tangent",
+                   local_size,
+                   global_size,
+                   wait_for)
+    }
+
+    /// Path length through a unit slab for each ray of angle `ia`
+    fn obliquity(self: &mut Self,
+                 buffers: &mut OpaqueBuffers,
+                 ia: usize,
+                 wait_for: &[Event])
+                 -> Result<Event, Error> {
+        let ones = buffers.ones.clone();
+        self.scale(&ones, &mut buffers.obliquity, ia, wait_for, true)
+    }
+
+    /// Composites `vol` front-to-back into `buffers.lights[ia]`
+    fn composite_volume(self: &mut Self,
+                        vol: &Mem,
+                        buffers: &mut OpaqueBuffers,
+                        ia: usize,
+                        wait_for: &[Event])
+                        -> Result<Event, Error> {
+        let order = self.geom.slices_front_to_back();
+        let mut projected = buffers.projected.clone();
+
+        let mut evt = try!(self.obliquity(buffers, ia, wait_for));
+
+        for (k, &iz) in order.iter().enumerate() {
+            evt = try!(self.forw_t(vol, ia, iz, &[evt]));
+            evt = try!(self.forw_s(&mut projected, ia, iz, true, &[evt]));
+            evt = try!(self.composite(buffers, ia, iz, k == 0, &[evt]));
+        }
+
+        Ok(evt)
+    }
+
+    /// The volume `forw` last projected at angle `ia`, if any
+    fn linearization(self: &Self, ia: usize) -> Option<Mem> {
+        match self.opaque {
+            Some(ref buffers) => buffers.linearizations[ia].clone(),
+            None => None,
+        }
     }
 
     pub fn forw(self: &mut Self,
@@ -357,21 +618,103 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
                 ia: usize,
                 wait_for: &[Event])
                 -> Result<Event, Error> {
+        if self.opaque.is_some() {
+            return self.forw_opaque(vol, dst, ia, wait_for);
+        }
+        self.forw_emission(vol, dst, ia, wait_for)
+    }
+
+    /// Project the emission of a volume, ignoring any absorption
+    fn forw_emission(self: &mut Self,
+                     vol: &Mem,
+                     dst: &mut Mem,
+                     ia: usize,
+                     wait_for: &[Event])
+                     -> Result<Event, Error> {
         let mut tmp_buf = self.scaled.clone();
         let mut evt = try!(self.zero(&mut tmp_buf, wait_for));
 
         evt = try!(self.forw_t(vol, ia, 0, &[evt]));
-        evt = try!(self.forw_s(&mut tmp_buf, ia, 0, &[evt]));
+        evt = try!(self.forw_s(&mut tmp_buf, ia, 0, false, &[evt]));
 
         for iz in 1..self.geom.nz {
             evt = try!(self.forw_t(vol, ia, iz, &[evt]));
-            evt = try!(self.forw_s(&mut tmp_buf, ia, iz, &[evt]));
+            evt = try!(self.forw_s(&mut tmp_buf, ia, iz, false, &[evt]));
         }
 
         let overwrite_forw = self.overwrite_forw;
         self.scale(&tmp_buf, dst, ia, &[evt], overwrite_forw)
     }
 
+    fn forw_opaque(self: &mut Self,
+                   vol: &Mem,
+                   dst: &mut Mem,
+                   ia: usize,
+                   wait_for: &[Event])
+                   -> Result<Event, Error> {
+        let mut buffers = self.opaque.clone().unwrap();
+        let evt = try!(self.composite_volume(vol, &mut buffers, ia, wait_for));
+
+        // record the linearization point for `back`
+        if let Some(ref mut opaque) = self.opaque {
+            opaque.linearizations[ia] = Some(vol.clone());
+        }
+
+        let overwrite_forw = self.overwrite_forw;
+        self.scale(&buffers.lights[ia], dst, ia, &[evt], overwrite_forw)
+    }
+
+    /// Project a volume through the derivative of `forw`
+    ///
+    /// The derivative is taken at the same point as in `back`, so this is
+    /// the operator `back` is the adjoint of.  It is `forw` itself for
+    /// volumes that are not opaque.
+    pub fn forw_linearized(self: &mut Self,
+                           vol: &Mem,
+                           dst: &mut Mem,
+                           ia: usize,
+                           wait_for: &[Event])
+                           -> Result<Event, Error> {
+        match self.linearization(ia) {
+            Some(lin) => {
+                let mut buffers = self.opaque.clone().unwrap();
+                self.forw_tangent(vol, &lin, &mut buffers, dst, ia, wait_for)
+            }
+            None => self.forw_emission(vol, dst, ia, wait_for),
+        }
+    }
+
+    fn forw_tangent(self: &mut Self,
+                    vol: &Mem,
+                    lin: &Mem,
+                    buffers: &mut OpaqueBuffers,
+                    dst: &mut Mem,
+                    ia: usize,
+                    wait_for: &[Event])
+                    -> Result<Event, Error> {
+        let order = self.geom.slices_front_to_back();
+        let mut projected = buffers.projected.clone();
+        let mut direction = buffers.direction.clone();
+
+        let mut evt = try!(self.obliquity(buffers, ia, wait_for));
+
+        for (k, &iz) in order.iter().enumerate() {
+            evt = try!(self.forw_t(lin, ia, iz, &[evt]));
+            evt = try!(self.forw_s(&mut projected, ia, iz, true, &[evt]));
+            evt = try!(self.forw_t(vol, ia, iz, &[evt]));
+            evt = try!(self.forw_s(&mut direction, ia, iz, true, &[evt]));
+            evt = try!(self.tangent(buffers, ia, iz, k == 0, &[evt]));
+        }
+
+        let overwrite_forw = self.overwrite_forw;
+        self.scale(&buffers.weights, dst, ia, &[evt], overwrite_forw)
+    }
+
+    /// Backproject into a volume
+    ///
+    /// For opaque volumes this is the adjoint of the derivative of `forw`
+    /// at the volume last projected at this angle, or at zero (where the
+    /// volume is transparent) if nothing has been projected yet.
     pub fn back(self: &mut Self,
                 dst: &Mem,
                 vol: &mut Mem,
@@ -381,8 +724,42 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
         let mut scaled_copy = self.scaled.clone();
         let mut evt = try!(self.scale(dst, &mut scaled_copy, ia, wait_for, true));
 
-        for iz in 0..self.geom.nz {
-            evt = try!(self.back_t(&scaled_copy, ia, iz, &[evt]));
+        match self.linearization(ia) {
+            Some(lin) => {
+                let mut buffers = self.opaque.clone().unwrap();
+                self.back_opaque(&scaled_copy, &lin, &mut buffers, vol, ia, &[evt])
+            }
+            None => {
+                for iz in 0..self.geom.nz {
+                    evt = try!(self.back_t(&scaled_copy, ia, iz, &[evt]));
+                    evt = try!(self.back_s(vol, ia, iz, &[evt]));
+                }
+                Ok(evt)
+            }
+        }
+    }
+
+    fn back_opaque(self: &mut Self,
+                   scaled: &Mem,
+                   lin: &Mem,
+                   buffers: &mut OpaqueBuffers,
+                   vol: &mut Mem,
+                   ia: usize,
+                   wait_for: &[Event])
+                   -> Result<Event, Error> {
+        let order = self.geom.slices_front_to_back();
+        let mut projected = buffers.projected.clone();
+        let weights = buffers.weights.clone();
+
+        // the total light at the linearization point is left over from
+        // `forw`, so only the obliquity is recomputed
+        let mut evt = try!(self.obliquity(buffers, ia, wait_for));
+
+        for (k, &iz) in order.iter().enumerate() {
+            evt = try!(self.forw_t(lin, ia, iz, &[evt]));
+            evt = try!(self.forw_s(&mut projected, ia, iz, true, &[evt]));
+            evt = try!(self.linearize(buffers, scaled, ia, iz, k == 0, &[evt]));
+            evt = try!(self.back_t(&weights, ia, iz, &[evt]));
             evt = try!(self.back_s(vol, ia, iz, &[evt]));
         }
 
@@ -390,6 +767,142 @@ impl<F> VolumeTransport<F> where F: Float + FromPrimitive
     }
 }
 
+/// Scale factor applied to each projected slice
+fn scale_factor<F>(geom: &LightVolume<F>,
+                   dst: &LightFieldGeometry<F>,
+                   ia: usize,
+                   onto_detector: bool)
+                   -> F
+    where F: Float + FromPrimitive
+{
+    if onto_detector {
+        geom.dz.abs() / dst.pixel_volume().sqrt() * dst.plane.w[ia]
+    } else {
+        geom.dz.abs() / dst.pixel_volume()
+    }
+}
+
+/// Transport from a volume through a camera's main lens
+///
+/// Lenses whose ray transfer is separable use a `VolumeTransport`.  Lenses
+/// that couple `s` and `t` use a `SkewVolumeTransport`, which only runs on
+/// the host, so every projection copies the volume and the view through host
+/// memory and is much slower.
+pub enum LensVolumeTransport<F: Float> {
+    Separable(VolumeTransport<F>),
+
+    /// Host transport with host copies of the volume and the destination
+    Skew(SkewVolumeTransport<F>, Vec<F>, Vec<F>, CommandQueue),
+}
+
+impl<F> LensVolumeTransport<F> where F: Float + FromPrimitive + ToPrimitive
+{
+    /// Create a new `LensVolumeTransport`
+    ///
+    /// `to_plane` is the ray transfer from the `z=0` plane of the volume to
+    /// the angular plane of `dst`; the arguments are otherwise those of
+    /// `VolumeTransport::new`.
+    pub fn new(src: LightVolume<F>,
+               dst: LightFieldGeometry<F>,
+               to_plane: RayTransfer<F>,
+               overwrite_forw: bool,
+               overwrite_back: bool,
+               onto_detector: bool,
+               queue: CommandQueue)
+               -> Result<Self, Error> {
+        match to_plane.as_optics() {
+            Some(optics) => {
+                Ok(LensVolumeTransport::Separable(try!(VolumeTransport::new(src,
+                                                                            dst,
+                                                                            optics,
+                                                                            overwrite_forw,
+                                                                            overwrite_back,
+                                                                            onto_detector,
+                                                                            queue))))
+            }
+            None => {
+                let src_np = src.nx * src.ny * src.nz;
+                let dst_np = dst.geom.ns * dst.geom.nt;
+                Ok(LensVolumeTransport::Skew(SkewVolumeTransport::new(src,
+                                                                      dst,
+                                                                      to_plane,
+                                                                      overwrite_forw,
+                                                                      overwrite_back,
+                                                                      onto_detector),
+                                             vec![F::zero(); src_np],
+                                             vec![F::zero(); dst_np],
+                                             queue))
+            }
+        }
+    }
+
+    pub fn forw(self: &mut Self,
+                vol: &Mem,
+                dst: &mut Mem,
+                ia: usize,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        match self {
+            &mut LensVolumeTransport::Separable(ref mut xport) => {
+                xport.forw(vol, dst, ia, wait_for)
+            }
+            &mut LensVolumeTransport::Skew(ref mut xport,
+                                           ref mut vol_host,
+                                           ref mut dst_host,
+                                           ref queue) => {
+                for evt in wait_for.iter() {
+                    try!(evt.wait());
+                }
+                try!(try!(queue.read_buffer(vol, vol_host)).wait());
+                try!(try!(queue.read_buffer(dst, dst_host)).wait());
+                xport.forw(vol_host, dst_host, ia);
+                queue.write_buffer(dst, dst_host)
+            }
+        }
+    }
+
+    /// Project through the derivative of `forw`; skew transports do not
+    /// support opaque volumes, so there this is `forw`
+    pub fn forw_linearized(self: &mut Self,
+                           vol: &Mem,
+                           dst: &mut Mem,
+                           ia: usize,
+                           wait_for: &[Event])
+                           -> Result<Event, Error> {
+        match self {
+            &mut LensVolumeTransport::Separable(ref mut xport) => {
+                xport.forw_linearized(vol, dst, ia, wait_for)
+            }
+            _ => self.forw(vol, dst, ia, wait_for),
+        }
+    }
+
+    pub fn back(self: &mut Self,
+                dst: &Mem,
+                vol: &mut Mem,
+                ia: usize,
+                wait_for: &[Event])
+                -> Result<Event, Error> {
+        match self {
+            &mut LensVolumeTransport::Separable(ref mut xport) => {
+                xport.back(dst, vol, ia, wait_for)
+            }
+            &mut LensVolumeTransport::Skew(ref mut xport,
+                                           ref mut vol_host,
+                                           ref mut dst_host,
+                                           ref queue) => {
+                for evt in wait_for.iter() {
+                    try!(evt.wait());
+                }
+                try!(try!(queue.read_buffer(dst, dst_host)).wait());
+                try!(try!(queue.read_buffer(vol, vol_host)).wait());
+                xport.back(dst_host, vol_host, ia);
+                queue.write_buffer(vol, vol_host)
+            }
+        }
+    }
+}
+
 #[test]
 fn test_volume_dirac() {
     use env::*;
@@ -420,6 +933,7 @@ fn test_volume_dirac() {
         offset_y: 2.9,
         offset_z: 0.0,
         opaque: false,
+        attenuation: 1.0,
     };
 
     let dst_geom = ImageGeometry {
@@ -497,6 +1011,7 @@ fn test_volume_pillbox() {
         offset_y: 2.9,
         offset_z: 0.0,
         opaque: false,
+        attenuation: 1.0,
     };
 
     let dst_geom = ImageGeometry {
@@ -543,3 +1058,90 @@ fn test_volume_pillbox() {
 
     assert!(nrmse < 1e-2);
 }
+
+#[test]
+fn test_volume_opaque_matches_host() {
+    use env::*;
+    use lens::*;
+    use host_transport::*;
+
+    let env = Environment::new_easy().unwrap();
+    let queue = &env.queues[0];
+
+    let lens = Lens {
+        center_s: 1f32,
+        center_t: -1.5f32,
+        radius_s: 20f32,
+        radius_t: 15f32,
+        focal_length_s: 30f32,
+        focal_length_t: 35f32,
+    };
+
+    for basis in vec![AngularBasis::Dirac, AngularBasis::Pillbox, AngularBasis::Linear] {
+        let plane = lens.as_angular_plane(basis, 5);
+
+        let vg = LightVolume {
+            nx: 20,
+            ny: 30,
+            nz: 4,
+            dx: 1.0,
+            dy: 1.1,
+            dz: 1.0,
+            offset_x: 0.5,
+            offset_y: 2.9,
+            offset_z: 0.0,
+            opaque: true,
+            attenuation: 0.5,
+        };
+        let dst_geom = ImageGeometry {
+            ns: 64,
+            nt: 96,
+            ds: 2e-1,
+            dt: 1.5e-1,
+            offset_s: -4.0,
+            offset_t: 2.1,
+        };
+        let dst = LightFieldGeometry {
+            geom: dst_geom.clone(),
+            plane: plane,
+            to_plane: Optics::translation(&40f32),
+        };
+        let to_plane = lens.optics().then(&Optics::translation(&500f32)).invert();
+
+        let mut host = HostVolumeTransport::new_simple(vg.clone(), dst.clone(), to_plane.clone());
+        let mut cl = VolumeTransport::new_simple(vg.clone(), dst, to_plane, queue.clone())
+                         .unwrap();
+
+        let x = vg.rands();
+        let y = dst_geom.rands();
+        let mut host_x = dst_geom.zeros();
+        let mut host_y = vg.zeros();
+        host.forw(&x, &mut host_x, 12);
+        host.back(&y, &mut host_y, 12);
+        let mut host_t = dst_geom.zeros();
+        host.forw_linearized(&x, &mut host_t, 12);
+
+        let x_buf = queue.create_buffer_from_slice(&x).unwrap();
+        let y_buf = queue.create_buffer_from_slice(&y).unwrap();
+        let mut cl_x_buf = dst_geom.zeros_buf(&queue).unwrap();
+        let mut cl_y_buf = vg.zeros_buf(&queue).unwrap();
+        cl.forw(&x_buf, &mut cl_x_buf, 12, &[]).unwrap().wait().unwrap();
+        cl.back(&y_buf, &mut cl_y_buf, 12, &[]).unwrap().wait().unwrap();
+        let mut cl_t_buf = dst_geom.zeros_buf(&queue).unwrap();
+        cl.forw_linearized(&x_buf, &mut cl_t_buf, 12, &[]).unwrap().wait().unwrap();
+        let mut cl_x = dst_geom.zeros();
+        let mut cl_y = vg.zeros();
+        let mut cl_t = dst_geom.zeros();
+        queue.read_buffer(&cl_x_buf, &mut cl_x).unwrap();
+        queue.read_buffer(&cl_y_buf, &mut cl_y).unwrap();
+        queue.read_buffer(&cl_t_buf, &mut cl_t).unwrap();
+
+        for (h, c) in vec![(host_x, cl_x), (host_y, cl_y), (host_t, cl_t)] {
+            let err = h.iter().zip(c.iter()).fold(0f32, |s, (a, b)| s + (a - b) * (a - b));
+            let nrm = h.iter().fold(0f32, |s, a| s + a * a);
+            let nrmse = (err / nrm).sqrt();
+            println!("NRMSE between opaque HostVolumeTransport and VolumeTransport: {}", nrmse);
+            assert!(nrmse < 1e-3);
+        }
+    }
+}